@@ -0,0 +1,779 @@
+// ---------------------------
+// Input expansion & album grouping
+// ---------------------------
+
+use crate::PlanEntry;
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use teloxide::types::{InlineKeyboardMarkup, InputMedia, ParseMode};
+use walkdir::WalkDir;
+
+/// Telegram's limit for a single media group
+pub const MAX_ITEMS: usize = 10;
+
+const SUBTITLE_EXTENSIONS: [&str; 2] = ["srt", "vtt"];
+
+/// Caption files for a whole directory, in the order they are looked for
+const ALBUM_CAPTION_FILES: [&str; 2] = ["album.txt", "_caption.txt"];
+
+/// A processed file ready to go into a media group
+pub struct AlbumItem {
+    pub entry: PlanEntry,
+    pub media: InputMedia,
+    // The item's own caption (sidecar + static, or the default caption)
+    pub caption: String,
+    // The same in the other chats' caption languages, by language
+    pub captions: HashMap<Option<String>, String>,
+    pub parse_mode: Option<ParseMode>,
+    // The file uploaded for `media` (a muted or remuxed copy for some videos)
+    pub upload: PathBuf,
+    // Size of the uploaded file, for --max-chunk-bytes
+    pub bytes: u64,
+    // Width and height as displayed, for the orientation check
+    pub dimensions: Option<(u32, u32)>,
+    // Buttons from the sidecar or package, for when it is sent on its own
+    pub keyboard: Option<InlineKeyboardMarkup>,
+}
+
+/// Files sent together as one or more consecutive media groups
+pub struct Album {
+    // Shown in logs, the dry-run plan and the summary
+    pub label: String,
+    // album.txt content or the directory's pretty name
+    pub caption: Option<String>,
+    // The same in the other chats' caption languages, by language
+    pub captions: HashMap<Option<String>, String>,
+    // The album.txt the caption was read from
+    pub caption_file: Option<PathBuf>,
+    // How `caption` is escaped and sent: --parse-mode, else plain text like
+    // album.txt, whatever the sidecars of the items say
+    pub parse_mode: Option<ParseMode>,
+    pub files: Vec<PathBuf>,
+    pub items: Vec<AlbumItem>,
+    // .srt/.vtt sidecars sent as documents after the items (--include-subtitles)
+    pub subtitles: Vec<PathBuf>,
+    // Forum topic a package manifest routes the album to
+    pub thread: Option<i32>,
+}
+
+/// Splits `items` into consecutive chunks of at most `max_items` items and,
+/// when set, at most `max_bytes` bytes, whichever is hit first. A single item
+/// larger than `max_bytes` gets a chunk of its own. Returns the chunk lengths.
+pub fn chunk_lengths(items: &[AlbumItem], max_items: usize, max_bytes: Option<u64>) -> Vec<usize> {
+    let mut lengths = Vec::new();
+    let (mut count, mut bytes) = (0, 0);
+    for item in items {
+        let over_bytes = max_bytes.is_some_and(|max| bytes + item.bytes > max);
+        if count > 0 && (count == max_items || over_bytes) {
+            lengths.push(count);
+            (count, bytes) = (0, 0);
+        }
+        count += 1;
+        bytes += item.bytes;
+    }
+    if count > 0 {
+        lengths.push(count);
+    }
+    lengths
+}
+
+/// Where the albums, sent in chunks as `chunk_lengths` splits them, would
+/// post files out of `order` (each input file's position). Files missing
+/// from `order`, such as --prepend-media, are left out of the comparison.
+pub fn order_problem(
+    albums: &[Album],
+    order: &HashMap<PathBuf, usize>,
+    max_items: usize,
+    max_bytes: Option<u64>,
+) -> Option<String> {
+    for album in albums {
+        let lengths = chunk_lengths(&album.items, max_items, max_bytes);
+        let chunked: usize = lengths.iter().sum();
+        if chunked != album.items.len() {
+            return Some(format!(
+                "album {}: its messages hold {} of its {} items",
+                album.label,
+                chunked,
+                album.items.len()
+            ));
+        }
+        let mut seen = std::collections::HashSet::new();
+        let mut last: Option<(usize, &Path)> = None;
+        for item in &album.items {
+            let path = item.entry.path.as_path();
+            let Some(&position) = order.get(path).filter(|_| seen.insert(path)) else {
+                continue;
+            };
+            if let Some((previous, previous_path)) = last
+                && position < previous
+            {
+                return Some(format!(
+                    "album {}: {:?} (input {}) is sent after {:?} (input {})",
+                    album.label,
+                    path,
+                    position + 1,
+                    previous_path,
+                    previous + 1
+                ));
+            }
+            last = Some((position, path));
+        }
+    }
+    None
+}
+
+/// Prints every message the albums would send and the input position of
+/// each file, for --check-order failures.
+pub fn dump_plan(
+    albums: &[Album],
+    order: &HashMap<PathBuf, usize>,
+    max_items: usize,
+    max_bytes: Option<u64>,
+) {
+    for album in albums {
+        eprintln!("Album {}:", album.label);
+        let mut items = album.items.iter();
+        for (i, length) in chunk_lengths(&album.items, max_items, max_bytes)
+            .into_iter()
+            .enumerate()
+        {
+            eprintln!("  message {}:", i + 1);
+            for item in items.by_ref().take(length) {
+                match order.get(&item.entry.path) {
+                    Some(position) => eprintln!("    {:>4}  {:?}", position + 1, item.entry.path),
+                    None => eprintln!("       -  {:?}", item.entry.path),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Orientation {
+    Portrait,
+    Landscape,
+}
+
+/// What --auto-split-types sends as separate albums
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum MediaType {
+    Photos,
+    Videos,
+    Audio,
+}
+
+impl MediaType {
+    fn name(self) -> &'static str {
+        match self {
+            MediaType::Photos => "photos",
+            MediaType::Videos => "videos",
+            MediaType::Audio => "audio",
+        }
+    }
+}
+
+impl AlbumItem {
+    /// None for media that goes in no media group, such as animations.
+    pub fn media_type(&self) -> Option<MediaType> {
+        match self.media {
+            InputMedia::Photo(_) => Some(MediaType::Photos),
+            InputMedia::Video(_) => Some(MediaType::Videos),
+            InputMedia::Audio(_) => Some(MediaType::Audio),
+            _ => None,
+        }
+    }
+
+    /// None for square items and unknown dimensions.
+    pub fn orientation(&self) -> Option<Orientation> {
+        match self.dimensions? {
+            (w, h) if h > w => Some(Orientation::Portrait),
+            (w, h) if w > h => Some(Orientation::Landscape),
+            _ => None,
+        }
+    }
+}
+
+impl Album {
+    /// Numbers of portrait and landscape items when the album has both.
+    pub fn mixed_orientation(&self) -> Option<(usize, usize)> {
+        let count = |o| {
+            self.items
+                .iter()
+                .filter(|item| item.orientation() == Some(o))
+                .count()
+        };
+        let (portrait, landscape) = (count(Orientation::Portrait), count(Orientation::Landscape));
+        (portrait > 0 && landscape > 0).then_some((portrait, landscape))
+    }
+
+    /// Splits a mixed album into one album per orientation, in the order the
+    /// orientations first appear. Square items and items of unknown size go
+    /// with the first one, as do the caption and subtitles.
+    pub fn split_by_orientation(mut self) -> Vec<Album> {
+        let Some(first) = self.items.iter().find_map(AlbumItem::orientation) else {
+            return vec![self];
+        };
+        let (other, rest): (Vec<AlbumItem>, Vec<AlbumItem>) = std::mem::take(&mut self.items)
+            .into_iter()
+            .partition(|item| item.orientation().is_some_and(|o| o != first));
+        if other.is_empty() {
+            self.items = rest;
+            return vec![self];
+        }
+        let name = |o| match o {
+            Orientation::Portrait => "portrait",
+            Orientation::Landscape => "landscape",
+        };
+        let second = match first {
+            Orientation::Portrait => Orientation::Landscape,
+            Orientation::Landscape => Orientation::Portrait,
+        };
+        let split = Album {
+            label: format!("{} ({})", self.label, name(second)),
+            caption: None,
+            captions: HashMap::new(),
+            caption_file: None,
+            parse_mode: None,
+            files: Vec::new(),
+            items: other,
+            subtitles: Vec::new(),
+            thread: self.thread,
+        };
+        self.label = format!("{} ({})", self.label, name(first));
+        self.items = rest;
+        vec![self, split]
+    }
+
+    /// Splits the audio of an album that also has photos or videos into an
+    /// album of its own, as Telegram groups audio only with audio. The part
+    /// the first item is in comes first and keeps the caption; subtitles
+    /// stay with the videos.
+    pub fn split_off_audio(mut self) -> Vec<Album> {
+        let is_audio = |item: &AlbumItem| item.media_type() == Some(MediaType::Audio);
+        let Some(audio_first) = self.items.first().map(is_audio) else {
+            return vec![self];
+        };
+        let (audio, other): (Vec<AlbumItem>, Vec<AlbumItem>) = std::mem::take(&mut self.items)
+            .into_iter()
+            .partition(is_audio);
+        let (rest, second, subtitles) = match audio_first {
+            true => (audio, other, std::mem::take(&mut self.subtitles)),
+            false => (other, audio, Vec::new()),
+        };
+        if second.is_empty() {
+            self.items = rest;
+            self.subtitles = subtitles;
+            return vec![self];
+        }
+        let split = Album {
+            label: format!(
+                "{} ({})",
+                self.label,
+                if audio_first {
+                    "photos and videos"
+                } else {
+                    "audio"
+                }
+            ),
+            caption: None,
+            captions: HashMap::new(),
+            caption_file: None,
+            parse_mode: None,
+            files: Vec::new(),
+            items: second,
+            subtitles,
+            thread: self.thread,
+        };
+        self.label = format!(
+            "{} ({})",
+            self.label,
+            if audio_first {
+                "audio"
+            } else {
+                "photos and videos"
+            }
+        );
+        self.items = rest;
+        vec![self, split]
+    }
+
+    /// Splits an album of several media types into one album per type, in
+    /// the order of `order`, then of first appearance for the types it
+    /// leaves out. Items of no type go with the first album, as does the
+    /// caption; subtitles go with the videos.
+    pub fn split_by_type(mut self, order: &[MediaType]) -> Vec<Album> {
+        let mut groups: Vec<(MediaType, Vec<AlbumItem>)> = Vec::new();
+        let mut untyped = Vec::new();
+        for item in std::mem::take(&mut self.items) {
+            match item.media_type() {
+                Some(kind) => match groups.iter_mut().find(|(k, _)| *k == kind) {
+                    Some((_, items)) => items.push(item),
+                    None => groups.push((kind, vec![item])),
+                },
+                None => untyped.push(item),
+            }
+        }
+        if groups.len() < 2 {
+            self.items = groups.into_iter().flat_map(|(_, items)| items).collect();
+            self.items.append(&mut untyped);
+            return vec![self];
+        }
+        // A stable sort keeps first appearance among the unlisted types
+        groups.sort_by_key(|(kind, _)| order.iter().position(|k| k == kind).unwrap_or(order.len()));
+        let mut subtitles = std::mem::take(&mut self.subtitles);
+        let mut split: Vec<Album> = groups
+            .into_iter()
+            .map(|(kind, items)| Album {
+                label: format!("{} ({})", self.label, kind.name()),
+                caption: None,
+                captions: HashMap::new(),
+                caption_file: None,
+                parse_mode: None,
+                files: Vec::new(),
+                subtitles: match kind {
+                    MediaType::Videos => std::mem::take(&mut subtitles),
+                    _ => Vec::new(),
+                },
+                items,
+                thread: self.thread,
+            })
+            .collect();
+        let first = &mut split[0];
+        first.items.append(&mut untyped);
+        first.subtitles.append(&mut subtitles);
+        first.caption = self.caption;
+        first.captions = self.captions;
+        first.caption_file = self.caption_file;
+        first.parse_mode = self.parse_mode;
+        split
+    }
+
+    /// Splits the files into one album per forum topic in `threads`, in the
+    /// order the topics first appear; files without one form an album for
+    /// the chat's default topic. Each album keeps the caption, since they
+    /// end up in different places.
+    pub fn split_by_thread(self, threads: &HashMap<PathBuf, i32>) -> Vec<Album> {
+        let mut groups: Vec<(Option<i32>, Vec<PathBuf>)> = Vec::new();
+        for file in self.files {
+            let thread = threads.get(&file).copied();
+            match groups.iter_mut().find(|(t, _)| *t == thread) {
+                Some((_, files)) => files.push(file),
+                None => groups.push((thread, vec![file])),
+            }
+        }
+        let routed = groups.iter().any(|(thread, _)| thread.is_some());
+        groups
+            .into_iter()
+            .map(|(thread, files)| Album {
+                label: match thread {
+                    Some(id) => format!("{} (thread {})", self.label, id),
+                    None if routed => format!("{} (General)", self.label),
+                    None => self.label.clone(),
+                },
+                caption: self.caption.clone(),
+                captions: HashMap::new(),
+                caption_file: self.caption_file.clone(),
+                parse_mode: self.parse_mode,
+                files,
+                items: Vec::new(),
+                subtitles: Vec::new(),
+                thread,
+            })
+            .collect()
+    }
+}
+
+/// Subtitle sidecars (`<video>.srt`, `<video>.vtt`) that exist next to `video`.
+pub fn subtitle_sidecars(video: &Path) -> Vec<PathBuf> {
+    SUBTITLE_EXTENSIONS
+        .iter()
+        .map(|ext| video.with_extension(ext))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/// Replaces directory arguments by the media files they contain, sorted by
+/// name. Without `recursive` only the directory's own files are used.
+pub fn expand_inputs(inputs: &[PathBuf], recursive: bool) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for input in inputs {
+        if !input.is_dir() {
+            files.push(input.clone());
+            continue;
+        }
+        let walker = WalkDir::new(input)
+            .min_depth(1)
+            .max_depth(if recursive { usize::MAX } else { 1 })
+            .sort_by_file_name();
+        let mut found = 0;
+        let mut dirs = Vec::new();
+        for entry in walker.into_iter().filter_map(Result::ok) {
+            let path = entry.path();
+            if entry.file_type().is_dir() {
+                dirs.push(path.to_path_buf());
+            } else if crate::is_media_ext(&crate::file_ext(path)) && !is_thumb_sidecar(path) {
+                files.push(path.to_path_buf());
+                found += 1;
+            } else if crate::is_partial_download(path) {
+                log::warn!("Skipping {:?}: appears to be a partial download", path);
+            }
+        }
+        if found == 0 {
+            log::warn!("No media found in directory {:?}", input);
+        }
+        for dir in dirs {
+            if is_empty_leaf(&dir) {
+                log::info!("Skipping empty directory {:?}", dir);
+            }
+        }
+    }
+    files
+}
+
+// `<video>.thumb.jpg` files are thumbnails for a video, not photos to send.
+pub fn is_thumb_sidecar(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.to_lowercase().ends_with(".thumb.jpg"))
+}
+
+// A directory without subdirectories and without media files.
+fn is_empty_leaf(dir: &Path) -> bool {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return false;
+    };
+    !entries.filter_map(Result::ok).any(|entry| {
+        let path = entry.path();
+        path.is_dir() || crate::is_media_ext(&crate::file_ext(&path))
+    })
+}
+
+/// Groups files into albums: one per parent directory (in sorted order) with
+/// `per_dir`, otherwise a single album keeping the given order.
+pub fn group(files: Vec<PathBuf>, per_dir: bool) -> Vec<Album> {
+    if !per_dir {
+        // Files all from one directory still get its album.txt
+        let dir = files.first().and_then(|file| file.parent());
+        let caption_file = dir
+            .filter(|dir| files.iter().all(|file| file.parent() == Some(*dir)))
+            .and_then(album_caption_file);
+        return vec![Album {
+            label: "batch".to_string(),
+            caption: caption_file
+                .as_deref()
+                .and_then(|path| std::fs::read_to_string(path).ok()),
+            captions: HashMap::new(),
+            caption_file,
+            parse_mode: None,
+            files,
+            items: Vec::new(),
+            subtitles: Vec::new(),
+            thread: None,
+        }];
+    }
+
+    let mut by_dir: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for file in files {
+        let dir = file.parent().map(Path::to_path_buf).unwrap_or_default();
+        by_dir.entry(dir).or_default().push(file);
+    }
+    by_dir
+        .into_iter()
+        .map(|(dir, files)| {
+            let caption_file = album_caption_file(&dir);
+            Album {
+                label: dir.display().to_string(),
+                caption: Some(dir_caption(&dir, caption_file.as_deref())),
+                captions: HashMap::new(),
+                caption_file,
+                parse_mode: None,
+                files,
+                items: Vec::new(),
+                subtitles: Vec::new(),
+                thread: None,
+            }
+        })
+        .collect()
+}
+
+/// Buckets files by capture day in chronological order, captioning each
+/// album with its date rendered through the strftime `date_format`.
+pub fn group_by_date(
+    files: Vec<PathBuf>,
+    tz: Option<FixedOffset>,
+    date_format: &str,
+) -> Vec<Album> {
+    let mut by_date: BTreeMap<NaiveDate, Vec<PathBuf>> = BTreeMap::new();
+    for file in files {
+        by_date
+            .entry(capture_date(&file, tz))
+            .or_default()
+            .push(file);
+    }
+    by_date
+        .into_iter()
+        .map(|(date, files)| Album {
+            label: date.to_string(),
+            caption: Some(date.format(date_format).to_string()),
+            captions: HashMap::new(),
+            caption_file: None,
+            parse_mode: None,
+            files,
+            items: Vec::new(),
+            subtitles: Vec::new(),
+            thread: None,
+        })
+        .collect()
+}
+
+// EXIF DateTimeOriginal for photos, the container creation_time for videos,
+// falling back to the file's mtime. EXIF times are already local wall-clock
+// times; the others are converted to `tz` (the system zone when unset).
+fn capture_date(path: &Path, tz: Option<FixedOffset>) -> NaiveDate {
+    let ext = crate::file_ext(path);
+    if crate::IMAGE_EXTENSIONS.contains(&ext.as_str())
+        && let Some(date) = exif_date(path)
+    {
+        return date;
+    }
+    let instant = video_creation_time(path).or_else(|| {
+        std::fs::metadata(path)
+            .and_then(|m| m.modified())
+            .ok()
+            .map(DateTime::<Utc>::from)
+    });
+    let instant = instant.unwrap_or_else(Utc::now);
+    match tz {
+        Some(offset) => instant.with_timezone(&offset).date_naive(),
+        None => instant.with_timezone(&Local).date_naive(),
+    }
+}
+
+fn exif_date(path: &Path) -> Option<NaiveDate> {
+    let file = std::fs::File::open(path).ok()?;
+    let exif = exif::Reader::new()
+        .read_from_container(&mut std::io::BufReader::new(file))
+        .ok()?;
+    let field = exif.get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY)?;
+    let exif::Value::Ascii(ref values) = field.value else {
+        return None;
+    };
+    let dt = exif::DateTime::from_ascii(values.first()?).ok()?;
+    NaiveDate::from_ymd_opt(dt.year.into(), dt.month.into(), dt.day.into())
+}
+
+/// A photo's width and height as displayed, with EXIF rotations applied.
+pub fn photo_dimensions(path: &Path) -> Option<(u32, u32)> {
+    let (w, h) = image::image_dimensions(path).ok()?;
+    let rotated = std::fs::File::open(path)
+        .ok()
+        .and_then(|file| {
+            exif::Reader::new()
+                .read_from_container(&mut std::io::BufReader::new(file))
+                .ok()
+        })
+        .and_then(|exif| {
+            exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+                .value
+                .get_uint(0)
+        })
+        // 5-8 are the orientations turned by 90 degrees
+        .is_some_and(|orientation| (5..=8).contains(&orientation));
+    Some(if rotated { (h, w) } else { (w, h) })
+}
+
+/// Decodes a photo and turns it the way its EXIF orientation says, for
+/// copies that are written without the EXIF.
+pub fn open_upright(path: &Path) -> Result<image::DynamicImage, String> {
+    use image::ImageDecoder;
+    let mut decoder = image::ImageReader::open(path)
+        .and_then(|r| r.with_guessed_format())
+        .map_err(|e| e.to_string())?
+        .into_decoder()
+        .map_err(|e| e.to_string())?;
+    let orientation = decoder.orientation().map_err(|e| e.to_string())?;
+    let mut img = image::DynamicImage::from_decoder(decoder).map_err(|e| e.to_string())?;
+    img.apply_orientation(orientation);
+    Ok(img)
+}
+
+fn video_creation_time(path: &Path) -> Option<DateTime<Utc>> {
+    let ext = crate::file_ext(path);
+    if !crate::VIDEO_EXTENSIONS.contains(&ext.as_str())
+        && !crate::ANIMATION_EXTENSIONS.contains(&ext.as_str())
+    {
+        return None;
+    }
+    let output = crate::subprocess::output(
+        std::process::Command::new("ffprobe")
+            .args([
+                "-v",
+                crate::ffmpeg_loglevel(),
+                "-show_entries",
+                "format_tags=creation_time",
+                "-of",
+                "default=noprint_wrappers=1:nokey=1",
+            ])
+            .arg(path),
+        crate::subprocess::Work::Probe,
+        path,
+        None,
+    )
+    .ok()?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    DateTime::parse_from_rfc3339(text.trim())
+        .ok()
+        .map(|dt| dt.with_timezone(&Utc))
+}
+
+/// The album.txt (or _caption.txt) of `dir`, if it has one.
+pub fn album_caption_file(dir: &Path) -> Option<PathBuf> {
+    ALBUM_CAPTION_FILES
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// Whether `path` is named like a directory caption file, so it is never
+/// taken for the caption sidecar of a file called e.g. `album.jpg`.
+pub fn is_album_caption_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| ALBUM_CAPTION_FILES.contains(&name))
+}
+
+/// `album.<lang>.txt` for `album.txt`: the variant of a caption file in
+/// the language `lang`.
+pub fn lang_variant(path: &Path, lang: &str) -> PathBuf {
+    match path.extension() {
+        Some(ext) => path.with_extension(format!("{}.{}", lang, ext.to_string_lossy())),
+        None => path.with_extension(lang),
+    }
+}
+
+// The directory's album.txt, or its name with `_`/`-` turned into spaces.
+fn dir_caption(dir: &Path, caption_file: Option<&Path>) -> String {
+    if let Some(text) = caption_file.and_then(|path| std::fs::read_to_string(path).ok()) {
+        return text;
+    }
+    let absolute = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    absolute
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default()
+        .replace(['_', '-'], " ")
+}
+
+/// Returns `media` with its caption replaced.
+pub fn with_caption(
+    media: InputMedia,
+    caption: String,
+    parse_mode: Option<ParseMode>,
+) -> InputMedia {
+    match media {
+        InputMedia::Photo(mut m) => {
+            m.caption = Some(caption);
+            m.parse_mode = parse_mode;
+            InputMedia::Photo(m)
+        }
+        InputMedia::Video(mut m) => {
+            m.caption = Some(caption);
+            m.parse_mode = parse_mode;
+            InputMedia::Video(m)
+        }
+        InputMedia::Animation(mut m) => {
+            m.caption = Some(caption);
+            m.parse_mode = parse_mode;
+            InputMedia::Animation(m)
+        }
+        InputMedia::Audio(mut m) => {
+            m.caption = Some(caption);
+            m.parse_mode = parse_mode;
+            InputMedia::Audio(m)
+        }
+        InputMedia::Document(mut m) => {
+            m.caption = Some(caption);
+            m.parse_mode = parse_mode;
+            InputMedia::Document(m)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use teloxide::types::{InputFile, InputMediaPhoto};
+
+    fn item(name: &str, bytes: u64) -> AlbumItem {
+        let path = Path::new(name);
+        AlbumItem {
+            entry: PlanEntry::new(path, "photo"),
+            media: InputMedia::Photo(InputMediaPhoto::new(InputFile::file(path))),
+            caption: String::new(),
+            captions: HashMap::new(),
+            parse_mode: None,
+            upload: path.to_path_buf(),
+            bytes,
+            dimensions: None,
+            keyboard: None,
+        }
+    }
+
+    fn items(sizes: &[u64]) -> Vec<AlbumItem> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &bytes)| item(&format!("{}.jpg", i), bytes))
+            .collect()
+    }
+
+    #[test]
+    fn chunks_hold_exactly_max_items() {
+        assert_eq!(chunk_lengths(&items(&[1; 3]), 3, None), [3]);
+        assert_eq!(chunk_lengths(&items(&[1; 4]), 3, None), [3, 1]);
+        assert_eq!(chunk_lengths(&items(&[1; 6]), 3, Some(100)), [3, 3]);
+    }
+
+    #[test]
+    fn chunks_hold_exactly_max_bytes() {
+        assert_eq!(chunk_lengths(&items(&[4, 6]), 10, Some(10)), [2]);
+        assert_eq!(chunk_lengths(&items(&[4, 7]), 10, Some(10)), [1, 1]);
+        assert_eq!(chunk_lengths(&items(&[5, 5, 5, 5]), 10, Some(10)), [2, 2]);
+    }
+
+    #[test]
+    fn an_item_over_max_bytes_goes_alone() {
+        assert_eq!(chunk_lengths(&items(&[11]), 10, Some(10)), [1]);
+        assert_eq!(chunk_lengths(&items(&[2, 11, 2]), 10, Some(10)), [1, 1, 1]);
+        assert!(chunk_lengths(&[], 10, Some(10)).is_empty());
+    }
+
+    fn album(items: Vec<AlbumItem>) -> Album {
+        Album {
+            label: "test".to_string(),
+            caption: None,
+            captions: HashMap::new(),
+            caption_file: None,
+            parse_mode: None,
+            files: Vec::new(),
+            items,
+            subtitles: Vec::new(),
+            thread: None,
+        }
+    }
+
+    #[test]
+    fn order_problem_finds_files_sent_out_of_order() {
+        let order: HashMap<PathBuf, usize> = (0..5)
+            .map(|i| (PathBuf::from(format!("{}.jpg", i)), i))
+            .collect();
+        let albums = [album(items(&[1; 5]))];
+        assert_eq!(order_problem(&albums, &order, 2, Some(1)), None);
+
+        let mut swapped = items(&[1; 5]);
+        swapped.swap(1, 2);
+        let problem = order_problem(&[album(swapped)], &order, 2, None).unwrap();
+        assert!(problem.contains("\"1.jpg\" (input 2) is sent after \"2.jpg\" (input 3)"));
+    }
+}
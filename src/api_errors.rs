@@ -0,0 +1,152 @@
+// ---------------------------
+// Telegram API error hints
+// ---------------------------
+
+use teloxide::RequestError;
+
+// Substrings of frequent Telegram error texts and what to do about them.
+// Matched case-insensitively against the error's display text.
+const HINTS: &[(&str, &str)] = &[
+    (
+        "wrong file identifier/http url specified",
+        "a file id or URL was rejected; check that the file exists and any URL is publicly reachable",
+    ),
+    (
+        "failed to get http url content",
+        "Telegram could not download a URL; make sure it is public and points directly at the file",
+    ),
+    (
+        "photo_invalid_dimensions",
+        "a photo is too large or too narrow; keep width + height under 10000 px and the aspect ratio under 20:1",
+    ),
+    (
+        "image_process_failed",
+        "Telegram could not process an image; it may be corrupt or in an unsupported format",
+    ),
+    (
+        "request entity too large",
+        "a file is over the upload limit (50 MB on the public Bot API, 2000 MB on a local server)",
+    ),
+    (
+        "upgraded to a supergroup",
+        "the group was upgraded to a supergroup; use its new -100... chat id",
+    ),
+    (
+        "too much messages to send as an album",
+        "albums can hold at most 10 items",
+    ),
+    (
+        "caption is too long",
+        "captions are limited to 1024 characters",
+    ),
+    (
+        "message to be replied not found",
+        "the --reply-to message was deleted or is not in this chat; --allow-orphan-reply posts without the reply",
+    ),
+    (
+        "message can't be edited",
+        "only the bot's own messages can be edited, and not once they are too old",
+    ),
+    (
+        "chat not found",
+        "the chat id is wrong or the bot has not been added to that chat",
+    ),
+    (
+        "need administrator rights in the channel chat",
+        "make the bot an administrator of the channel with permission to post",
+    ),
+    (
+        "not enough rights to send",
+        "the bot is not allowed to send this kind of media in that chat",
+    ),
+    (
+        "bot was kicked",
+        "the bot was removed from the chat; add it back first",
+    ),
+    (
+        "bot can't initiate conversation with a user",
+        "the user has to open the bot and press Start before it can message them",
+    ),
+    (
+        "bot was blocked by the user",
+        "the user blocked the bot; they need to unblock it",
+    ),
+    (
+        "invalid bot token",
+        "the bot token is wrong; check TELOXIDE_TOKEN",
+    ),
+];
+
+/// Returns a human hint for a known error, or `None` for unknown ones.
+pub fn hint(err: &RequestError) -> Option<String> {
+    match err {
+        RequestError::MigrateToChatId(new_id) => Some(format!(
+            "the group was upgraded to a supergroup; use the new chat id {} (or drop --no-follow-migration)",
+            new_id
+        )),
+        RequestError::RetryAfter(secs) => Some(format!(
+            "Telegram is rate limiting this bot; wait {} seconds before retrying",
+            secs.seconds()
+        )),
+        RequestError::Network(_) => Some(
+            "could not reach the Bot API server; check api_url and that the server is running"
+                .to_string(),
+        ),
+        _ => {
+            let text = err.to_string().to_lowercase();
+            HINTS
+                .iter()
+                .find(|(needle, _)| text.contains(needle))
+                .map(|(_, hint)| hint.to_string())
+        }
+    }
+}
+
+/// Formats the raw error text followed by a hint when one is known.
+pub fn describe(err: &RequestError) -> String {
+    match hint(err) {
+        Some(h) => format!("{} (hint: {})", err, h),
+        None => err.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use teloxide::ApiError;
+    use teloxide::types::{ChatId, Seconds};
+
+    fn api_error(text: &str) -> RequestError {
+        RequestError::Api(ApiError::Unknown(text.to_string()))
+    }
+
+    #[test]
+    fn each_known_text_gets_its_hint() {
+        for (needle, hint_text) in HINTS {
+            let err = api_error(&format!("Bad Request: {}", needle.to_uppercase()));
+            assert_eq!(hint(&err).as_deref(), Some(*hint_text), "{}", needle);
+            assert_eq!(describe(&err), format!("{} (hint: {})", err, hint_text));
+        }
+    }
+
+    #[test]
+    fn migration_and_rate_limit_hints_carry_their_numbers() {
+        let migrated = RequestError::MigrateToChatId(ChatId(-1001234));
+        assert!(hint(&migrated).unwrap().contains("new chat id -1001234"));
+        let limited = RequestError::RetryAfter(Seconds::from_seconds(42));
+        assert!(hint(&limited).unwrap().contains("wait 42 seconds"));
+    }
+
+    #[test]
+    fn unknown_errors_pass_through() {
+        let errors = [
+            api_error("Bad Request: something new"),
+            RequestError::Io(Arc::new(std::io::Error::other("disk on fire"))),
+        ];
+        for err in errors {
+            assert_eq!(hint(&err), None);
+            assert_eq!(describe(&err), err.to_string());
+        }
+    }
+}
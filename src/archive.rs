@@ -0,0 +1,112 @@
+// ---------------------------
+// Archive directory lookups
+// ---------------------------
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// How --skip-if-archived recognizes a file's archived copy
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ArchiveMatch {
+    /// A file of the same name anywhere in the archive
+    Name,
+    /// A file with the same contents, whatever its name
+    Content,
+}
+
+/// The files of an archive directory (and its subdirectories), listed once
+/// per run.
+pub struct Archive {
+    by: ArchiveMatch,
+    names: HashMap<OsString, Vec<PathBuf>>,
+    // Candidates for a content match, by size
+    sizes: HashMap<u64, Vec<PathBuf>>,
+}
+
+impl Archive {
+    pub fn scan(dir: &Path, by: ArchiveMatch) -> Result<Self, String> {
+        if !dir.is_dir() {
+            return Err(format!("{:?} is not a directory", dir));
+        }
+        let mut archive = Archive {
+            by,
+            names: HashMap::new(),
+            sizes: HashMap::new(),
+        };
+        for entry in WalkDir::new(dir)
+            .min_depth(1)
+            .into_iter()
+            .filter_map(Result::ok)
+        {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let path = entry.into_path();
+            match by {
+                ArchiveMatch::Name => {
+                    let name = path.file_name().unwrap_or_default().to_os_string();
+                    archive.names.entry(name).or_default().push(path);
+                }
+                ArchiveMatch::Content => {
+                    if let Ok(meta) = std::fs::metadata(&path) {
+                        archive.sizes.entry(meta.len()).or_default().push(path);
+                    }
+                }
+            }
+        }
+        Ok(archive)
+    }
+
+    /// The archived copy of `path`, if there is one.
+    pub fn find(&self, path: &Path) -> Option<&Path> {
+        let candidates = match self.by {
+            ArchiveMatch::Name => self.names.get(path.file_name()?)?,
+            ArchiveMatch::Content => self.sizes.get(&std::fs::metadata(path).ok()?.len())?,
+        };
+        let source = std::fs::canonicalize(path).ok();
+        candidates
+            .iter()
+            // The file itself, when it is inside the archive
+            .filter(|copy| std::fs::canonicalize(copy).ok() != source)
+            .find(|copy| {
+                self.by == ArchiveMatch::Name || same_contents(path, copy).unwrap_or(false)
+            })
+            .map(PathBuf::as_path)
+    }
+}
+
+// Compares two files of the same size byte for byte.
+fn same_contents(a: &Path, b: &Path) -> std::io::Result<bool> {
+    let (mut a, mut b) = (std::fs::File::open(a)?, std::fs::File::open(b)?);
+    let (mut buf_a, mut buf_b) = (vec![0; 64 * 1024], vec![0; 64 * 1024]);
+    loop {
+        let n = a.read(&mut buf_a)?;
+        if n == 0 {
+            return Ok(b.read(&mut buf_b)? == 0);
+        }
+        b.read_exact(&mut buf_b[..n])?;
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Drops the files of `files` that have an archived copy, returning them
+/// with that copy.
+pub fn skip_archived(files: &mut Vec<PathBuf>, archive: &Archive) -> Vec<(PathBuf, PathBuf)> {
+    let mut skipped = Vec::new();
+    let mut seen = HashSet::new();
+    files.retain(|path| match archive.find(path) {
+        Some(copy) => {
+            if seen.insert(path.clone()) {
+                skipped.push((path.clone(), copy.to_path_buf()));
+            }
+            false
+        }
+        None => true,
+    });
+    skipped
+}
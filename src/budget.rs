@@ -0,0 +1,144 @@
+// ---------------------------
+// Send-rate budget
+// ---------------------------
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Bucket state shared by every invocation run from the same directory
+pub const STATE_FILE: &str = "telegoy_budget.json";
+
+const HOUR: f64 = 3600.0;
+const MINUTE: f64 = 60.0;
+
+/// A token bucket holding up to `limit` tokens, refilled evenly over `period`
+/// seconds.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct Bucket {
+    tokens: f64,
+    // Unix time of the last refill, in seconds
+    updated: f64,
+}
+
+impl Bucket {
+    fn full(limit: f64, now: f64) -> Self {
+        Bucket {
+            tokens: limit,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: f64, period: f64, now: f64) {
+        let gained = (now - self.updated).max(0.0) * limit / period;
+        self.tokens = (self.tokens + gained).min(limit);
+        self.updated = now;
+    }
+
+    // Seconds until `n` tokens are available.
+    fn wait_for(&self, n: f64, limit: f64, period: f64) -> f64 {
+        if self.tokens >= n {
+            0.0
+        } else {
+            (n - self.tokens) * period / limit
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    albums: Option<Bucket>,
+    messages: Option<Bucket>,
+}
+
+/// Limits from Settings (`max_albums_per_hour`, `max_messages_per_minute`)
+/// applied to every send, with the bucket state persisted in [`STATE_FILE`].
+pub struct Budget {
+    path: PathBuf,
+    albums_per_hour: Option<u32>,
+    messages_per_minute: Option<u32>,
+    state: State,
+}
+
+impl Budget {
+    pub fn load(albums_per_hour: Option<u32>, messages_per_minute: Option<u32>) -> Self {
+        let path = PathBuf::from(STATE_FILE);
+        let state = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default();
+        Budget {
+            path,
+            albums_per_hour,
+            messages_per_minute,
+            state,
+        }
+    }
+
+    /// Waits until the budget allows `albums` media groups holding `messages`
+    /// messages, then spends it. `queued` is the number of items still
+    /// waiting behind this send, reported while the budget is exhausted.
+    pub async fn acquire(&mut self, albums: u32, messages: u32, queued: usize) {
+        loop {
+            // Re-read so concurrent invocations share the same bucket
+            *self = Budget::load(self.albums_per_hour, self.messages_per_minute);
+            let now = unix_now();
+            let mut wait: f64 = 0.0;
+            if let Some(limit) = self.albums_per_hour {
+                let (limit, n) = (f64::from(limit), f64::from(albums.min(limit)));
+                let bucket = self
+                    .state
+                    .albums
+                    .get_or_insert_with(|| Bucket::full(limit, now));
+                bucket.refill(limit, HOUR, now);
+                wait = wait.max(bucket.wait_for(n, limit, HOUR));
+            }
+            if let Some(limit) = self.messages_per_minute {
+                let (limit, n) = (f64::from(limit), f64::from(messages.min(limit)));
+                let bucket = self
+                    .state
+                    .messages
+                    .get_or_insert_with(|| Bucket::full(limit, now));
+                bucket.refill(limit, MINUTE, now);
+                wait = wait.max(bucket.wait_for(n, limit, MINUTE));
+            }
+
+            if wait <= 0.0 {
+                if let (Some(limit), Some(bucket)) = (self.albums_per_hour, &mut self.state.albums)
+                {
+                    bucket.tokens -= f64::from(albums.min(limit));
+                }
+                if let (Some(limit), Some(bucket)) =
+                    (self.messages_per_minute, &mut self.state.messages)
+                {
+                    bucket.tokens -= f64::from(messages.min(limit));
+                }
+                self.save();
+                return;
+            }
+            let delay = Duration::from_secs_f64(wait);
+            log::info!(
+                "Send budget exhausted, waiting {} ({} item(s) queued)",
+                humantime::format_duration(Duration::from_secs(delay.as_secs().max(1))),
+                queued
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    fn save(&self) {
+        let result = serde_json::to_string(&self.state)
+            .map_err(|e| e.to_string())
+            .and_then(|text| std::fs::write(&self.path, text).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            log::warn!("Could not save send budget to {:?}: {}", self.path, e);
+        }
+    }
+}
+
+fn unix_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
@@ -0,0 +1,109 @@
+// ---------------------------
+// Derived artifact cache
+// ---------------------------
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+/// Entries unused for this long are removed when a run starts
+const MAX_AGE: Duration = Duration::from_secs(24 * 3600);
+
+// None with --no-cache
+static CACHE_DIR: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// Turns the cache on (in $XDG_CACHE_HOME/telegoy, else ~/.cache/telegoy)
+/// or off for the whole run, and drops entries older than a day.
+pub fn init(enabled: bool) {
+    let dir = enabled.then(cache_dir).flatten();
+    if let Some(dir) = &dir {
+        prune(dir);
+    }
+    let _ = CACHE_DIR.set(dir);
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    let dir = base.join("telegoy");
+    match std::fs::create_dir_all(&dir) {
+        Ok(()) => Some(dir),
+        Err(e) => {
+            log::warn!(
+                "Not caching thumbnails and transcodes: cannot create {:?}: {}",
+                dir,
+                e
+            );
+            None
+        }
+    }
+}
+
+fn prune(dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let expired = entry
+            .metadata()
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age > MAX_AGE);
+        if expired {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
+
+// Cache file of the `kind` artifact of `source`. The key covers the
+// source's size and mtime, so editing the source makes a new entry.
+fn entry(source: &Path, kind: &str) -> Option<PathBuf> {
+    let dir = CACHE_DIR.get()?.as_ref()?;
+    let meta = std::fs::metadata(source).ok()?;
+    let mtime = meta
+        .modified()
+        .ok()?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()?;
+    let mut hasher = DefaultHasher::new();
+    std::fs::canonicalize(source).ok()?.hash(&mut hasher);
+    (meta.len(), mtime, kind).hash(&mut hasher);
+    Some(dir.join(format!("{}-{:016x}", kind, hasher.finish())))
+}
+
+/// Puts the `kind` artifact of `source` (a thumbnail, a remux, ...) at
+/// `dest`: a cached copy when there is one, else whatever `make` writes
+/// there, which is then cached. Returns whether the copy was reused.
+pub fn reuse_or_make<E>(
+    source: &Path,
+    kind: &str,
+    dest: &Path,
+    make: impl FnOnce() -> Result<(), E>,
+) -> Result<bool, E> {
+    let entry = entry(source, kind);
+    if let Some(entry) = &entry
+        && link_or_copy(entry, dest).is_ok()
+    {
+        log::debug!("Reusing cached {} of {:?}", kind, source);
+        // Keeps entries in use from expiring
+        let _ = std::fs::File::open(entry).and_then(|f| f.set_modified(SystemTime::now()));
+        return Ok(true);
+    }
+    make()?;
+    if let Some(entry) = entry {
+        let partial = entry.with_extension(format!("partial-{}", uuid::Uuid::new_v4()));
+        let stored = link_or_copy(dest, &partial).and_then(|_| std::fs::rename(&partial, &entry));
+        if let Err(e) = stored {
+            let _ = std::fs::remove_file(&partial);
+            log::debug!("Could not cache {} of {:?}: {}", kind, source, e);
+        }
+    }
+    Ok(false)
+}
+
+fn link_or_copy(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::hard_link(from, to).or_else(|_| std::fs::copy(from, to).map(|_| ()))
+}
@@ -0,0 +1,76 @@
+// ---------------------------
+// Destination chat types
+// ---------------------------
+
+use crate::send;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use teloxide::RequestError;
+use teloxide::prelude::*;
+use teloxide::types::{ChatFullInfoKind, ChatFullInfoPublicKind, Recipient};
+
+/// What get_chat said a destination is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChatKind {
+    Private,
+    Group,
+    Supergroup {
+        forum: bool,
+    },
+    /// With the discussion group linked to it, where its posts get comments
+    Channel {
+        discussion: Option<ChatId>,
+    },
+}
+
+impl ChatKind {
+    pub fn describe(self) -> &'static str {
+        match self {
+            ChatKind::Private => "private chat",
+            ChatKind::Group => "basic group",
+            ChatKind::Supergroup { forum: true } => "forum",
+            ChatKind::Supergroup { forum: false } => "supergroup",
+            ChatKind::Channel { .. } => "channel",
+        }
+    }
+
+    /// Whether posts can go to a forum topic (--thread-id).
+    pub fn has_topics(self) -> bool {
+        self == ChatKind::Supergroup { forum: true }
+    }
+}
+
+// Looked up chats by the name they were given as, kept for the whole
+// process so --watch batches do not ask again
+static KNOWN: Mutex<Option<HashMap<String, ChatKind>>> = Mutex::new(None);
+
+/// The kind of `chat`, asking Telegram the first time.
+pub async fn lookup(bot: &Bot, chat: &str) -> Result<ChatKind, RequestError> {
+    if let Some(kind) = known(chat) {
+        return Ok(kind);
+    }
+    let info = send::logged(bot, bot.get_chat(Recipient::from(chat.to_string()))).await?;
+    let kind = match info.kind {
+        ChatFullInfoKind::Private(_) => ChatKind::Private,
+        ChatFullInfoKind::Public(public) => match public.kind {
+            ChatFullInfoPublicKind::Group(_) => ChatKind::Group,
+            ChatFullInfoPublicKind::Supergroup(group) => ChatKind::Supergroup {
+                forum: group.is_forum,
+            },
+            ChatFullInfoPublicKind::Channel(channel) => ChatKind::Channel {
+                discussion: channel.linked_chat_id.map(ChatId),
+            },
+        },
+    };
+    KNOWN
+        .lock()
+        .unwrap()
+        .get_or_insert_default()
+        .insert(chat.to_string(), kind);
+    Ok(kind)
+}
+
+/// The kind of `chat` if it was looked up.
+pub fn known(chat: &str) -> Option<ChatKind> {
+    KNOWN.lock().unwrap().as_ref()?.get(chat).copied()
+}
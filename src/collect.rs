@@ -0,0 +1,127 @@
+// ---------------------------
+// Collecting the batch's files
+// ---------------------------
+
+use crate::{Abort, Cli, EXIT_TOO_FEW_FILES, Summary, albums, archive, keyboard, package};
+use crate::{split_inline_caption, temp_path};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use teloxide::types::InlineKeyboardMarkup;
+
+/// What the inputs and a --from-package manifest say about the files
+pub struct Inputs {
+    // Captions given after `::` on the command line, by file
+    pub inline_captions: HashMap<PathBuf, String>,
+    // The manifest's chat, album caption and buttons
+    pub package_chat_id: Option<String>,
+    pub package_caption: Option<String>,
+    pub package_keyboard: Option<InlineKeyboardMarkup>,
+    // Forum topic of each of the package's files
+    pub package_threads: HashMap<PathBuf, i32>,
+}
+
+/// The files of the batch: the inputs expanded, what --from-package
+/// extracts (its options going into `args`) and none --skip-if-archived
+/// finds in the archive. Stops the run when there are fewer than
+/// --min-files.
+pub fn files(
+    args: &mut Cli,
+    summary: &mut Summary,
+    temp_files: &mut Vec<PathBuf>,
+) -> Result<(Vec<PathBuf>, Inputs), Abort> {
+    let mut inline_captions = HashMap::new();
+    let inputs: Vec<PathBuf> = std::mem::take(&mut args.files)
+        .iter()
+        .map(|input| {
+            let (path, caption) = split_inline_caption(input);
+            match caption {
+                Some(_) if path.is_dir() => {
+                    log::warn!("Ignoring inline caption on directory {:?}", path);
+                }
+                Some(caption) => {
+                    inline_captions.insert(path.clone(), caption);
+                }
+                None => {}
+            }
+            path
+        })
+        .collect();
+    let mut files = albums::expand_inputs(&inputs, args.recursive);
+
+    // Post package: extract to temp and apply its manifest (CLI flags still win)
+    let mut package_chat_id = None;
+    let mut package_caption = None;
+    let mut package_keyboard = None;
+    let mut package_threads = HashMap::new();
+    if let Some(zip_path) = args.from_package.clone() {
+        let dest = temp_path(&format!("temp_package_{}", uuid::Uuid::new_v4()));
+        temp_files.push(dest.clone());
+        match package::extract(&zip_path, &dest) {
+            Ok((manifest, package_files)) => {
+                log::info!(
+                    "Loaded package {:?} with {} files",
+                    zip_path,
+                    package_files.files.len()
+                );
+                files.extend(package_files.files);
+                package_threads = package_files.threads;
+                package_chat_id = manifest.chat_id;
+                package_caption = manifest.caption;
+                if let Some(rows) = &manifest.buttons {
+                    match keyboard::build_post_buttons(rows) {
+                        Ok(keyboard) => package_keyboard = Some(keyboard),
+                        Err(e) => {
+                            return Err(format!(
+                                "Invalid buttons in package {:?}: {}",
+                                zip_path, e
+                            )
+                            .into());
+                        }
+                    }
+                }
+                args.parse_mode = args.parse_mode.or(manifest.parse_mode);
+                args.as_animation |= manifest.options.as_animation;
+                args.no_thumbnail |= manifest.options.no_thumbnail && args.thumbnail.is_none();
+                args.strict_metadata |= manifest.options.strict_metadata;
+            }
+            Err(e) => return Err(format!("Cannot read package {:?}: {}", zip_path, e).into()),
+        }
+    }
+
+    if let Some(dir) = &args.skip_if_archived {
+        let archive = match archive::Archive::scan(dir, args.archive_match) {
+            Ok(archive) => archive,
+            Err(e) => return Err(format!("Cannot read the archive: {}", e).into()),
+        };
+        for (path, copy) in archive::skip_archived(&mut files, &archive) {
+            log::info!("Skipping {:?}: archived as {:?}", path, copy);
+            summary
+                .skipped
+                .push((path, format!("already archived as {:?}", copy)));
+        }
+    }
+
+    if let Some(min) = args.min_files
+        && files.len() < min
+    {
+        log::warn!(
+            "Found {} file(s) to send, but --min-files needs {}; not sending yet",
+            files.len(),
+            min
+        );
+        return Err(Abort {
+            error: None,
+            status: EXIT_TOO_FEW_FILES,
+            summary: false,
+        });
+    }
+
+    let inputs = Inputs {
+        inline_captions,
+        package_chat_id,
+        package_caption,
+        package_keyboard,
+        package_threads,
+    };
+    Ok((files, inputs))
+}
@@ -0,0 +1,90 @@
+// ---------------------------
+// Duplicates left by retried sends
+// ---------------------------
+
+use crate::send;
+use teloxide::RequestError;
+use teloxide::prelude::*;
+use teloxide::types::{MessageId, Recipient};
+
+/// Removes the copy a retried send may have left behind: when the reply
+/// to the first attempt was lost, it can still have been posted. `earlier`
+/// are the messages the ledger recorded for that attempt; bots cannot read
+/// a chat's history, so they are forwarded to `scratch` to see their files,
+/// and deleted from `chat` when they hold the same files in the same order
+/// as `messages`. Returns the ids deleted.
+pub async fn remove_earlier_copy(
+    bot: &Bot,
+    chat: &Recipient,
+    scratch: &Recipient,
+    earlier: &[i32],
+    messages: &[Message],
+) -> Result<Vec<i32>, RequestError> {
+    let Some(sent) = messages
+        .iter()
+        .map(send::file_unique_id)
+        .collect::<Option<Vec<_>>>()
+    else {
+        return Ok(Vec::new());
+    };
+    if earlier.len() != sent.len() {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    let mut forwarded = Vec::new();
+    for id in earlier {
+        let forward = bot.forward_message(scratch.clone(), chat.clone(), MessageId(*id));
+        match send::logged(bot, forward).await {
+            Ok(copy) => {
+                files.push(send::file_unique_id(&copy));
+                forwarded.push(copy.id);
+            }
+            // Deleted, or not a message the bot can see
+            Err(RequestError::Api(_)) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    if !forwarded.is_empty() {
+        send::logged(bot, bot.delete_messages(scratch.clone(), forwarded)).await?;
+    }
+    if files.len() != sent.len() || files.iter().zip(&sent).any(|(a, b)| a.as_ref() != Some(b)) {
+        return Ok(Vec::new());
+    }
+    let ids = earlier.iter().map(|id| MessageId(*id));
+    send::logged(bot, bot.delete_messages(chat.clone(), ids)).await?;
+    Ok(earlier.to_vec())
+}
+
+/// Runs `remove_earlier_copy` on the messages recorded for the first
+/// attempt and logs what it did; without any, nothing is deleted.
+pub async fn after_retry(
+    bot: &Bot,
+    chat: &Recipient,
+    scratch: &Recipient,
+    earlier: Option<&[i32]>,
+    messages: &[Message],
+) {
+    let name = crate::recipient_name(chat);
+    let Some(earlier) = earlier else {
+        log::warn!(
+            "[{}] The first attempt of the retried post may have been posted too, but no messages were recorded for it; not deleting anything",
+            name
+        );
+        return;
+    };
+    match remove_earlier_copy(bot, chat, scratch, earlier, messages).await {
+        Ok(ids) if ids.is_empty() => {
+            log::debug!("[{}] No earlier copy of the retried post", name);
+        }
+        Ok(ids) => log::warn!(
+            "[{}] The first attempt had been posted after all; deleted that copy (messages {:?})",
+            name,
+            ids
+        ),
+        Err(e) => log::warn!(
+            "[{}] Could not check for an earlier copy of the retried post: {}",
+            name,
+            crate::api_errors::describe(&e)
+        ),
+    }
+}
@@ -0,0 +1,112 @@
+// ---------------------------
+// File content hashes
+// ---------------------------
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Default of --full-hash-max-bytes
+pub const DEFAULT_FULL_HASH_BYTES: u64 = 100_000_000;
+
+// Hash of every file whose upload was read to the end, by path
+static STREAMED: Mutex<Option<HashMap<PathBuf, String>>> = Mutex::new(None);
+
+/// SHA-256 of a file fed in pieces, as an upload reads it. Gives the same
+/// hash as `file_hash` for the same bytes, however they are split.
+#[derive(Default)]
+pub struct Streaming {
+    hasher: Sha256,
+}
+
+impl Streaming {
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.hasher.update(bytes);
+    }
+
+    pub fn finish(self) -> String {
+        hex(&self.hasher.finalize())
+    }
+
+    /// Records the hash of the fully read `path`, for `streamed`.
+    pub fn finish_upload(self, path: &Path) {
+        let hash = self.finish();
+        log::debug!("Hashed {:?} while uploading it: {}", path, hash);
+        STREAMED
+            .lock()
+            .unwrap()
+            .get_or_insert_default()
+            .insert(path.to_path_buf(), hash);
+    }
+}
+
+/// The hash of `path` taken while it was last uploaded, if its upload was
+/// read to the end.
+pub fn streamed(path: &Path) -> Option<String> {
+    STREAMED.lock().unwrap().as_ref()?.get(path).cloned()
+}
+
+/// Lowercase hex of `bytes`.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Reads `path` once to hash its contents.
+pub fn file_hash(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut buf = vec![0; 64 * 1024];
+    let mut hash = Streaming::default();
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hash.finish());
+        }
+        hash.update(&buf[..n]);
+    }
+}
+
+/// What tells `path` apart before it is uploaded: its content hash when it
+/// is at most `max_bytes`, else its size and modification time, which cost
+/// no read of a big video. The same file gets the same identity whether or
+/// not it was uploaded since.
+pub fn identity(path: &Path, max_bytes: u64) -> std::io::Result<String> {
+    let meta = std::fs::metadata(path)?;
+    if meta.len() <= max_bytes {
+        return file_hash(path);
+    }
+    let mtime = meta
+        .modified()?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok(format!("{}b@{}ns", meta.len(), mtime.as_nanos()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streamed_pieces_hash_like_the_whole() {
+        let mut pieces = Streaming::default();
+        pieces.update(b"ab");
+        pieces.update(b"");
+        pieces.update(b"c");
+        let mut whole = Streaming::default();
+        whole.update(b"abc");
+        let expected = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(pieces.finish(), expected);
+        assert_eq!(whole.finish(), expected);
+    }
+
+    #[test]
+    fn identity_of_big_files_is_size_and_mtime() {
+        let path = std::env::temp_dir().join(format!("telegoy-digest-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, b"abc").unwrap();
+        assert_eq!(identity(&path, 3).unwrap(), file_hash(&path).unwrap());
+        assert!(identity(&path, 2).unwrap().starts_with("3b@"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,51 @@
+// ---------------------------
+// Comments under channel posts
+// ---------------------------
+
+use crate::send;
+use std::time::{Duration, Instant};
+use teloxide::RequestError;
+use teloxide::prelude::*;
+use teloxide::types::{AllowedUpdate, MessageId, MessageOrigin, UpdateKind};
+
+/// How long Telegram gets to copy a channel post into its discussion group
+const COPY_WAIT: Duration = Duration::from_secs(30);
+
+/// The copy Telegram makes of channel post `post` in the discussion group
+/// `group`, which comments on the post reply to, or None when it did not
+/// show up in time. It is found among the bot's updates, so the bot must be
+/// in the group and see its messages (as an admin, or with privacy mode
+/// off) and have no webhook set; the updates read on the way are confirmed,
+/// so anything else polling the same bot misses them.
+pub async fn find_copy(
+    bot: &Bot,
+    channel: ChatId,
+    group: ChatId,
+    post: MessageId,
+) -> Result<Option<MessageId>, RequestError> {
+    let deadline = Instant::now() + COPY_WAIT;
+    let mut offset = 0;
+    while Instant::now() < deadline {
+        let request = bot
+            .get_updates()
+            .offset(offset)
+            .timeout(5)
+            .allowed_updates([AllowedUpdate::Message]);
+        let updates = send::logged(bot, request).await?;
+        for update in updates {
+            offset = update.id.0 as i32 + 1;
+            if let UpdateKind::Message(message) = update.kind
+                && message.chat.id == group
+                && message.is_automatic_forward()
+                && let Some(MessageOrigin::Channel {
+                    chat, message_id, ..
+                }) = message.forward_origin()
+                && chat.id == channel
+                && *message_id == post
+            {
+                return Ok(Some(message.id));
+            }
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,492 @@
+// ---------------------------
+// Caption markup
+// ---------------------------
+
+use crate::CaptionFormat;
+use std::collections::HashSet;
+
+// Tags Telegram turns into entities; anything else is rejected by its parser
+const HTML_TAGS: &[&str] = &[
+    "b",
+    "strong",
+    "i",
+    "em",
+    "u",
+    "ins",
+    "s",
+    "strike",
+    "del",
+    "span",
+    "tg-spoiler",
+    "a",
+    "tg-emoji",
+    "code",
+    "pre",
+    "blockquote",
+];
+
+/// Number of formatting entities Telegram will create for `text` in
+/// `format`. Entities it detects by itself (links, mentions, hashtags) are
+/// not counted.
+pub fn count(text: &str, format: CaptionFormat) -> usize {
+    match format {
+        CaptionFormat::Plain => 0,
+        CaptionFormat::Markdown => count_markdown(text),
+        CaptionFormat::Html => count_html(text),
+    }
+}
+
+fn count_html(text: &str) -> usize {
+    text.split('<')
+        .skip(1)
+        .filter(|tag| !tag.starts_with('/'))
+        .filter_map(|tag| tag.split(|c: char| c.is_whitespace() || c == '>').next())
+        .filter(|name| HTML_TAGS.contains(&name.to_ascii_lowercase().as_str()))
+        .count()
+}
+
+// Counts MarkdownV2 entities as they open: each `*`, `_`, `__`, `~`, `||`
+// pair, code span, code block, `[link]` and run of `>` quote lines.
+fn count_markdown(text: &str) -> usize {
+    let mut count = 0;
+    // Span markers currently open
+    let mut open: Vec<&str> = Vec::new();
+    let mut quoting = false;
+    let mut rest = text;
+    let mut line_start = true;
+    while let Some(c) = rest.chars().next() {
+        if line_start {
+            let quote = c == '>';
+            if quote && !quoting {
+                count += 1;
+            }
+            quoting = quote;
+        }
+        line_start = c == '\n';
+        let marker = ["```", "||", "__", "*", "_", "~", "`", "["]
+            .into_iter()
+            .find(|m| rest.starts_with(m));
+        match marker {
+            _ if c == '\\' => {
+                let mut chars = rest.chars();
+                chars.next();
+                chars.next();
+                rest = chars.as_str();
+                continue;
+            }
+            Some(fence @ ("```" | "`")) => {
+                count += 1;
+                // Nothing inside code is markup
+                let body = &rest[fence.len()..];
+                rest = body
+                    .find(fence)
+                    .map_or("", |end| &body[end + fence.len()..]);
+                continue;
+            }
+            Some("[") => count += 1,
+            Some(marker) => match open.iter().rposition(|m| *m == marker) {
+                Some(at) => open.truncate(at),
+                None => {
+                    count += 1;
+                    open.push(marker);
+                }
+            },
+            None => {}
+        }
+        rest = &rest[marker.map_or(c.len_utf8(), str::len)..];
+    }
+    count
+}
+
+/// Length of `text` once Telegram has parsed the markup, which is what its
+/// caption limit applies to.
+pub fn text_len(text: &str, format: CaptionFormat) -> usize {
+    match format {
+        CaptionFormat::Plain => text.chars().count(),
+        CaptionFormat::Markdown => markdown_len(text),
+        CaptionFormat::Html => html_len(text),
+    }
+}
+
+/// Splits `text` at its last line or word break outside any markup with at
+/// most `max` characters (as `text_len` counts them) before it, so both
+/// parts parse on their own. With no such break it cuts mid-word, which can
+/// split an entity.
+pub fn cut(text: &str, format: CaptionFormat, max: usize) -> (&str, &str) {
+    if text_len(text, format) <= max {
+        return (text, "");
+    }
+    let fits = |at: &usize| text_len(&text[..*at], format) <= max;
+    let at = breaks(text, format)
+        .into_iter()
+        .take_while(fits)
+        .last()
+        .filter(|at| *at > 0)
+        .unwrap_or_else(|| {
+            let chars = text.char_indices().map(|(at, _)| at);
+            chars.take_while(fits).last().unwrap_or(0)
+        });
+    (text[..at].trim_end(), text[at..].trim_start())
+}
+
+// Byte offsets of the spaces and line breaks of `text` where no markup of
+// `format` is open
+fn breaks(text: &str, format: CaptionFormat) -> Vec<usize> {
+    let mut breaks = Vec::new();
+    // Tag names or MarkdownV2 markers currently open
+    let mut open: Vec<&str> = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let at = text.len() - rest.len();
+        let mut skip = c.len_utf8();
+        match format {
+            CaptionFormat::Plain => {}
+            CaptionFormat::Html if c == '<' => {
+                let end = rest.find('>').map_or(rest.len(), |end| end + 1);
+                let tag = rest[1..end].trim_end_matches('>');
+                if let Some(name) = tag.strip_prefix('/') {
+                    let name = name.trim();
+                    if let Some(i) = open.iter().rposition(|n| n.eq_ignore_ascii_case(name)) {
+                        open.truncate(i);
+                    }
+                } else if let Some(name) = tag.split(|c: char| c.is_whitespace() || c == '/').next()
+                    && HTML_TAGS.contains(&name.to_ascii_lowercase().as_str())
+                {
+                    open.push(name);
+                }
+                skip = end;
+            }
+            CaptionFormat::Html => {}
+            CaptionFormat::Markdown => {
+                let marker = ["```", "||", "__", "*", "_", "~", "`", "[", "]"]
+                    .into_iter()
+                    .find(|m| rest.starts_with(m));
+                match marker {
+                    _ if c == '\\' => skip = rest.chars().take(2).map(char::len_utf8).sum(),
+                    Some(fence @ ("```" | "`")) => {
+                        let body = &rest[fence.len()..];
+                        skip = fence.len()
+                            + body.find(fence).map_or(body.len(), |end| end + fence.len());
+                    }
+                    Some("]") => {
+                        if open.last() == Some(&"[") {
+                            open.pop();
+                        }
+                        if rest[1..].starts_with('(') {
+                            skip = rest.find(')').map_or(rest.len(), |end| end + 1);
+                        }
+                    }
+                    Some(marker) => {
+                        match open.iter().rposition(|m| *m == marker) {
+                            Some(i) if marker != "[" => open.truncate(i),
+                            _ => open.push(marker),
+                        }
+                        skip = marker.len();
+                    }
+                    None => {}
+                }
+            }
+        }
+        if (c == ' ' || c == '\n') && open.is_empty() {
+            breaks.push(at);
+        }
+        rest = &rest[skip..];
+    }
+    breaks
+}
+
+// Characters outside tags, with each `&...;` reference counting as one
+fn html_len(text: &str) -> usize {
+    let mut len = 0;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '<' => {
+                chars.by_ref().find(|&c| c == '>');
+            }
+            '&' => {
+                let rest = chars.as_str();
+                if let Some(end) = rest.find(';').filter(|&end| end <= 10) {
+                    chars = rest[end + 1..].chars();
+                }
+                len += 1;
+            }
+            _ => len += 1,
+        }
+    }
+    len
+}
+
+// Characters that are not markup: escaped ones count, unescaped markers and
+// the `(url)` of a link do not
+fn markdown_len(text: &str) -> usize {
+    let mut len = 0;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => len += usize::from(chars.next().is_some()),
+            '*' | '_' | '~' | '|' | '`' | '[' => {}
+            ']' if chars.peek() == Some(&'(') => {
+                chars.by_ref().find(|&c| c == ')');
+            }
+            ']' => {}
+            _ => len += 1,
+        }
+    }
+    len
+}
+
+/// `text` with the characters `format` treats as markup escaped.
+pub fn escape(text: &str, format: CaptionFormat) -> String {
+    match format {
+        CaptionFormat::Plain => text.to_string(),
+        CaptionFormat::Markdown => {
+            let mut out = String::with_capacity(text.len());
+            for c in text.chars() {
+                if "_*[]()~`>#+-=|{}.!\\".contains(c) {
+                    out.push('\\');
+                }
+                out.push(c);
+            }
+            out
+        }
+        CaptionFormat::Html => text
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;"),
+    }
+}
+
+/// The hashtags in `text`, lowercased and without the `#`.
+pub fn hashtags(text: &str, format: CaptionFormat) -> HashSet<String> {
+    // `\#tag` is how MarkdownV2 writes `#tag`
+    let text = match format {
+        CaptionFormat::Markdown => text.replace('\\', ""),
+        _ => text.to_string(),
+    };
+    text.split('#')
+        .skip(1)
+        .map(|rest| {
+            rest.chars()
+                .take_while(|c| c.is_alphanumeric() || *c == '_')
+                .collect::<String>()
+                .to_lowercase()
+        })
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
+/// `text` rendered as web HTML, roughly the way Telegram shows it: the
+/// entity markup of `format` turned into tags, everything else escaped.
+pub fn to_html(text: &str, format: CaptionFormat) -> String {
+    let html = match format {
+        CaptionFormat::Plain => escape(text, CaptionFormat::Html),
+        CaptionFormat::Markdown => markdown_to_html(text),
+        CaptionFormat::Html => clean_html(text),
+    };
+    html.replace('\n', "<br>")
+}
+
+// Keeps the tags Telegram knows (links only with their href, spoilers as
+// `span.spoiler`) and escapes anything else that looks like a tag.
+fn clean_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        let tag = &rest[start..];
+        let Some(end) = tag.find('>') else {
+            out.push_str(&escape(tag, CaptionFormat::Html));
+            return out;
+        };
+        let inner = &tag[1..end];
+        let closing = inner.starts_with('/');
+        let name = inner
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace())
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let slash = if closing { "/" } else { "" };
+        match name.as_str() {
+            _ if !HTML_TAGS.contains(&name.as_str()) => {
+                out.push_str(&escape(&tag[..=end], CaptionFormat::Html));
+            }
+            "a" if !closing => {
+                let href = attribute(inner, "href").filter(|href| {
+                    ["http://", "https://", "tg://", "mailto:"]
+                        .iter()
+                        .any(|scheme| href.starts_with(scheme))
+                });
+                match href {
+                    Some(href) => out.push_str(&format!("<a href=\"{}\">", href)),
+                    None => out.push_str("<a>"),
+                }
+            }
+            "tg-spoiler" | "span" if closing => out.push_str("</span>"),
+            "tg-spoiler" | "span" => out.push_str("<span class=\"spoiler\">"),
+            "tg-emoji" => out.push_str(&format!("<{}span>", slash)),
+            _ => out.push_str(&format!("<{}{}>", slash, name)),
+        }
+        rest = &tag[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// Value of `name="..."` in the inside of a tag, as written (so still escaped)
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let at = tag.find(&format!("{}=", name))? + name.len() + 1;
+    let value = &tag[at..];
+    let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let value = &value[1..];
+    Some(&value[..value.find(quote)?])
+}
+
+// MarkdownV2 spans as tags: each marker opens its tag and the next one of
+// the same kind closes it, as `count_markdown` reads them.
+fn markdown_to_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut open: Vec<&str> = Vec::new();
+    let mut quoting = false;
+    let mut line_start = true;
+    // Where the text of the `[link]` being read started in `out`
+    let mut link_start = None;
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if line_start {
+            let quote = c == '>';
+            if quote != quoting {
+                out.push_str(if quote {
+                    "<blockquote>"
+                } else {
+                    "</blockquote>"
+                });
+            }
+            quoting = quote;
+            if quote {
+                rest = &rest[1..];
+                line_start = false;
+                continue;
+            }
+        }
+        line_start = c == '\n';
+        let marker = ["```", "||", "__", "*", "_", "~", "`", "[", "]"]
+            .into_iter()
+            .find(|m| rest.starts_with(m));
+        match marker {
+            _ if c == '\\' => {
+                let mut chars = rest.chars();
+                chars.next();
+                if let Some(escaped) = chars.next() {
+                    out.push_str(&escape(&escaped.to_string(), CaptionFormat::Html));
+                }
+                rest = chars.as_str();
+                continue;
+            }
+            Some(fence @ ("```" | "`")) => {
+                let body = &rest[fence.len()..];
+                let end = body.find(fence).unwrap_or(body.len());
+                let mut code = &body[..end];
+                let tag = if fence == "```" {
+                    // The first line of a block names its language
+                    code = code.split_once('\n').map_or(code, |(_, code)| code);
+                    "pre"
+                } else {
+                    "code"
+                };
+                out.push_str(&format!(
+                    "<{tag}>{}</{tag}>",
+                    escape(code, CaptionFormat::Html)
+                ));
+                rest = body.get(end + fence.len()..).unwrap_or("");
+                continue;
+            }
+            Some("[") => link_start = Some(out.len()),
+            Some("]") => match link_start.take() {
+                Some(start) if rest[1..].starts_with('(') => {
+                    let url_end = rest.find(')').unwrap_or(rest.len());
+                    let url = &rest[2..url_end];
+                    out.insert_str(
+                        start,
+                        &format!("<a href=\"{}\">", escape(url, CaptionFormat::Html)),
+                    );
+                    out.push_str("</a>");
+                    rest = rest.get(url_end + 1..).unwrap_or("");
+                    continue;
+                }
+                _ => {}
+            },
+            Some(marker) => {
+                let tag = match marker {
+                    "*" => "b",
+                    "_" => "i",
+                    "__" => "u",
+                    "~" => "s",
+                    _ => "span",
+                };
+                match open.iter().rposition(|m| *m == marker) {
+                    Some(at) => {
+                        open.truncate(at);
+                        out.push_str(&format!("</{}>", tag));
+                    }
+                    None => {
+                        open.push(marker);
+                        if marker == "||" {
+                            out.push_str("<span class=\"spoiler\">");
+                        } else {
+                            out.push_str(&format!("<{}>", tag));
+                        }
+                    }
+                }
+            }
+            None => out.push_str(&escape(&c.to_string(), CaptionFormat::Html)),
+        }
+        rest = &rest[marker.map_or(c.len_utf8(), str::len)..];
+    }
+    if quoting {
+        out.push_str("</blockquote>");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cut_keeps_plain_text_under_max() {
+        assert_eq!(
+            cut("one two three", CaptionFormat::Plain, 9),
+            ("one two", "three")
+        );
+        assert_eq!(cut("one two", CaptionFormat::Plain, 7), ("one two", ""));
+        assert_eq!(cut("abcdef", CaptionFormat::Plain, 4), ("abcd", "ef"));
+    }
+
+    #[test]
+    fn cut_counts_and_avoids_html_markup() {
+        let text = "<b>one two</b> three <a href=\"x y\">four</a>";
+        // "one two three" fits; the break inside <b> does not count
+        assert_eq!(
+            cut(text, CaptionFormat::Html, 14),
+            ("<b>one two</b> three", "<a href=\"x y\">four</a>")
+        );
+        assert_eq!(
+            cut(text, CaptionFormat::Html, 8),
+            ("<b>one two</b>", "three <a href=\"x y\">four</a>")
+        );
+    }
+
+    #[test]
+    fn cut_avoids_markdown_entities() {
+        let text = "*one two* three [four five](http://x) six";
+        assert_eq!(
+            cut(text, CaptionFormat::Markdown, 13),
+            ("*one two* three", "[four five](http://x) six")
+        );
+        let (head, rest) = cut(text, CaptionFormat::Markdown, 24);
+        assert_eq!(head, "*one two* three [four five](http://x)");
+        assert_eq!(rest, "six");
+    }
+}
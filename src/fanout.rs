@@ -0,0 +1,517 @@
+// ---------------------------
+// Fan-out to secondary chats
+// ---------------------------
+
+use crate::send::{self, Resend};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use teloxide::RequestError;
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardMarkup, ParseMode, Recipient};
+use tokio::task::JoinSet;
+
+/// Destinations re-sent to at the same time, unless --concurrency-auto
+/// lowers it
+pub const MAX_CONCURRENT: usize = 4;
+
+/// Attempts per post before a destination gives up on it
+const MAX_ATTEMPTS: u32 = 3;
+
+/// A post sent to the primary chat, with the files it contains
+pub struct Post {
+    pub paths: Vec<PathBuf>,
+    // The files actually uploaded for `paths`, for --limit-rate
+    pub uploads: Vec<PathBuf>,
+    // As sent to the primary chat, uploading the files
+    pub upload: Resend,
+    // The same post by the primary bot's file_ids, when Telegram returned them
+    pub resend: Option<Resend>,
+    // Versions for chats whose quality differs from the primary chat's, by
+    // quality name (None: the originals)
+    pub variants: HashMap<Option<String>, Variant>,
+    // The caption in the other chats' caption languages, by language (None:
+    // the unsuffixed caption files)
+    pub captions: HashMap<Option<String>, Caption>,
+    // The post's own buttons (from its sidecar or package), instead of the
+    // config's keyboard
+    pub keyboard: Option<InlineKeyboardMarkup>,
+}
+
+/// The caption of a post in another language than the primary chat got
+pub struct Caption {
+    // Position of the captioned item in the post
+    pub index: usize,
+    pub text: String,
+    pub parse_mode: Option<ParseMode>,
+}
+
+/// A post in another quality than the primary chat got
+pub struct Variant {
+    pub upload: Resend,
+    pub uploads: Vec<PathBuf>,
+}
+
+/// Per-chat results of the fan-out
+#[derive(Debug)]
+pub struct DestinationReport {
+    pub chat: String,
+    // Username of the bot that posted there, when known
+    pub bot: Option<String>,
+    pub sent: usize,
+    pub failed: Vec<PathBuf>,
+    pub message_ids: Vec<i32>,
+    // The first message of each post, which its t.me link points to
+    pub post_ids: Vec<i32>,
+    // The quality the chat got, when it has one
+    pub quality: Option<String>,
+    // Telegram's ids of the files as posted there
+    pub file_ids: Vec<String>,
+    // The configured id of the chat, when it turned out to have become a
+    // supergroup (with `chat` its new id)
+    pub migrated_from: Option<String>,
+    // The caption language the chat got, when it had caption files of its own
+    pub caption_lang: Option<String>,
+}
+
+/// Options shared by every destination
+#[derive(Clone)]
+pub struct FanOut {
+    // The primary chat's bot first, then the config's extra bot_tokens
+    pub bots: Vec<Bot>,
+    pub keyboard: Option<InlineKeyboardMarkup>,
+    // --thread-id and --effect-id, narrowed down for each chat
+    pub options: send::PostOptions,
+    // Destinations re-sent to at the same time
+    pub concurrency: usize,
+    pub chunk_delay: Duration,
+    pub follow_migration: bool,
+    // With --dedupe-retries, the scratch chat posts are checked in
+    pub dedupe: Option<Recipient>,
+    // The primary chat's quality and the other chats', by chat
+    pub primary_quality: Option<String>,
+    pub qualities: HashMap<String, Option<String>>,
+    // The same for caption languages
+    pub primary_caption_lang: Option<String>,
+    pub caption_langs: HashMap<String, Option<String>>,
+}
+
+// One bot of the fan-out and what it has learned so far
+struct Sender {
+    bot: Bot,
+    username: Option<String>,
+    // Chats (by index) the bot is a member of
+    allowed: Vec<bool>,
+    // Per post and quality, this bot's file_ids once it uploaded the post;
+    // the lock is held during the upload so each bot uploads a post only once
+    file_ids: Vec<tokio::sync::Mutex<HashMap<Option<String>, Resend>>>,
+    // Set by RetryAfter so every destination of a throttled bot waits
+    throttled_until: Mutex<Option<Instant>>,
+}
+
+struct Shared {
+    fan_out: FanOut,
+    posts: Vec<Post>,
+    senders: Vec<Sender>,
+    // Chats not taken by a worker yet, by index
+    queue: Mutex<VecDeque<usize>>,
+}
+
+impl DestinationReport {
+    /// Adds the results of a later fan-out to the same chat.
+    pub fn merge(&mut self, later: DestinationReport) {
+        self.chat = later.chat;
+        self.bot = self.bot.take().or(later.bot);
+        self.sent += later.sent;
+        self.failed.extend(later.failed);
+        self.message_ids.extend(later.message_ids);
+        self.post_ids.extend(later.post_ids);
+        self.file_ids.extend(later.file_ids);
+        self.migrated_from = self.migrated_from.take().or(later.migrated_from);
+    }
+}
+
+impl FanOut {
+    /// Sends `posts` on to the secondary `chats` like `run`, in the other
+    /// --quality versions where chats have one, and adds the results to
+    /// `reports`. A chat that turns out to have become a supergroup is
+    /// updated in `chats` and gets later posts at its new id.
+    pub async fn deliver(
+        &mut self,
+        chats: &mut [String],
+        mut posts: Vec<Post>,
+        reports: &mut Vec<DestinationReport>,
+        qualities: &HashMap<String, crate::quality::Quality>,
+    ) {
+        if posts.is_empty() {
+            return;
+        }
+        // Each other quality is encoded once, for all the chats sharing it
+        let wanted: BTreeSet<&String> = self
+            .qualities
+            .values()
+            .filter(|name| **name != self.primary_quality)
+            .flatten()
+            .collect();
+        for name in wanted {
+            let quality = &qualities[name];
+            for post in &mut posts {
+                let (upload, uploads) = match post.variants.get(&None) {
+                    Some(original) => (&original.upload, &original.uploads),
+                    None => (&post.upload, &post.uploads),
+                };
+                let Resend::Media(media) = upload else {
+                    continue;
+                };
+                match crate::quality::variant(media, uploads, quality).await {
+                    Ok((media, uploads)) => {
+                        let upload = Resend::Media(media);
+                        let variant = Variant { upload, uploads };
+                        post.variants.insert(Some(name.clone()), variant);
+                    }
+                    Err(e) => log::warn!(
+                        "Sending {:?} to the {} chats as the first chat got it: {}",
+                        post.paths,
+                        name,
+                        e
+                    ),
+                }
+            }
+        }
+        for report in self.run(chats.to_vec(), posts).await {
+            if let Some(from) = &report.migrated_from {
+                for chat in chats.iter_mut().filter(|chat| *chat == from) {
+                    chat.clone_from(&report.chat);
+                }
+                let quality = self.qualities.get(from).cloned();
+                self.qualities
+                    .extend(quality.map(|q| (report.chat.clone(), q)));
+                let lang = self.caption_langs.get(from).cloned();
+                self.caption_langs
+                    .extend(lang.map(|l| (report.chat.clone(), l)));
+            }
+            let earlier = reports.iter_mut().find(|earlier| {
+                earlier.chat == report.chat || Some(&earlier.chat) == report.migrated_from.as_ref()
+            });
+            match earlier {
+                Some(earlier) => earlier.merge(report),
+                None => reports.push(report),
+            }
+        }
+    }
+
+    /// Sends `posts` to every chat in `chats`, up to `concurrency` chats at a
+    /// time (at least one per bot). Posts go out in order within a chat; a
+    /// failing chat does not affect the others. With several bots, each
+    /// worker takes the next chat its bot is a member of, so a bot that is
+    /// waiting out a flood limit leaves the remaining chats to the others.
+    pub async fn run(&self, chats: Vec<String>, posts: Vec<Post>) -> Vec<DestinationReport> {
+        let senders = self.senders(&chats, &posts).await;
+        let workers = match senders.len() {
+            0 => 0,
+            bots => self.concurrency.max(bots),
+        };
+        let shared = Arc::new(Shared {
+            fan_out: self.clone(),
+            posts,
+            senders,
+            queue: Mutex::new((0..chats.len()).collect()),
+        });
+        let chats = Arc::new(chats);
+        let mut reports = Vec::new();
+        let mut pending = JoinSet::new();
+        for worker in 0..workers {
+            let (shared, chats) = (shared.clone(), chats.clone());
+            pending.spawn(async move {
+                let sender = worker % shared.senders.len();
+                let mut reports = Vec::new();
+                while let Some(chat) = shared.next_chat(sender) {
+                    reports.push(shared.send_all(sender, chats[chat].clone()).await);
+                }
+                reports
+            });
+        }
+        while let Some(result) = pending.join_next().await {
+            match result {
+                Ok(done) => reports.extend(done),
+                Err(e) => log::error!("Fan-out task failed: {}", e),
+            }
+        }
+        // Chats none of the bots can post to
+        let left: Vec<usize> = shared.queue.lock().unwrap().drain(..).collect();
+        for chat in left {
+            log::error!(
+                "[{}] None of the bots is a member of this chat",
+                chats[chat]
+            );
+            reports.push(DestinationReport {
+                chat: chats[chat].clone(),
+                bot: None,
+                sent: 0,
+                failed: shared.posts.iter().flat_map(|p| p.paths.clone()).collect(),
+                message_ids: Vec::new(),
+                post_ids: Vec::new(),
+                quality: shared.fan_out.quality_of(&chats[chat]).clone(),
+                file_ids: Vec::new(),
+                migrated_from: None,
+                caption_lang: shared.fan_out.caption_lang_of(&chats[chat]).clone(),
+            });
+        }
+        reports
+    }
+
+    // With a single bot every chat is tried. With several, each bot's
+    // membership of each chat is checked first, and bots whose getMe fails
+    // are left out.
+    async fn senders(&self, chats: &[String], posts: &[Post]) -> Vec<Sender> {
+        let file_ids = |primary: bool| {
+            posts
+                .iter()
+                .map(|post| {
+                    let resend = post.resend.clone().filter(|_| primary);
+                    let known = resend.map(|resend| (self.primary_quality.clone(), resend));
+                    tokio::sync::Mutex::new(known.into_iter().collect())
+                })
+                .collect()
+        };
+        if self.bots.len() == 1 {
+            return vec![Sender {
+                bot: self.bots[0].clone(),
+                username: None,
+                allowed: vec![true; chats.len()],
+                file_ids: file_ids(true),
+                throttled_until: Mutex::new(None),
+            }];
+        }
+        let mut senders = Vec::new();
+        for (i, bot) in self.bots.iter().enumerate() {
+            let me = match send::logged(bot, bot.get_me()).await {
+                Ok(me) => me,
+                Err(e) => {
+                    log::warn!("Leaving bot {} out of the fan-out: {}", i + 1, e);
+                    continue;
+                }
+            };
+            let mut allowed = Vec::new();
+            for chat in chats {
+                let request = bot.get_chat_member(Recipient::from(chat.clone()), me.id);
+                let member = send::logged(bot, request).await;
+                let present = member.as_ref().is_ok_and(|m| m.is_present());
+                if !present {
+                    log::warn!("@{} is not a member of {}", me.username(), chat);
+                }
+                allowed.push(present);
+            }
+            senders.push(Sender {
+                bot: bot.clone(),
+                username: Some(me.username().to_string()),
+                allowed,
+                file_ids: file_ids(i == 0),
+                throttled_until: Mutex::new(None),
+            });
+        }
+        senders
+    }
+
+    fn quality_of(&self, chat: &str) -> &Option<String> {
+        self.qualities.get(chat).unwrap_or(&self.primary_quality)
+    }
+
+    fn caption_lang_of(&self, chat: &str) -> &Option<String> {
+        self.caption_langs
+            .get(chat)
+            .unwrap_or(&self.primary_caption_lang)
+    }
+}
+
+impl Shared {
+    fn next_chat(&self, sender: usize) -> Option<usize> {
+        let allowed = &self.senders.get(sender)?.allowed;
+        let mut queue = self.queue.lock().unwrap();
+        let position = queue.iter().position(|&chat| allowed[chat])?;
+        queue.remove(position)
+    }
+
+    async fn send_all(&self, sender: usize, chat: String) -> DestinationReport {
+        let fan_out = &self.fan_out;
+        let sender = &self.senders[sender];
+        let mut target = Recipient::from(chat.clone());
+        let options = fan_out.options.for_chat(&chat);
+        let mut report = DestinationReport {
+            chat: chat.clone(),
+            bot: sender.username.clone(),
+            sent: 0,
+            failed: Vec::new(),
+            message_ids: Vec::new(),
+            post_ids: Vec::new(),
+            quality: fan_out.quality_of(&chat).clone(),
+            file_ids: Vec::new(),
+            migrated_from: None,
+            caption_lang: fan_out.caption_lang_of(&chat).clone(),
+        };
+        let caption_lang = match &report.caption_lang {
+            lang if *lang != fan_out.primary_caption_lang => Some(lang),
+            _ => None,
+        };
+        for (i, post) in self.posts.iter().enumerate() {
+            if i > 0 {
+                tokio::time::sleep(fan_out.chunk_delay).await;
+            }
+            // Posts without a version in the chat's quality (stickers, or a
+            // failed encode) go out as the primary chat got them
+            let (quality, upload, uploads) = match post.variants.get(&report.quality) {
+                Some(variant) if report.quality != fan_out.primary_quality => {
+                    (&report.quality, &variant.upload, &variant.uploads)
+                }
+                _ => (&fan_out.primary_quality, &post.upload, &post.uploads),
+            };
+            // The chat's own caption goes onto whichever version is sent
+            let caption = caption_lang.and_then(|lang| post.captions.get(lang));
+            let mut file_ids = sender.file_ids[i].lock().await;
+            let result = match file_ids.get(quality).cloned() {
+                Some(resend) => {
+                    drop(file_ids);
+                    let resend = recaption(resend, caption);
+                    sender
+                        .send_with_retry(fan_out, post, &options, &mut target, &resend, &[])
+                        .await
+                }
+                None => {
+                    let sent = recaption(upload.clone(), caption);
+                    let result = sender
+                        .send_with_retry(fan_out, post, &options, &mut target, &sent, uploads)
+                        .await;
+                    if let Ok(messages) = &result
+                        && let Some(resend) = upload.reuse(messages)
+                    {
+                        file_ids.insert(quality.clone(), resend);
+                    }
+                    result
+                }
+            };
+            match result {
+                Ok(messages) => {
+                    report.sent += post.paths.len();
+                    report.message_ids.extend(messages.iter().map(|m| m.id.0));
+                    report.post_ids.extend(messages.first().map(|m| m.id.0));
+                    report
+                        .file_ids
+                        .extend(messages.iter().filter_map(send::file_id));
+                }
+                Err(e) => {
+                    log::error!(
+                        "[{}] Failed to send {:?}: {}",
+                        chat,
+                        post.paths,
+                        crate::api_errors::describe(&e)
+                    );
+                    report.failed.extend(post.paths.iter().cloned());
+                }
+            }
+            log::info!("[{}] {}/{} posts done", chat, i + 1, self.posts.len());
+        }
+        if let Recipient::Id(id) = target
+            && id.to_string() != chat
+        {
+            report.migrated_from = Some(std::mem::replace(&mut report.chat, id.to_string()));
+        }
+        report
+    }
+}
+
+// `resend` with its caption replaced by `caption`.
+fn recaption(resend: Resend, caption: Option<&Caption>) -> Resend {
+    match (resend, caption) {
+        (Resend::Media(mut media), Some(caption)) if caption.index < media.len() => {
+            let item = media.remove(caption.index);
+            let item = crate::albums::with_caption(item, caption.text.clone(), caption.parse_mode);
+            media.insert(caption.index, item);
+            Resend::Media(media)
+        }
+        (resend, _) => resend,
+    }
+}
+
+impl Sender {
+    // Waits out flood limits as often as Telegram asks, and retries network
+    // errors up to MAX_ATTEMPTS times with a growing pause.
+    async fn send_with_retry(
+        &self,
+        fan_out: &FanOut,
+        post: &Post,
+        options: &send::PostOptions,
+        target: &mut Recipient,
+        resend: &Resend,
+        uploads: &[PathBuf],
+    ) -> Result<Vec<Message>, RequestError> {
+        let keyboard = post.keyboard.as_ref().or(fan_out.keyboard.as_ref());
+        let mut attempt = 1;
+        // Whether an earlier attempt may have been posted without a reply
+        let mut lost_reply = false;
+        loop {
+            let until = *self.throttled_until.lock().unwrap();
+            if let Some(until) = until {
+                tokio::time::sleep_until(until.into()).await;
+            }
+            let result = match resend {
+                Resend::Media(media) => {
+                    send::send_media(
+                        &self.bot,
+                        target.clone(),
+                        options,
+                        media.clone(),
+                        keyboard,
+                        uploads,
+                    )
+                    .await
+                }
+                Resend::Sticker(sticker) => send::send_sticker(
+                    &self.bot,
+                    target.clone(),
+                    options,
+                    sticker.clone(),
+                    keyboard,
+                    uploads.first().map(PathBuf::as_path),
+                )
+                .await
+                .map(|message| vec![message]),
+            };
+            if let Ok(messages) = &result
+                && lost_reply
+                && let Some(scratch) = &fan_out.dedupe
+            {
+                // Nothing records what the lost attempt posted
+                crate::dedupe::after_retry(&self.bot, target, scratch, None, messages).await;
+            }
+            let pause = match &result {
+                Err(RequestError::MigrateToChatId(new_id)) if fan_out.follow_migration => {
+                    log::warn!(
+                        "[{}] Chat was upgraded to a supergroup, resending to its new id {}",
+                        crate::recipient_name(target),
+                        new_id
+                    );
+                    *target = Recipient::from(*new_id);
+                    Duration::ZERO
+                }
+                // Waited out at the top of the loop, without using up an
+                // attempt
+                Err(RequestError::RetryAfter(secs)) => {
+                    let until = Instant::now() + secs.duration();
+                    *self.throttled_until.lock().unwrap() = Some(until);
+                    continue;
+                }
+                Err(RequestError::Network(_) | RequestError::Io(_)) => {
+                    lost_reply = true;
+                    Duration::from_secs(2u64.pow(attempt))
+                }
+                _ => return result,
+            };
+            if attempt == MAX_ATTEMPTS {
+                return result;
+            }
+            attempt += 1;
+            tokio::time::sleep(pause).await;
+        }
+    }
+}
@@ -0,0 +1,141 @@
+// ---------------------------
+// Inline keyboard layouts
+// ---------------------------
+
+use config::{Config, File, FileFormat};
+use serde::Deserialize;
+use std::path::Path;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+
+// Telegram's limit for callback_data, in bytes
+const MAX_CALLBACK_BYTES: usize = 64;
+
+/// Inline keyboard from the `[keyboard]` config section or --keyboard-file,
+/// attached to messages sent individually (media groups can't carry one).
+///
+/// ```toml
+/// [keyboard]
+/// rows = [
+///     [{ text = "Website", url = "https://example.com" }],
+///     [{ text = "Like", callback_data = "like" }, { text = "Share", switch_inline_query = "" }],
+/// ]
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct KeyboardConfig {
+    pub rows: Vec<Vec<ButtonSpec>>,
+}
+
+/// One button; exactly one action must be set
+#[derive(Clone, Debug, Deserialize)]
+pub struct ButtonSpec {
+    pub text: String,
+    pub url: Option<String>,
+    pub callback_data: Option<String>,
+    pub switch_inline_query: Option<String>,
+    pub switch_inline_query_current_chat: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct KeyboardFile {
+    keyboard: KeyboardConfig,
+}
+
+/// Reads a TOML file with a `[keyboard]` section.
+pub fn load_file(path: &Path) -> Result<KeyboardConfig, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let file: KeyboardFile = Config::builder()
+        .add_source(File::from_str(&text, FileFormat::Toml))
+        .build()
+        .and_then(|c| c.try_deserialize())
+        .map_err(|e| e.to_string())?;
+    Ok(file.keyboard)
+}
+
+impl KeyboardConfig {
+    /// Validates every button and builds the markup.
+    pub fn build(&self) -> Result<InlineKeyboardMarkup, String> {
+        build_rows(&self.rows)
+    }
+}
+
+/// Buttons of a single post, from a sidecar's or a package's `buttons`
+/// (rows of `{ text, url }`). Callback buttons are refused: nothing would
+/// answer their presses, since telegoy does not poll for updates.
+pub fn build_post_buttons(rows: &[Vec<ButtonSpec>]) -> Result<InlineKeyboardMarkup, String> {
+    for (r, row) in rows.iter().enumerate() {
+        for (b, spec) in row.iter().enumerate() {
+            if spec.callback_data.is_some() {
+                return Err(format!(
+                    "row {} button {}: callback_data buttons need a bot answering them; use a url",
+                    r + 1,
+                    b + 1
+                ));
+            }
+        }
+    }
+    build_rows(rows)
+}
+
+fn build_rows(specs: &[Vec<ButtonSpec>]) -> Result<InlineKeyboardMarkup, String> {
+    if specs.is_empty() || specs.iter().any(Vec::is_empty) {
+        return Err("keyboard rows must not be empty".to_string());
+    }
+    let mut rows = Vec::new();
+    for (r, row) in specs.iter().enumerate() {
+        let mut buttons = Vec::new();
+        for (b, spec) in row.iter().enumerate() {
+            let button = spec
+                .build()
+                .map_err(|e| format!("row {} button {}: {}", r + 1, b + 1, e))?;
+            buttons.push(button);
+        }
+        rows.push(buttons);
+    }
+    Ok(InlineKeyboardMarkup::new(rows))
+}
+
+impl ButtonSpec {
+    fn build(&self) -> Result<InlineKeyboardButton, String> {
+        if self.text.trim().is_empty() {
+            return Err("text is empty".to_string());
+        }
+        let text = self.text.clone();
+        let button = match (
+            &self.url,
+            &self.callback_data,
+            &self.switch_inline_query,
+            &self.switch_inline_query_current_chat,
+        ) {
+            (Some(url), None, None, None) => {
+                let parsed =
+                    reqwest::Url::parse(url).map_err(|e| format!("url {:?}: {}", url, e))?;
+                if !matches!(parsed.scheme(), "http" | "https" | "tg") {
+                    return Err(format!("url {:?} must use http, https or tg", url));
+                }
+                InlineKeyboardButton::url(text, parsed)
+            }
+            (None, Some(data), None, None) => {
+                if data.is_empty() || data.len() > MAX_CALLBACK_BYTES {
+                    return Err(format!(
+                        "callback_data must be 1 to {} bytes",
+                        MAX_CALLBACK_BYTES
+                    ));
+                }
+                InlineKeyboardButton::callback(text, data)
+            }
+            (None, None, Some(query), None) => {
+                InlineKeyboardButton::switch_inline_query(text, query)
+            }
+            (None, None, None, Some(query)) => {
+                InlineKeyboardButton::switch_inline_query_current_chat(text, query)
+            }
+            _ => {
+                return Err(
+                    "set exactly one of url, callback_data, switch_inline_query or switch_inline_query_current_chat"
+                        .to_string(),
+                );
+            }
+        };
+        Ok(button)
+    }
+}
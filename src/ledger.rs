@@ -0,0 +1,433 @@
+// ---------------------------
+// Write-ahead send ledger
+// ---------------------------
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+/// Ledger of the current batch, kept next to the config
+pub const LEDGER_FILE: &str = "telegoy_ledger.jsonl";
+
+/// What --resume does with chunks whose send may or may not have happened
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ResumePolicy {
+    /// Ask for each in-doubt chunk
+    Prompt,
+    Skip,
+    Resend,
+}
+
+/// One JSON line of the ledger
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Record {
+    /// Appended before a send is attempted, and again with the messages
+    /// Telegram answered with, before the send is seen through to `Done`
+    Intent {
+        files: Vec<PathBuf>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        message_ids: Vec<i32>,
+    },
+    /// Appended once Telegram accepted the send
+    Done {
+        files: Vec<PathBuf>,
+        // The chat they were posted in, which differs from the configured
+        // one after a supergroup migration
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        chat: Option<String>,
+        message_ids: Vec<i32>,
+        // Content hash of each uploaded file, taken while it was sent
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        hashes: BTreeMap<PathBuf, String>,
+    },
+    /// Messages --delete-after scheduled for deletion; kept across batches
+    /// until `telegoy reaper` has deleted them
+    Expiry(Expiry),
+    /// The last batch seen through to a chat, for --dedup-window; kept
+    /// across batches, the newest per chat
+    Batch(Batch),
+}
+
+/// Messages of one chat to delete once `delete_at` (Unix seconds) has passed
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Expiry {
+    pub chat: String,
+    pub message_ids: Vec<i32>,
+    pub delete_at: i64,
+}
+
+/// A batch all of whose files reached `chat` at `sent_at` (Unix seconds)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Batch {
+    pub chat: String,
+    // content_hash of its files
+    pub hash: String,
+    pub sent_at: i64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ChunkState {
+    InDoubt,
+    Sent,
+}
+
+/// Appends intent/done records around every send, fsyncing each one, so a
+/// killed run can be resumed without guessing what reached the chat. The
+/// file is locked while written, since the reaper may rewrite it meanwhile.
+pub struct Ledger {
+    // None for --validate runs, which must not touch the real batch's ledger
+    file: Option<std::fs::File>,
+    // State of each chunk from the previous run, keyed by its files
+    previous: HashMap<Vec<PathBuf>, ChunkState>,
+    // Messages the previous run was answered with for chunks it left in
+    // doubt
+    posted: HashMap<Vec<PathBuf>, Vec<i32>>,
+    policy: ResumePolicy,
+}
+
+impl Ledger {
+    /// Opens the ledger, continuing the previous run's one with `resume` and
+    /// starting a new batch otherwise.
+    pub fn open(resume: bool, policy: ResumePolicy) -> Result<Self, String> {
+        let path = Path::new(LEDGER_FILE);
+        let mut previous = HashMap::new();
+        let mut posted = HashMap::new();
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| e.to_string())?;
+        locked(&file.try_clone().map_err(|e| e.to_string())?, || {
+            if !resume {
+                // A new batch starts over, keeping the scheduled deletions
+                // and the last batch of each chat
+                let records = read(path).map(|(records, _)| records).unwrap_or_default();
+                let mut batches = HashMap::new();
+                file.set_len(0).map_err(|e| e.to_string())?;
+                for record in records {
+                    match record {
+                        Record::Expiry(_) => write_record(&mut file, &record)?,
+                        Record::Batch(batch) => {
+                            batches.insert(batch.chat.clone(), batch);
+                        }
+                        _ => {}
+                    }
+                }
+                for batch in batches.into_values() {
+                    write_record(&mut file, &Record::Batch(batch))?;
+                }
+                return Ok(());
+            }
+            let (records, torn) = read(path)?;
+            if !torn.is_empty() {
+                log::warn!(
+                    "Ignoring {} torn record(s) in {} (lines {:?})",
+                    torn.len(),
+                    LEDGER_FILE,
+                    torn
+                );
+            }
+            for record in records {
+                match record {
+                    Record::Intent { files, message_ids } => {
+                        if !message_ids.is_empty() {
+                            posted.insert(files.clone(), message_ids);
+                        }
+                        previous.entry(files).or_insert(ChunkState::InDoubt);
+                    }
+                    Record::Done { files, .. } => {
+                        posted.remove(&files);
+                        previous.insert(files, ChunkState::Sent);
+                    }
+                    Record::Expiry(_) | Record::Batch(_) => {}
+                }
+            }
+            Ok(())
+        })?;
+        Ok(Ledger {
+            file: Some(file),
+            previous,
+            posted,
+            policy,
+        })
+    }
+
+    /// A ledger that records nothing and lets every chunk through.
+    pub fn disabled() -> Self {
+        Ledger {
+            file: None,
+            previous: HashMap::new(),
+            posted: HashMap::new(),
+            policy: ResumePolicy::Resend,
+        }
+    }
+
+    /// Whether `files` still need sending: chunks recorded as done are
+    /// skipped and in-doubt ones follow the resume policy.
+    pub fn should_send(&self, files: &[PathBuf]) -> bool {
+        match self.previous.get(files) {
+            None => true,
+            Some(ChunkState::Sent) => {
+                log::info!("Already sent according to {}: {:?}", LEDGER_FILE, files);
+                false
+            }
+            Some(ChunkState::InDoubt) => match self.policy {
+                ResumePolicy::Skip => false,
+                ResumePolicy::Resend => true,
+                ResumePolicy::Prompt => {
+                    eprint!("{:?} may already have been sent. Send again? [y/N] ", files);
+                    let mut answer = String::new();
+                    let _ = std::io::stdin().read_line(&mut answer);
+                    matches!(answer.trim(), "y" | "Y" | "yes")
+                }
+            },
+        }
+    }
+
+    /// Whether the previous run left `files` in doubt, so a send now may
+    /// post them a second time.
+    pub fn in_doubt(&self, files: &[PathBuf]) -> bool {
+        self.previous.get(files) == Some(&ChunkState::InDoubt)
+    }
+
+    /// The messages the previous run's send of in-doubt `files` was
+    /// answered with, when it got that far.
+    pub fn posted_ids(&self, files: &[PathBuf]) -> Option<&[i32]> {
+        self.posted.get(files).map(Vec::as_slice)
+    }
+
+    pub fn intent(&mut self, files: &[PathBuf]) -> Result<(), String> {
+        self.append(&Record::Intent {
+            files: files.to_vec(),
+            message_ids: Vec::new(),
+        })
+    }
+
+    /// Records the messages a send of `files` was answered with, so a run
+    /// stopped before `done` leaves them to --dedupe-retries.
+    pub fn posted(&mut self, files: &[PathBuf], message_ids: Vec<i32>) -> Result<(), String> {
+        self.append(&Record::Intent {
+            files: files.to_vec(),
+            message_ids,
+        })
+    }
+
+    pub fn done(
+        &mut self,
+        files: &[PathBuf],
+        chat: &str,
+        message_ids: Vec<i32>,
+        hashes: BTreeMap<PathBuf, String>,
+    ) -> Result<(), String> {
+        self.append(&Record::Done {
+            files: files.to_vec(),
+            chat: Some(chat.to_string()),
+            message_ids,
+            hashes,
+        })
+    }
+
+    /// Schedules `message_ids` of `chat` for deletion by the reaper.
+    pub fn expire(
+        &mut self,
+        chat: &str,
+        message_ids: Vec<i32>,
+        delete_at: i64,
+    ) -> Result<(), String> {
+        self.append(&Record::Expiry(Expiry {
+            chat: chat.to_string(),
+            message_ids,
+            delete_at,
+        }))
+    }
+
+    /// Records that the batch of `hash` reached `chat` at `sent_at`.
+    pub fn batch_sent(&mut self, chat: &str, hash: &str, sent_at: i64) -> Result<(), String> {
+        self.append(&Record::Batch(Batch {
+            chat: chat.to_string(),
+            hash: hash.to_string(),
+            sent_at,
+        }))
+    }
+
+    fn append(&mut self, record: &Record) -> Result<(), String> {
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        let handle = file.try_clone().map_err(|e| e.to_string())?;
+        locked(&handle, || write_record(file, record))
+    }
+}
+
+fn write_record(file: &mut std::fs::File, record: &Record) -> Result<(), String> {
+    let mut line = serde_json::to_string(record).map_err(|e| e.to_string())?;
+    line.push('\n');
+    file.write_all(line.as_bytes())
+        .and_then(|_| file.sync_all())
+        .map_err(|e| e.to_string())
+}
+
+// Runs `f` holding the exclusive lock on the ledger file.
+fn locked<T>(file: &std::fs::File, f: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+    file.lock()
+        .map_err(|e| format!("cannot lock {}: {}", LEDGER_FILE, e))?;
+    let result = f();
+    let _ = file.unlock();
+    result
+}
+
+/// The scheduled deletions whose time has come by `now` (Unix seconds).
+pub fn due(now: i64) -> Result<Vec<Expiry>, String> {
+    let (records, _) = read(Path::new(LEDGER_FILE))?;
+    Ok(records
+        .into_iter()
+        .filter_map(|record| match record {
+            Record::Expiry(expiry) if expiry.delete_at <= now => Some(expiry),
+            _ => None,
+        })
+        .collect())
+}
+
+/// The last batch recorded as sent to `chat`.
+pub fn last_batch(chat: &str) -> Result<Option<Batch>, String> {
+    let (records, _) = read(Path::new(LEDGER_FILE))?;
+    Ok(records.into_iter().rev().find_map(|record| match record {
+        Record::Batch(batch) if batch.chat == chat => Some(batch),
+        _ => None,
+    }))
+}
+
+/// SHA-256 of the files of a batch, in order and whatever their names, from
+/// each one's `digest::identity`. It is kept in the ledger, so it must stay
+/// the same across builds.
+pub fn batch_hash(files: &[PathBuf], full_hash_max_bytes: u64) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    for path in files {
+        hasher.update(crate::digest::identity(path, full_hash_max_bytes)?.as_bytes());
+        hasher.update([0]);
+    }
+    Ok(crate::digest::hex(&hasher.finalize()))
+}
+
+/// Removes the scheduled deletions in `done` from the ledger, leaving every
+/// other line as it was.
+pub fn forget(done: &[Expiry]) -> Result<(), String> {
+    if done.is_empty() {
+        return Ok(());
+    }
+    let mut file = match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(LEDGER_FILE)
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.to_string()),
+    };
+    let handle = file.try_clone().map_err(|e| e.to_string())?;
+    locked(&handle, || {
+        let mut text = String::new();
+        file.read_to_string(&mut text).map_err(|e| e.to_string())?;
+        let kept: String = text
+            .split_inclusive('\n')
+            .filter(
+                |line| match serde_json::from_str::<Record>(line.trim_end()) {
+                    Ok(Record::Expiry(expiry)) => !done.contains(&expiry),
+                    _ => true,
+                },
+            )
+            .collect();
+        file.set_len(0)
+            .and_then(|_| file.rewind())
+            .and_then(|_| file.write_all(kept.as_bytes()))
+            .and_then(|_| file.sync_all())
+            .map_err(|e| e.to_string())
+    })
+}
+
+// Parses every complete record and returns the (1-based) numbers of lines
+// that are torn: cut short without a newline or not a valid record.
+fn read(path: &Path) -> Result<(Vec<Record>, Vec<usize>), String> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), Vec::new())),
+        Err(e) => return Err(e.to_string()),
+    };
+    let mut reader = std::io::BufReader::new(file);
+    let (mut records, mut torn) = (Vec::new(), Vec::new());
+    let mut line = String::new();
+    let mut number = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+            break;
+        }
+        number += 1;
+        match serde_json::from_str::<Record>(line.trim_end()) {
+            Ok(record) if line.ends_with('\n') => records.push(record),
+            _ => torn.push(number),
+        }
+    }
+    Ok((records, torn))
+}
+
+/// `telegoy ledger verify`: reports torn records and in-doubt chunks,
+/// failing when the ledger is damaged.
+pub fn verify() -> Result<(), String> {
+    let (records, torn) = read(Path::new(LEDGER_FILE))?;
+    let mut state: HashMap<&[PathBuf], ChunkState> = HashMap::new();
+    for record in &records {
+        match record {
+            Record::Intent { files, .. } => {
+                state.entry(files).or_insert(ChunkState::InDoubt);
+            }
+            Record::Done { files, .. } => {
+                state.insert(files, ChunkState::Sent);
+            }
+            Record::Expiry(_) | Record::Batch(_) => {}
+        }
+    }
+    let sent = state.values().filter(|s| **s == ChunkState::Sent).count();
+    println!(
+        "{}: {} record(s), {} chunk(s) sent, {} in doubt",
+        LEDGER_FILE,
+        records.len(),
+        sent,
+        state.len() - sent
+    );
+    for (files, _) in state.iter().filter(|(_, s)| **s == ChunkState::InDoubt) {
+        println!("  in doubt: {:?}", files);
+    }
+    if torn.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("torn record(s) on line(s) {:?}", torn))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_hash_is_stable_and_ordered() {
+        let dir = std::env::temp_dir().join(format!("telegoy-ledger-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b, c) = (dir.join("a.jpg"), dir.join("b.jpg"), dir.join("c.jpg"));
+        std::fs::write(&a, b"a").unwrap();
+        std::fs::write(&b, b"b").unwrap();
+        std::fs::write(&c, b"a").unwrap();
+        let hash = batch_hash(&[a.clone(), b.clone()], 1024).unwrap();
+        assert_eq!(
+            hash,
+            "79144d4537275cb09ce5ae0246b0fb3269f39e68aa6a759fcdd842e1a91da889"
+        );
+        // Names do not matter, order does
+        assert_eq!(batch_hash(&[c, b.clone()], 1024).unwrap(), hash);
+        assert_ne!(batch_hash(&[b, a], 1024).unwrap(), hash);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,3696 @@
+mod albums;
+mod api_errors;
+mod archive;
+mod budget;
+mod cache;
+mod chats;
+mod collect;
+mod dedupe;
+mod digest;
+mod discussion;
+mod entities;
+mod fanout;
+mod keyboard;
+mod ledger;
+mod links;
+mod loudnorm;
+mod package;
+mod plan;
+pub mod post;
+mod preview;
+mod process;
+mod progress;
+mod publish;
+mod quality;
+mod quarantine;
+mod reaper;
+mod report;
+mod send;
+mod setup;
+mod space;
+mod sticker;
+mod subprocess;
+mod throttle;
+mod watch;
+mod watermark;
+
+use clap::{CommandFactory, FromArgMatches, Parser};
+use config::{Config, Environment, File};
+use image::ImageReader;
+use image::{DynamicImage, codecs::jpeg::JpegEncoder};
+use serde::{Deserialize, Serialize};
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use teloxide::RequestError;
+use teloxide::prelude::*;
+use teloxide::types::{EffectId, InlineKeyboardMarkup, InputFile, Me, ParseMode, Recipient};
+use tokio::task;
+
+// ---------------------------
+// 1. Configuration & CLI
+// ---------------------------
+
+#[derive(Debug, Default, Deserialize)]
+struct Settings {
+    // Default chat_id can be loaded from env/config
+    chat_id: String,
+    // API URL for local bot server
+    #[serde(default = "default_api_url")]
+    api_url: String,
+    // Caption used when neither the sidecar nor the static caption has text
+    #[serde(default)]
+    default_caption: Option<String>,
+    // UTC offset such as "+02:00" for date bucketing and {{now}}; the system
+    // zone when unset
+    #[serde(default)]
+    timezone: Option<String>,
+    // Send-rate budget applied with --respect-budget
+    #[serde(default)]
+    max_albums_per_hour: Option<u32>,
+    #[serde(default)]
+    max_messages_per_minute: Option<u32>,
+    // Default for --streaming when neither it nor --no-streaming is given
+    #[serde(default = "default_supports_streaming")]
+    supports_streaming: bool,
+    // Inline keyboard for individually sent messages ([keyboard] section)
+    #[serde(default)]
+    keyboard: Option<keyboard::KeyboardConfig>,
+    // More bots the fan-out to secondary chats spreads over
+    #[serde(default)]
+    bot_tokens: Vec<String>,
+    // Named destinations and identities selected with --profile
+    #[serde(default)]
+    profiles: std::collections::HashMap<String, Profile>,
+    // Seconds before a hung ffmpeg/ffprobe is killed; 60 for probes and 300
+    // for thumbnails and transcodes when unset
+    #[serde(default)]
+    subprocess_timeout_secs: Option<u64>,
+    // Chat --validate sends to and deletes from, which nobody else reads
+    #[serde(default)]
+    scratch_chat_id: Option<String>,
+    // Line --caption-tech-info appends, with {{width}}, {{height}},
+    // {{duration}}, {{size}} and {{codec}}; TECH_INFO_FORMAT when unset
+    #[serde(default)]
+    tech_info_format: Option<String>,
+    // Default for --watermark, turned off with --no-watermark
+    #[serde(default)]
+    watermark: Option<PathBuf>,
+    // Named ways of scaling media down for the chats that use them
+    #[serde(default)]
+    qualities: std::collections::HashMap<String, quality::Quality>,
+    // Per-destination options, by chat id or @username ([chats."@name"])
+    #[serde(default)]
+    chats: std::collections::HashMap<String, ChatOptions>,
+    // Size and quality of --photo-preset custom
+    #[serde(default)]
+    photo_preset: Option<quality::PhotoPreset>,
+    // User id of the bot's owner, which `me` stands for as a chat
+    #[serde(default)]
+    owner_id: Option<i64>,
+    // Hashtags --caption-hashtag-prefix uses instead of the type names, by
+    // type: photo, video or animation
+    #[serde(default)]
+    type_hashtags: std::collections::HashMap<String, String>,
+    // Language of the first chat's captions: static_caption.<lang>.txt and
+    // album.<lang>.txt are used instead of the unsuffixed files when they
+    // exist
+    #[serde(default)]
+    caption_lang: Option<String>,
+}
+
+/// A `[chats."<chat>"]` table
+#[derive(Debug, Default, Deserialize)]
+struct ChatOptions {
+    // Name of the [qualities.<name>] the chat gets its photos and videos
+    // in; the originals when unset
+    quality: Option<String>,
+    // Language of the chat's captions, as the top-level caption_lang
+    caption_lang: Option<String>,
+}
+
+/// A `[profiles.<name>]` table: its values replace the top-level ones
+#[derive(Debug, Default, Deserialize)]
+struct Profile {
+    chat_id: Option<String>,
+    api_url: Option<String>,
+    // Overrides TELOXIDE_TOKEN; bot_token_file is read when this is unset
+    bot_token: Option<String>,
+    bot_token_file: Option<PathBuf>,
+    caption_lang: Option<String>,
+}
+
+impl Profile {
+    fn token(&self) -> Result<Option<String>, String> {
+        if let Some(token) = &self.bot_token {
+            return Ok(Some(token.clone()));
+        }
+        match &self.bot_token_file {
+            Some(path) => std::fs::read_to_string(path)
+                .map(|text| Some(text.trim().to_string()))
+                .map_err(|e| format!("cannot read {:?}: {}", path, e)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn default_api_url() -> String {
+    "http://localhost:8081".to_string()
+}
+
+fn default_supports_streaming() -> bool {
+    true
+}
+
+// What serde makes of an empty config
+fn default_settings() -> Settings {
+    Settings {
+        api_url: default_api_url(),
+        supports_streaming: default_supports_streaming(),
+        ..Default::default()
+    }
+}
+
+/// Optional per-file overrides read from `<file>.json`
+#[derive(Debug, Default, Deserialize)]
+struct Sidecar {
+    // Hosted poster image used instead of a generated thumbnail
+    thumbnail_url: Option<String>,
+    // Video metadata overrides, taking precedence over --video-* and ffprobe
+    width: Option<u16>,
+    height: Option<u16>,
+    duration: Option<u16>,
+    // Image shown before playback, relative to the video's directory
+    cover: Option<PathBuf>,
+    // Suggested playback start in seconds
+    start_timestamp: Option<u32>,
+    // false leaves the file without --watermark
+    watermark: Option<bool>,
+    // Inline keyboard of the file when it is sent as a single message, as
+    // rows of `{ "text": ..., "url": ... }`
+    buttons: Option<Vec<Vec<keyboard::ButtonSpec>>>,
+}
+
+/// What to do with a file that fails a strict check
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum ErrorPolicy {
+    /// Leave the file out and continue with the rest
+    Skip,
+    /// Stop the whole run before anything is sent
+    Abort,
+}
+
+/// How photos are shrunk before upload, to save upload time
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum PhotoPresetChoice {
+    /// Upload photos as they are
+    None,
+    /// At most 2560 px on the long edge, JPEG quality 88
+    Web,
+    /// The config's [photo_preset] max_edge and jpeg_quality
+    Custom,
+}
+
+/// What happens when the temp filesystem looks too small for the copies
+/// (muted, watermarked, remuxed, transcoded, shrunk) the batch needs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum LowSpace {
+    /// Stop before any work starts
+    Abort,
+    /// Keep going one video at a time, deleting each intermediate copy as
+    /// soon as the next one is made from it
+    Serial,
+}
+
+/// How `supports_streaming` is set on uploaded videos
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum StreamingMode {
+    On,
+    Off,
+    /// Enable only for files whose moov atom comes before the media data
+    Auto,
+}
+
+/// How caption text is interpreted by Telegram
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CaptionFormat {
+    Plain,
+    /// MarkdownV2
+    Markdown,
+    Html,
+}
+
+impl CaptionFormat {
+    fn parse_mode(self) -> Option<ParseMode> {
+        match self {
+            CaptionFormat::Plain => None,
+            CaptionFormat::Markdown => Some(ParseMode::MarkdownV2),
+            CaptionFormat::Html => Some(ParseMode::Html),
+        }
+    }
+}
+
+/// Where an album's caption is posted
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum CaptionPlacement {
+    /// On the first item, as usual
+    Media,
+    /// In its own message replying to the album, split at 4096 characters
+    Reply,
+}
+
+/// What is done with a caption over Telegram's 1024 characters
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum LongCaption {
+    /// Cut it at a word break and end it with an ellipsis
+    Truncate,
+    /// Cut it at a word break and post the rest as a comment on the post
+    Comment,
+}
+
+/// Which other captions an inline `file::caption` replaces
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum InlineCaption {
+    /// The sidecar caption; static_caption.txt is still appended
+    Sidecar,
+    /// The sidecar and static captions
+    All,
+}
+
+/// Format of the end-of-run summary, printed to stdout regardless of RUST_LOG
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum SummaryFormat {
+    None,
+    /// A single line with the totals and elapsed time
+    Oneline,
+    /// The totals followed by every skipped and failed file
+    Full,
+    Json,
+    /// `<chat> <message id>` per message sent (--porcelain)
+    #[value(skip)]
+    Porcelain,
+}
+
+#[derive(clap::Subcommand, Clone, Debug)]
+enum Command {
+    /// Inspect the send ledger
+    Ledger {
+        #[command(subcommand)]
+        action: LedgerAction,
+    },
+    /// Check that ffmpeg is installed and that a --watch backend can see
+    /// changes in these directories
+    Doctor {
+        /// Directories that will be watched
+        dirs: Vec<PathBuf>,
+
+        /// Backend that will be used with --watch
+        #[arg(long, value_enum, default_value_t = watch::WatchBackend::Notify)]
+        watch_backend: watch::WatchBackend,
+    },
+    /// Delete the posts whose --delete-after time has passed (for cron;
+    /// --watch runs it every minute)
+    Reaper {
+        /// List what would be deleted without deleting it
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// The files --watch moved to its --quarantine-dir
+    Quarantine {
+        #[command(subcommand)]
+        action: QuarantineAction,
+    },
+}
+
+#[derive(clap::Subcommand, Clone, Debug)]
+enum LedgerAction {
+    /// Check telegoy_ledger.jsonl for torn records and list in-doubt chunks
+    Verify,
+}
+
+#[derive(clap::Subcommand, Clone, Debug)]
+enum QuarantineAction {
+    /// List the quarantined files, where they came from and their last error
+    List,
+    /// Move quarantined files back where they came from, so a running
+    /// --watch sends them again
+    Retry {
+        /// Files to move back, by path or name (default: all of them)
+        files: Vec<PathBuf>,
+    },
+}
+
+/// Layout of the log lines on stderr
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum LogFormat {
+    /// Colored, human-readable lines
+    Text,
+    /// One JSON object per line: timestamp, level, target and message
+    Json,
+}
+
+/// Whether log lines are colored
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum ColorChoice {
+    /// When stderr is a terminal and NO_COLOR is not set
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn write_style(self) -> pretty_env_logger::env_logger::WriteStyle {
+        use pretty_env_logger::env_logger::WriteStyle;
+        match self {
+            ColorChoice::Always => WriteStyle::Always,
+            ColorChoice::Never => WriteStyle::Never,
+            // https://no-color.org: any non-empty value turns color off
+            ColorChoice::Auto if std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) => {
+                WriteStyle::Never
+            }
+            ColorChoice::Auto => WriteStyle::Auto,
+        }
+    }
+}
+
+/// Criterion for splitting the batch into several albums
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum AlbumBy {
+    /// One album per capture day, in chronological order
+    Date,
+}
+
+/// How thoroughly photos are checked before upload
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum ImageValidation {
+    /// Parse the header and dimensions only
+    Header,
+    /// Decode every pixel
+    Full,
+}
+
+/// How thoroughly videos are checked before upload
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum VideoValidation {
+    /// Reject files ffprobe can't read or that have no streams
+    Probe,
+    /// Additionally decode the first --validate-seconds with ffmpeg
+    Decode,
+}
+
+#[derive(Parser, Clone, Debug)]
+#[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Files or directories to upload (space separated); `photo.jpg::text`
+    /// captions a single file inline (write `\::` for a literal `::`)
+    #[arg(required_unless_present = "from_package")]
+    files: Vec<PathBuf>,
+
+    /// Videos processed (muted, remuxed, probed, thumbnailed) at a time
+    /// [default: 1]
+    #[arg(long, value_name = "N")]
+    concurrency: Option<std::num::NonZeroUsize>,
+
+    /// Process one video per logical CPU, since the work is ffmpeg-bound,
+    /// and post to at most 2 --chat-id targets at a time; --concurrency
+    /// still sets the processing jobs
+    #[arg(long)]
+    concurrency_auto: bool,
+
+    /// Captions an inline `file::caption` replaces
+    #[arg(long, value_enum, default_value_t = InlineCaption::Sidecar)]
+    inline_caption_replaces: InlineCaption,
+
+    /// Post each album's caption on its first item, or as a reply to the
+    /// album (only in the first chat), which allows up to 4096 characters
+    #[arg(long, value_enum, default_value_t = CaptionPlacement::Media)]
+    caption_placement: CaptionPlacement,
+
+    /// Fit captions over 1024 characters on the media instead of leaving
+    /// Telegram to refuse them. `comment` posts the rest in the discussion
+    /// group linked to the channel, replying to Telegram's copy of the post
+    /// there: the bot must be a member of that group who sees its messages
+    /// (an admin, or with privacy mode off) and must not have a webhook set,
+    /// as the copy is found with get_updates. Without a linked group, or
+    /// when the copy does not show up within 30s, the rest is posted as a
+    /// reply to the post itself. Only the first chat gets the comment;
+    /// other chats and caption languages get the caption truncated
+    #[arg(long, value_enum, value_name = "MODE")]
+    long_caption: Option<LongCaption>,
+
+    /// Pin the comment of --long-caption comment in the discussion group
+    /// (the bot needs the right to pin messages there)
+    #[arg(long, requires = "long_caption")]
+    pin_comment: bool,
+
+    /// Put the buttons of albums sent as media groups, which cannot carry
+    /// any, on their caption posted as a reply (as with --caption-placement
+    /// reply); otherwise those buttons are left out
+    #[arg(long)]
+    buttons_on_reply: bool,
+
+    /// Caption to replace each post's caption with once it was sent, or
+    /// @FILE to read it from FILE (in the first chat only)
+    #[arg(long, value_name = "TEXT|@FILE", value_parser = parse_text_or_file)]
+    edit_caption_after: Option<String>,
+
+    /// Caption of each album of several files, or @FILE to read it from
+    /// FILE, in place of album.txt; the other items keep their own
+    /// captions. Telegram shows a caption under an album only when just one
+    /// item has one: with per-item captions too, each shows when its item is
+    /// opened, this one included
+    #[arg(long, value_name = "TEXT|@FILE", value_parser = parse_text_or_file)]
+    album_caption: Option<String>,
+
+    /// Put each album's caption on its Nth item (0-based) instead of the
+    /// first, e.g. the "after" of a before/after pair
+    #[arg(long, value_name = "N")]
+    caption_on_index: Option<usize>,
+
+    /// Send the media of a .zip post package, configured by its post.toml
+    #[arg(long)]
+    from_package: Option<PathBuf>,
+
+    /// Expand directory arguments recursively instead of only their top level
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// After sending, keep watching the directory arguments and send new
+    /// media files as batches once they stop changing
+    #[arg(long)]
+    watch: bool,
+
+    /// Send nothing and exit with status 75 unless at least N files are left
+    /// to send once the inputs are expanded and filtered, so a cron job can
+    /// try again later
+    #[arg(long, value_name = "N", conflicts_with = "watch")]
+    min_files: Option<usize>,
+
+    /// How --watch notices new files
+    #[arg(long, value_enum, default_value_t = watch::WatchBackend::Notify)]
+    watch_backend: watch::WatchBackend,
+
+    /// Rescan interval of --watch-backend poll (e.g. 30s)
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "10s")]
+    poll_interval: Duration,
+
+    /// How long --watch waits after the last new or changed file before
+    /// sending what has settled as one batch, in milliseconds (or e.g. 5s)
+    #[arg(long, value_name = "MS", value_parser = parse_millis, default_value = "2000")]
+    group_window: Duration,
+
+    /// Send a --watch batch after this long (e.g. 10m) even if files keep
+    /// arriving, so a steady trickle is still posted
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "5m")]
+    max_group_wait: Duration,
+
+    /// Retry the files of a --watch batch that failed, and move one that
+    /// failed --quarantine-after times in a row (with its sidecars) into
+    /// this directory. Files of a media group that Telegram refused count
+    /// together. `telegoy quarantine list|retry` shows them and moves them
+    /// back
+    #[arg(long, value_name = "DIR", global = true)]
+    quarantine_dir: Option<PathBuf>,
+
+    /// Failed sends in a row before --quarantine-dir takes a file
+    #[arg(long, value_name = "N", default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    quarantine_after: u32,
+
+    /// Send one album per directory, captioned by its album.txt or
+    /// _caption.txt ({{dir_name}} is the directory's name), else by its name
+    #[arg(long)]
+    album_per_dir: bool,
+
+    /// Split the batch into albums by capture date
+    #[arg(long, value_enum, conflicts_with = "album_per_dir")]
+    album_by: Option<AlbumBy>,
+
+    /// strftime format of the date caption used by --album-by date
+    #[arg(long, default_value = "%Y-%m-%d")]
+    album_date_format: String,
+
+    /// strftime format of the {{now}} caption placeholder, the time the run started
+    #[arg(long, default_value = "%Y-%m-%d %H:%M")]
+    date_format: String,
+
+    /// UTC offset such as "+02:00" for {{now}} and --album-by date [default:
+    /// the config's timezone, else the system zone]
+    #[arg(long, value_name = "OFFSET")]
+    timezone: Option<String>,
+
+    /// Maximum items per media group (1 sends every file as its own message)
+    #[arg(long, default_value_t = albums::MAX_ITEMS, value_parser = parse_album_size)]
+    album_size: usize,
+
+    /// Also start a new media group before the items' total size exceeds this many bytes
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_chunk_bytes: Option<u64>,
+
+    /// Pause between consecutive media groups (e.g. 1s, 500ms)
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "1s")]
+    chunk_delay: Duration,
+
+    /// Have `telegoy reaper` delete the posts this long after they were
+    /// sent (e.g. 24h), recording them in the ledger
+    #[arg(long, value_parser = humantime::parse_duration, value_name = "DURATION")]
+    delete_after: Option<Duration>,
+
+    /// Wait between albums for drip-feed posting; a range (10m-20m) picks a
+    /// random delay within it each time
+    #[arg(long, value_parser = parse_delay)]
+    album_delay: Option<DelayRange>,
+
+    /// Use the config's [profiles.<NAME>] chat, API server and bot token
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Optional Chat ID (overrides config/env); repeat to also post to more
+    /// chats, re-sending the first chat's uploads by file_id. `me` is the
+    /// bot's private chat with the config's owner_id: bots cannot reach a
+    /// user's Saved Messages, so this is the closest thing, and the owner
+    /// must have started the bot once
+    #[arg(short, long)]
+    chat_id: Vec<String>,
+
+    /// Forum topic to post in. With several --chat-id targets it applies to
+    /// the secondary ones, so e.g. a channel gets the album and its
+    /// discussion group a copy in this thread
+    #[arg(long, value_name = "ID")]
+    thread_id: Option<i32>,
+
+    /// Post the messages in the first chat as replies to this message
+    #[arg(long, value_name = "MESSAGE_ID")]
+    reply_to: Option<i32>,
+
+    /// With --reply-to, post anyway (without the reply) when the message
+    /// replied to was deleted, instead of failing the send
+    #[arg(long, requires = "reply_to")]
+    allow_orphan_reply: bool,
+
+    /// Message effect shown in private chats: fire, thumbs-up, thumbs-down,
+    /// heart, party, poop or an effect id (ignored for other chats)
+    #[arg(long, value_parser = send::parse_effect, value_name = "EFFECT")]
+    effect_id: Option<EffectId>,
+
+    /// Optional static_caption.txt path (overrides config/env)
+    #[arg(short, long)]
+    static_caption_path: Option<String>,
+
+    /// Caption layout rendered for every file, with {{caption}} (the sidecar
+    /// or default caption), {{static}}, {{filename}}, {{index}}, {{date}},
+    /// {{now}} and {{count}}
+    #[arg(long, value_name = "FILE")]
+    caption_template_file: Option<PathBuf>,
+
+    /// End each caption with the file's dimensions, duration, size and codec
+    /// (the config's tech_info_format), and album captions with the album's
+    /// file count and total size
+    #[arg(long)]
+    caption_tech_info: bool,
+
+    /// Hashtag to end every caption with (repeatable, with or without the
+    /// `#`); tags the caption already has are not repeated. Also available
+    /// as {{tags}}
+    #[arg(long = "tag", value_name = "TAG", value_parser = parse_tag)]
+    tags: Vec<String>,
+
+    /// Also end captions with a hashtag of the album's media type (#photo,
+    /// #video, one per type for mixed albums, the most common first, and
+    /// #animation for animations); rename them in the config's
+    /// [type_hashtags]
+    #[arg(long)]
+    caption_hashtag_prefix: bool,
+
+    /// Author line to end every caption with, in italics after the tags.
+    /// The Bot API has no signature field for bots (a channel only signs
+    /// posts made by its admins), so it is always part of the caption text
+    #[arg(long, value_name = "TEXT")]
+    signature: Option<String>,
+
+    /// Force a caption parse mode instead of inferring it from the caption file extension
+    #[arg(long, value_enum)]
+    parse_mode: Option<CaptionFormat>,
+
+    /// Fail instead of resending to the new chat id when a group turns out
+    /// to have been upgraded to a supergroup
+    #[arg(long)]
+    no_follow_migration: bool,
+
+    /// Resending to the new chat id is the default now; kept so older
+    /// scripts keep working
+    #[arg(long, hide = true, conflicts_with = "no_follow_migration")]
+    follow_migration: bool,
+
+    /// When a chat turns out to have become a supergroup, replace its old
+    /// id with the new one in config.toml (chat_id, the profiles' chat_id
+    /// and [chats."<id>"])
+    #[arg(long, conflicts_with = "no_follow_migration")]
+    update_config_on_migrate: bool,
+
+    /// Treat missing video width/height/duration as an error instead of sending without them
+    #[arg(long)]
+    strict_metadata: bool,
+
+    /// Warn about markdown/HTML captions that would produce more than N
+    /// formatting entities, which Telegram's parser may reject
+    #[arg(long, value_name = "N")]
+    max_caption_entities: Option<usize>,
+
+    /// Treat a caption that is too long or over --max-caption-entities as an
+    /// error instead of a warning
+    #[arg(long)]
+    strict_captions: bool,
+
+    /// What to do with files that fail a strict check
+    #[arg(long, value_enum, default_value_t = ErrorPolicy::Skip)]
+    on_error: ErrorPolicy,
+
+    /// Check that every album sends its files in the order they were given
+    /// (within an album) and stop before sending anything if not
+    #[arg(long)]
+    check_order: bool,
+
+    /// Skip files that already have a copy in this directory (searched
+    /// recursively), such as the archive sent files are moved to
+    #[arg(long, value_name = "DIR")]
+    skip_if_archived: Option<PathBuf>,
+
+    /// How --skip-if-archived recognizes a file's copy
+    #[arg(long, value_enum, default_value_t = archive::ArchiveMatch::Name, requires = "skip_if_archived")]
+    archive_match: archive::ArchiveMatch,
+
+    /// Longest time one file's decoding, thumbnail and metadata may take
+    /// (e.g. 2m); slower files are treated like a failed strict check
+    #[arg(long, value_parser = humantime::parse_duration, value_name = "DURATION")]
+    file_timeout: Option<Duration>,
+
+    /// Whether videos are marked as streamable [default: the config's
+    /// supports_streaming, else on]
+    #[arg(long, value_enum)]
+    streaming: Option<StreamingMode>,
+
+    /// Shorthand for --streaming off
+    #[arg(long, conflicts_with = "streaming")]
+    no_streaming: bool,
+
+    /// TOML file with a [keyboard] section (overrides the config's keyboard)
+    #[arg(long)]
+    keyboard_file: Option<PathBuf>,
+
+    /// Image to use as the thumbnail of every video (overrides sidecars and generation)
+    #[arg(long, conflicts_with = "no_thumbnail")]
+    thumbnail: Option<PathBuf>,
+
+    /// Always generate thumbnails with ffmpeg, ignoring <video>.thumb.jpg and thumbnail_url sidecars
+    #[arg(long)]
+    overwrite_thumbnail: bool,
+
+    /// Make generated thumbnails from the cover art or poster a video
+    /// carries as an attached picture, when it has one, rather than from a
+    /// frame
+    #[arg(long)]
+    prefer_embedded_thumbnail: bool,
+
+    /// Send videos without a thumbnail
+    #[arg(long)]
+    no_thumbnail: bool,
+
+    /// Point of the video generated thumbnails are taken from: seconds
+    /// (5, 5.5), MM:SS or HH:MM:SS.mmm; the first frame by default
+    #[arg(long, value_name = "TIME", value_parser = parse_timestamp, conflicts_with = "no_thumbnail")]
+    thumbnail_time: Option<Duration>,
+
+    /// Also write every video's thumbnail, as sent, to DIR/<file name>.jpg
+    /// (dry runs included) and list them in the summary
+    #[arg(long, value_name = "DIR")]
+    save_thumbnails: Option<PathBuf>,
+
+    /// Lead every album with this photo or video, processed like the other
+    /// files; it takes one of the album's places and gets its caption
+    #[arg(long, value_name = "PATH")]
+    prepend_media: Option<PathBuf>,
+
+    /// Width to report for every video instead of the probed value
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    video_width: Option<u16>,
+
+    /// Height to report for every video instead of the probed value
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    video_height: Option<u16>,
+
+    /// Duration in seconds to report for every video instead of the probed value
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    video_duration: Option<u16>,
+
+    /// Image shown before playback of every video (unlike the thumbnail it
+    /// is not downscaled); a sidecar's `cover` takes precedence
+    #[arg(long, value_name = "PATH")]
+    video_cover: Option<PathBuf>,
+
+    /// Suggested playback start of every video, in seconds
+    #[arg(long, value_name = "SECONDS")]
+    video_start: Option<u32>,
+
+    /// Remux MP4/MOV files that are not faststart-optimized before upload (stream copy)
+    #[arg(long)]
+    fix_faststart: bool,
+
+    /// PNG composited onto copies of the photos and videos before upload
+    /// [default: the config's watermark]
+    #[arg(long, value_name = "PNG")]
+    watermark: Option<PathBuf>,
+
+    /// Where --watermark goes
+    #[arg(long, value_enum, default_value_t = watermark::Position::BottomRight)]
+    watermark_pos: watermark::Position,
+
+    /// What to do when the temp directory has less free space than the
+    /// batch's derived copies are estimated to need
+    #[arg(long, value_enum, default_value_t = LowSpace::Abort)]
+    low_space: LowSpace,
+
+    // Set by --low-space serial once the space check failed
+    #[arg(skip)]
+    serial_processing: bool,
+
+    /// Shrink photos larger than the preset to JPEG copies before upload;
+    /// smaller photos are uploaded as they are
+    #[arg(long, value_enum, default_value_t = PhotoPresetChoice::None)]
+    photo_preset: PhotoPresetChoice,
+
+    /// Opacity of --watermark, from 0 (invisible) to 1
+    #[arg(long, default_value_t = 1.0, value_parser = parse_opacity)]
+    watermark_opacity: f32,
+
+    /// Send without the config's watermark
+    #[arg(long, conflicts_with = "watermark")]
+    no_watermark: bool,
+
+    /// Drop the audio track of videos before upload (stream copy, so the
+    /// video is not re-encoded and the remux stays fast)
+    #[arg(long)]
+    mute: bool,
+
+    /// Bring the audio of videos to --target-lufs with ffmpeg's two-pass
+    /// loudnorm (the video stream is copied); left out when there is no
+    /// ffmpeg, and a video it fails on is sent as it is
+    #[arg(long, conflicts_with = "mute")]
+    normalize_video_audio: bool,
+
+    /// Bring audio files (mp3, m4a, ogg, opus, flac, wav) to --target-lufs
+    /// with ffmpeg's two-pass loudnorm; left out when there is no ffmpeg,
+    /// and a file it fails on is sent as it is
+    #[arg(long)]
+    normalize_audio: bool,
+
+    /// Integrated loudness --normalize-audio and --normalize-video-audio aim
+    /// for
+    #[arg(
+        long,
+        value_name = "LUFS",
+        default_value_t = loudnorm::DEFAULT_TARGET_LUFS,
+        allow_hyphen_values = true
+    )]
+    target_lufs: f64,
+
+    /// Check that photos decode before adding them to the album
+    #[arg(long, value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "header")]
+    validate_images: Option<ImageValidation>,
+
+    /// How thoroughly videos are checked for corruption before upload
+    #[arg(long, value_enum, default_value_t = VideoValidation::Probe)]
+    validate_videos: VideoValidation,
+
+    /// Seconds of each video decoded by --validate-videos=decode
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    validate_seconds: u32,
+
+    /// Send .tgs/.webm files as animated stickers (one message each, outside the album)
+    #[arg(long)]
+    as_sticker: bool,
+
+    /// Send .mp4/.gif files as silent auto-playing animations (one message each)
+    #[arg(long)]
+    as_animation: bool,
+
+    /// Hide the photos, videos and animations behind a spoiler until tapped
+    #[arg(long)]
+    spoiler: bool,
+
+    /// Transcode .gif animations to H.264 MP4 before upload, which is
+    /// usually 10-20x smaller [default: on when ffmpeg is installed]
+    #[arg(long, overrides_with = "no_gif_to_mp4")]
+    gif_to_mp4: bool,
+
+    /// Upload .gif animations as they are
+    #[arg(long, overrides_with = "gif_to_mp4")]
+    no_gif_to_mp4: bool,
+
+    /// GIFs smaller than this many bytes are uploaded without transcoding
+    #[arg(long, default_value_t = 200_000, value_name = "BYTES")]
+    gif_min_bytes: u64,
+
+    /// Warn about albums that mix portrait and landscape media, which look
+    /// uneven in Telegram's grid
+    #[arg(long)]
+    check_orientation: bool,
+
+    /// Send the portrait and the landscape media of mixed albums as
+    /// separate albums
+    #[arg(long)]
+    split_by_orientation: bool,
+
+    /// Send the photos, videos and audio of mixed albums as separate albums,
+    /// one after the other
+    #[arg(long)]
+    auto_split_types: bool,
+
+    /// The order --auto-split-types sends the albums of each type in, e.g.
+    /// `photos,videos`; unlisted types follow in the order they first appear
+    #[arg(
+        long,
+        value_name = "TYPES",
+        value_delimiter = ',',
+        requires = "auto_split_types"
+    )]
+    type_order: Vec<albums::MediaType>,
+
+    /// Send <video>.srt/.vtt sidecars as documents after their album
+    #[arg(long)]
+    include_subtitles: bool,
+
+    /// Process files and print what would be sent without sending anything
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Check that Telegram accepts the batch without publishing it: send
+    /// everything to the config's scratch_chat_id and delete each message
+    /// as soon as it was accepted. The Bot API has no validate-only call, so
+    /// the files are really uploaded, and members of the scratch chat may
+    /// see the messages for a moment
+    #[arg(long, conflicts_with_all = ["dry_run", "resume"])]
+    validate: bool,
+
+    /// Rehearse in CHAT first: send everything there, then ask (or wait
+    /// --test-delay) before posting it to the real chats by the rehearsal's
+    /// file_ids
+    #[arg(long, value_name = "CHAT", conflicts_with_all = ["validate", "reply_to"])]
+    test_chat: Option<String>,
+
+    /// Post to the real chats this long after the rehearsal instead of asking
+    #[arg(long, value_parser = humantime::parse_duration, value_name = "DURATION", requires = "test_chat")]
+    test_delay: Option<Duration>,
+
+    /// Delete the rehearsal messages when posting is not confirmed
+    #[arg(long, requires = "test_chat")]
+    cleanup_test: bool,
+
+    /// Format of the --dry-run plan
+    #[arg(long, value_enum, default_value_t = plan::PlanFormat::Text)]
+    output: plan::PlanFormat,
+
+    /// Upload bandwidth used to estimate send times in the --dry-run plan (e.g. 20mbit, 5MB)
+    #[arg(long, value_parser = plan::parse_bandwidth)]
+    assume_bandwidth: Option<u64>,
+
+    /// Cap the combined upload rate of all files (e.g. 20mbit, 5MB)
+    #[arg(long, value_parser = plan::parse_bandwidth)]
+    limit_rate: Option<u64>,
+
+    /// Cap the upload rate of each file on its own; with --limit-rate, the stricter applies
+    #[arg(long, value_parser = plan::parse_bandwidth)]
+    limit_rate_per_file: Option<u64>,
+
+    /// Save a contact sheet of the albums' items to this image file (not uploaded)
+    #[arg(long, value_name = "OUT.png")]
+    preview_image: Option<PathBuf>,
+
+    /// Render the albums and animations as they would be posted (laid out
+    /// like Telegram, captions formatted, metadata below each message) to a
+    /// self-contained HTML file; works with --dry-run
+    #[arg(long, value_name = "OUT.html")]
+    preview: Option<PathBuf>,
+
+    /// Open the --preview file in the browser once written
+    #[arg(long, requires = "preview")]
+    preview_open: bool,
+
+    /// Wait for the send-rate budget from the config (shared through telegoy_budget.json)
+    #[arg(long)]
+    respect_budget: bool,
+
+    /// Continue the batch recorded in telegoy_ledger.jsonl, skipping chunks already sent
+    #[arg(long)]
+    resume: bool,
+
+    /// With --resume, what to do with chunks that may or may not have been sent
+    #[arg(long, value_enum, default_value_t = ledger::ResumePolicy::Prompt)]
+    resume_policy: ledger::ResumePolicy,
+
+    /// Refuse to send a batch of the same files (by content) that was sent
+    /// in full to the same chat less than this long ago (e.g. 30m), so a
+    /// double cron run posts nothing twice
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    dedup_window: Option<Duration>,
+
+    /// Files up to this size are read in full to hash them for
+    /// --dedup-window; bigger ones are told apart by size and modification
+    /// time instead, unless an upload in this run hashed them already
+    #[arg(long, value_name = "BYTES", default_value_t = digest::DEFAULT_FULL_HASH_BYTES)]
+    full_hash_max_bytes: u64,
+
+    /// Send the batch even when --dedup-window would refuse it
+    #[arg(long, requires = "dedup_window")]
+    force: bool,
+
+    /// After resending an in-doubt chunk of --resume, delete the earlier
+    /// copy the ledger recorded messages for, when they hold the same files.
+    /// A retry with nothing recorded (such as a --chat-id send retried after
+    /// a network error) is only warned about. Needs scratch_chat_id in the
+    /// config, where the messages are forwarded to be compared.
+    #[arg(long)]
+    dedupe_retries: bool,
+
+    /// -v level of ffmpeg and ffprobe (e.g. warning, info, debug); their
+    /// output is logged when they fail. The decode check always uses error
+    #[arg(long, default_value = "error", value_name = "LEVEL")]
+    ffmpeg_loglevel: String,
+
+    /// Layout of the log lines (RUST_LOG still picks which are shown)
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Color the log lines
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Log every Telegram request (token masked, attachments as name and size) and its response
+    #[arg(long)]
+    debug_requests: bool,
+
+    /// Skip the get_me check of the Bot API server and token before processing
+    #[arg(long)]
+    no_preflight: bool,
+
+    /// Skip the get_chat lookup of each destination's type, which drops
+    /// --thread-id outside forums and --effect-id outside private chats
+    /// (then judged by the chat id alone)
+    #[arg(long)]
+    no_chat_lookup: bool,
+
+    /// Keep retrying the get_me check for this long while the Bot API
+    /// server is unreachable (e.g. 60s, for a server starting alongside)
+    #[arg(long, value_parser = humantime::parse_duration, value_name = "DURATION")]
+    wait_for_api: Option<Duration>,
+
+    /// Regenerate thumbnails, muted copies, remuxes and GIF transcodes
+    /// instead of reusing the copies cached by earlier runs
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Format of the summary printed at the end of the run
+    #[arg(long, value_enum, default_value_t = SummaryFormat::Full)]
+    summary: SummaryFormat,
+
+    /// Print only `<chat_id> <message_id>` for each message sent, one per
+    /// line, instead of a summary; everything else goes to stderr
+    #[arg(long, conflicts_with_all = ["summary", "dry_run"])]
+    porcelain: bool,
+
+    /// Copy the t.me links of the posts (also listed in the summary) to the
+    /// clipboard
+    #[arg(long)]
+    copy_links: bool,
+
+    /// When files failed, write a command retrying only them, with the same
+    /// flags, to FILE as a shell script; without FILE it is shown in the
+    /// summary. Failures in the other --chat-id targets are not included.
+    #[arg(long, value_name = "FILE", num_args = 0..=1, require_equals = true, default_missing_value = "-")]
+    rerun_command: Option<PathBuf>,
+
+    // The command line without its input files, for --rerun-command
+    #[arg(skip)]
+    rerun_base: Vec<std::ffi::OsString>,
+}
+
+/// Parallelism of the processing and sending stages
+struct Concurrency {
+    processing: usize,
+    validation: usize,
+    fan_out: usize,
+}
+
+impl Concurrency {
+    fn new(args: &Cli) -> Self {
+        let cpus = std::thread::available_parallelism().map_or(4, |n| n.get());
+        let processing = match (args.concurrency, args.concurrency_auto) {
+            (Some(n), _) => n.get(),
+            (None, true) => cpus,
+            (None, false) => 1,
+        };
+        Concurrency {
+            processing,
+            // Photo decodes are cheap enough to always use every CPU
+            validation: args.concurrency.map_or(cpus, |n| n.get()),
+            fan_out: if args.concurrency_auto {
+                2
+            } else {
+                fanout::MAX_CONCURRENT
+            },
+        }
+    }
+}
+
+fn parse_album_size(value: &str) -> Result<usize, String> {
+    let size: usize = value
+        .parse()
+        .map_err(|_| format!("`{}` is not a number", value))?;
+    if !(1..=albums::MAX_ITEMS).contains(&size) {
+        return Err(format!("must be between 1 and {}", albums::MAX_ITEMS));
+    }
+    Ok(size)
+}
+
+// Seconds ("5", "5.5"), MM:SS or HH:MM:SS, each with optional fractions of
+// the last field
+fn parse_timestamp(value: &str) -> Result<Duration, String> {
+    let fields: Vec<&str> = value.trim().split(':').collect();
+    if fields.len() > 3 {
+        return Err("use seconds, MM:SS or HH:MM:SS".to_string());
+    }
+    let (last, whole) = fields.split_last().unwrap_or((&"", &[]));
+    let is_number = |field: &str, fraction: bool| {
+        let (int, frac) = match field.split_once('.') {
+            Some((int, frac)) if fraction => (int, Some(frac)),
+            _ => (field, None),
+        };
+        !int.is_empty()
+            && int.chars().all(|c| c.is_ascii_digit())
+            && frac.is_none_or(|frac| !frac.is_empty() && frac.chars().all(|c| c.is_ascii_digit()))
+    };
+    if !is_number(last, true) || !whole.iter().all(|field| is_number(field, false)) {
+        return Err(format!(
+            "{:?} is not a time; use seconds (5, 5.5), MM:SS or HH:MM:SS.mmm",
+            value
+        ));
+    }
+    let seconds: f64 = last
+        .parse()
+        .map_err(|_| format!("{:?} is not a time", value))?;
+    if !whole.is_empty() && seconds >= 60.0 {
+        return Err(format!("{:?} has more than 59 seconds", value));
+    }
+    let mut total = seconds;
+    for (i, field) in whole.iter().rev().enumerate() {
+        let n: u64 = field
+            .parse()
+            .map_err(|_| format!("{:?} is not a time", value))?;
+        if i == 0 && whole.len() == 2 && n >= 60 {
+            return Err(format!("{:?} has more than 59 minutes", value));
+        }
+        total += n as f64 * 60f64.powi(i as i32 + 1);
+    }
+    Ok(Duration::from_secs_f64(total))
+}
+
+// `time` as ffmpeg reads it, HH:MM:SS.mmm
+fn ffmpeg_timestamp(time: Duration) -> String {
+    let millis = time.as_millis();
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+// A plain number of milliseconds, or a duration such as "1.5s"
+fn parse_millis(value: &str) -> Result<Duration, String> {
+    match value.parse::<u64>() {
+        Ok(millis) => Ok(Duration::from_millis(millis)),
+        Err(_) => humantime::parse_duration(value).map_err(|e| e.to_string()),
+    }
+}
+
+fn parse_opacity(value: &str) -> Result<f32, String> {
+    let opacity: f32 = value
+        .parse()
+        .map_err(|_| format!("{:?} is not a number", value))?;
+    if (0.0..=1.0).contains(&opacity) {
+        Ok(opacity)
+    } else {
+        Err("must be between 0 and 1".to_string())
+    }
+}
+
+fn parse_text_or_file(value: &str) -> Result<String, String> {
+    match value.strip_prefix('@') {
+        Some(path) => {
+            std::fs::read_to_string(path).map_err(|e| format!("cannot read {:?}: {}", path, e))
+        }
+        None => Ok(value.to_string()),
+    }
+}
+
+// A hashtag without its `#`: letters, digits and underscores, not only digits,
+// since Telegram links nothing else.
+fn parse_tag(value: &str) -> Result<String, String> {
+    let tag = value.strip_prefix('#').unwrap_or(value);
+    if tag.is_empty() {
+        return Err("tag is empty".to_string());
+    }
+    if let Some(c) = tag.chars().find(|c| !(c.is_alphanumeric() || *c == '_')) {
+        return Err(format!(
+            "{:?} is not allowed in a hashtag, only letters, digits and _",
+            c
+        ));
+    }
+    if tag.chars().all(|c| c.is_ascii_digit()) {
+        return Err("a hashtag needs at least one letter".to_string());
+    }
+    Ok(tag.to_string())
+}
+
+/// A fixed delay, or a range to pick a random delay from
+#[derive(Clone, Copy, Debug)]
+struct DelayRange {
+    min: Duration,
+    max: Duration,
+}
+
+impl DelayRange {
+    fn pick(self) -> Duration {
+        if self.min == self.max {
+            return self.min;
+        }
+        rand::random_range(self.min..=self.max)
+    }
+}
+
+fn parse_delay(value: &str) -> Result<DelayRange, String> {
+    let parse = |s: &str| humantime::parse_duration(s.trim()).map_err(|e| e.to_string());
+    let (min, max) = match value.split_once('-') {
+        Some((min, max)) => (parse(min)?, parse(max)?),
+        None => (parse(value)?, parse(value)?),
+    };
+    if min > max {
+        return Err(format!("range start {:?} is after its end {:?}", min, max));
+    }
+    Ok(DelayRange { min, max })
+}
+
+// ---------------------------
+// 2. Helper Functions
+// ---------------------------
+
+const IMAGE_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "png", "webp"];
+const VIDEO_EXTENSIONS: [&str; 4] = ["mp4", "mov", "avi", "mkv"];
+const ANIMATION_EXTENSIONS: [&str; 2] = ["mp4", "gif"];
+const AUDIO_EXTENSIONS: [&str; 6] = ["mp3", "m4a", "ogg", "opus", "flac", "wav"];
+// Suffixes browsers and download tools add while a file is incomplete
+const PARTIAL_DOWNLOAD_EXTENSIONS: [&str; 4] = ["part", "download", "crdownload", "tmp"];
+// Longest caption Telegram accepts on a media message, after parsing the markup
+const MAX_CAPTION_CHARS: usize = 1024;
+// Exit status of a run --min-files held back (EX_TEMPFAIL: try again later)
+const EXIT_TOO_FEW_FILES: i32 = 75;
+
+// Extensions picked up when expanding a directory
+fn is_media_ext(ext: &str) -> bool {
+    IMAGE_EXTENSIONS.contains(&ext)
+        || VIDEO_EXTENSIONS.contains(&ext)
+        || ANIMATION_EXTENSIONS.contains(&ext)
+        || AUDIO_EXTENSIONS.contains(&ext)
+        || sticker::EXTENSIONS.contains(&ext)
+}
+
+// `clip.MP4.part` and the like: a media file that is still downloading
+fn is_partial_download(path: &Path) -> bool {
+    PARTIAL_DOWNLOAD_EXTENSIONS.contains(&file_ext(path).as_str())
+        && path
+            .file_stem()
+            .is_some_and(|stem| is_media_ext(&file_ext(Path::new(stem))))
+}
+
+fn file_size(path: &Path) -> u64 {
+    std::fs::metadata(path).map(|m| m.len()).unwrap_or_default()
+}
+
+fn file_ext(path: &Path) -> String {
+    path.extension()
+        .and_then(|os| os.to_str())
+        .map(|s| s.to_lowercase())
+        .unwrap_or_default()
+}
+
+/// Per-file outcomes reported at the end of the run
+#[derive(Debug)]
+struct Summary {
+    format: SummaryFormat,
+    started: Instant,
+    // Username of the bot that posted, known once get_me succeeded
+    bot: Option<String>,
+    sent: usize,
+    bytes_sent: u64,
+    // Upload bytes --photo-preset took off the photos
+    preset_bytes_saved: u64,
+    skipped: Vec<(PathBuf, String)>,
+    failed: Vec<PathBuf>,
+    incomplete_metadata: Vec<PathBuf>,
+    // (video, thumbnail) pairs written by --save-thumbnails
+    thumbnails: Vec<(PathBuf, PathBuf)>,
+    albums: Vec<AlbumReport>,
+    // Ids of the messages posted in the primary chat, and that chat
+    message_ids: Vec<i32>,
+    // The first message of each post there
+    post_ids: Vec<i32>,
+    chat: Option<String>,
+    // With --test-chat, `chat` is the rehearsal and the real chats are
+    // `destinations`
+    rehearsal: bool,
+    // Secondary --chat-id results
+    destinations: Vec<fanout::DestinationReport>,
+    // (old id, new id) of the chats that turned out to be supergroups now
+    migrations: Vec<(String, String)>,
+    // Whether --update-config-on-migrate rewrote config.toml
+    config_updated: bool,
+    // The caption language `chat` got, when it had caption files of its own
+    caption_lang: Option<String>,
+    // --rerun-command without a file
+    rerun_command: Option<String>,
+    // Content hash of each file uploaded to `chat`, taken as it was read
+    hashes: std::collections::BTreeMap<PathBuf, String>,
+    // Each file posted to `chat`, with its message, for the builder API
+    sent_items: Vec<post::SentItem>,
+    // Why each failed or rejected file was not sent, for --quarantine-dir
+    errors: std::collections::BTreeMap<PathBuf, String>,
+}
+
+/// Why a run stopped before its end
+#[derive(Debug)]
+struct Abort {
+    // Logged as an error
+    error: Option<String>,
+    // What the command exits with
+    status: i32,
+    // Whether the summary of what was done until then is logged too
+    summary: bool,
+}
+
+impl Abort {
+    // An error once the files are being processed or sent, which leaves a
+    // summary worth reading
+    fn midway(error: String) -> Self {
+        Abort {
+            error: Some(error),
+            status: 1,
+            summary: true,
+        }
+    }
+
+    // Stopped without an error, the summary so far logged
+    fn stopped(status: i32) -> Self {
+        Abort {
+            error: None,
+            status,
+            summary: true,
+        }
+    }
+}
+
+impl From<String> for Abort {
+    fn from(error: String) -> Self {
+        Abort {
+            error: Some(error),
+            status: 1,
+            summary: false,
+        }
+    }
+}
+
+/// Per-album results, listed when more than one album was sent
+#[derive(Debug)]
+struct AlbumReport {
+    label: String,
+    sent: usize,
+    failed: usize,
+    // The caption posted as a reply (--caption-placement reply)
+    caption_message_ids: Vec<i32>,
+    // The rest of a --long-caption comment caption, in the discussion group
+    comment_message_ids: Vec<i32>,
+}
+
+/// One media item as it will be sent, printed by --dry-run
+#[derive(Clone, Debug, Serialize)]
+struct PlanEntry {
+    path: PathBuf,
+    kind: &'static str,
+    details: Vec<String>,
+    // Where --save-thumbnails wrote the item's thumbnail
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail: Option<PathBuf>,
+    // What ffmpeg/ffprobe reported when they failed on the item
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+    // The video's thumbnail as sent (JPEG), shown by --preview
+    #[serde(skip)]
+    frame: Option<Vec<u8>>,
+}
+
+impl PlanEntry {
+    fn new(path: &Path, kind: &'static str) -> Self {
+        PlanEntry {
+            path: path.to_path_buf(),
+            kind,
+            details: Vec::new(),
+            thumbnail: None,
+            warnings: Vec::new(),
+            frame: None,
+        }
+    }
+}
+
+impl Summary {
+    /// The t.me link of each post, in the primary chat and then the others.
+    fn links(&self) -> Vec<String> {
+        let primary = self.chat.iter().map(|chat| (chat, &self.post_ids));
+        let others = self
+            .destinations
+            .iter()
+            .map(|dest| (&dest.chat, &dest.post_ids));
+        primary
+            .chain(others)
+            .flat_map(|(chat, ids)| ids.iter().filter_map(|id| links::message_link(chat, *id)))
+            .collect()
+    }
+
+    fn new(format: SummaryFormat) -> Self {
+        Summary {
+            format,
+            started: Instant::now(),
+            bot: None,
+            sent: 0,
+            bytes_sent: 0,
+            preset_bytes_saved: 0,
+            skipped: Vec::new(),
+            failed: Vec::new(),
+            incomplete_metadata: Vec::new(),
+            thumbnails: Vec::new(),
+            albums: Vec::new(),
+            message_ids: Vec::new(),
+            post_ids: Vec::new(),
+            chat: None,
+            rehearsal: false,
+            destinations: Vec::new(),
+            migrations: Vec::new(),
+            config_updated: false,
+            caption_lang: None,
+            rerun_command: None,
+            hashes: Default::default(),
+            sent_items: Vec::new(),
+            errors: Default::default(),
+        }
+    }
+
+    fn log(&self) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if let Some(command) = &self.rerun_command
+            && !matches!(self.format, SummaryFormat::Full | SummaryFormat::Json)
+        {
+            eprintln!("Retry the failed files with: {}", command);
+        }
+        match self.format {
+            SummaryFormat::None => {}
+            SummaryFormat::Oneline => println!(
+                "telegoy: {} sent, {} skipped, {} failed in {:.1}s",
+                self.sent,
+                self.skipped.len(),
+                self.failed.len(),
+                elapsed
+            ),
+            SummaryFormat::Full => self.print_full(elapsed),
+            SummaryFormat::Porcelain => {
+                if let Some(chat) = &self.chat {
+                    for id in &self.message_ids {
+                        println!("{} {}", chat, id);
+                    }
+                }
+                for dest in &self.destinations {
+                    for id in &dest.message_ids {
+                        println!("{} {}", dest.chat, id);
+                    }
+                }
+            }
+            SummaryFormat::Json => {
+                let report = serde_json::json!({
+                    "bot": self.bot,
+                    "sent": self.sent,
+                    "bytes_sent": self.bytes_sent,
+                    "preset_bytes_saved": self.preset_bytes_saved,
+                    "skipped": self.skipped.iter().map(|(path, reason)| {
+                        serde_json::json!({ "path": path, "reason": reason })
+                    }).collect::<Vec<_>>(),
+                    "failed": self.failed,
+                    "incomplete_metadata": self.incomplete_metadata,
+                    "thumbnails": self.thumbnails.iter().map(|(path, thumbnail)| {
+                        serde_json::json!({ "path": path, "thumbnail": thumbnail })
+                    }).collect::<Vec<_>>(),
+                    "albums": self.albums.iter().map(|album| {
+                        serde_json::json!({
+                            "label": album.label,
+                            "sent": album.sent,
+                            "failed": album.failed,
+                            "caption_message_ids": album.caption_message_ids,
+                            "comment_message_ids": album.comment_message_ids,
+                        })
+                    }).collect::<Vec<_>>(),
+                    "chat": self.chat,
+                    "caption_lang": self.caption_lang,
+                    "message_ids": self.message_ids,
+                    "rehearsal": self.rehearsal,
+                    "destinations": self.destinations.iter().map(|dest| {
+                        serde_json::json!({
+                            "chat": dest.chat,
+                            "bot": dest.bot,
+                            "sent": dest.sent,
+                            "failed": dest.failed,
+                            "message_ids": dest.message_ids,
+                            "quality": dest.quality,
+                            "file_ids": dest.file_ids,
+                            "migrated_from": dest.migrated_from,
+                            "caption_lang": dest.caption_lang,
+                        })
+                    }).collect::<Vec<_>>(),
+                    "migrations": self.migrations.iter().map(|(from, to)| {
+                        serde_json::json!({ "from": from, "to": to })
+                    }).collect::<Vec<_>>(),
+                    "config_updated": self.config_updated,
+                    "rerun_command": self.rerun_command,
+                    "hashes": self.hashes,
+                    "links": self.links(),
+                    "elapsed_seconds": elapsed,
+                });
+                println!("{}", report);
+            }
+        }
+    }
+
+    fn print_full(&self, elapsed: f64) {
+        println!(
+            "Summary: {} sent, {} skipped, {} failed in {:.1}s",
+            self.sent,
+            self.skipped.len(),
+            self.failed.len(),
+            elapsed
+        );
+        if let Some(bot) = &self.bot {
+            println!("  bot: @{}", bot);
+        }
+        if let (Some(chat), Some(lang)) = (&self.chat, &self.caption_lang) {
+            println!("  chat {}: {} captions", chat, lang);
+        }
+        for (from, to) in &self.migrations {
+            if self.config_updated {
+                println!("  chat {} is now {} (config.toml updated)", from, to);
+            } else {
+                println!(
+                    "  chat {} is now {}: UPDATE ITS ID IN YOUR CONFIG, it was upgraded to a supergroup",
+                    from, to
+                );
+            }
+        }
+        if self.rehearsal
+            && let Some(chat) = &self.chat
+        {
+            println!("  rehearsal in {}: messages {:?}", chat, self.message_ids);
+        }
+        if self.bytes_sent > 0 {
+            println!(
+                "  uploaded {} at {}/s on average",
+                plan::format_bytes(self.bytes_sent),
+                plan::format_bytes((self.bytes_sent as f64 / elapsed.max(0.001)) as u64)
+            );
+        }
+        if self.preset_bytes_saved > 0 {
+            println!(
+                "  photo preset saved {}",
+                plan::format_bytes(self.preset_bytes_saved)
+            );
+        }
+        for (path, reason) in &self.skipped {
+            println!("  skipped: {:?} ({})", path, reason);
+        }
+        for path in &self.failed {
+            println!("  failed: {:?}", path);
+        }
+        if let Some(command) = &self.rerun_command {
+            println!("  retry them with: {}", command);
+        }
+        for link in self.links() {
+            println!("  link: {}", link);
+        }
+        if self.albums.len() > 1 {
+            for album in &self.albums {
+                println!(
+                    "  album {}: {} sent, {} failed",
+                    album.label, album.sent, album.failed
+                );
+            }
+        }
+        for dest in &self.destinations {
+            let via = [
+                dest.bot.as_ref().map(|bot| format!("via @{}", bot)),
+                dest.quality
+                    .as_ref()
+                    .map(|name| format!("{} quality", name)),
+                dest.caption_lang
+                    .as_ref()
+                    .map(|lang| format!("{} captions", lang)),
+            ];
+            let via = match via.into_iter().flatten().collect::<Vec<_>>() {
+                parts if parts.is_empty() => String::new(),
+                parts => format!(" ({})", parts.join(", ")),
+            };
+            println!(
+                "  chat {}{}: {} sent, {} failed",
+                dest.chat,
+                via,
+                dest.sent,
+                dest.failed.len()
+            );
+            for path in &dest.failed {
+                println!("    failed: {:?}", path);
+            }
+        }
+        if !self.incomplete_metadata.is_empty() {
+            println!(
+                "{} video(s) had incomplete metadata:",
+                self.incomplete_metadata.len()
+            );
+            for path in &self.incomplete_metadata {
+                println!("  {:?}", path);
+            }
+        }
+        if !self.thumbnails.is_empty() {
+            println!("Thumbnails:");
+            for (path, thumbnail) in &self.thumbnails {
+                println!("  {:?}: {:?}", path, thumbnail);
+            }
+        }
+    }
+}
+
+fn encode_thumbnail(img: DynamicImage) -> Vec<u8> {
+    let resized = img.thumbnail(320, 320);
+    let mut bytes = Vec::new();
+    resized
+        .write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, 100))
+        .ok();
+    bytes
+}
+
+async fn load_thumbnail(image_path: PathBuf) -> Result<Vec<u8>, String> {
+    task::spawn_blocking(move || {
+        let img = ImageReader::open(&image_path)
+            .and_then(|r| r.with_guessed_format())
+            .map_err(|e| e.to_string())?
+            .decode()
+            .map_err(|e| e.to_string())?;
+        Ok(encode_thumbnail(img))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+// The frame at `at` of `video_path` as a thumbnail, or with `embedded` its
+// attached picture when it has one. It is cached under `source`, the file
+// the video was made from, and `kind`, which differs for watermarked videos.
+async fn generate_thumbnail(
+    video_path: String,
+    source: PathBuf,
+    kind: String,
+    at: Duration,
+    embedded: bool,
+) -> Result<Vec<u8>, String> {
+    if embedded {
+        match embedded_thumbnail(video_path.clone(), source.clone(), kind.clone()).await {
+            Ok(bytes) => return Ok(bytes),
+            Err(e) => log::debug!("{:?}: no embedded cover to use: {}", source, e),
+        }
+    }
+    let kind = if at.is_zero() {
+        kind
+    } else {
+        format!("{}-at{}ms", kind, at.as_millis())
+    };
+    let at = ffmpeg_timestamp(at);
+    task::spawn_blocking(move || {
+        let temp_file = temp_path(&format!("temp_thumb_{}.jpg", uuid::Uuid::new_v4()));
+
+        let made = cache::reuse_or_make(&source, &kind, &temp_file, || {
+            let output = subprocess::output(
+                std::process::Command::new("ffmpeg")
+                    .args([
+                        "-hide_banner",
+                        "-v",
+                        ffmpeg_loglevel(),
+                        "-y",
+                        "-i",
+                        &video_path,
+                        "-ss",
+                        &at,
+                        "-frames:v",
+                        "1",
+                        "-update",
+                        "1",
+                        "-q:v",
+                        "2",
+                    ])
+                    .arg(&temp_file),
+                subprocess::Work::Transcode,
+                Path::new(&video_path),
+                Some(&temp_file),
+            )
+            .map_err(|e| format!("could not run ffmpeg: {}", e))?;
+            if output.status.success() {
+                Ok(())
+            } else {
+                Err(format!(
+                    "ffmpeg exited with {}: {}",
+                    output.status,
+                    stderr_snippet(&output.stderr)
+                ))
+            }
+        });
+
+        let bytes = made.and_then(|_| {
+            let image = ImageReader::open(&temp_file)
+                .map_err(|e| e.to_string())?
+                .decode()
+                .map_err(|e| format!("ffmpeg wrote an unreadable frame: {}", e))?;
+            Ok(encode_thumbnail(image))
+        });
+
+        let _ = std::fs::remove_file(temp_file);
+        bytes
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+// The first attached picture (cover art, poster frame) of the video, copied
+// out as it is and re-encoded like a generated thumbnail.
+async fn embedded_thumbnail(
+    video_path: String,
+    source: PathBuf,
+    kind: String,
+) -> Result<Vec<u8>, String> {
+    task::spawn_blocking(move || {
+        let temp_file = temp_path(&format!("temp_cover_{}.img", uuid::Uuid::new_v4()));
+
+        let made = cache::reuse_or_make(&source, &format!("embedded-{}", kind), &temp_file, || {
+            let output = subprocess::output(
+                std::process::Command::new("ffmpeg")
+                    .args(["-hide_banner", "-v", ffmpeg_loglevel(), "-y", "-i"])
+                    .arg(&video_path)
+                    // Video streams minus those that are not attached pictures
+                    .args(["-map", "0:v", "-map", "-0:V", "-c", "copy"])
+                    .args(["-frames:v", "1", "-update", "1", "-f", "image2"])
+                    .arg(&temp_file),
+                subprocess::Work::Transcode,
+                Path::new(&video_path),
+                Some(&temp_file),
+            )
+            .map_err(|e| format!("could not run ffmpeg: {}", e))?;
+            if output.status.success() {
+                Ok(())
+            } else {
+                Err(stderr_snippet(&output.stderr))
+            }
+        });
+
+        let bytes = made.and_then(|_| {
+            let image = ImageReader::open(&temp_file)
+                .and_then(|r| r.with_guessed_format())
+                .map_err(|e| e.to_string())?
+                .decode()
+                .map_err(|e| format!("unreadable attached picture: {}", e))?;
+            Ok(encode_thumbnail(image))
+        });
+
+        let _ = std::fs::remove_file(temp_file);
+        bytes
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+// Telegram only accepts thumbnails uploaded as new files (no URLs or file_ids),
+// so remote posters are downloaded and re-encoded like generated thumbnails.
+async fn download_thumbnail(url: String) -> Option<Vec<u8>> {
+    let response = match reqwest::get(&url).await.and_then(|r| r.error_for_status()) {
+        Ok(r) => r,
+        Err(e) => {
+            log::warn!("Failed to download thumbnail {}: {}", url, e);
+            return None;
+        }
+    };
+    let body = match response.bytes().await {
+        Ok(b) => b,
+        Err(e) => {
+            log::warn!("Failed to read thumbnail {}: {}", url, e);
+            return None;
+        }
+    };
+
+    task::spawn_blocking(move || match image::load_from_memory(&body) {
+        Ok(img) => Some(encode_thumbnail(img)),
+        Err(e) => {
+            log::warn!("Thumbnail {} is not a valid image: {}", url, e);
+            None
+        }
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+// --ffmpeg-loglevel, set once at startup
+static FFMPEG_LOGLEVEL: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+
+/// The `-v` level ffmpeg and ffprobe run with.
+fn ffmpeg_loglevel() -> &'static str {
+    FFMPEG_LOGLEVEL.get().map_or("error", String::as_str)
+}
+
+// The end of ffmpeg's stderr, where the error is, at a length that fits a
+// log line. The whole text goes to the debug log.
+fn stderr_snippet(stderr: &[u8]) -> String {
+    const MAX_CHARS: usize = 500;
+    let text = String::from_utf8_lossy(stderr);
+    let text = text.trim();
+    let chars = text.chars().count();
+    if chars > MAX_CHARS {
+        log::debug!("Full ffmpeg output: {}", text);
+    }
+    match text.char_indices().nth(chars.saturating_sub(MAX_CHARS)) {
+        Some((cut, _)) if cut > 0 => format!("...{}", &text[cut..]),
+        _ => text.to_string(),
+    }
+}
+
+/// What ffprobe reported; `warnings` explain the values it could not read
+#[derive(Default)]
+struct ProbedMetadata {
+    width: Option<u16>,
+    height: Option<u16>,
+    duration: Option<u16>,
+    // Of the first video stream, e.g. "h264"
+    codec: Option<String>,
+    warnings: Vec<String>,
+}
+
+// Returns Err when ffprobe runs but considers the file unreadable (e.g. a
+// half-copied mp4 without moov atom). A missing ffprobe binary is not an error.
+async fn get_video_metadata(video_path: String) -> Result<ProbedMetadata, String> {
+    task::spawn_blocking(move || {
+        let mut probed = ProbedMetadata::default();
+
+        // Stream count & duration
+        let streams = subprocess::output(
+            std::process::Command::new("ffprobe").args([
+                "-v",
+                ffmpeg_loglevel(),
+                "-show_entries",
+                "format=nb_streams,duration",
+                "-of",
+                "default=noprint_wrappers=1",
+                &video_path,
+            ]),
+            subprocess::Work::Probe,
+            Path::new(&video_path),
+            None,
+        );
+        // A hung ffprobe is as bad as a failing one; a missing one is not
+        if let Err(e) = &streams
+            && e.kind() == std::io::ErrorKind::TimedOut
+        {
+            return Err(e.to_string());
+        }
+        if let Ok(output) = streams {
+            if !output.status.success() {
+                let stderr = stderr_snippet(&output.stderr);
+                log::warn!("ffprobe failed on {}: {}", video_path, stderr);
+                return Err(if stderr.is_empty() {
+                    format!("ffprobe exited with {}", output.status)
+                } else {
+                    stderr
+                });
+            }
+            let text = String::from_utf8_lossy(&output.stdout);
+            for line in text.lines() {
+                match line.split_once('=') {
+                    Some(("nb_streams", n)) if n.trim() == "0" => {
+                        return Err("ffprobe found no streams".to_string());
+                    }
+                    Some(("duration", d)) => {
+                        if let Ok(d_f) = d.trim().parse::<f64>()
+                            && d_f > 0.0
+                        {
+                            probed.duration = Some(d_f.round() as u16);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // Width, height & codec
+        let dimensions = subprocess::output(
+            std::process::Command::new("ffprobe").args([
+                "-v",
+                ffmpeg_loglevel(),
+                "-select_streams",
+                "v:0",
+                "-show_entries",
+                "stream=width,height,codec_name",
+                "-of",
+                "default=noprint_wrappers=1",
+                &video_path,
+            ]),
+            subprocess::Work::Probe,
+            Path::new(&video_path),
+            None,
+        );
+        if let Err(e) = &dimensions
+            && e.kind() == std::io::ErrorKind::TimedOut
+        {
+            probed.warnings.push(e.to_string());
+        }
+        if let Ok(output) = dimensions {
+            if !output.status.success() {
+                let warning = format!(
+                    "ffprobe could not read the dimensions: {}",
+                    stderr_snippet(&output.stderr)
+                );
+                log::warn!("{}: {}", video_path, warning);
+                probed.warnings.push(warning);
+            }
+            let text = String::from_utf8_lossy(&output.stdout);
+            for line in text.lines() {
+                let Some((key, value)) = line.split_once('=') else {
+                    continue;
+                };
+                let value = value.trim();
+                match key {
+                    "width" => probed.width = value.parse().ok().filter(|w| *w > 0),
+                    "height" => probed.height = value.parse().ok().filter(|h| *h > 0),
+                    "codec_name" if !value.is_empty() => probed.codec = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(probed)
+    })
+    .await
+    .unwrap_or_else(|_| Ok(ProbedMetadata::default()))
+}
+
+// The file to upload for audio file `path` and its duration: with
+// --normalize-audio a loudness-normalized copy, whose duration is probed
+// again as the new encoding can end a little earlier or later. A copy that
+// fails leaves the original to be sent.
+async fn prepare_audio(
+    path: &Path,
+    args: &Cli,
+    entry: &mut PlanEntry,
+    temp_files: &mut Vec<PathBuf>,
+) -> Result<(PathBuf, Option<u16>), String> {
+    let duration = get_video_metadata(path.display().to_string())
+        .await?
+        .duration;
+    if !args.normalize_audio {
+        return Ok((path.to_path_buf(), duration));
+    }
+    if args.dry_run {
+        entry
+            .details
+            .push(format!("normalize audio to {} LUFS", args.target_lufs));
+        return Ok((path.to_path_buf(), duration));
+    }
+    let source = path.to_path_buf();
+    match loudnorm::normalize(source.clone(), source, args.target_lufs).await {
+        Ok(Some(normalized)) => {
+            temp_files.push(normalized.clone());
+            let probed = get_video_metadata(normalized.display().to_string())
+                .await
+                .ok()
+                .and_then(|probed| probed.duration)
+                .or(duration);
+            if probed != duration {
+                log::debug!(
+                    "{:?} lasts {:?}s after normalizing, {:?}s before",
+                    path,
+                    probed,
+                    duration
+                );
+            }
+            log::info!("Normalized the audio of {:?}", path);
+            Ok((normalized, probed))
+        }
+        Ok(None) => {
+            log::debug!("{:?} has no audio to normalize", path);
+            Ok((path.to_path_buf(), duration))
+        }
+        Err(e) => {
+            log::warn!(
+                "Sending {:?} with its audio as it is: could not normalize it: {}",
+                path,
+                e
+            );
+            Ok((path.to_path_buf(), duration))
+        }
+    }
+}
+
+// Decodes the first `seconds` of the video to catch corruption ffprobe misses.
+async fn decode_check(video_path: PathBuf, seconds: u32) -> Result<(), String> {
+    task::spawn_blocking(move || {
+        let output = match subprocess::output(
+            std::process::Command::new("ffmpeg")
+                .args([
+                    "-hide_banner",
+                    "-v",
+                    "error",
+                    "-t",
+                    &seconds.to_string(),
+                    "-i",
+                ])
+                .arg(&video_path)
+                .args(["-f", "null", "-"]),
+            subprocess::Work::Transcode,
+            &video_path,
+            None,
+        ) {
+            Ok(output) => output,
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => return Err(e.to_string()),
+            Err(e) => {
+                log::warn!("Cannot run ffmpeg to decode {:?}: {}", video_path, e);
+                return Ok(());
+            }
+        };
+        let stderr = stderr_snippet(&output.stderr);
+        if !output.status.success() || !stderr.is_empty() {
+            return Err(if stderr.is_empty() {
+                format!("ffmpeg exited with {}", output.status)
+            } else {
+                stderr
+            });
+        }
+        Ok(())
+    })
+    .await
+    .unwrap_or(Ok(()))
+}
+
+// Walks the top-level MP4 boxes: a file is faststart when `moov` precedes `mdat`.
+fn is_faststart(path: &Path) -> std::io::Result<bool> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut file = std::fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut pos = 0u64;
+    while pos + 8 <= len {
+        file.seek(SeekFrom::Start(pos))?;
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header)?;
+        match &header[4..8] {
+            b"moov" => return Ok(true),
+            b"mdat" => return Ok(false),
+            _ => {}
+        }
+        let size = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+            // 64-bit box size follows the type
+            1 => {
+                let mut large = [0u8; 8];
+                file.read_exact(&mut large)?;
+                u64::from_be_bytes(large)
+            }
+            // Box extends to the end of the file
+            0 => break,
+            n => n as u64,
+        };
+        if size < 8 {
+            break;
+        }
+        pos += size;
+    }
+    Ok(false)
+}
+
+async fn probe_faststart(video_path: PathBuf) -> Option<bool> {
+    task::spawn_blocking(move || is_faststart(&video_path).ok())
+        .await
+        .ok()
+        .flatten()
+}
+
+// Stream copy only, so this is about as fast as copying the file. `source`
+// and `kind` name the result in the artifact cache, since `video_path` may
+// itself be a temp file (a muted or normalized copy).
+async fn remux_faststart(
+    video_path: PathBuf,
+    source: PathBuf,
+    kind: String,
+) -> Result<PathBuf, String> {
+    task::spawn_blocking(move || {
+        let ext = video_path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("mp4");
+        let temp_file = temp_path(&format!("temp_faststart_{}.{}", uuid::Uuid::new_v4(), ext));
+
+        let made = cache::reuse_or_make(&source, &kind, &temp_file, || {
+            let output = subprocess::output(
+                std::process::Command::new("ffmpeg")
+                    .args(["-hide_banner", "-v", ffmpeg_loglevel(), "-y", "-i"])
+                    .arg(&video_path)
+                    .args(["-c", "copy", "-movflags", "+faststart"])
+                    .arg(&temp_file),
+                subprocess::Work::Transcode,
+                &source,
+                Some(&temp_file),
+            )
+            .map_err(|e| e.to_string())?;
+            if output.status.success() {
+                Ok(())
+            } else {
+                Err(stderr_snippet(&output.stderr))
+            }
+        });
+
+        if let Err(e) = made {
+            let _ = std::fs::remove_file(&temp_file);
+            return Err(e);
+        }
+        Ok(temp_file)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+// Copies the video (and any subtitle/data streams) without its audio. Stream
+// copy means no re-encoding, so this takes about as long as reading the file.
+async fn strip_audio(video_path: PathBuf) -> Result<PathBuf, String> {
+    task::spawn_blocking(move || {
+        let ext = video_path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("mp4");
+        let temp_file = temp_path(&format!("temp_mute_{}.{}", uuid::Uuid::new_v4(), ext));
+
+        let made = cache::reuse_or_make(&video_path, "mute", &temp_file, || {
+            let output = subprocess::output(
+                std::process::Command::new("ffmpeg")
+                    .args(["-hide_banner", "-v", ffmpeg_loglevel(), "-y", "-i"])
+                    .arg(&video_path)
+                    .args(["-map", "0", "-map", "-0:a", "-c", "copy"])
+                    .arg(&temp_file),
+                subprocess::Work::Transcode,
+                &video_path,
+                Some(&temp_file),
+            )
+            .map_err(|e| format!("could not run ffmpeg: {}", e))?;
+            if output.status.success() {
+                Ok(())
+            } else {
+                Err(stderr_snippet(&output.stderr))
+            }
+        });
+
+        match made {
+            Ok(_) => Ok(temp_file),
+            Err(e) => {
+                let _ = std::fs::remove_file(&temp_file);
+                Err(e)
+            }
+        }
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+// The file's --watermark, unless its sidecar opts out
+fn watermark_for(args: &Cli, sidecar: &Sidecar) -> Option<watermark::Watermark> {
+    let logo = args.watermark.clone()?;
+    (sidecar.watermark != Some(false)).then_some(watermark::Watermark {
+        logo,
+        position: args.watermark_pos,
+        opacity: args.watermark_opacity,
+    })
+}
+
+// Copy of the photo with the watermark on it, PNG for PNGs and JPEG otherwise
+async fn watermark_photo(photo: PathBuf, mark: watermark::Watermark) -> Result<PathBuf, String> {
+    task::spawn_blocking(move || {
+        let ext = if file_ext(&photo) == "png" {
+            "png"
+        } else {
+            "jpg"
+        };
+        let temp_file = temp_path(&format!("temp_watermark_{}.{}", uuid::Uuid::new_v4(), ext));
+        let made = cache::reuse_or_make(&photo, &mark.kind(), &temp_file, || {
+            mark.photo(&photo, &temp_file)
+        });
+        match made {
+            Ok(_) => Ok(temp_file),
+            Err(e) => {
+                let _ = std::fs::remove_file(&temp_file);
+                Err(e)
+            }
+        }
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+// Overlays the watermark on `video_path` (the input, or the muted or
+// normalized copy of `source` cached as `copy_kind`), re-encoding the video
+// as a faststart H.264 MP4; audio is copied.
+async fn watermark_video(
+    (video_path, source): (PathBuf, PathBuf),
+    mark: watermark::Watermark,
+    copy_kind: Option<String>,
+) -> Result<PathBuf, String> {
+    task::spawn_blocking(move || {
+        let temp_file = temp_path(&format!("temp_watermark_{}.mp4", uuid::Uuid::new_v4()));
+        let kind = match copy_kind {
+            Some(copy_kind) => format!("{}-{}", copy_kind, mark.kind()),
+            None => mark.kind(),
+        };
+
+        let made = cache::reuse_or_make(&source, &kind, &temp_file, || {
+            let output = subprocess::output(
+                std::process::Command::new("ffmpeg")
+                    .args(["-hide_banner", "-v", ffmpeg_loglevel(), "-y", "-i"])
+                    .arg(&video_path)
+                    .arg("-i")
+                    .arg(&mark.logo)
+                    .args(["-filter_complex", &mark.filter()])
+                    .args([
+                        "-map",
+                        "[out]",
+                        "-map",
+                        "0:a?",
+                        "-c:v",
+                        "libx264",
+                        "-c:a",
+                        "copy",
+                        "-movflags",
+                        "+faststart",
+                    ])
+                    .arg(&temp_file),
+                subprocess::Work::Transcode,
+                &video_path,
+                Some(&temp_file),
+            )
+            .map_err(|e| format!("could not run ffmpeg: {}", e))?;
+            if output.status.success() {
+                Ok(())
+            } else {
+                Err(stderr_snippet(&output.stderr))
+            }
+        });
+
+        match made {
+            Ok(_) => Ok(temp_file),
+            Err(e) => {
+                let _ = std::fs::remove_file(&temp_file);
+                Err(e)
+            }
+        }
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+// Re-encodes a GIF as a faststart H.264 MP4. yuv420p and even dimensions are
+// what Telegram's players (and libx264) need, so odd-sized GIFs are cropped
+// by at most one pixel.
+async fn transcode_gif(gif_path: PathBuf) -> Result<PathBuf, String> {
+    task::spawn_blocking(move || {
+        let temp_file = temp_path(&format!("temp_gif_{}.mp4", uuid::Uuid::new_v4()));
+
+        let made = cache::reuse_or_make(&gif_path, "gif-mp4", &temp_file, || {
+            let output = subprocess::output(
+                std::process::Command::new("ffmpeg")
+                    .args(["-hide_banner", "-v", ffmpeg_loglevel(), "-y", "-i"])
+                    .arg(&gif_path)
+                    .args([
+                        "-an",
+                        "-c:v",
+                        "libx264",
+                        "-pix_fmt",
+                        "yuv420p",
+                        "-vf",
+                        "crop=trunc(iw/2)*2:trunc(ih/2)*2",
+                        "-movflags",
+                        "+faststart",
+                    ])
+                    .arg(&temp_file),
+                subprocess::Work::Transcode,
+                &gif_path,
+                Some(&temp_file),
+            )
+            .map_err(|e| format!("could not run ffmpeg: {}", e))?;
+            if output.status.success() {
+                Ok(())
+            } else {
+                Err(stderr_snippet(&output.stderr))
+            }
+        });
+
+        match made {
+            Ok(_) => Ok(temp_file),
+            Err(e) => {
+                let _ = std::fs::remove_file(&temp_file);
+                Err(e)
+            }
+        }
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+fn ffmpeg_installed() -> bool {
+    std::process::Command::new("ffmpeg")
+        .arg("-version")
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success())
+}
+
+/// A clip sent on its own through send_animation (Telegram plays these muted)
+struct AnimationPost {
+    entry: PlanEntry,
+    path: PathBuf,
+    // The file actually uploaded: `path` or its MP4 transcode
+    upload_path: PathBuf,
+    meta: VideoMeta,
+    caption: String,
+    // The same in the other chats' caption languages, by language
+    captions: std::collections::HashMap<Option<String>, String>,
+    parse_mode: Option<ParseMode>,
+    // Its own buttons, instead of the config's keyboard
+    keyboard: Option<InlineKeyboardMarkup>,
+}
+
+/// Thumbnail and dimensions shared by videos and animations
+#[derive(Clone)]
+struct VideoMeta {
+    thumbnail: Option<InputFile>,
+    width: Option<u16>,
+    height: Option<u16>,
+    duration: Option<u16>,
+    cover: Option<PathBuf>,
+    start_timestamp: Option<u32>,
+    // Copy of the thumbnail written by --save-thumbnails
+    saved_thumbnail: Option<PathBuf>,
+    // The thumbnail's JPEG, for --preview
+    frame: Option<Vec<u8>>,
+    // Problems ffmpeg/ffprobe reported without making the video unusable
+    warnings: Vec<String>,
+    // Codec of the video stream, for --caption-tech-info
+    codec: Option<String>,
+    dims_overridden: bool,
+    duration_overridden: bool,
+}
+
+impl VideoMeta {
+    fn missing(&self) -> Vec<&'static str> {
+        [
+            ("width", self.width),
+            ("height", self.height),
+            ("duration", self.duration),
+        ]
+        .into_iter()
+        .filter(|(_, v)| v.is_none())
+        .map(|(name, _)| name)
+        .collect()
+    }
+
+    fn describe(&self, entry: &mut PlanEntry) {
+        let mark = |overridden: bool| if overridden { " (override)" } else { "" };
+        if let (Some(w), Some(h)) = (self.width, self.height) {
+            entry
+                .details
+                .push(format!("{}x{}{}", w, h, mark(self.dims_overridden)));
+        }
+        if let Some(d) = self.duration {
+            entry
+                .details
+                .push(format!("{}s{}", d, mark(self.duration_overridden)));
+        }
+        if let Some(cover) = &self.cover {
+            entry.details.push(format!("cover {:?}", cover));
+        }
+        if let Some(start) = self.start_timestamp {
+            entry.details.push(format!("starts at {}s", start));
+        }
+        if let Some(saved) = &self.saved_thumbnail {
+            entry
+                .details
+                .push(format!("thumbnail saved to {:?}", saved));
+            entry.thumbnail = Some(saved.clone());
+        }
+        entry.warnings.extend(self.warnings.iter().cloned());
+        entry.frame = self.frame.clone();
+    }
+}
+
+/// A video after the ffmpeg work of the processing stage
+#[derive(Clone)]
+struct PreparedVideo {
+    entry: PlanEntry,
+    // The file to upload: the input or its muted/remuxed copy
+    upload_path: PathBuf,
+    supports_streaming: bool,
+    temp_files: Vec<PathBuf>,
+    // Err holds the reason the video is rejected
+    meta: Result<VideoMeta, String>,
+}
+
+// prepare_video within --file-timeout, rejecting the video when it runs over
+async fn prepare_video_within(
+    path: &Path,
+    args: &Cli,
+    streaming: StreamingMode,
+    batch_thumbnail: Option<&Vec<u8>>,
+) -> PreparedVideo {
+    let work = prepare_video(path, args, streaming, batch_thumbnail);
+    within(args.file_timeout, work)
+        .await
+        .unwrap_or_else(|reason| PreparedVideo {
+            entry: PlanEntry::new(path, "video"),
+            upload_path: path.to_path_buf(),
+            supports_streaming: false,
+            temp_files: Vec::new(),
+            meta: Err(reason),
+        })
+}
+
+// Makes `copy` the file to upload. When `serial`, the copy it was made from
+// is deleted right away, as nothing reads it any more.
+fn use_copy(video: &mut PreparedVideo, copy: PathBuf, serial: bool) {
+    if serial
+        && let Some(at) = video
+            .temp_files
+            .iter()
+            .position(|file| *file == video.upload_path)
+    {
+        let _ = std::fs::remove_file(video.temp_files.remove(at));
+    }
+    video.temp_files.push(copy.clone());
+    video.upload_path = copy;
+}
+
+// Strips audio, decides streaming (remuxing for faststart) and reads the
+// metadata of one video. Runs for several videos at a time (--concurrency),
+// so it reports problems instead of rejecting the file itself.
+async fn prepare_video(
+    path: &Path,
+    args: &Cli,
+    streaming: StreamingMode,
+    batch_thumbnail: Option<&Vec<u8>>,
+) -> PreparedVideo {
+    let mut video = PreparedVideo {
+        entry: PlanEntry::new(path, "video"),
+        upload_path: path.to_path_buf(),
+        supports_streaming: false,
+        temp_files: Vec::new(),
+        meta: Err(String::new()),
+    };
+
+    // What the artifact cache calls the copy being uploaded, if it is one
+    let mut copy_kind = args.mute.then(|| "mute".to_string());
+    if args.mute {
+        if args.dry_run {
+            video.entry.details.push("strip audio".to_string());
+        } else {
+            match strip_audio(path.to_path_buf()).await {
+                Ok(muted) => {
+                    log::info!("Stripped audio from {:?}", path);
+                    use_copy(&mut video, muted, args.serial_processing);
+                }
+                Err(e) => {
+                    video.meta = Err(format!("could not strip audio: {}", e));
+                    return video;
+                }
+            }
+        }
+    }
+
+    // The duration is probed from the copy, as the new audio can end a
+    // little earlier or later
+    if args.normalize_video_audio {
+        if args.dry_run {
+            video
+                .entry
+                .details
+                .push(format!("normalize audio to {} LUFS", args.target_lufs));
+        } else {
+            let source = path.to_path_buf();
+            match loudnorm::normalize(video.upload_path.clone(), source, args.target_lufs).await {
+                Ok(Some(normalized)) => {
+                    log::info!("Normalized the audio of {:?}", path);
+                    use_copy(&mut video, normalized, args.serial_processing);
+                    copy_kind = Some(loudnorm::kind(args.target_lufs));
+                }
+                Ok(None) => log::debug!("{:?} has no audio to normalize", path),
+                Err(e) => log::warn!(
+                    "Sending {:?} with its audio as it is: could not normalize it: {}",
+                    path,
+                    e
+                ),
+            }
+        }
+    }
+
+    // Re-encoded with faststart, so no remux follows
+    if let Some(mark) = watermark_for(args, &get_sidecar(path).await) {
+        if args.dry_run {
+            video.entry.details.push("watermark".to_string());
+        } else {
+            let source = (video.upload_path.clone(), path.to_path_buf());
+            match watermark_video(source, mark, copy_kind.clone()).await {
+                Ok(marked) => {
+                    log::info!("Watermarked {:?}", path);
+                    use_copy(&mut video, marked, args.serial_processing);
+                }
+                Err(e) => {
+                    video.meta = Err(format!("could not add the watermark: {}", e));
+                    return video;
+                }
+            }
+        }
+    }
+
+    // Streaming decision
+    let is_mp4 = ["mp4", "mov"].contains(&file_ext(path).as_str());
+    let mut faststart = if is_mp4 && (streaming == StreamingMode::Auto || args.fix_faststart) {
+        probe_faststart(video.upload_path.clone()).await
+    } else {
+        None
+    };
+    if faststart == Some(false) && args.fix_faststart {
+        if args.dry_run {
+            video.entry.details.push("remux to faststart".to_string());
+        } else {
+            match remux_faststart(
+                video.upload_path.clone(),
+                path.to_path_buf(),
+                match &copy_kind {
+                    Some(copy_kind) => format!("{}-faststart", copy_kind),
+                    None => "faststart".to_string(),
+                },
+            )
+            .await
+            {
+                Ok(remuxed) => {
+                    log::info!("Remuxed {:?} for faststart", path);
+                    use_copy(&mut video, remuxed, args.serial_processing);
+                    faststart = Some(true);
+                }
+                Err(e) => log::warn!("Failed to remux {:?} for faststart: {}", path, e),
+            }
+        }
+    }
+    video.supports_streaming = match streaming {
+        StreamingMode::On => true,
+        StreamingMode::Off => false,
+        StreamingMode::Auto => faststart != Some(false),
+    };
+    let streaming_reason = match (streaming, faststart) {
+        (StreamingMode::Auto, Some(true)) => " (faststart)",
+        (StreamingMode::Auto, Some(false)) => " (moov after mdat)",
+        _ => "",
+    };
+    let streaming_desc = format!(
+        "streaming {}{}",
+        if video.supports_streaming {
+            "on"
+        } else {
+            "off"
+        },
+        streaming_reason
+    );
+    log::info!("{:?}: {}", path, streaming_desc);
+    video.entry.details.push(streaming_desc);
+
+    video.meta = get_video_meta(path, &video.upload_path, args, batch_thumbnail)
+        .await
+        .map_err(|e| format!("unreadable or corrupt video: {}", e));
+    video
+}
+
+// Sidecars are looked up next to `path`; probing, decoding and thumbnail
+// generation use `media_path`, the file actually uploaded.
+async fn get_video_meta(
+    path: &Path,
+    media_path: &Path,
+    args: &Cli,
+    batch_thumbnail: Option<&Vec<u8>>,
+) -> Result<VideoMeta, String> {
+    let path_str = media_path.display().to_string();
+    let sidecar = get_sidecar(path).await;
+
+    let width_override = metadata_override("width", path, sidecar.width, args.video_width);
+    let height_override = metadata_override("height", path, sidecar.height, args.video_height);
+    let duration_override =
+        metadata_override("duration", path, sidecar.duration, args.video_duration);
+    // Skip ffprobe entirely when everything is supplied
+    let probed =
+        if width_override.is_some() && height_override.is_some() && duration_override.is_some() {
+            ProbedMetadata::default()
+        } else {
+            get_video_metadata(path_str.clone()).await?
+        };
+    let mut warnings = probed.warnings;
+    if args.validate_videos == VideoValidation::Decode {
+        decode_check(media_path.to_path_buf(), args.validate_seconds).await?;
+    }
+
+    let thumb_kind = watermark_for(args, &sidecar)
+        .map_or("thumb".to_string(), |mark| format!("thumb-{}", mark.kind()));
+    // Past the end ffmpeg writes no frame at all
+    let thumb_at = match (args.thumbnail_time, duration_override.or(probed.duration)) {
+        (Some(at), Some(duration)) if at.as_secs_f64() >= f64::from(duration) => {
+            let warning = format!(
+                "--thumbnail-time {} is past the end ({}s), using the first frame",
+                ffmpeg_timestamp(at),
+                duration
+            );
+            log::warn!("{:?}: {}", path, warning);
+            warnings.push(warning);
+            Duration::ZERO
+        }
+        (at, _) => at.unwrap_or_default(),
+    };
+    let thumbnail = if args.no_thumbnail {
+        None
+    } else if let Some(bytes) = batch_thumbnail {
+        Some(bytes.clone())
+    } else if args.overwrite_thumbnail {
+        let generated = generate_thumbnail(
+            path_str,
+            path.to_path_buf(),
+            thumb_kind,
+            thumb_at,
+            args.prefer_embedded_thumbnail,
+        )
+        .await;
+        thumbnail_or_warn(generated, path, &mut warnings)
+    } else {
+        let thumb_sidecar = path.with_extension("thumb.jpg");
+        let from_sidecar = if thumb_sidecar.is_file() {
+            match load_thumbnail(thumb_sidecar.clone()).await {
+                Ok(bytes) => Some(bytes),
+                Err(e) => {
+                    log::warn!("Ignoring thumbnail {:?}: {}", thumb_sidecar, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let from_url = match (&from_sidecar, sidecar.thumbnail_url.clone()) {
+            (None, Some(url)) => download_thumbnail(url).await,
+            _ => None,
+        };
+        match from_sidecar.or(from_url) {
+            Some(thumb) => Some(thumb),
+            None => {
+                let generated = generate_thumbnail(
+                    path_str,
+                    path.to_path_buf(),
+                    thumb_kind,
+                    thumb_at,
+                    args.prefer_embedded_thumbnail,
+                )
+                .await;
+                thumbnail_or_warn(generated, path, &mut warnings)
+            }
+        }
+    };
+
+    let duration = duration_override.or(probed.duration);
+    let cover = sidecar
+        .cover
+        .map(|cover| path.parent().unwrap_or(Path::new("")).join(cover))
+        .or_else(|| args.video_cover.clone())
+        .filter(
+            |cover| match validate_image(cover, ImageValidation::Header) {
+                Ok(()) => true,
+                Err(e) => {
+                    log::warn!("Ignoring cover {:?} for {:?}: {}", cover, path, e);
+                    false
+                }
+            },
+        );
+    let start_timestamp =
+        sidecar
+            .start_timestamp
+            .or(args.video_start)
+            .filter(|start| match duration {
+                Some(d) if *start >= u32::from(d) => {
+                    log::warn!(
+                        "Ignoring start timestamp {}s for {:?}: the video is {}s long",
+                        start,
+                        path,
+                        d
+                    );
+                    false
+                }
+                _ => true,
+            });
+
+    let saved_thumbnail = match (&args.save_thumbnails, &thumbnail) {
+        (Some(dir), Some(bytes)) => save_thumbnail(dir, path, bytes),
+        _ => None,
+    };
+    Ok(VideoMeta {
+        frame: thumbnail.clone(),
+        thumbnail: thumbnail.map(|bytes| InputFile::memory(bytes).file_name(thumbnail_name(path))),
+        saved_thumbnail,
+        warnings,
+        width: width_override.or(probed.width),
+        height: height_override.or(probed.height),
+        duration,
+        codec: probed.codec,
+        cover,
+        start_timestamp,
+        dims_overridden: width_override.is_some() || height_override.is_some(),
+        duration_overridden: duration_override.is_some(),
+    })
+}
+
+// The generated thumbnail, or None with the reason logged and kept for the plan
+fn thumbnail_or_warn(
+    generated: Result<Vec<u8>, String>,
+    video: &Path,
+    warnings: &mut Vec<String>,
+) -> Option<Vec<u8>> {
+    match generated {
+        Ok(bytes) => Some(bytes),
+        Err(e) => {
+            let warning = format!("no thumbnail: {}", e);
+            log::warn!("{:?}: {}", video, warning);
+            warnings.push(warning);
+            None
+        }
+    }
+}
+
+// `<stem>_thumb.jpg`, so uploads and request logs show which video a
+// thumbnail belongs to.
+fn thumbnail_name(video: &Path) -> String {
+    let stem = video.file_stem().unwrap_or_default().to_string_lossy();
+    format!("{}_thumb.jpg", stem)
+}
+
+// Writes a thumbnail as sent to `<dir>/<video file name>.jpg`, replacing any
+// earlier copy.
+fn save_thumbnail(dir: &Path, video: &Path, bytes: &[u8]) -> Option<PathBuf> {
+    let mut name = video.file_name().unwrap_or_default().to_os_string();
+    name.push(".jpg");
+    let out = dir.join(name);
+    match std::fs::write(&out, bytes) {
+        Ok(()) => Some(out),
+        Err(e) => {
+            log::warn!(
+                "Could not save the thumbnail of {:?} to {:?}: {}",
+                video,
+                out,
+                e
+            );
+            None
+        }
+    }
+}
+
+// Excludes a file per --on-error; under `abort` nothing is sent and the
+// run stops with the error returned.
+fn reject_file(path: &Path, reason: &str, args: &Cli, summary: &mut Summary) -> Result<(), Abort> {
+    if args.on_error == ErrorPolicy::Abort && args.dry_run {
+        log::error!("Would abort: {:?}: {}", path, reason);
+        summary
+            .skipped
+            .push((path.to_path_buf(), format!("{} (aborts the run)", reason)));
+        return Ok(());
+    }
+    if args.on_error == ErrorPolicy::Abort {
+        return Err(Abort::midway(format!("Aborting: {:?}: {}", path, reason)));
+    }
+    log::error!("Skipping {:?}: {}", path, reason);
+    summary
+        .errors
+        .insert(path.to_path_buf(), reason.to_string());
+    summary
+        .skipped
+        .push((path.to_path_buf(), reason.to_string()));
+    Ok(())
+}
+
+// Applies --strict-metadata; returns false when the file must be skipped.
+fn check_metadata(
+    path: &Path,
+    meta: &VideoMeta,
+    args: &Cli,
+    summary: &mut Summary,
+) -> Result<bool, Abort> {
+    let missing = meta.missing();
+    if missing.is_empty() {
+        return Ok(true);
+    }
+    summary.incomplete_metadata.push(path.to_path_buf());
+    if !args.strict_metadata {
+        log::warn!("Missing {} for {:?}", missing.join("/"), path);
+        return Ok(true);
+    }
+    let reason = format!("missing {}", missing.join("/"));
+    reject_file(path, &reason, args, summary)?;
+    Ok(false)
+}
+
+// Applies --max-caption-entities; returns false when the file must be skipped.
+fn check_caption(
+    path: &Path,
+    caption: &str,
+    format: CaptionFormat,
+    args: &Cli,
+    summary: &mut Summary,
+) -> Result<bool, Abort> {
+    let length = entities::text_len(caption, format);
+    // A reply is a text message, which send_text splits as needed, and
+    // --long-caption makes the caption fit
+    let reason = if args.caption_placement == CaptionPlacement::Media
+        && args.long_caption.is_none()
+        && length > MAX_CAPTION_CHARS
+    {
+        format!(
+            "caption is {} characters, over Telegram's {}",
+            length, MAX_CAPTION_CHARS
+        )
+    } else {
+        let Some(max) = args.max_caption_entities else {
+            return Ok(true);
+        };
+        let count = entities::count(caption, format);
+        if count <= max {
+            return Ok(true);
+        }
+        format!("caption has {} formatting entities, over {}", count, max)
+    };
+    if !args.strict_captions {
+        log::warn!("{:?}: {}", path, reason);
+        return Ok(true);
+    }
+    reject_file(path, &reason, args, summary)?;
+    Ok(false)
+}
+
+// Per-file sidecar values win over batch-wide CLI values; zeros are rejected.
+fn metadata_override(
+    name: &str,
+    path: &Path,
+    sidecar: Option<u16>,
+    cli: Option<u16>,
+) -> Option<u16> {
+    if sidecar == Some(0) {
+        log::warn!("Ignoring zero {} in sidecar for {:?}", name, path);
+    }
+    sidecar.filter(|v| *v > 0).or(cli)
+}
+
+fn validate_image(path: &Path, mode: ImageValidation) -> Result<(), String> {
+    let reader = ImageReader::open(path)
+        .and_then(|r| r.with_guessed_format())
+        .map_err(|e| e.to_string())?;
+    match mode {
+        ImageValidation::Header => reader.into_dimensions().map(|_| ()),
+        ImageValidation::Full => reader.decode().map(|_| ()),
+    }
+    .map_err(|e| e.to_string())
+}
+
+// Runs `work` within --file-timeout; Err is the reason to reject the file.
+// Work still running on the blocking pool is abandoned, not stopped.
+async fn within<T>(limit: Option<Duration>, work: impl Future<Output = T>) -> Result<T, String> {
+    let Some(limit) = limit else {
+        return Ok(work.await);
+    };
+    tokio::time::timeout(limit, work).await.map_err(|_| {
+        format!(
+            "processing timed out after {}",
+            humantime::format_duration(limit)
+        )
+    })
+}
+
+// Decodes run on the blocking pool, at most `workers` at a time. Maps each
+// failed photo to the reason it is rejected.
+async fn validate_images(
+    paths: Vec<PathBuf>,
+    mode: ImageValidation,
+    workers: usize,
+    limit: Option<Duration>,
+) -> std::collections::HashMap<PathBuf, String> {
+    let mut failures = std::collections::HashMap::new();
+    let mut pending = task::JoinSet::new();
+    let mut paths = paths.into_iter();
+    loop {
+        while pending.len() < workers
+            && let Some(path) = paths.next()
+        {
+            pending.spawn(async move {
+                let decode = {
+                    let path = path.clone();
+                    task::spawn_blocking(move || validate_image(&path, mode))
+                };
+                let result = match within(limit, decode).await {
+                    Ok(Ok(result)) => result.map_err(|e| format!("image failed to decode: {}", e)),
+                    Ok(Err(e)) => Err(format!("image failed to decode: {}", e)),
+                    Err(reason) => Err(reason),
+                };
+                (path, result)
+            });
+        }
+        match pending.join_next().await {
+            Some(Ok((path, Err(reason)))) => {
+                failures.insert(path, reason);
+            }
+            Some(_) => {}
+            None => break,
+        }
+    }
+    failures
+}
+
+// Directory of this run's intermediate files, under the system temp dir so
+// concurrent runs never share (or clean up) each other's files
+fn run_dir() -> &'static Path {
+    static RUN_DIR: std::sync::OnceLock<PathBuf> = std::sync::OnceLock::new();
+    RUN_DIR.get_or_init(|| std::env::temp_dir().join(format!("telegoy-{}", uuid::Uuid::new_v4())))
+}
+
+// `name` in the run directory, which is created on first use
+fn temp_path(name: &str) -> PathBuf {
+    let dir = run_dir();
+    if let Err(e) = std::fs::create_dir_all(dir) {
+        log::warn!("Cannot create temp directory {:?}: {}", dir, e);
+    }
+    dir.join(name)
+}
+
+// Set while the album delay waits; it handles Ctrl-C itself, listing what
+// was not sent
+static DELAY_HANDLES_INTERRUPT: std::sync::atomic::AtomicBool =
+    std::sync::atomic::AtomicBool::new(false);
+
+// Stops any ffmpeg/ffprobe still running, then removes the run's
+// intermediate files and the whole run directory
+fn remove_temp_files(files: &[PathBuf]) {
+    subprocess::kill_all();
+    remove_listed_files(files);
+    let _ = std::fs::remove_dir_all(run_dir());
+}
+
+fn remove_listed_files(files: &[PathBuf]) {
+    for file in files {
+        if file.is_dir() {
+            let _ = std::fs::remove_dir_all(file);
+        } else {
+            let _ = std::fs::remove_file(file);
+        }
+    }
+}
+
+// Caption sidecars are looked up in order `<file>.txt` (plain), `<file>.md`
+// (MarkdownV2), `<file>.html` (HTML); the first one found decides the format.
+async fn get_caption(file_path: &Path) -> (String, CaptionFormat) {
+    let candidates = [
+        ("txt", CaptionFormat::Plain),
+        ("md", CaptionFormat::Markdown),
+        ("html", CaptionFormat::Html),
+    ];
+    for (ext, format) in candidates {
+        let sidecar = file_path.with_extension(ext);
+        // album.txt belongs to the directory, not to album.jpg
+        if albums::is_album_caption_file(&sidecar) {
+            continue;
+        }
+        if let Ok(text) = tokio::fs::read_to_string(sidecar).await {
+            return (text, format);
+        }
+    }
+    (String::new(), CaptionFormat::Plain)
+}
+
+// Splits `path::caption` at the first unescaped `::`; `\::` stands for a
+// literal `::` in the path.
+fn split_inline_caption(arg: &Path) -> (PathBuf, Option<String>) {
+    let Some(text) = arg.to_str() else {
+        return (arg.to_path_buf(), None);
+    };
+    let mut search = 0;
+    while let Some(found) = text[search..].find("::").map(|i| search + i) {
+        if text[..found].ends_with('\\') {
+            search = found + 2;
+            continue;
+        }
+        let path = text[..found].replace("\\::", "::");
+        return (PathBuf::from(path), Some(text[found + 2..].to_string()));
+    }
+    (PathBuf::from(text.replace("\\::", "::")), None)
+}
+
+async fn get_sidecar(file_path: &Path) -> Sidecar {
+    let sidecar_path = file_path.with_extension("json");
+    let Ok(text) = tokio::fs::read_to_string(&sidecar_path).await else {
+        return Sidecar::default();
+    };
+    serde_json::from_str(&text).unwrap_or_else(|e| {
+        log::warn!("Ignoring invalid sidecar {:?}: {}", sidecar_path, e);
+        Sidecar::default()
+    })
+}
+
+/// Line --caption-tech-info appends when the config has no tech_info_format
+const TECH_INFO_FORMAT: &str = "{{width}}×{{height}} · {{duration}} · {{size}} · {{codec}}";
+
+// The file's tech_info_format line. Parts of the template (split at " · ")
+// that use a value the file lacks, such as a photo's duration, are dropped.
+fn tech_info(
+    template: &str,
+    dimensions: Option<(u32, u32)>,
+    duration: Option<u16>,
+    codec: Option<&str>,
+    bytes: u64,
+) -> String {
+    let vars = [
+        ("width", dimensions.map(|(w, _)| w.to_string())),
+        ("height", dimensions.map(|(_, h)| h.to_string())),
+        ("duration", duration.map(format_duration)),
+        ("size", Some(plan::format_bytes(bytes))),
+        ("codec", codec.map(str::to_string)),
+    ];
+    let known: Vec<(&str, String)> = vars
+        .iter()
+        .filter_map(|(name, value)| Some((*name, value.clone()?)))
+        .collect();
+    template
+        .split(" · ")
+        .filter(|part| {
+            vars.iter().all(|(name, value)| {
+                value.is_some() || !part.contains(&format!("{{{{{}}}}}", name))
+            })
+        })
+        .map(|part| render_template(part, &known))
+        .collect::<Vec<_>>()
+        .join(" · ")
+}
+
+// The --caption-tech-info line of `path`. Videos were probed ahead of the
+// captions; animations are probed here. Stickers have no caption.
+async fn file_tech_info(
+    path: &Path,
+    template: &str,
+    args: &Cli,
+    prepared: Option<&PreparedVideo>,
+) -> Option<String> {
+    let ext = file_ext(path);
+    if args.as_sticker && sticker::EXTENSIONS.contains(&ext.as_str()) {
+        None
+    } else if args.as_animation && ANIMATION_EXTENSIONS.contains(&ext.as_str()) {
+        let probed = get_video_metadata(path.display().to_string())
+            .await
+            .unwrap_or_default();
+        let dimensions = probed.width.zip(probed.height);
+        Some(tech_info(
+            template,
+            dimensions.map(|(w, h)| (w.into(), h.into())),
+            probed.duration,
+            probed.codec.as_deref(),
+            file_size(path),
+        ))
+    } else if IMAGE_EXTENSIONS.contains(&ext.as_str()) {
+        Some(tech_info(
+            template,
+            albums::photo_dimensions(path),
+            None,
+            None,
+            file_size(path),
+        ))
+    } else if let Some(video) = prepared {
+        let meta = video.meta.as_ref().ok();
+        let dimensions = meta.and_then(|meta| meta.width.zip(meta.height));
+        Some(tech_info(
+            template,
+            dimensions.map(|(w, h)| (w.into(), h.into())),
+            meta.and_then(|meta| meta.duration),
+            meta.and_then(|meta| meta.codec.as_deref()),
+            file_size(&video.upload_path),
+        ))
+    } else {
+        None
+    }
+}
+
+// 02:13, or 1:02:13 from an hour on
+fn format_duration(seconds: u16) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{:02}:{:02}", minutes, seconds)
+    }
+}
+
+// Appends the --tag hashtags `caption` does not have yet (in any case) on a
+// line of their own.
+fn append_tags(caption: &mut String, tags: &[String], format: CaptionFormat) {
+    let present = entities::hashtags(caption, format);
+    let mut added = std::collections::HashSet::new();
+    let missing: Vec<String> = tags
+        .iter()
+        .filter(|tag| {
+            let tag = tag.to_lowercase();
+            !present.contains(&tag) && added.insert(tag)
+        })
+        .map(|tag| entities::escape(&format!("#{}", tag), format))
+        .collect();
+    if !missing.is_empty() {
+        append_line(caption, &missing.join(" "));
+    }
+}
+
+// Photo, video, audio, animation or sticker, as `path` will be sent; None
+// for files that are not sent.
+fn media_type(path: &Path, args: &Cli) -> Option<&'static str> {
+    let ext = file_ext(path);
+    if args.as_sticker && sticker::EXTENSIONS.contains(&ext.as_str()) {
+        Some("sticker")
+    } else if args.as_animation && ANIMATION_EXTENSIONS.contains(&ext.as_str()) {
+        Some("animation")
+    } else if IMAGE_EXTENSIONS.contains(&ext.as_str()) {
+        Some("photo")
+    } else if VIDEO_EXTENSIONS.contains(&ext.as_str()) {
+        Some("video")
+    } else if AUDIO_EXTENSIONS.contains(&ext.as_str()) {
+        Some("audio")
+    } else {
+        None
+    }
+}
+
+// The --caption-hashtag-prefix tags of an album of `files`: one per type of
+// the photos and videos among them, the most common first.
+fn type_tags(
+    files: &[PathBuf],
+    args: &Cli,
+    names: &std::collections::HashMap<String, String>,
+) -> Vec<String> {
+    let mut counts = [("photo", 0), ("video", 0)];
+    for path in files {
+        if let Some(kind) = media_type(path, args)
+            && let Some((_, count)) = counts.iter_mut().find(|(k, _)| *k == kind)
+        {
+            *count += 1;
+        }
+    }
+    // Stable, so photos stay ahead of an equal number of videos
+    counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    counts
+        .into_iter()
+        .filter(|(_, count)| *count > 0)
+        .map(|(kind, _)| type_tag(kind, names))
+        .collect()
+}
+
+// The [type_hashtags] name of `kind`, or the kind itself.
+fn type_tag(kind: &str, names: &std::collections::HashMap<String, String>) -> String {
+    names.get(kind).cloned().unwrap_or_else(|| kind.to_string())
+}
+
+// Appends the --signature as an italic "— name" line.
+fn append_signature(caption: &mut String, signature: Option<&str>, format: CaptionFormat) {
+    let Some(signature) = signature.filter(|s| !s.trim().is_empty()) else {
+        return;
+    };
+    let text = entities::escape(&format!("— {}", signature.trim()), format);
+    let line = match format {
+        CaptionFormat::Plain => text,
+        CaptionFormat::Markdown => format!("_{}_", text),
+        CaptionFormat::Html => format!("<i>{}</i>", text),
+    };
+    append_line(caption, &line);
+}
+
+// Appends `line` to `caption` on a line of its own.
+fn append_line(caption: &mut String, line: &str) {
+    if !caption.is_empty() && !caption.ends_with('\n') {
+        caption.push('\n');
+    }
+    caption.push_str(line);
+}
+
+// Rejects strftime formats chrono cannot render, which would panic later.
+fn check_strftime(format: &str) -> Result<(), String> {
+    use chrono::format::{Item, StrftimeItems};
+    match StrftimeItems::new(format).position(|item| item == Item::Error) {
+        Some(_) => Err("unknown or incomplete % specifier".to_string()),
+        None => Ok(()),
+    }
+}
+
+// Replaces `{{name}}` placeholders; unknown placeholders are left as-is.
+fn render_template(text: &str, vars: &[(&str, String)]) -> String {
+    let mut out = text.to_string();
+    for (name, value) in vars {
+        out = out.replace(&format!("{{{{{}}}}}", name), value);
+    }
+    out
+}
+
+// static_caption.txt, or static_caption.<lang>.txt when there is one (then
+// with true)
+async fn get_static_caption(lang: Option<&str>) -> (String, bool) {
+    let base = Path::new("static_caption.txt");
+    if let Some(lang) = lang
+        && let Ok(text) = tokio::fs::read_to_string(albums::lang_variant(base, lang)).await
+    {
+        return (text, true);
+    }
+    let text = tokio::fs::read_to_string(base).await.unwrap_or_default();
+    (text, false)
+}
+
+// config.toml, overridden by TELEGOY_* environment variables
+fn load_settings() -> Result<Settings, config::ConfigError> {
+    Config::builder()
+        .add_source(File::with_name("config").required(false)) // Optional config.toml
+        .add_source(Environment::with_prefix("TELEGOY")) // e.g. TELEGOY_CHAT_ID
+        .build()
+        .and_then(|c| c.try_deserialize())
+}
+
+// Replaces the top-level settings with those of --profile and returns its
+// bot token, if it has one.
+fn apply_profile(settings: &mut Settings, name: Option<&str>) -> Result<Option<String>, String> {
+    let Some(name) = name else {
+        return Ok(None);
+    };
+    let Some(profile) = settings.profiles.remove(name) else {
+        return Err(format!("No [profiles.{}] in the config", name));
+    };
+    let token = profile
+        .token()
+        .map_err(|e| format!("Bot token of profile {}: {}", name, e))?;
+    settings.chat_id = profile
+        .chat_id
+        .unwrap_or(std::mem::take(&mut settings.chat_id));
+    settings.api_url = profile
+        .api_url
+        .unwrap_or(std::mem::take(&mut settings.api_url));
+    settings.caption_lang = profile.caption_lang.or(settings.caption_lang.take());
+    Ok(token)
+}
+
+// The bot of the config and --profile, for the commands that only need a bot
+fn configured_bot(args: &Cli) -> Result<Bot, String> {
+    let mut settings = load_settings().map_err(|e| format!("Configuration error: {}", e))?;
+    let token = apply_profile(&mut settings, args.profile.as_deref())?;
+    let url = reqwest::Url::parse(&settings.api_url)
+        .map_err(|e| format!("Invalid API URL {:?}: {}", settings.api_url, e))?;
+    Ok(make_bot(token.as_deref(), url))
+}
+
+// The chat id or @username `target` stands for.
+pub(crate) fn recipient_name(target: &Recipient) -> String {
+    match target {
+        Recipient::Id(id) => id.to_string(),
+        Recipient::ChannelUsername(name) => name.clone(),
+    }
+}
+
+// The profile's bot, or the one of TELOXIDE_TOKEN.
+fn make_bot(token: Option<&str>, api_url: reqwest::Url) -> Bot {
+    match token {
+        Some(token) => Bot::new(token),
+        None => Bot::from_env(),
+    }
+    .set_api_url(api_url)
+}
+
+// Calls get_me until it succeeds, the error is not a connection failure, or
+// `timeout` has passed. A zero timeout means a single attempt.
+async fn wait_for_api(bot: &Bot, timeout: Duration) -> Result<Me, RequestError> {
+    const MAX_PAUSE: Duration = Duration::from_secs(5);
+    let deadline = Instant::now() + timeout;
+    let mut pause = Duration::from_millis(250);
+    let mut attempt = 1;
+    loop {
+        let result = send::logged(bot, bot.get_me()).await;
+        let left = deadline.saturating_duration_since(Instant::now());
+        match &result {
+            Err(RequestError::Network(e)) if !left.is_zero() => {
+                log::debug!("Bot API not reachable yet (attempt {}): {}", attempt, e);
+            }
+            _ => return result,
+        }
+        tokio::time::sleep(pause.min(left)).await;
+        pause = (pause * 2).min(MAX_PAUSE);
+        attempt += 1;
+    }
+}
+
+// ---------------------------
+// 3. Main Logic
+// ---------------------------
+
+/// The `telegoy` command: parses the command line and runs it.
+pub async fn cli_main() {
+    // 1. Parse CLI Args
+    let matches = Cli::command().get_matches();
+    let mut args = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    args.rerun_base = rerun_base(&matches);
+    let mut logger = pretty_env_logger::formatted_builder();
+    logger.filter_level(log::LevelFilter::Error);
+    logger.write_style(args.color.write_style());
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        logger.parse_filters(&filters);
+    }
+    if args.debug_requests {
+        logger.filter_module(send::REQUEST_LOG, log::LevelFilter::Debug);
+        send::enable_request_logging();
+    }
+    if args.log_format == LogFormat::Json {
+        logger.format(|buf, record| {
+            let line = serde_json::json!({
+                "timestamp": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    logger.init();
+    let limits = throttle::Limits::new(args.limit_rate, args.limit_rate_per_file);
+    send::set_rate_limits(limits.clone());
+    cache::init(!args.no_cache);
+    let _ = FFMPEG_LOGLEVEL.set(args.ffmpeg_loglevel.clone());
+    if (args.normalize_audio || args.normalize_video_audio) && !loudnorm::ffmpeg_present() {
+        log::warn!(
+            "ffmpeg is not installed, so --normalize-audio and --normalize-video-audio are left out"
+        );
+        args.normalize_audio = false;
+        args.normalize_video_audio = false;
+    }
+    // Ctrl-C leaves no ffmpeg children or half-written files behind
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok()
+            && !DELAY_HANDLES_INTERRUPT.load(std::sync::atomic::Ordering::SeqCst)
+        {
+            log::warn!("Interrupted");
+            subprocess::shutdown();
+            remove_temp_files(&[]);
+            std::process::exit(130);
+        }
+    });
+    match &args.command {
+        Some(Command::Ledger {
+            action: LedgerAction::Verify,
+        }) => {
+            if let Err(e) = ledger::verify() {
+                log::error!("Ledger is damaged: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Doctor {
+            dirs,
+            watch_backend,
+        }) => {
+            if !doctor(dirs, *watch_backend) {
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Reaper { dry_run }) => {
+            let result = match configured_bot(&args) {
+                Ok(bot) => reaper::run(&bot, *dry_run).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                log::error!("Reaper failed: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Quarantine { action }) => {
+            let Some(dir) = &args.quarantine_dir else {
+                log::error!("telegoy quarantine needs the --quarantine-dir to look in");
+                std::process::exit(1);
+            };
+            if !quarantine_command(dir, action) {
+                std::process::exit(1);
+            }
+            return;
+        }
+        None => {}
+    }
+    if !args.watch {
+        run_or_exit(args, &limits).await;
+        return;
+    }
+
+    // Watch mode: the first batch is what is there now, then every file
+    // that appears later
+    let roots: Vec<PathBuf> = args
+        .files
+        .iter()
+        .map(|input| split_inline_caption(input).0)
+        .filter(|path| path.is_dir())
+        .collect();
+    if roots.is_empty() {
+        log::error!("--watch needs at least one directory argument");
+        std::process::exit(1);
+    }
+    for root in &roots {
+        if let Ok(Some(warning)) = watch::check_backend(root, args.watch_backend) {
+            log::warn!("{}", warning);
+        }
+    }
+    match configured_bot(&args) {
+        Ok(bot) => {
+            tokio::spawn(async move {
+                loop {
+                    if let Err(e) = reaper::run(&bot, false).await {
+                        log::warn!("Reaper failed: {}", e);
+                    }
+                    tokio::time::sleep(REAP_INTERVAL).await;
+                }
+            });
+        }
+        Err(e) => log::warn!("Not deleting expired posts: {}", e),
+    }
+    let mut watcher = match watch::DirWatcher::new(
+        args.watch_backend,
+        &roots,
+        args.recursive,
+        args.poll_interval,
+        watch::Grouping {
+            window: args.group_window,
+            max_wait: args.max_group_wait,
+        },
+    ) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            log::error!("Cannot watch {:?}: {}", roots, e);
+            std::process::exit(1);
+        }
+    };
+    let files = albums::expand_inputs(&roots, args.recursive);
+    watcher.mark_seen(&files);
+    let summary = run_or_exit(args.clone(), &limits).await;
+    count_failures(&mut watcher, &files, &summary.errors, &args);
+    loop {
+        log::info!("Watching {:?} for new files...", roots);
+        let files;
+        (watcher, files) = task::spawn_blocking(move || {
+            let files = watcher.next_batch();
+            (watcher, files)
+        })
+        .await
+        .expect("watcher panicked");
+        log::info!("{} new file(s) to send", files.len());
+        let mut batch = args.clone();
+        batch.files = files.clone();
+        batch.from_package = None;
+        let summary = run_or_exit(batch, &limits).await;
+        count_failures(&mut watcher, &files, &summary.errors, &args);
+    }
+}
+
+// A batch, ending the command with the exit status of a run that stopped
+// early
+async fn run_or_exit(args: Cli, limits: &throttle::Limits) -> Summary {
+    run(args, limits)
+        .await
+        .unwrap_or_else(|abort| std::process::exit(abort.status))
+}
+
+// With --quarantine-dir, hands the failed files of a --watch batch out again,
+// or moves them into the quarantine once they failed --quarantine-after
+// times in a row.
+fn count_failures(
+    watcher: &mut watch::DirWatcher,
+    files: &[PathBuf],
+    errors: &std::collections::BTreeMap<PathBuf, String>,
+    args: &Cli,
+) {
+    let Some(dir) = &args.quarantine_dir else {
+        return;
+    };
+    for path in files {
+        let Some(error) = errors.get(path) else {
+            watcher.succeeded(path);
+            continue;
+        };
+        let failures = watcher.failed(path);
+        if failures < args.quarantine_after {
+            log::warn!(
+                "{:?} failed {} time(s) in a row; trying it again",
+                path,
+                failures
+            );
+            continue;
+        }
+        watcher.forget(path);
+        match quarantine::put(dir, path, error, failures) {
+            Ok(moved) => log::error!(
+                "QUARANTINED {:?} as {:?} after it failed {} time(s) in a row (`telegoy quarantine retry` moves it back); last error: {}",
+                path,
+                moved,
+                failures,
+                error
+            ),
+            Err(e) => log::error!(
+                "{:?} failed {} times in a row (last: {}) and cannot be quarantined: {}",
+                path,
+                failures,
+                error,
+                e
+            ),
+        }
+    }
+}
+
+// Runs `telegoy quarantine`; false when it failed.
+fn quarantine_command(dir: &Path, action: &QuarantineAction) -> bool {
+    match action {
+        QuarantineAction::List => match quarantine::list(dir) {
+            Ok(entries) if entries.is_empty() => {
+                println!("Nothing quarantined in {:?}", dir);
+                true
+            }
+            Ok(entries) => {
+                for entry in entries {
+                    let at = chrono::DateTime::from_timestamp(entry.at, 0)
+                        .map(|at| at.to_rfc3339())
+                        .unwrap_or_default();
+                    println!(
+                        "{:?} from {:?}: {} failures, quarantined {}: {}",
+                        entry.file, entry.from, entry.failures, at, entry.error
+                    );
+                }
+                true
+            }
+            Err(e) => {
+                log::error!("{}", e);
+                false
+            }
+        },
+        QuarantineAction::Retry { files } => match quarantine::retry(dir, files) {
+            Ok(restored) => {
+                for path in &restored {
+                    println!("Moved back {:?}", path);
+                }
+                if restored.is_empty() {
+                    println!("Nothing moved back");
+                }
+                true
+            }
+            Err(e) => {
+                log::error!("{}", e);
+                false
+            }
+        },
+    }
+}
+
+/// How often --watch deletes the posts whose --delete-after time has passed
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Reports what `telegoy doctor` checks; false when something will not work.
+fn doctor(dirs: &[PathBuf], backend: watch::WatchBackend) -> bool {
+    let mut ok = true;
+    if ffmpeg_installed() {
+        println!("ffmpeg: found");
+    } else {
+        println!("ffmpeg: NOT FOUND (videos are sent without thumbnails or metadata)");
+    }
+    let temp = std::env::temp_dir();
+    match space::free_bytes(&temp) {
+        Ok(free) => println!("temp dir {:?}: {} free", temp, plan::format_bytes(free)),
+        Err(e) => println!("temp dir {:?}: {}", temp, e),
+    }
+    for dir in dirs {
+        match watch::check_backend(dir, backend) {
+            Ok(None) => println!("{:?}: ok", dir),
+            Ok(Some(warning)) => println!("WARNING: {}", warning),
+            Err(e) => {
+                println!("{:?}: {}", dir, e);
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+// One batch: everything from loading the config to the summary, which it
+// returns. When it stops early the error and, past setup, the summary so far
+// are logged, and the temp files removed in either case.
+async fn run(mut args: Cli, limits: &throttle::Limits) -> Result<Summary, Abort> {
+    let mut summary = Summary::new(if args.porcelain {
+        SummaryFormat::Porcelain
+    } else {
+        args.summary
+    });
+    let mut temp_files: Vec<PathBuf> = Vec::new();
+    let result = run_stages(&mut args, limits, &mut summary, &mut temp_files).await;
+    if let Err(abort) = &result {
+        if let Some(error) = &abort.error {
+            log::error!("{}", error);
+        }
+        if abort.summary {
+            summary.log();
+        }
+    }
+    remove_temp_files(&temp_files);
+    result.map(|()| summary)
+}
+
+// The stages of run, each handing the next what it made of the batch
+async fn run_stages(
+    args: &mut Cli,
+    limits: &throttle::Limits,
+    summary: &mut Summary,
+    temp_files: &mut Vec<PathBuf>,
+) -> Result<(), Abort> {
+    let (files, mut inputs) = collect::files(args, summary, temp_files)?;
+    let package_chat_id = inputs.package_chat_id.take();
+    let setup = setup::load(args, package_chat_id, summary).await?;
+    let batch = process::files(args, files, &mut inputs, &setup, summary, temp_files).await?;
+    if !plan::review(args, &setup, &batch, summary).await? {
+        return Ok(());
+    }
+    let bot = make_bot(setup.bot_token.as_deref(), setup.bot_url.clone());
+    publish::batch(bot, args, limits, setup, batch, &inputs, summary).await?;
+    report::finish(args, &inputs, summary);
+    summary.log();
+    Ok(())
+}
+
+// The command line minus its input files (and --watch), which
+// --rerun-command replaces by the failed ones
+fn rerun_base(matches: &clap::ArgMatches) -> Vec<std::ffi::OsString> {
+    let mut argv: Vec<std::ffi::OsString> = std::env::args_os().collect();
+    let files: Vec<&std::ffi::OsStr> = matches.get_raw("files").into_iter().flatten().collect();
+    // Files usually follow the flags, so a flag value that happens to equal
+    // one is left alone
+    for file in files.iter().rev() {
+        if let Some(at) = argv.iter().rposition(|arg| arg == file) {
+            argv.remove(at);
+        }
+    }
+    argv.retain(|arg| arg != "--watch");
+    argv
+}
+
+// The command retrying `failed`, with their inline captions, quoted for a
+// POSIX shell
+fn rerun_command(
+    args: &Cli,
+    failed: &[PathBuf],
+    inline_captions: &std::collections::HashMap<PathBuf, String>,
+) -> String {
+    let mut words: Vec<String> = args
+        .rerun_base
+        .iter()
+        .map(|arg| shell_quote(&arg.to_string_lossy()))
+        .collect();
+    let mut seen = std::collections::HashSet::new();
+    for path in failed.iter().filter(|path| seen.insert(*path)) {
+        let mut file = path.to_string_lossy().replace("::", "\\::");
+        if file.starts_with('-') {
+            file = format!("./{}", file);
+        }
+        if let Some(caption) = inline_captions.get(path) {
+            file = format!("{}::{}", file, caption);
+        }
+        words.push(shell_quote(&file));
+    }
+    words.join(" ")
+}
+
+fn shell_quote(word: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-./:=@%+,".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+fn caption_format(parse_mode: Option<ParseMode>) -> CaptionFormat {
+    match parse_mode {
+        Some(ParseMode::Html) => CaptionFormat::Html,
+        Some(ParseMode::MarkdownV2) => CaptionFormat::Markdown,
+        _ => CaptionFormat::Plain,
+    }
+}
+
+// Opens `path` with the desktop's default application.
+fn open_file(path: &Path) {
+    let mut command = if cfg!(target_os = "macos") {
+        std::process::Command::new("open")
+    } else if cfg!(windows) {
+        let mut command = std::process::Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    } else {
+        std::process::Command::new("xdg-open")
+    };
+    if let Err(e) = command.arg(path).spawn() {
+        log::warn!("Could not open {:?}: {}", path, e);
+    }
+}
@@ -0,0 +1,24 @@
+// ---------------------------
+// t.me links & the clipboard
+// ---------------------------
+
+/// The t.me link of message `id` in `chat`: by username for public chats,
+/// by internal id for other supergroups and channels. Private chats and
+/// basic groups have none.
+pub fn message_link(chat: &str, id: i32) -> Option<String> {
+    if let Some(name) = chat.strip_prefix('@') {
+        return Some(format!("https://t.me/{}/{}", name, id));
+    }
+    let internal = chat.strip_prefix("-100")?;
+    internal
+        .parse::<u64>()
+        .is_ok()
+        .then(|| format!("https://t.me/c/{}/{}", internal, id))
+}
+
+/// Puts `text` on the system clipboard. Fails on machines without one,
+/// such as a server with no display.
+pub fn copy(text: &str) -> Result<(), String> {
+    let mut clipboard = arboard::Clipboard::new().map_err(|e| e.to_string())?;
+    clipboard.set_text(text).map_err(|e| e.to_string())
+}
@@ -0,0 +1,162 @@
+// ---------------------------
+// Loudness normalization
+// ---------------------------
+
+use crate::{cache, subprocess};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tokio::task;
+
+/// Default of --target-lufs, what most streaming services play at
+pub const DEFAULT_TARGET_LUFS: f64 = -16.0;
+
+// What the first loudnorm pass measured. ffmpeg prints the numbers as
+// strings.
+#[derive(Debug, Deserialize)]
+struct Measured {
+    input_i: String,
+    input_tp: String,
+    input_lra: String,
+    input_thresh: String,
+    target_offset: String,
+}
+
+/// Whether there is an ffmpeg to run.
+pub fn ffmpeg_present() -> bool {
+    Command::new("ffmpeg")
+        .arg("-version")
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// A copy of `video` (made from `source`) with its audio brought to `target`
+/// LUFS by ffmpeg's two-pass EBU R128 loudnorm; the other streams (the
+/// video, or an audio file's cover art) are copied. None when it has no
+/// audio track.
+pub async fn normalize(
+    video: PathBuf,
+    source: PathBuf,
+    target: f64,
+) -> Result<Option<PathBuf>, String> {
+    task::spawn_blocking(move || {
+        let Some(measured) = measure(&video, target)? else {
+            return Ok(None);
+        };
+        log::info!(
+            "{:?}: measured {} LUFS, true peak {} dBTP, range {} LU, threshold {} LUFS, offset {} LU",
+            source,
+            measured.input_i,
+            measured.input_tp,
+            measured.input_lra,
+            measured.input_thresh,
+            measured.target_offset
+        );
+        let ext = video
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("mp4");
+        let temp_file = crate::temp_path(&format!("temp_loudnorm_{}.{}", uuid::Uuid::new_v4(), ext));
+        let made = cache::reuse_or_make(&source, &kind(target), &temp_file, || {
+            apply(&video, &temp_file, target, &measured)
+        });
+        match made {
+            Ok(_) => Ok(Some(temp_file)),
+            Err(e) => {
+                let _ = std::fs::remove_file(&temp_file);
+                Err(e)
+            }
+        }
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// The artifact cache kind of the copies normalized to `target`.
+pub fn kind(target: f64) -> String {
+    format!("loudnorm{}", target)
+}
+
+// The encoder of the normalized audio, one the container of `media` holds
+fn audio_codec(media: &Path) -> &'static str {
+    match crate::file_ext(media).as_str() {
+        "mp3" => "libmp3lame",
+        "ogg" | "opus" => "libopus",
+        "flac" => "flac",
+        "wav" => "pcm_s16le",
+        _ => "aac",
+    }
+}
+
+fn filter(target: f64) -> String {
+    format!("loudnorm=I={}:TP=-1.5:LRA=11", target)
+}
+
+// The first pass, which only measures. loudnorm prints its JSON at the info
+// level, whatever --ffmpeg-loglevel says.
+fn measure(video: &Path, target: f64) -> Result<Option<Measured>, String> {
+    let output = subprocess::output(
+        Command::new("ffmpeg")
+            .args(["-hide_banner", "-nostats", "-v", "info", "-i"])
+            .arg(video)
+            .args(["-map", "0:a:0?", "-vn", "-sn", "-dn", "-af"])
+            .arg(format!("{}:print_format=json", filter(target)))
+            .args(["-f", "null", "-"]),
+        subprocess::Work::Transcode,
+        video,
+        None,
+    )
+    .map_err(|e| format!("could not run ffmpeg: {}", e))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.contains("does not contain any stream") {
+        return Ok(None);
+    }
+    if !output.status.success() {
+        return Err(crate::stderr_snippet(&output.stderr));
+    }
+    let json = stderr
+        .rfind('{')
+        .and_then(|start| Some(&stderr[start..=start + stderr[start..].find('}')?]))
+        .ok_or("loudnorm printed no measurements")?;
+    serde_json::from_str(json)
+        .map(Some)
+        .map_err(|e| format!("unreadable loudnorm measurements: {}", e))
+}
+
+// The second pass, with the measurements of the first so the gain is
+// linear rather than a dynamic compressor's
+fn apply(video: &Path, dest: &Path, target: f64, measured: &Measured) -> Result<(), String> {
+    let filter = format!(
+        "{}:measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:offset={}:linear=true",
+        filter(target),
+        measured.input_i,
+        measured.input_tp,
+        measured.input_lra,
+        measured.input_thresh,
+        measured.target_offset
+    );
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-hide_banner", "-v", crate::ffmpeg_loglevel(), "-y", "-i"])
+        .arg(video)
+        .args(["-map", "0", "-c", "copy", "-af"])
+        .arg(&filter)
+        .args(["-c:a", audio_codec(video), "-b:a", "192k"])
+        // loudnorm works at 192 kHz, so the rate is set back explicitly
+        .args(["-ar", "48000"]);
+    if ["mp4", "mov"].contains(&crate::file_ext(video).as_str()) {
+        command.args(["-movflags", "+faststart"]);
+    }
+    let output = subprocess::output(
+        command.arg(dest),
+        subprocess::Work::Transcode,
+        video,
+        Some(dest),
+    )
+    .map_err(|e| format!("could not run ffmpeg: {}", e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(crate::stderr_snippet(&output.stderr))
+    }
+}
@@ -0,0 +1,117 @@
+// ---------------------------
+// Post packages (.zip with post.toml)
+// ---------------------------
+
+use crate::CaptionFormat;
+use config::{Config, File, FileFormat};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+/// Manifest read from `post.toml` at the root of a package
+#[derive(Debug, Default, Deserialize)]
+pub struct PostManifest {
+    // Destination chat, used unless --chat-id is given
+    pub chat_id: Option<String>,
+    // Album caption, rendered like a static caption
+    pub caption: Option<String>,
+    // Caption format, used unless --parse-mode is given
+    pub parse_mode: Option<CaptionFormat>,
+    // Inline keyboard of the package's single posts, as rows of
+    // `{ text, url }`, instead of the config's [keyboard]
+    pub buttons: Option<Vec<Vec<crate::keyboard::ButtonSpec>>>,
+    // Send order relative to the package root; defaults to all media sorted by name
+    pub files: Option<Vec<ManifestFile>>,
+    #[serde(default)]
+    pub options: PackageOptions,
+}
+
+/// An entry of `files`: a bare path, or a table also naming the forum topic
+/// of the chat the file is posted in (`{ path = "a.jpg", thread_id = 7 }`)
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ManifestFile {
+    Path(PathBuf),
+    Routed {
+        path: PathBuf,
+        thread_id: Option<i32>,
+    },
+}
+
+impl ManifestFile {
+    fn path(&self) -> &Path {
+        match self {
+            ManifestFile::Path(path) | ManifestFile::Routed { path, .. } => path,
+        }
+    }
+
+    fn thread_id(&self) -> Option<i32> {
+        match self {
+            ManifestFile::Path(_) => None,
+            ManifestFile::Routed { thread_id, .. } => *thread_id,
+        }
+    }
+}
+
+/// Switches a package can turn on in addition to the command line
+#[derive(Debug, Default, Deserialize)]
+pub struct PackageOptions {
+    #[serde(default)]
+    pub as_animation: bool,
+    #[serde(default)]
+    pub no_thumbnail: bool,
+    #[serde(default)]
+    pub strict_metadata: bool,
+}
+
+const MANIFEST_FILE: &str = "post.toml";
+
+/// Media of a package, in send order, and the forum topic of the files
+/// the manifest routes to one
+pub struct PackageFiles {
+    pub files: Vec<PathBuf>,
+    pub threads: HashMap<PathBuf, i32>,
+}
+
+/// Extracts `zip_path` into `dest` and returns its manifest with the media
+/// files to send.
+pub fn extract(zip_path: &Path, dest: &Path) -> Result<(PostManifest, PackageFiles), String> {
+    let file = std::fs::File::open(zip_path).map_err(|e| e.to_string())?;
+    let mut archive = zip::ZipArchive::new(file).map_err(|e| e.to_string())?;
+    // Entries escaping `dest` (zip slip) are rejected by extract()
+    archive.extract(dest).map_err(|e| e.to_string())?;
+
+    let manifest: PostManifest = match std::fs::read_to_string(dest.join(MANIFEST_FILE)) {
+        Ok(text) => Config::builder()
+            .add_source(File::from_str(&text, FileFormat::Toml))
+            .build()
+            .and_then(|c| c.try_deserialize())
+            .map_err(|e| format!("invalid {}: {}", MANIFEST_FILE, e))?,
+        Err(_) => {
+            log::warn!("Package {:?} has no {}", zip_path, MANIFEST_FILE);
+            PostManifest::default()
+        }
+    };
+
+    let mut threads = HashMap::new();
+    let files = match &manifest.files {
+        Some(listed) => {
+            let mut files = Vec::new();
+            for entry in listed {
+                let name = entry.path();
+                let path = dest.join(name);
+                let inside = name.components().all(|c| matches!(c, Component::Normal(_)));
+                if !inside || !path.is_file() {
+                    return Err(format!("{} lists missing file {:?}", MANIFEST_FILE, name));
+                }
+                if let Some(thread) = entry.thread_id() {
+                    threads.insert(path.clone(), thread);
+                }
+                files.push(path);
+            }
+            files
+        }
+        None => crate::albums::expand_inputs(&[dest.to_path_buf()], true),
+    };
+    Ok((manifest, PackageFiles { files, threads }))
+}
@@ -0,0 +1,533 @@
+// ---------------------------
+// Dry-run plan
+// ---------------------------
+
+use crate::albums::{self, Album};
+use crate::process::Batch;
+use crate::setup::Setup;
+use crate::{Abort, caption_format, open_file};
+use crate::{AnimationPost, CaptionPlacement, Cli, PlanEntry, Summary, entities, preview};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use teloxide::types::ParseMode;
+use tokio::task;
+
+// Per-file upload limits of the public Bot API and of a local server
+const PUBLIC_LIMIT: u64 = 50_000_000;
+const LOCAL_LIMIT: u64 = 2_000_000_000;
+// Telegram's limit for files sent as photos
+const PHOTO_LIMIT: u64 = 10_000_000;
+const CAPTION_PREVIEW_CHARS: usize = 60;
+
+/// Output format of --dry-run
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum PlanFormat {
+    Text,
+    Json,
+}
+
+/// Everything a run would send, with problems marked on the items they
+/// concern instead of stopping the plan.
+#[derive(Debug, Serialize)]
+pub struct Plan {
+    pub chats: Vec<String>,
+    // "name (max 720p, crf 26)" of the chats with a quality
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub qualities: BTreeMap<String, String>,
+    pub api_url: String,
+    pub upload_limit_bytes: u64,
+    pub albums: Vec<AlbumPlan>,
+    // Animations and stickers, each sent as its own message
+    pub singles: Vec<ItemPlan>,
+    pub rejected: Vec<Rejected>,
+    pub total_bytes: u64,
+    pub estimated_seconds: Option<f64>,
+    #[serde(skip)]
+    bytes_per_second: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AlbumPlan {
+    pub label: String,
+    pub caption: Option<String>,
+    // The album.txt the caption came from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub caption_file: Option<PathBuf>,
+    pub chunks: Vec<ChunkPlan>,
+}
+
+/// One media group; `first`/`last` are 1-based positions in the album
+#[derive(Debug, Serialize)]
+pub struct ChunkPlan {
+    pub first: usize,
+    pub last: usize,
+    pub bytes: u64,
+    pub estimated_seconds: Option<f64>,
+    pub items: Vec<ItemPlan>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ItemPlan {
+    #[serde(flatten)]
+    pub entry: PlanEntry,
+    pub bytes: u64,
+    pub problems: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Rejected {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+impl Plan {
+    pub fn new(chats: Vec<String>, api_url: &str, bytes_per_second: Option<u64>) -> Self {
+        let is_public = reqwest::Url::parse(api_url)
+            .ok()
+            .and_then(|url| url.host_str().map(|h| h == "api.telegram.org"))
+            .unwrap_or(false);
+        Plan {
+            chats,
+            qualities: BTreeMap::new(),
+            api_url: api_url.to_string(),
+            upload_limit_bytes: if is_public { PUBLIC_LIMIT } else { LOCAL_LIMIT },
+            albums: Vec::new(),
+            singles: Vec::new(),
+            rejected: Vec::new(),
+            total_bytes: 0,
+            estimated_seconds: bytes_per_second.map(|_| 0.0),
+            bytes_per_second,
+        }
+    }
+
+    /// Adds an album split into chunks of the given lengths.
+    pub fn add_album(&mut self, album: &Album, lengths: &[usize]) {
+        let caption = album
+            .caption
+            .as_deref()
+            .or(album.items.first().map(|item| item.caption.as_str()))
+            .filter(|caption| !caption.is_empty())
+            .map(preview);
+        let mut items = album.items.iter();
+        let mut chunks = Vec::new();
+        let mut first = 1;
+        for &length in lengths {
+            let items: Vec<ItemPlan> = items
+                .by_ref()
+                .take(length)
+                .map(|item| self.item(&item.entry, item.bytes))
+                .collect();
+            let bytes = items.iter().map(|item| item.bytes).sum();
+            chunks.push(ChunkPlan {
+                first,
+                last: first + length - 1,
+                bytes,
+                estimated_seconds: self.seconds(bytes),
+                items,
+            });
+            first += length;
+        }
+        if !album.subtitles.is_empty() {
+            let items: Vec<ItemPlan> = album
+                .subtitles
+                .iter()
+                .map(|path| self.item(&PlanEntry::new(path, "subtitles"), crate::file_size(path)))
+                .collect();
+            let bytes = items.iter().map(|item| item.bytes).sum();
+            chunks.push(ChunkPlan {
+                first,
+                last: first + items.len() - 1,
+                bytes,
+                estimated_seconds: self.seconds(bytes),
+                items,
+            });
+        }
+        for chunk in &chunks {
+            self.count(chunk.bytes);
+        }
+        self.albums.push(AlbumPlan {
+            label: album.label.clone(),
+            caption,
+            caption_file: album.caption_file.clone(),
+            chunks,
+        });
+    }
+
+    pub fn add_single(&mut self, entry: &PlanEntry) {
+        let item = self.item(entry, crate::file_size(&entry.path));
+        self.count(item.bytes);
+        self.singles.push(item);
+    }
+
+    pub fn reject(&mut self, path: &Path, reason: &str) {
+        self.rejected.push(Rejected {
+            path: path.to_path_buf(),
+            reason: reason.trim().to_string(),
+        });
+    }
+
+    fn item(&self, entry: &PlanEntry, bytes: u64) -> ItemPlan {
+        let mut problems = Vec::new();
+        if bytes > self.upload_limit_bytes {
+            problems.push(format!(
+                "over the {} upload limit",
+                format_bytes(self.upload_limit_bytes)
+            ));
+        }
+        if entry.kind == "photo" && bytes > PHOTO_LIMIT {
+            problems.push(format!(
+                "over the {} photo limit",
+                format_bytes(PHOTO_LIMIT)
+            ));
+        }
+        ItemPlan {
+            entry: PlanEntry {
+                path: entry.path.clone(),
+                kind: entry.kind,
+                details: entry.details.clone(),
+                thumbnail: entry.thumbnail.clone(),
+                warnings: entry.warnings.clone(),
+                frame: None,
+            },
+            bytes,
+            problems,
+        }
+    }
+
+    fn seconds(&self, bytes: u64) -> Option<f64> {
+        self.bytes_per_second.map(|rate| bytes as f64 / rate as f64)
+    }
+
+    fn count(&mut self, bytes: u64) {
+        self.total_bytes += bytes;
+        self.estimated_seconds = self.seconds(self.total_bytes);
+    }
+
+    pub fn print(&self, format: PlanFormat) {
+        match format {
+            PlanFormat::Json => match serde_json::to_string_pretty(self) {
+                Ok(json) => println!("{}", json),
+                Err(e) => log::error!("Could not serialize plan: {}", e),
+            },
+            PlanFormat::Text => self.print_text(),
+        }
+    }
+
+    fn print_text(&self) {
+        let eta = |seconds: Option<f64>| match seconds {
+            Some(s) => format!(", ~{:.0}s", s.ceil()),
+            None => String::new(),
+        };
+        println!("Dry run: nothing will be sent");
+        println!("  destination(s): {}", self.chats.join(", "));
+        for (chat, quality) in &self.qualities {
+            println!("    {} gets {}", chat, quality);
+        }
+        println!(
+            "  endpoint: {} ({} per file)",
+            self.api_url,
+            format_bytes(self.upload_limit_bytes)
+        );
+        for (n, album) in self.albums.iter().enumerate() {
+            let items: usize = album.chunks.iter().map(|c| c.items.len()).sum();
+            println!(
+                "Album {} ({}): {} items in {} message(s)",
+                n + 1,
+                album.label,
+                items,
+                album.chunks.len()
+            );
+            if let Some(caption) = &album.caption {
+                println!("  caption: {:?}", caption);
+            }
+            if let Some(file) = &album.caption_file {
+                println!("  caption from {:?}", file);
+            }
+            for (i, chunk) in album.chunks.iter().enumerate() {
+                println!(
+                    "  message {}: items {}-{}, {}{}",
+                    i + 1,
+                    chunk.first,
+                    chunk.last,
+                    format_bytes(chunk.bytes),
+                    eta(chunk.estimated_seconds)
+                );
+                for item in &chunk.items {
+                    item.print();
+                }
+            }
+        }
+        if !self.singles.is_empty() {
+            println!("Individual messages:");
+            for item in &self.singles {
+                item.print();
+            }
+        }
+        if !self.rejected.is_empty() {
+            println!("Rejected:");
+            for rejected in &self.rejected {
+                println!("    {:?}: {}", rejected.path, rejected.reason);
+            }
+        }
+        println!(
+            "Total: {}{}",
+            format_bytes(self.total_bytes),
+            eta(self.estimated_seconds)
+        );
+    }
+}
+
+impl ItemPlan {
+    fn print(&self) {
+        let mut details = self.entry.details.clone();
+        details.push(format_bytes(self.bytes));
+        details.extend(
+            self.entry
+                .warnings
+                .iter()
+                .map(|w| format!("warning: {}", w)),
+        );
+        details.extend(self.problems.iter().map(|p| format!("PROBLEM: {}", p)));
+        println!(
+            "    {} {:?}: {}",
+            self.entry.kind,
+            self.entry.path,
+            details.join(", ")
+        );
+    }
+}
+
+fn preview(caption: &str) -> String {
+    match caption.char_indices().nth(CAPTION_PREVIEW_CHARS) {
+        Some((cut, _)) => format!("{}...", &caption[..cut]),
+        None => caption.to_string(),
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1_000_000_000 => format!("{:.1} GB", b as f64 / 1e9),
+        b if b >= 1_000_000 => format!("{:.1} MB", b as f64 / 1e6),
+        b if b >= 1_000 => format!("{:.1} kB", b as f64 / 1e3),
+        b => format!("{} B", b),
+    }
+}
+
+/// Parses a bandwidth such as `20mbit`, `5MB` or `800kb` (per second) into
+/// bytes per second.
+pub fn parse_bandwidth(value: &str) -> Result<u64, String> {
+    let lower = value.trim().to_lowercase();
+    let split = lower
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(lower.len());
+    let (number, unit) = lower.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("`{}` does not start with a number", value))?;
+    let unit = unit.trim().trim_end_matches("/s");
+    let bytes = match unit {
+        "" | "b" => number,
+        "kb" => number * 1e3,
+        "mb" => number * 1e6,
+        "gb" => number * 1e9,
+        "kbit" => number * 1e3 / 8.0,
+        "mbit" => number * 1e6 / 8.0,
+        "gbit" => number * 1e9 / 8.0,
+        _ => {
+            return Err(format!(
+                "unknown unit `{}` (use B, kB, MB, GB, kbit, mbit or gbit)",
+                unit
+            ));
+        }
+    };
+    if bytes < 1.0 {
+        return Err("bandwidth must be at least 1 B/s".to_string());
+    }
+    Ok(bytes as u64)
+}
+
+/// Checks the order the albums send their files in, renders --preview and
+/// --preview-image, and prints the --dry-run plan. False when the run ends
+/// here, with nothing to send or nothing to be sent; --check-order stops it
+/// when the plan reorders the input.
+pub async fn review(
+    args: &Cli,
+    setup: &Setup,
+    batch: &Batch,
+    summary: &mut Summary,
+) -> Result<bool, Abort> {
+    let Batch {
+        albums,
+        animations,
+        stickers,
+        input_order,
+        ..
+    } = batch;
+    let Setup {
+        settings,
+        chat_id,
+        secondary_chats,
+        primary_quality,
+        qualities,
+        ..
+    } = setup;
+    let order_problem = (args.check_order || cfg!(debug_assertions))
+        .then(|| albums::order_problem(albums, input_order, args.album_size, args.max_chunk_bytes))
+        .flatten();
+    if let Some(problem) = &order_problem
+        && args.check_order
+    {
+        log::error!("Aborting: the send plan reorders the input: {}", problem);
+        albums::dump_plan(albums, input_order, args.album_size, args.max_chunk_bytes);
+        return Err(Abort {
+            error: None,
+            status: 1,
+            summary: false,
+        });
+    }
+    debug_assert!(
+        order_problem.is_none(),
+        "the send plan reorders the input: {:?}",
+        order_problem
+    );
+
+    if !args.dry_run && albums.is_empty() && stickers.is_empty() && animations.is_empty() {
+        log::error!("No valid media found to send.");
+        summary.log();
+        return Ok(false);
+    }
+
+    if let Some(out) = args.preview_image.clone() {
+        let sheet: Vec<Vec<PathBuf>> = albums
+            .iter()
+            .map(|album| {
+                album
+                    .items
+                    .iter()
+                    .map(|item| item.entry.path.clone())
+                    .collect()
+            })
+            .collect();
+        match task::spawn_blocking(move || preview::contact_sheet(&sheet, &out).map(|_| out)).await
+        {
+            Ok(Ok(out)) => log::info!("Saved preview to {:?}", out),
+            Ok(Err(e)) => log::error!("Could not render preview: {}", e),
+            Err(e) => log::error!("Could not render preview: {}", e),
+        }
+    }
+
+    if let Some(out) = args.preview.clone() {
+        let posts = preview_posts(albums, animations, args);
+        match task::spawn_blocking(move || preview::html(&posts, &out).map(|_| out)).await {
+            Ok(Ok(out)) => {
+                log::info!("Saved preview to {:?}", out);
+                if args.preview_open {
+                    open_file(&out);
+                }
+            }
+            Ok(Err(e)) => log::error!("Could not render preview: {}", e),
+            Err(e) => log::error!("Could not render preview: {}", e),
+        }
+    }
+
+    if args.dry_run {
+        let mut chats = vec![chat_id.clone()];
+        chats.extend(secondary_chats.iter().cloned());
+        let mut plan = Plan::new(chats, &settings.api_url, args.assume_bandwidth);
+        let named = qualities
+            .iter()
+            .map(|(chat, name)| (chat, name.as_ref()))
+            .chain([(chat_id, primary_quality.as_ref())]);
+        for (chat, name) in named {
+            if let Some(name) = name {
+                let quality = settings.qualities[name].describe();
+                plan.qualities
+                    .insert(chat.clone(), format!("{} ({})", name, quality));
+            }
+        }
+        for album in albums {
+            let lengths =
+                albums::chunk_lengths(&album.items, args.album_size, args.max_chunk_bytes);
+            plan.add_album(album, &lengths);
+        }
+        for post in animations {
+            plan.add_single(&post.entry);
+        }
+        for path in stickers {
+            plan.add_single(&PlanEntry::new(path, "sticker"));
+        }
+        for (path, reason) in &summary.skipped {
+            plan.reject(path, reason);
+        }
+        plan.print(args.output);
+        if args.output != PlanFormat::Json {
+            summary.log();
+        }
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+// The albums as send_album posts them and the animations, for --preview.
+fn preview_posts(albums: &[Album], animations: &[AnimationPost], args: &Cli) -> Vec<preview::Post> {
+    let tile = |entry: &PlanEntry| preview::Tile {
+        path: entry.path.clone(),
+        kind: entry.kind,
+        details: entry.details.clone(),
+        warnings: entry.warnings.clone(),
+        frame: entry.frame.clone(),
+    };
+    let caption_html = |caption: &str, parse_mode: Option<ParseMode>| {
+        (!caption.is_empty()).then(|| entities::to_html(caption, caption_format(parse_mode)))
+    };
+    let mut posts = Vec::new();
+    for album in albums {
+        let lengths = albums::chunk_lengths(&album.items, args.album_size, args.max_chunk_bytes);
+        let mut items = album.items.iter();
+        let messages: Vec<Vec<preview::Tile>> = lengths
+            .into_iter()
+            .map(|length| {
+                items
+                    .by_ref()
+                    .take(length)
+                    .map(|item| tile(&item.entry))
+                    .collect()
+            })
+            .collect();
+        // Same choice of caption and captioned item as send_album
+        let index = args
+            .caption_on_index
+            .filter(|index| *index < album.items.len())
+            .unwrap_or(0);
+        let caption = album
+            .caption
+            .clone()
+            .or_else(|| album.items.first().map(|item| item.caption.clone()));
+        let parse_mode = album.items.first().and_then(|item| item.parse_mode);
+        let mut caption_at = (0, index);
+        for message in &messages {
+            if caption_at.1 < message.len() {
+                break;
+            }
+            caption_at = (caption_at.0 + 1, caption_at.1 - message.len());
+        }
+        posts.push(preview::Post {
+            label: format!("Album {}", album.label),
+            messages,
+            caption: caption.and_then(|caption| caption_html(&caption, parse_mode)),
+            caption_at,
+            caption_reply: args.caption_placement == CaptionPlacement::Reply,
+        });
+    }
+    for post in animations {
+        posts.push(preview::Post {
+            label: format!("Animation {:?}", post.path),
+            messages: vec![vec![tile(&post.entry)]],
+            caption: caption_html(&post.caption, post.parse_mode),
+            caption_at: (0, 0),
+            caption_reply: false,
+        });
+    }
+    posts
+}
@@ -0,0 +1,368 @@
+// ---------------------------
+// Builder API
+// ---------------------------
+
+//! Posting files from Rust code the way the `telegoy` command does: a post
+//! is the command run on its files, so they go through the same
+//! classification (photos, videos, audio, and with [`Post::as_animation`] /
+//! [`Post::as_sticker`] animations and stickers), sidecar captions and
+//! metadata, probing, thumbnailing and chunking into media groups.
+//!
+//! ```no_run
+//! # async fn post() -> Result<(), telegoy::post::PostError> {
+//! use telegoy::post::Post;
+//! use teloxide::prelude::*;
+//! use teloxide::types::ParseMode;
+//!
+//! let bot = Bot::from_env();
+//! let sent = Post::new(ChatId(-1001234567890))
+//!     .add_file("a.jpg")
+//!     .add_file("b.mp4")
+//!     .caption("*hi*")
+//!     .parse_mode(ParseMode::MarkdownV2)
+//!     .spoiler(true)
+//!     .send(&bot)
+//!     .await?;
+//! println!("posted messages {:?}", sent.message_ids());
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::albums::AlbumItem;
+use crate::process::Batch;
+use crate::setup::Setup;
+use crate::{Abort, CaptionFormat, Cli, StreamingMode, Summary, VideoMeta, entities, throttle};
+use crate::{collect, process, publish};
+use clap::Parser;
+use std::path::{Path, PathBuf};
+use teloxide::prelude::*;
+use teloxide::types::{InputFile, InputMedia, ParseMode, Recipient};
+
+/// What a video or animation is sent with
+#[derive(Clone, Debug, Default)]
+pub struct ItemMeta {
+    pub width: Option<u16>,
+    pub height: Option<u16>,
+    /// Seconds
+    pub duration: Option<u16>,
+    /// An image to use as the thumbnail instead of the generated one
+    pub thumbnail: Option<PathBuf>,
+}
+
+type MetaHook = Box<dyn Fn(&Path, &mut ItemMeta) + Send + Sync>;
+
+/// Files posted to one chat as an album, captioned on the first item.
+pub struct Post {
+    chat: Recipient,
+    files: Vec<PathBuf>,
+    caption: Option<String>,
+    parse_mode: Option<ParseMode>,
+    spoiler: bool,
+    streaming: Option<bool>,
+    as_animation: bool,
+    as_sticker: bool,
+    meta_hook: Option<MetaHook>,
+}
+
+/// Why a post was not sent, or not in full
+#[derive(Debug)]
+pub enum PostError {
+    NoFiles,
+    /// Not a kind of file telegoy sends, going by the extension
+    Unsupported(PathBuf),
+    CaptionTooLong(usize),
+    /// A file could not be read, converted or captioned; nothing was sent
+    Rejected(String),
+    /// Telegram refused some of the files, given with the error of each;
+    /// `sent` are the ones that did go out
+    Failed {
+        errors: Vec<(PathBuf, String)>,
+        sent: Sent,
+    },
+}
+
+impl std::fmt::Display for PostError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PostError::NoFiles => write!(f, "no files to post"),
+            PostError::Unsupported(path) => write!(f, "{:?} is not a file telegoy sends", path),
+            PostError::CaptionTooLong(length) => write!(
+                f,
+                "caption is {} characters, over Telegram's {}",
+                length,
+                crate::MAX_CAPTION_CHARS
+            ),
+            PostError::Rejected(e) => write!(f, "{}", e),
+            PostError::Failed { errors, .. } => {
+                let errors: Vec<String> = errors
+                    .iter()
+                    .map(|(path, e)| format!("{:?}: {}", path, e))
+                    .collect();
+                write!(f, "not sent: {}", errors.join("; "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for PostError {}
+
+/// One posted file
+#[derive(Clone, Debug)]
+pub struct SentItem {
+    pub path: PathBuf,
+    pub message_id: i32,
+    /// For sending the same file again without uploading it
+    pub file_id: Option<String>,
+}
+
+/// The messages of a post, in order
+#[derive(Clone, Debug, Default)]
+pub struct Sent {
+    pub items: Vec<SentItem>,
+}
+
+impl Sent {
+    pub fn message_ids(&self) -> Vec<i32> {
+        self.items.iter().map(|item| item.message_id).collect()
+    }
+}
+
+impl Post {
+    pub fn new(chat: impl Into<Recipient>) -> Self {
+        Post {
+            chat: chat.into(),
+            files: Vec::new(),
+            caption: None,
+            parse_mode: None,
+            spoiler: false,
+            streaming: None,
+            as_animation: false,
+            as_sticker: false,
+            meta_hook: None,
+        }
+    }
+
+    /// A file, or a directory whose media files are all posted.
+    pub fn add_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.files.push(path.into());
+        self
+    }
+
+    /// The post's caption, in place of the first file's caption sidecar.
+    pub fn caption(mut self, text: impl Into<String>) -> Self {
+        self.caption = Some(text.into());
+        self
+    }
+
+    /// The markup of the caption, and of the caption sidecars, as with
+    /// `--parse-mode`.
+    pub fn parse_mode(mut self, mode: ParseMode) -> Self {
+        self.parse_mode = Some(mode);
+        self
+    }
+
+    /// Hide the media behind a spoiler until tapped.
+    pub fn spoiler(mut self, spoiler: bool) -> Self {
+        self.spoiler = spoiler;
+        self
+    }
+
+    /// Whether videos can be played while they download; on unless set,
+    /// as with `supports_streaming` in the config.
+    pub fn streaming(mut self, streaming: bool) -> Self {
+        self.streaming = Some(streaming);
+        self
+    }
+
+    /// Send .mp4/.gif files as animations, one message each.
+    pub fn as_animation(mut self, as_animation: bool) -> Self {
+        self.as_animation = as_animation;
+        self
+    }
+
+    /// Send .tgs/.webm files as stickers, one message each.
+    pub fn as_sticker(mut self, as_sticker: bool) -> Self {
+        self.as_sticker = as_sticker;
+        self
+    }
+
+    /// Calls `hook` with each video and animation and the [`ItemMeta`] it
+    /// was probed with; the item is sent with what the hook leaves in it.
+    pub fn with_meta(
+        mut self,
+        hook: impl Fn(&Path, &mut ItemMeta) + Send + Sync + 'static,
+    ) -> Self {
+        self.meta_hook = Some(Box::new(hook));
+        self
+    }
+
+    pub async fn send(self, bot: &Bot) -> Result<Sent, PostError> {
+        if self.files.is_empty() {
+            return Err(PostError::NoFiles);
+        }
+        let format = match self.parse_mode {
+            Some(ParseMode::MarkdownV2) => CaptionFormat::Markdown,
+            Some(ParseMode::Html) => CaptionFormat::Html,
+            _ => CaptionFormat::Plain,
+        };
+        if let Some(caption) = &self.caption {
+            let length = entities::text_len(caption, format);
+            if length > crate::MAX_CAPTION_CHARS {
+                return Err(PostError::CaptionTooLong(length));
+            }
+        }
+        let mut args = self.command_line(format)?;
+        let mut temp_files = Vec::new();
+        let sent = self.deliver(bot, &mut args, &mut temp_files).await;
+        crate::remove_listed_files(&temp_files);
+        // Only once empty: other posts of this process may still be using it
+        let _ = std::fs::remove_dir(crate::run_dir());
+        sent
+    }
+
+    // The command's stages on the files of the post, leaving in
+    // `temp_files` what they made
+    async fn deliver(
+        &self,
+        bot: &Bot,
+        args: &mut Cli,
+        temp_files: &mut Vec<PathBuf>,
+    ) -> Result<Sent, PostError> {
+        let mut summary = Summary::new(args.summary);
+        let (files, mut inputs) =
+            collect::files(args, &mut summary, temp_files).map_err(rejected)?;
+        if files.is_empty() {
+            return Err(PostError::NoFiles);
+        }
+        if let Some(path) = files
+            .iter()
+            .find(|path| crate::media_type(path, args).is_none())
+        {
+            return Err(PostError::Unsupported(path.clone()));
+        }
+        if let Some(caption) = self.caption.clone() {
+            inputs.inline_captions.insert(files[0].clone(), caption);
+        }
+        let chat = crate::recipient_name(&self.chat);
+        let setup = Setup::new(chat, bot.api_url(), args);
+        let mut batch = process::files(args, files, &mut inputs, &setup, &mut summary, temp_files)
+            .await
+            .map_err(rejected)?;
+        if let Some(hook) = &self.meta_hook {
+            apply_meta_hook(hook, &mut batch).await;
+        }
+        let limits = throttle::Limits::new(args.limit_rate, args.limit_rate_per_file);
+        let bot = bot.clone();
+        publish::batch(bot, args, &limits, setup, batch, &inputs, &mut summary)
+            .await
+            .map_err(rejected)?;
+
+        let sent = Sent {
+            items: summary.sent_items,
+        };
+        if summary.failed.is_empty() {
+            return Ok(sent);
+        }
+        let errors = summary
+            .failed
+            .into_iter()
+            .map(|path| {
+                let error = summary.errors.remove(&path).unwrap_or_default();
+                (path, error)
+            })
+            .collect();
+        Err(PostError::Failed { errors, sent })
+    }
+
+    // The `telegoy` command line this post amounts to, up to the caption:
+    // its files, and a file that fails stops the post
+    fn command_line(&self, format: CaptionFormat) -> Result<Cli, PostError> {
+        let argv = ["telegoy".into(), "--".into()]
+            .into_iter()
+            .chain(self.files.iter().map(|path| path.clone().into_os_string()));
+        let mut args = Cli::try_parse_from::<_, std::ffi::OsString>(argv)
+            .map_err(|e| PostError::Rejected(e.to_string()))?;
+        args.on_error = crate::ErrorPolicy::Abort;
+        args.inline_caption_replaces = crate::InlineCaption::All;
+        // No static_caption.txt from the working directory
+        args.static_caption_path = Some(String::new());
+        args.parse_mode = self.parse_mode.map(|_| format);
+        args.spoiler = self.spoiler;
+        args.streaming = self.streaming.map(|on| match on {
+            true => StreamingMode::On,
+            false => StreamingMode::Off,
+        });
+        args.as_animation = self.as_animation;
+        args.as_sticker = self.as_sticker;
+        Ok(args)
+    }
+}
+
+fn rejected(abort: Abort) -> PostError {
+    PostError::Rejected(abort.error.unwrap_or_else(|| "stopped".to_string()))
+}
+
+// Lets the hook change what each video and animation of the batch is sent
+// with.
+async fn apply_meta_hook(hook: &MetaHook, batch: &mut Batch) {
+    for item in batch.albums.iter_mut().flat_map(|album| &mut album.items) {
+        let AlbumItem {
+            entry,
+            media: InputMedia::Video(video),
+            dimensions,
+            ..
+        } = item
+        else {
+            continue;
+        };
+        let mut meta = ItemMeta {
+            width: video.width,
+            height: video.height,
+            duration: video.duration,
+            thumbnail: None,
+        };
+        hook(&entry.path, &mut meta);
+        (video.width, video.height, video.duration) = (meta.width, meta.height, meta.duration);
+        *dimensions = meta
+            .width
+            .zip(meta.height)
+            .map(|(w, h)| (w.into(), h.into()));
+        if let Some(image) = meta.thumbnail {
+            video.thumbnail = own_thumbnail(&entry.path, image)
+                .await
+                .or(video.thumbnail.take());
+        }
+    }
+    for post in &mut batch.animations {
+        let VideoMeta {
+            width,
+            height,
+            duration,
+            thumbnail,
+            ..
+        } = &mut post.meta;
+        let mut meta = ItemMeta {
+            width: *width,
+            height: *height,
+            duration: *duration,
+            thumbnail: None,
+        };
+        hook(&post.path, &mut meta);
+        (*width, *height, *duration) = (meta.width, meta.height, meta.duration);
+        if let Some(image) = meta.thumbnail {
+            *thumbnail = own_thumbnail(&post.path, image).await.or(thumbnail.take());
+        }
+    }
+}
+
+// The hook's thumbnail for `path`, or None (keeping the generated one) when
+// it cannot be read
+async fn own_thumbnail(path: &Path, image: PathBuf) -> Option<InputFile> {
+    match crate::load_thumbnail(image).await {
+        Ok(bytes) => Some(InputFile::memory(bytes).file_name(crate::thumbnail_name(path))),
+        Err(e) => {
+            log::warn!("{:?}: keeping the generated thumbnail: {}", path, e);
+            None
+        }
+    }
+}
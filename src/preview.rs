@@ -0,0 +1,268 @@
+// ---------------------------
+// Contact sheet and HTML previews
+// ---------------------------
+
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, Rgba, RgbaImage, imageops};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+const TILE: u32 = 240;
+const GAP: u32 = 8;
+const COLUMNS: u32 = 5;
+const BACKGROUND: Rgba<u8> = Rgba([24, 24, 24, 255]);
+// Shown for files that could not be decoded
+const PLACEHOLDER: Rgba<u8> = Rgba([90, 90, 90, 255]);
+
+/// Renders the albums (each a list of files in send order) as one contact
+/// sheet, every album starting on a new row, and saves it to `out`.
+pub fn contact_sheet(albums: &[Vec<PathBuf>], out: &Path) -> Result<(), String> {
+    let rows: u32 = albums
+        .iter()
+        .map(|files| (files.len() as u32).div_ceil(COLUMNS))
+        .sum();
+    if rows == 0 {
+        return Err("nothing to preview".to_string());
+    }
+    let columns = albums
+        .iter()
+        .map(|files| files.len() as u32)
+        .max()
+        .unwrap_or(1)
+        .min(COLUMNS);
+    let mut sheet = RgbaImage::from_pixel(
+        GAP + columns * (TILE + GAP),
+        GAP + rows * (TILE + GAP),
+        BACKGROUND,
+    );
+
+    let mut row = 0;
+    for files in albums {
+        for (i, path) in files.iter().enumerate() {
+            let (col, y) = (i as u32 % COLUMNS, row + i as u32 / COLUMNS);
+            let (x0, y0) = (GAP + col * (TILE + GAP), GAP + y * (TILE + GAP));
+            match load_frame(path) {
+                Some(img) => {
+                    let thumb = img.thumbnail(TILE, TILE).to_rgba8();
+                    let x = x0 + (TILE - thumb.width()) / 2;
+                    let y = y0 + (TILE - thumb.height()) / 2;
+                    imageops::overlay(&mut sheet, &thumb, x.into(), y.into());
+                }
+                None => {
+                    log::warn!("No preview frame for {:?}", path);
+                    let tile = RgbaImage::from_pixel(TILE, TILE, PLACEHOLDER);
+                    imageops::overlay(&mut sheet, &tile, x0.into(), y0.into());
+                }
+            }
+        }
+        row += (files.len() as u32).div_ceil(COLUMNS);
+    }
+    sheet.save(out).map_err(|e| e.to_string())
+}
+
+// The image itself, or the first frame of a video decoded by ffmpeg.
+fn load_frame(path: &Path) -> Option<DynamicImage> {
+    let ext = crate::file_ext(path);
+    if crate::IMAGE_EXTENSIONS.contains(&ext.as_str()) || ext == "gif" {
+        return image::open(path).ok();
+    }
+    let output = crate::subprocess::output(
+        std::process::Command::new("ffmpeg")
+            .args(["-hide_banner", "-v", crate::ffmpeg_loglevel(), "-i"])
+            .arg(path)
+            .args(["-frames:v", "1", "-f", "image2pipe", "-vcodec", "png", "-"]),
+        crate::subprocess::Work::Transcode,
+        path,
+        None,
+    )
+    .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    image::load_from_memory(&output.stdout).ok()
+}
+
+// Longest side of the images embedded in an HTML preview
+const HTML_IMAGE: u32 = 640;
+
+/// One item of an HTML preview
+pub struct Tile {
+    pub path: PathBuf,
+    pub kind: &'static str,
+    pub details: Vec<String>,
+    pub warnings: Vec<String>,
+    // JPEG to show, such as the thumbnail generated for a video; the file's
+    // own image or first frame when None
+    pub frame: Option<Vec<u8>>,
+}
+
+/// One album or animation of an HTML preview, as the messages it is sent in
+pub struct Post {
+    pub label: String,
+    pub messages: Vec<Vec<Tile>>,
+    // Already rendered as HTML, with the (message, item) carrying it
+    pub caption: Option<String>,
+    pub caption_at: (usize, usize),
+    // --caption-placement reply: the caption comes as its own message
+    pub caption_reply: bool,
+}
+
+/// Writes the posts as one self-contained HTML page (images embedded as
+/// data URIs), each message's items laid out in rows the way Telegram
+/// arranges media groups of that size.
+pub fn html(posts: &[Post], out: &Path) -> Result<(), String> {
+    if posts.is_empty() {
+        return Err("nothing to preview".to_string());
+    }
+    let mut page = String::from(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>telegoy preview</title>\n<style>\n\
+         body{background:#0e1621;color:#e9edf1;font:15px/1.4 sans-serif;margin:24px}\n\
+         h2{font-size:14px;color:#8da2b5;font-weight:normal;margin:32px 0 8px}\n\
+         .message{background:#182533;border-radius:12px;max-width:480px;overflow:hidden;margin-bottom:8px}\n\
+         .row{display:flex;gap:2px;margin-bottom:2px}\n\
+         .row img,.row .missing{flex:1;min-width:0;height:180px;object-fit:cover;background:#2b3a4a}\n\
+         .single img{height:auto;max-height:480px;object-fit:contain}\n\
+         .missing{display:flex;align-items:center;justify-content:center;color:#8da2b5}\n\
+         .caption{padding:8px 12px;white-space:normal}\n\
+         .spoiler{background:#8da2b5;color:transparent;border-radius:3px}\n\
+         blockquote{border-left:3px solid #6ab2f2;margin:4px 0;padding-left:8px}\n\
+         a{color:#6ab2f2}\n\
+         .meta{font-size:12px;color:#8da2b5;margin:4px 0 0 4px}\n\
+         .warning{color:#e5a55d}\n\
+         </style></head><body>\n",
+    );
+    for post in posts {
+        let _ = writeln!(page, "<h2>{}</h2>", escape(&post.label));
+        for (m, items) in post.messages.iter().enumerate() {
+            page.push_str("<div class=\"message\">\n");
+            let mut tiles = items.iter();
+            for length in rows(items.len()) {
+                let class = if items.len() == 1 {
+                    "row single"
+                } else {
+                    "row"
+                };
+                let _ = writeln!(page, "<div class=\"{}\">", class);
+                for tile in tiles.by_ref().take(length) {
+                    match embed(tile) {
+                        Some(src) => {
+                            let _ = writeln!(
+                                page,
+                                "<img src=\"{}\" alt=\"{}\">",
+                                src,
+                                escape(&tile.path.to_string_lossy())
+                            );
+                        }
+                        None => {
+                            let _ = writeln!(page, "<div class=\"missing\">{}</div>", tile.kind);
+                        }
+                    }
+                }
+                page.push_str("</div>\n");
+            }
+            if let Some(caption) = &post.caption
+                && !post.caption_reply
+                && post.caption_at.0 == m
+            {
+                let _ = writeln!(page, "<div class=\"caption\">{}</div>", caption);
+            }
+            page.push_str("</div>\n<div class=\"meta\">\n");
+            for (i, tile) in items.iter().enumerate() {
+                let mut line = format!(
+                    "{}. {} {}",
+                    i + 1,
+                    tile.kind,
+                    escape(&tile.path.to_string_lossy())
+                );
+                if !tile.details.is_empty() {
+                    let _ = write!(line, ": {}", escape(&tile.details.join(", ")));
+                }
+                if post.caption.is_some() && !post.caption_reply && post.caption_at == (m, i) {
+                    line.push_str(" (captioned)");
+                }
+                let _ = writeln!(page, "<div>{}</div>", line);
+                for warning in &tile.warnings {
+                    let _ = writeln!(page, "<div class=\"warning\">{}</div>", escape(warning));
+                }
+            }
+            page.push_str("</div>\n");
+        }
+        if let Some(caption) = &post.caption
+            && post.caption_reply
+        {
+            let _ = writeln!(
+                page,
+                "<div class=\"message\"><div class=\"caption\">{}</div></div>",
+                caption
+            );
+        }
+    }
+    page.push_str("</body></html>\n");
+    std::fs::write(out, page).map_err(|e| e.to_string())
+}
+
+// Items per row of a media group of `n`: rows of two or three, with the
+// odd ones out on top the way Telegram puts a wide item first.
+fn rows(n: usize) -> Vec<usize> {
+    match n {
+        0 => Vec::new(),
+        1..=2 => vec![n],
+        3 => vec![1, 2],
+        4 => vec![1, 3],
+        _ => {
+            let mut rows = vec![3; n / 3];
+            match n % 3 {
+                1 => {
+                    rows.pop();
+                    rows.insert(0, 2);
+                    rows.insert(0, 2);
+                }
+                2 => rows.insert(0, 2),
+                _ => {}
+            }
+            rows
+        }
+    }
+}
+
+// The tile's picture as a JPEG data URI
+fn embed(tile: &Tile) -> Option<String> {
+    let jpeg = match &tile.frame {
+        Some(jpeg) => jpeg.clone(),
+        None => {
+            let img = load_frame(&tile.path)?.thumbnail(HTML_IMAGE, HTML_IMAGE);
+            let mut jpeg = Vec::new();
+            img.into_rgb8()
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, 85))
+                .ok()?;
+            jpeg
+        }
+    };
+    Some(format!("data:image/jpeg;base64,{}", base64(&jpeg)))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | u32::from(*b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
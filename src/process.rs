@@ -0,0 +1,829 @@
+// ---------------------------
+// Processing the files
+// ---------------------------
+
+use crate::albums::{self, Album, AlbumItem};
+use crate::collect::Inputs;
+use crate::setup::Setup;
+use crate::{
+    ANIMATION_EXTENSIONS, AUDIO_EXTENSIONS, Abort, AlbumBy, AnimationPost, CaptionFormat,
+    CaptionPlacement, Cli, ErrorPolicy, IMAGE_EXTENSIONS, InlineCaption, LowSpace,
+    MAX_CAPTION_CHARS, PlanEntry, PreparedVideo, Summary, TECH_INFO_FORMAT, VIDEO_EXTENSIONS,
+    append_line, append_signature, append_tags, caption_format, check_caption, check_metadata,
+    check_strftime, entities, file_ext, file_size, file_tech_info, get_caption, get_sidecar,
+    get_static_caption, get_video_meta, is_partial_download, keyboard, media_type, plan,
+    prepare_audio, prepare_video_within, quality, reject_file, render_template, space, sticker,
+    transcode_gif, type_tag, type_tags, validate_images, watermark_for, watermark_photo, within,
+};
+use futures::StreamExt;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use teloxide::types::{
+    InputFile, InputMedia, InputMediaAudio, InputMediaPhoto, InputMediaVideo, Seconds,
+};
+use tokio::task;
+
+/// The batch ready to send
+pub struct Batch {
+    pub albums: Vec<Album>,
+    // Sent one by one after the albums, animations first
+    pub animations: Vec<AnimationPost>,
+    pub stickers: Vec<PathBuf>,
+    // Each input file's position, for --check-order
+    pub input_order: HashMap<PathBuf, usize>,
+    // Caption languages that have a static_caption or album caption file of
+    // their own
+    pub found_langs: HashSet<String>,
+}
+
+/// Checks, converts and captions `files` and groups them into albums; a
+/// file that fails is skipped, or stops the run as --on-error says.
+pub async fn files(
+    args: &mut Cli,
+    files: Vec<PathBuf>,
+    inputs: &mut Inputs,
+    setup: &Setup,
+    summary: &mut Summary,
+    temp_files: &mut Vec<PathBuf>,
+) -> Result<Batch, Abort> {
+    let Setup {
+        settings,
+        primary_quality,
+        qualities,
+        caption_langs,
+        concurrency,
+        batch_thumbnail,
+        ..
+    } = setup;
+    let (photo_preset, streaming, gif_to_mp4) =
+        (setup.photo_preset, setup.streaming, setup.gif_to_mp4);
+    let primary_lang = setup.primary_lang.clone();
+    let Inputs {
+        inline_captions,
+        package_caption,
+        package_keyboard,
+        package_threads,
+        ..
+    } = inputs;
+    // Pre-flight: decode photos so a corrupt one can't break the whole group
+    let invalid_images = match args.validate_images {
+        Some(mode) => {
+            let images: Vec<PathBuf> = files
+                .iter()
+                .filter(|p| IMAGE_EXTENSIONS.contains(&file_ext(p).as_str()))
+                .cloned()
+                .collect();
+            log::info!("Validating {} photos...", images.len());
+            validate_images(images, mode, concurrency.validation, args.file_timeout).await
+        }
+        None => Default::default(),
+    };
+    if !invalid_images.is_empty() && args.on_error == ErrorPolicy::Abort {
+        for (path, reason) in &invalid_images {
+            log::error!("Invalid image {:?}: {}", path, reason);
+        }
+        return Err(format!(
+            "Aborting: {} photos failed validation",
+            invalid_images.len()
+        )
+        .into());
+    }
+    let mut stickers: Vec<PathBuf> = Vec::new();
+    let mut animations: Vec<AnimationPost> = Vec::new();
+    let timezone = match args
+        .timezone
+        .as_deref()
+        .or(settings.timezone.as_deref())
+        .map(str::parse::<chrono::FixedOffset>)
+    {
+        Some(Ok(offset)) => Some(offset),
+        Some(Err(e)) => return Err(format!("Invalid timezone: {}", e).into()),
+        None => None,
+    };
+    for (flag, format) in [
+        ("--date-format", &args.date_format),
+        ("--album-date-format", &args.album_date_format),
+    ] {
+        if let Err(e) = check_strftime(format) {
+            return Err(format!("Invalid {} {:?}: {}", flag, format, e).into());
+        }
+    }
+    let now = match timezone {
+        Some(offset) => chrono::Utc::now()
+            .with_timezone(&offset)
+            .format(&args.date_format)
+            .to_string(),
+        None => chrono::Local::now().format(&args.date_format).to_string(),
+    };
+    // Run-level template variables, available in the static and default captions
+    let run_vars = [
+        ("date", chrono::Local::now().format("%Y-%m-%d").to_string()),
+        ("now", now),
+        ("count", files.len().to_string()),
+        (
+            "tags",
+            args.tags
+                .iter()
+                .map(|tag| format!("#{}", tag))
+                .collect::<Vec<_>>()
+                .join(" "),
+        ),
+    ];
+    // The static caption of each caption language in use, the first chat's
+    // as static_cap. The unsuffixed one is kept whenever the first chat has
+    // a language, for the chats that lack a variant.
+    let mut other_langs: std::collections::BTreeSet<Option<String>> =
+        caption_langs.values().cloned().collect();
+    if primary_lang.is_some() {
+        other_langs.insert(None);
+    }
+    other_langs.remove(&primary_lang);
+    // Languages that have a caption file of their own
+    let mut found_langs = HashSet::new();
+    let mut lang_statics = HashMap::new();
+    for lang in std::iter::once(&primary_lang).chain(&other_langs) {
+        let (text, found) = match &args.static_caption_path {
+            Some(text) => (text.clone(), false),
+            None => get_static_caption(lang.as_deref()).await,
+        };
+        if found {
+            found_langs.extend(lang.clone());
+        }
+        lang_statics.insert(lang.clone(), render_template(&text, &run_vars));
+    }
+    let static_cap = lang_statics.remove(&primary_lang).unwrap_or_default();
+    let default_cap = settings
+        .default_caption
+        .as_deref()
+        .map(|text| render_template(text, &run_vars))
+        .unwrap_or_default();
+    let caption_template = match &args.caption_template_file {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(text) => Some(render_template(&text, &run_vars)),
+            Err(e) => return Err(format!("Cannot read caption template {:?}: {}", path, e).into()),
+        },
+        None => None,
+    };
+    let tech_format = settings
+        .tech_info_format
+        .as_deref()
+        .unwrap_or(TECH_INFO_FORMAT);
+    let mut file_index = 0;
+
+    // The order files were given in, for --check-order
+    let mut input_order = HashMap::new();
+    if args.check_order || cfg!(debug_assertions) {
+        for (position, path) in files.iter().enumerate() {
+            input_order.entry(path.clone()).or_insert(position);
+        }
+        if let Some(intro) = &args.prepend_media {
+            input_order.remove(intro);
+        }
+    }
+
+    // Group the files into albums
+    let mut albums = match args.album_by {
+        Some(AlbumBy::Date) => {
+            let date_format = args.album_date_format.clone();
+            task::spawn_blocking(move || albums::group_by_date(files, timezone, &date_format))
+                .await
+                .expect("date bucketing panicked")
+        }
+        None => albums::group(files, args.album_per_dir),
+    };
+    if let Some(caption) = package_caption {
+        for album in &mut albums {
+            album.caption.get_or_insert_with(|| caption.clone());
+        }
+    }
+    if !package_threads.is_empty() {
+        albums = albums
+            .into_iter()
+            .flat_map(|album| album.split_by_thread(package_threads))
+            .collect();
+    }
+    if let Some(intro) = &args.prepend_media {
+        for album in &mut albums {
+            album.files.insert(0, intro.clone());
+        }
+    }
+
+    // Room for the derived copies, checked before any of them is made
+    if !args.dry_run {
+        let copy_qualities: Vec<&quality::Quality> = primary_quality
+            .iter()
+            .chain(qualities.values().flatten())
+            .collect::<std::collections::BTreeSet<_>>()
+            .into_iter()
+            .map(|name| &settings.qualities[name])
+            .collect();
+        let needed = temp_space_needed(
+            &albums,
+            args,
+            gif_to_mp4,
+            photo_preset.is_some(),
+            &copy_qualities,
+        );
+        let dir = std::env::temp_dir();
+        match space::free_bytes(&dir) {
+            Ok(free) if needed > free => {
+                let message = format!(
+                    "The batch needs about {} of temp space in {:?} for its converted copies, but only {} is free",
+                    plan::format_bytes(needed),
+                    dir,
+                    plan::format_bytes(free)
+                );
+                if args.low_space == LowSpace::Serial {
+                    log::warn!("{}; processing one video at a time", message);
+                    args.serial_processing = true;
+                } else {
+                    return Err(format!(
+                        "{}. Free some space, point TMPDIR at a bigger disk or pass --low-space serial",
+                        message
+                    )
+                    .into());
+                }
+            }
+            Ok(_) => {}
+            Err(e) => log::debug!("Not checking temp space: {}", e),
+        }
+    }
+
+    // The ffmpeg work for videos runs --concurrency at a time, ahead of the
+    // in-order pass below (which does it file by file when serial)
+    let mut listed = HashSet::new();
+    let videos: Vec<&PathBuf> = albums
+        .iter()
+        .filter(|_| !args.serial_processing)
+        .flat_map(|album| &album.files)
+        .filter(|path| listed.insert(*path))
+        .filter(|path| {
+            let ext = file_ext(path);
+            VIDEO_EXTENSIONS.contains(&ext.as_str())
+                && !(args.as_sticker && sticker::EXTENSIONS.contains(&ext.as_str()))
+                && !(args.as_animation && ANIMATION_EXTENSIONS.contains(&ext.as_str()))
+        })
+        .collect();
+    let mut prepared_videos: HashMap<PathBuf, PreparedVideo> = futures::stream::iter(videos)
+        .map(|path| async {
+            let video = prepare_video_within(path, args, streaming, batch_thumbnail.as_ref()).await;
+            (path.clone(), video)
+        })
+        .buffered(concurrency.processing)
+        .collect()
+        .await;
+    // Tracked right away so an abort on an earlier file still removes them
+    for video in prepared_videos.values_mut() {
+        temp_files.append(&mut video.temp_files);
+    }
+
+    for album in &mut albums {
+        let caption = match &args.album_caption {
+            Some(text) if album.files.len() > 1 => {
+                album.caption = None;
+                Some(text.clone())
+            }
+            _ => album.caption.take(),
+        };
+        if let Some(caption) = caption {
+            let dir_name = album
+                .caption_file
+                .as_deref()
+                .and_then(Path::parent)
+                .map(|dir| dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf()))
+                .and_then(|dir| Some(dir.file_name()?.to_string_lossy().into_owned()));
+            let mut vars = run_vars.to_vec();
+            vars.extend(dir_name.map(|name| ("dir_name", name)));
+            // album.<lang>.txt when there is one, else the album's caption
+            let mut caption_in = |lang: &Option<String>, static_cap: &str| {
+                let variant = lang
+                    .as_deref()
+                    .zip(album.caption_file.as_deref())
+                    .filter(|_| args.album_caption.is_none())
+                    .and_then(|(lang, file)| {
+                        std::fs::read_to_string(albums::lang_variant(file, lang)).ok()
+                    });
+                if variant.is_some() {
+                    found_langs.extend(lang.clone());
+                }
+                let text = variant.as_deref().unwrap_or(&caption);
+                format!("{}{}", render_template(text, &vars), static_cap)
+            };
+            let captions = lang_statics
+                .iter()
+                .map(|(lang, static_cap)| (lang.clone(), caption_in(lang, static_cap)))
+                .collect();
+            album.caption = Some(caption_in(&primary_lang, &static_cap));
+            album.captions = captions;
+            album.parse_mode = args.parse_mode.and_then(CaptionFormat::parse_mode);
+        }
+        // --tag, and with --caption-hashtag-prefix the album's media types
+        let mut album_tags = args.tags.clone();
+        let mut animation_tags = args.tags.clone();
+        if args.caption_hashtag_prefix {
+            album_tags.extend(type_tags(&album.files, args, &settings.type_hashtags));
+            animation_tags.push(type_tag("animation", &settings.type_hashtags));
+        }
+        for path in std::mem::take(&mut album.files) {
+            log::info!("Processing file: {:?}", path);
+            file_index += 1;
+
+            let ext = file_ext(&path);
+            let is_image = IMAGE_EXTENSIONS.contains(&ext.as_str());
+            let is_video = VIDEO_EXTENSIONS.contains(&ext.as_str());
+            let is_audio = AUDIO_EXTENSIONS.contains(&ext.as_str());
+
+            let (file_caption, caption_format) = match inline_captions.get(&path) {
+                Some(caption) => (caption.clone(), CaptionFormat::Plain),
+                None => get_caption(&path).await,
+            };
+            // The static and default captions are plain text, escaped into a
+            // .md or .html sidecar's format unless --parse-mode says they are
+            // written in it
+            let plain = |text: &str| match args.parse_mode {
+                Some(_) => text.to_string(),
+                None => entities::escape(text, caption_format),
+            };
+            // Precedence: sidecar (or inline) + static caption, falling back to default_caption
+            let caption_with = |static_cap: &str| {
+                if let Some(template) = &caption_template {
+                    let caption = if file_caption.is_empty() {
+                        plain(&default_cap)
+                    } else {
+                        file_caption.clone()
+                    };
+                    // Sidecar text goes in last so placeholders in it stay as written
+                    let file_vars = [
+                        (
+                            "filename",
+                            path.file_name()
+                                .unwrap_or_default()
+                                .to_string_lossy()
+                                .into_owned(),
+                        ),
+                        ("index", file_index.to_string()),
+                        ("static", plain(static_cap)),
+                        ("caption", caption),
+                    ];
+                    render_template(template, &file_vars)
+                } else if inline_captions.contains_key(&path)
+                    && args.inline_caption_replaces == InlineCaption::All
+                {
+                    file_caption.clone()
+                } else if file_caption.is_empty() && static_cap.is_empty() {
+                    plain(&default_cap)
+                } else {
+                    format!("{}{}", file_caption, plain(static_cap))
+                }
+            };
+            let mut full_caption = caption_with(&static_cap);
+            let mut captions: HashMap<Option<String>, String> = lang_statics
+                .iter()
+                .map(|(lang, static_cap)| (lang.clone(), caption_with(static_cap)))
+                .collect();
+            // --parse-mode wins over the format implied by the caption file
+            let caption_format = args.parse_mode.unwrap_or(caption_format);
+            let tech_line = match args.caption_tech_info {
+                true => file_tech_info(&path, tech_format, args, prepared_videos.get(&path))
+                    .await
+                    .map(|line| entities::escape(&line, caption_format)),
+                false => None,
+            };
+            let tags = if media_type(&path, args) == Some("animation") {
+                &animation_tags
+            } else {
+                &album_tags
+            };
+            for caption in std::iter::once(&mut full_caption).chain(captions.values_mut()) {
+                if let Some(line) = &tech_line {
+                    append_line(caption, line);
+                }
+                append_tags(caption, tags, caption_format);
+                append_signature(caption, args.signature.as_deref(), caption_format);
+            }
+            if !check_caption(&path, &full_caption, caption_format, args, summary)? {
+                continue;
+            }
+            let parse_mode = caption_format.parse_mode();
+            // The sidecar's buttons, else the package's
+            let keyboard = match get_sidecar(&path).await.buttons {
+                Some(rows) => match keyboard::build_post_buttons(&rows) {
+                    Ok(keyboard) => Some(keyboard),
+                    Err(e) => {
+                        let reason = format!("invalid buttons in its sidecar: {}", e);
+                        reject_file(&path, &reason, args, summary)?;
+                        continue;
+                    }
+                },
+                None => package_keyboard.clone(),
+            };
+
+            if args.as_sticker && sticker::EXTENSIONS.contains(&ext.as_str()) {
+                // Stickers can't be grouped, they are sent one by one after the album
+                if let Err(e) = sticker::validate(&path, &ext).await {
+                    let reason = format!("not a valid sticker: {}", e);
+                    reject_file(&path, &reason, args, summary)?;
+                    continue;
+                }
+                stickers.push(path);
+            } else if args.as_animation && ANIMATION_EXTENSIONS.contains(&ext.as_str()) {
+                // Animations can't be grouped either; any audio track is simply not played
+                let mut entry = PlanEntry::new(&path, "animation");
+                let processed = within(args.file_timeout, async {
+                    let mut upload_path = path.clone();
+                    if gif_to_mp4 && ext == "gif" && file_size(&path) >= args.gif_min_bytes {
+                        if args.dry_run {
+                            entry.details.push("transcode to MP4".to_string());
+                        } else {
+                            match transcode_gif(path.clone()).await {
+                                Ok(mp4) => {
+                                    log::info!(
+                                        "Transcoded {:?} to MP4 ({} -> {})",
+                                        path,
+                                        plan::format_bytes(file_size(&path)),
+                                        plan::format_bytes(file_size(&mp4))
+                                    );
+                                    temp_files.push(mp4.clone());
+                                    upload_path = mp4;
+                                }
+                                Err(e) => {
+                                    log::warn!(
+                                        "Could not transcode {:?} to MP4, uploading the GIF: {}",
+                                        path,
+                                        e
+                                    );
+                                }
+                            }
+                        }
+                    }
+                    let meta =
+                        get_video_meta(&path, &upload_path, args, batch_thumbnail.as_ref()).await;
+                    (upload_path, meta)
+                })
+                .await;
+                let (upload_path, mut meta) = match processed {
+                    Ok((upload_path, Ok(meta))) => (upload_path, meta),
+                    Ok((_, Err(e))) => {
+                        let reason = format!("unreadable or corrupt video: {}", e);
+                        reject_file(&path, &reason, args, summary)?;
+                        continue;
+                    }
+                    Err(reason) => {
+                        reject_file(&path, &reason, args, summary)?;
+                        continue;
+                    }
+                };
+                if !check_metadata(&path, &meta, args, summary)? {
+                    continue;
+                }
+                // sendAnimation has no cover or start position
+                (meta.cover, meta.start_timestamp) = (None, None);
+                meta.describe(&mut entry);
+                if let Some(saved) = &meta.saved_thumbnail {
+                    summary.thumbnails.push((path.clone(), saved.clone()));
+                }
+                animations.push(AnimationPost {
+                    entry,
+                    path,
+                    upload_path,
+                    meta,
+                    caption: full_caption,
+                    captions,
+                    parse_mode,
+                    keyboard,
+                });
+            } else if let Some(reason) = invalid_images.get(&path) {
+                log::error!("Skipping {:?}: {}", path, reason);
+                summary.errors.insert(path.clone(), reason.clone());
+                summary.skipped.push((path.clone(), reason.clone()));
+            } else if is_image {
+                let mut entry = PlanEntry::new(&path, "photo");
+                let mut upload = path.clone();
+                if let Some(mark) = watermark_for(args, &get_sidecar(&path).await) {
+                    if args.dry_run {
+                        entry.details.push("watermark".to_string());
+                    } else {
+                        match within(args.file_timeout, watermark_photo(path.clone(), mark)).await {
+                            Ok(Ok(marked)) => {
+                                temp_files.push(marked.clone());
+                                upload = marked;
+                            }
+                            Ok(Err(e)) => {
+                                let reason = format!("could not add the watermark: {}", e);
+                                reject_file(&path, &reason, args, summary)?;
+                                continue;
+                            }
+                            Err(reason) => {
+                                reject_file(&path, &reason, args, summary)?;
+                                continue;
+                            }
+                        }
+                    }
+                }
+                let long_edge = albums::photo_dimensions(&upload).map(|(w, h)| w.max(h));
+                if let Some(preset) = photo_preset
+                    && long_edge.is_some_and(|edge| edge > preset.max_edge)
+                {
+                    if args.dry_run {
+                        entry
+                            .details
+                            .push(format!("shrink to {} px", preset.max_edge));
+                    } else {
+                        let shrink = quality::apply_preset(upload.clone(), preset);
+                        match within(args.file_timeout, shrink).await {
+                            Ok(Ok(Some(smaller))) => {
+                                summary.preset_bytes_saved +=
+                                    file_size(&upload).saturating_sub(file_size(&smaller));
+                                temp_files.push(smaller.clone());
+                                upload = smaller;
+                            }
+                            Ok(Ok(None)) => {}
+                            Ok(Err(e)) => log::warn!("Uploading {:?} at full size: {}", path, e),
+                            Err(reason) => {
+                                reject_file(&path, &reason, args, summary)?;
+                                continue;
+                            }
+                        }
+                    }
+                }
+                let mut media = InputMediaPhoto::new(InputFile::file(&upload));
+                media.has_spoiler = args.spoiler;
+                album.items.push(AlbumItem {
+                    entry,
+                    media: InputMedia::Photo(media),
+                    caption: full_caption,
+                    captions: captions.clone(),
+                    parse_mode,
+                    keyboard: keyboard.clone(),
+                    bytes: file_size(&upload),
+                    dimensions: albums::photo_dimensions(&upload),
+                    upload,
+                });
+            } else if is_video {
+                // --prepend-media is in every album, so each gets a copy
+                let prepared = if args.prepend_media.as_ref() == Some(&path) {
+                    prepared_videos.get(&path).cloned()
+                } else {
+                    prepared_videos.remove(&path)
+                };
+                let video = match prepared {
+                    Some(video) => video,
+                    // Listed twice; the first occurrence took the prepared one
+                    None => {
+                        prepare_video_within(&path, args, streaming, batch_thumbnail.as_ref()).await
+                    }
+                };
+                temp_files.extend(video.temp_files);
+                let (mut entry, upload_path) = (video.entry, video.upload_path);
+                let meta = match video.meta {
+                    Ok(meta) => meta,
+                    Err(reason) => {
+                        reject_file(&path, &reason, args, summary)?;
+                        continue;
+                    }
+                };
+                if !check_metadata(&path, &meta, args, summary)? {
+                    continue;
+                }
+
+                let mut media = InputMediaVideo::new(InputFile::file(&upload_path))
+                    .supports_streaming(video.supports_streaming);
+                media.has_spoiler = args.spoiler;
+
+                meta.describe(&mut entry);
+                if let Some(saved) = &meta.saved_thumbnail {
+                    summary.thumbnails.push((path.clone(), saved.clone()));
+                }
+                if let Some(thumb) = meta.thumbnail {
+                    media = media.thumbnail(thumb);
+                }
+                if let Some(w) = meta.width {
+                    media = media.width(w);
+                }
+                if let Some(h) = meta.height {
+                    media = media.height(h);
+                }
+                if let Some(d) = meta.duration {
+                    media = media.duration(d);
+                }
+                media.cover = meta.cover.as_deref().map(InputFile::file);
+                media.start_timestamp = meta.start_timestamp.map(Seconds::from_seconds);
+
+                album.items.push(AlbumItem {
+                    entry,
+                    media: InputMedia::Video(media),
+                    caption: full_caption,
+                    captions: captions.clone(),
+                    parse_mode,
+                    keyboard: keyboard.clone(),
+                    bytes: file_size(&upload_path),
+                    upload: upload_path,
+                    dimensions: meta
+                        .width
+                        .zip(meta.height)
+                        .map(|(w, h)| (w.into(), h.into())),
+                });
+                if args.include_subtitles {
+                    album.subtitles.extend(albums::subtitle_sidecars(&path));
+                }
+            } else if is_audio {
+                let mut entry = PlanEntry::new(&path, "audio");
+                let (upload, duration) = match within(
+                    args.file_timeout,
+                    prepare_audio(&path, args, &mut entry, temp_files),
+                )
+                .await
+                {
+                    Ok(Ok(prepared)) => prepared,
+                    Ok(Err(e)) => {
+                        let reason = format!("unreadable or corrupt audio: {}", e);
+                        reject_file(&path, &reason, args, summary)?;
+                        continue;
+                    }
+                    Err(reason) => {
+                        reject_file(&path, &reason, args, summary)?;
+                        continue;
+                    }
+                };
+                let mut file = InputFile::file(&upload);
+                if upload != path {
+                    // Shown when the audio has no title tag
+                    file = file.file_name(
+                        path.file_name()
+                            .unwrap_or_default()
+                            .to_string_lossy()
+                            .into_owned(),
+                    );
+                }
+                let mut media = InputMediaAudio::new(file);
+                media.duration = duration;
+                if let Some(d) = duration {
+                    entry.details.push(format!("{}s", d));
+                }
+                album.items.push(AlbumItem {
+                    entry,
+                    media: InputMedia::Audio(media),
+                    caption: full_caption,
+                    captions: captions.clone(),
+                    parse_mode,
+                    keyboard: keyboard.clone(),
+                    bytes: file_size(&upload),
+                    upload,
+                    dimensions: None,
+                });
+            } else if is_partial_download(&path) {
+                log::warn!("Skipping {:?}: appears to be a partial download", path);
+                summary
+                    .skipped
+                    .push((path, "appears to be a partial download".to_string()));
+            } else {
+                log::warn!("Skipping unsupported file type: {:?}", path);
+                summary
+                    .skipped
+                    .push((path, "unsupported file type".to_string()));
+            }
+        }
+        let format = caption_format(album.parse_mode);
+        for caption in album.caption.iter_mut().chain(album.captions.values_mut()) {
+            if args.caption_tech_info {
+                let count = album.items.len();
+                let bytes: u64 = album.items.iter().map(|item| item.bytes).sum();
+                let files = if count == 1 { "file" } else { "files" };
+                let line = format!("{} {} · {} total", count, files, plan::format_bytes(bytes));
+                append_line(caption, &entities::escape(&line, format));
+            }
+            append_tags(caption, &album_tags, format);
+            append_signature(caption, args.signature.as_deref(), format);
+            let length = entities::text_len(caption, format);
+            if args.caption_placement == CaptionPlacement::Media
+                && args.long_caption.is_none()
+                && length > MAX_CAPTION_CHARS
+            {
+                log::warn!(
+                    "Caption of album {} is {} characters, over Telegram's {}",
+                    album.label,
+                    length,
+                    MAX_CAPTION_CHARS
+                );
+            }
+        }
+    }
+    // The first chat's caption variant, and the languages nothing was
+    // written in
+    summary.caption_lang = primary_lang
+        .clone()
+        .filter(|lang| found_langs.contains(lang));
+    let missing: std::collections::BTreeSet<&String> = std::iter::once(&primary_lang)
+        .chain(caption_langs.values())
+        .flatten()
+        .filter(|lang| !found_langs.contains(*lang))
+        .collect();
+    for lang in missing {
+        log::warn!(
+            "No static_caption.{0}.txt or album.{0}.txt found, so the {0} chats get the unsuffixed captions",
+            lang
+        );
+    }
+    // An album left with only the --prepend-media item is not sent
+    albums.retain(|album| {
+        album
+            .items
+            .iter()
+            .any(|item| args.prepend_media.as_ref() != Some(&item.entry.path))
+    });
+    if args.auto_split_types {
+        albums = albums
+            .into_iter()
+            .flat_map(|album| {
+                let split = album.split_by_type(&args.type_order);
+                if split.len() > 1 {
+                    let labels: Vec<&str> = split.iter().map(|a| a.label.as_str()).collect();
+                    log::info!("Splitting by type into {}", labels.join(", "));
+                }
+                split
+            })
+            .collect();
+    }
+    albums = albums
+        .into_iter()
+        .flat_map(|album| {
+            let split = album.split_off_audio();
+            if split.len() > 1 {
+                let labels: Vec<&str> = split.iter().map(|a| a.label.as_str()).collect();
+                log::info!("Sending the audio apart: {}", labels.join(", "));
+            }
+            split
+        })
+        .collect();
+    if args.check_orientation || args.split_by_orientation {
+        albums = albums
+            .into_iter()
+            .flat_map(|album| match album.mixed_orientation() {
+                Some(_) if args.split_by_orientation => {
+                    log::info!("Splitting album {} by orientation", album.label);
+                    album.split_by_orientation()
+                }
+                Some((portrait, landscape)) => {
+                    log::warn!(
+                        "Album {} mixes {} portrait and {} landscape items (--split-by-orientation sends them as separate albums)",
+                        album.label,
+                        portrait,
+                        landscape
+                    );
+                    vec![album]
+                }
+                None => vec![album],
+            })
+            .collect();
+    }
+
+    Ok(Batch {
+        albums,
+        animations,
+        stickers,
+        input_order,
+        found_langs,
+    })
+}
+
+// Estimated temp space for the copies the batch will make: each muted,
+// watermarked or remuxed video, each GIF transcode, each watermarked or
+// shrunk photo and each version in another quality is counted at its
+// source's size, times the safety factor.
+fn temp_space_needed(
+    albums: &[Album],
+    args: &Cli,
+    gif_to_mp4: bool,
+    photo_preset: bool,
+    qualities: &[&quality::Quality],
+) -> u64 {
+    let mut listed = std::collections::HashSet::new();
+    let mut needed = 0;
+    for path in albums.iter().flat_map(|album| &album.files) {
+        if !listed.insert(path) {
+            continue;
+        }
+        let copies = match media_type(path, args) {
+            Some("video") => {
+                usize::from(args.mute)
+                    + usize::from(args.watermark.is_some())
+                    + usize::from(args.fix_faststart)
+                    + usize::from(args.normalize_video_audio)
+                    + qualities
+                        .iter()
+                        .filter(|q| q.max_height.is_some() || q.crf.is_some())
+                        .count()
+            }
+            Some("photo") => {
+                usize::from(args.watermark.is_some())
+                    + usize::from(photo_preset)
+                    + qualities.iter().filter(|q| q.max_height.is_some()).count()
+            }
+            Some("animation") => usize::from(gif_to_mp4 && file_ext(path) == "gif"),
+            Some("audio") => usize::from(args.normalize_audio),
+            _ => 0,
+        };
+        needed += file_size(path) * copies as u64;
+    }
+    (needed as f64 * space::SAFETY_FACTOR) as u64
+}
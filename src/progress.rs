@@ -0,0 +1,103 @@
+// ---------------------------
+// Batch progress & ETA
+// ---------------------------
+
+use crate::plan::format_bytes;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Span of the sliding window the current rate is measured over
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Files and bytes uploaded so far against the totals known when sending
+/// starts, logged after every send.
+pub struct Progress {
+    total_files: usize,
+    total_bytes: u64,
+    done_files: usize,
+    done_bytes: u64,
+    // (time, done_bytes) samples within the window, oldest first
+    samples: VecDeque<(Instant, u64)>,
+    // --limit-rate/--limit-rate-per-file, as shown in the log line
+    limits: Option<String>,
+}
+
+impl Progress {
+    pub fn new(total_files: usize, total_bytes: u64, limits: Option<String>) -> Self {
+        Progress {
+            total_files,
+            total_bytes,
+            done_files: 0,
+            done_bytes: 0,
+            samples: VecDeque::from([(Instant::now(), 0)]),
+            limits,
+        }
+    }
+
+    /// Counts files whose upload finished (sent or failed) and logs progress.
+    pub fn advance(&mut self, files: usize, bytes: u64) {
+        self.done_files += files;
+        self.done_bytes += bytes;
+        let now = Instant::now();
+        self.samples.push_back((now, self.done_bytes));
+        // Keep one sample older than the window so the rate spans all of it
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) > WINDOW {
+            self.samples.pop_front();
+        }
+        self.log();
+    }
+
+    /// Removes files that will not be uploaded from the totals.
+    pub fn skip(&mut self, files: usize, bytes: u64) {
+        self.total_files = self.total_files.saturating_sub(files);
+        self.total_bytes = self.total_bytes.saturating_sub(bytes);
+    }
+
+    /// Bytes per second over the sliding window.
+    fn rate(&self) -> Option<f64> {
+        let (&(start, start_bytes), &(end, end_bytes)) =
+            (self.samples.front()?, self.samples.back()?);
+        let seconds = end.duration_since(start).as_secs_f64();
+        (seconds > 0.0 && end_bytes > start_bytes)
+            .then(|| (end_bytes - start_bytes) as f64 / seconds)
+    }
+
+    fn log(&self) {
+        let remaining = self.total_bytes.saturating_sub(self.done_bytes);
+        let (rate, eta) = match self.rate() {
+            Some(rate) if remaining == 0 => {
+                (format!(", {}/s", format_bytes(rate as u64)), String::new())
+            }
+            Some(rate) => (
+                format!(", {}/s", format_bytes(rate as u64)),
+                format!(
+                    ", ~{} remaining",
+                    format_eta(Duration::from_secs_f64(remaining as f64 / rate))
+                ),
+            ),
+            None => (String::new(), String::new()),
+        };
+        let limits = match &self.limits {
+            Some(limits) => format!(" (limit {})", limits),
+            None => String::new(),
+        };
+        log::info!(
+            "{}/{} files, {}/{}{}{}{}",
+            self.done_files,
+            self.total_files,
+            format_bytes(self.done_bytes),
+            format_bytes(self.total_bytes),
+            rate,
+            limits,
+            eta
+        );
+    }
+}
+
+fn format_eta(eta: Duration) -> String {
+    match eta.as_secs() {
+        s if s >= 3600 => format!("{}h {} min", s / 3600, s % 3600 / 60),
+        s if s >= 60 => format!("{} min", s.div_ceil(60)),
+        s => format!("{}s", s.max(1)),
+    }
+}
@@ -0,0 +1,1152 @@
+// ---------------------------
+// Sending the batch
+// ---------------------------
+
+use crate::albums::{self, Album, AlbumItem};
+use crate::collect::Inputs;
+use crate::post::SentItem;
+use crate::process::Batch;
+use crate::setup::Setup;
+use crate::{
+    Abort, AlbumReport, CaptionFormat, CaptionPlacement, Cli, DELAY_HANDLES_INTERRUPT, LongCaption,
+    MAX_CAPTION_CHARS, Summary, api_errors, budget, caption_format, chats, dedupe, digest,
+    discussion, entities, fanout, file_size, ledger, make_bot, progress, quality, recipient_name,
+    send, throttle,
+};
+use std::path::PathBuf;
+use std::time::Duration;
+use teloxide::RequestError;
+use teloxide::prelude::*;
+use teloxide::types::{
+    InlineKeyboardMarkup, InputFile, InputMedia, InputMediaAnimation, InputMediaDocument,
+    MessageId, ParseMode, Recipient, ReplyParameters, ThreadId,
+};
+use tokio::task;
+
+/// Sends the albums, animations and stickers to the first chat and each
+/// post on to the secondary chats, records the batch for --dedup-window
+/// and puts the results in `summary`. Ctrl-C during --album-delay or a
+/// rehearsal that is not confirmed stops the run.
+pub async fn batch(
+    bot: Bot,
+    args: &Cli,
+    limits: &throttle::Limits,
+    setup: Setup,
+    batch: Batch,
+    inputs: &Inputs,
+    summary: &mut Summary,
+) -> Result<(), Abort> {
+    let Setup {
+        settings,
+        bot_url,
+        chat_id,
+        mut secondary_chats,
+        primary_quality,
+        qualities,
+        caption_langs,
+        concurrency,
+        keyboard,
+        dedupe,
+        ledger: setup_ledger,
+        ..
+    } = setup;
+    let Batch {
+        albums,
+        animations,
+        stickers,
+        found_langs,
+        ..
+    } = batch;
+    let package_threads = &inputs.package_threads;
+    let budget = args.respect_budget.then(|| {
+        if settings.max_albums_per_hour.is_none() && settings.max_messages_per_minute.is_none() {
+            log::warn!("--respect-budget given but no max_albums_per_hour/max_messages_per_minute is configured");
+        }
+        budget::Budget::load(settings.max_albums_per_hour, settings.max_messages_per_minute)
+    });
+    let ledger = if !setup_ledger {
+        ledger::Ledger::disabled()
+    } else {
+        match ledger::Ledger::open(args.resume, args.resume_policy) {
+            Ok(ledger) => ledger,
+            Err(e) => return Err(format!("Cannot open {}: {}", ledger::LEDGER_FILE, e).into()),
+        }
+    };
+    // --dedup-window: the batch's hash and the chats to check it against
+    let mut dedup_chats = vec![chat_id.clone()];
+    dedup_chats.extend(secondary_chats.iter().cloned());
+    let batch_hash = match args.dedup_window {
+        Some(window) if !args.validate => {
+            let files: Vec<PathBuf> = albums
+                .iter()
+                .flat_map(|album| {
+                    let items = album.items.iter().map(|item| item.entry.path.clone());
+                    items.chain(album.subtitles.iter().cloned())
+                })
+                .chain(animations.iter().map(|post| post.entry.path.clone()))
+                .chain(stickers.iter().cloned())
+                .collect();
+            match ledger::batch_hash(&files, args.full_hash_max_bytes) {
+                Ok(hash) => {
+                    let now = chrono::Utc::now().timestamp();
+                    for chat in &dedup_chats {
+                        let last = match ledger::last_batch(chat) {
+                            Ok(last) => last,
+                            Err(e) => {
+                                log::warn!("Could not read {}: {}", ledger::LEDGER_FILE, e);
+                                None
+                            }
+                        };
+                        let Some(last) = last.filter(|last| last.hash == hash) else {
+                            continue;
+                        };
+                        let ago = Duration::from_secs((now - last.sent_at).max(0) as u64);
+                        if ago >= window {
+                            continue;
+                        }
+                        let ago = humantime::format_duration(Duration::from_secs(ago.as_secs()));
+                        if args.force {
+                            log::warn!("Sending again what {} got {} ago (--force)", chat, ago);
+                        } else {
+                            return Err(format!(
+                                "Aborting: {} got these same files {} ago, within --dedup-window (--force sends them anyway)",
+                                chat,
+                                ago
+                            )
+                            .into());
+                        }
+                    }
+                    Some(hash)
+                }
+                Err(e) => {
+                    log::warn!("No --dedup-window check: cannot read the files: {}", e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+    let total_files = albums
+        .iter()
+        .map(|album| album.items.len() + album.subtitles.len())
+        .sum::<usize>()
+        + animations.len()
+        + stickers.len();
+    let total_bytes = albums
+        .iter()
+        .flat_map(|album| {
+            let items = album.items.iter().map(|item| item.bytes);
+            items.chain(album.subtitles.iter().map(|path| file_size(path)))
+        })
+        .chain(animations.iter().map(|post| file_size(&post.upload_path)))
+        .chain(stickers.iter().map(|path| file_size(path)))
+        .sum();
+    let thread = args.thread_id.map(|id| ThreadId(MessageId(id)));
+    let options = send::PostOptions {
+        thread,
+        effect: args.effect_id.clone(),
+        reply: None,
+    };
+    let primary_options = options.for_chat(&chat_id);
+    // Topics of the real chat do not exist in the scratch chat
+    let primary_thread = primary_options
+        .thread
+        .filter(|_| secondary_chats.is_empty() && !args.validate);
+    let reply = args.reply_to.map(|id| {
+        let reply = ReplyParameters::new(MessageId(id));
+        if args.allow_orphan_reply {
+            log::info!(
+                "Replying to message {}, or posting without the reply if it is gone",
+                id
+            );
+            reply.allow_sending_without_reply()
+        } else {
+            log::info!("Replying to message {}; sends fail if it was deleted", id);
+            reply
+        }
+    });
+    let mut ctx = SendContext {
+        bot,
+        target: Recipient::from(chat_id),
+        options: send::PostOptions {
+            thread: primary_thread,
+            reply,
+            ..primary_options
+        },
+        args,
+        keyboard,
+        budget,
+        ledger,
+        progress: progress::Progress::new(total_files, total_bytes, limits.describe()),
+        default_thread: primary_thread,
+        quality: primary_quality
+            .as_ref()
+            .map(|name| settings.qualities[name].clone()),
+        dedupe: dedupe.clone(),
+    };
+
+    // Secondary chats get each album right after the first chat, so they
+    // follow its --album-delay pacing; a rehearsal holds everything back
+    // until it is confirmed
+    let mut fan_out = (!secondary_chats.is_empty()).then(|| {
+        let mut bots = vec![ctx.bot.clone()];
+        bots.extend(
+            settings
+                .bot_tokens
+                .iter()
+                .map(|token| make_bot(Some(token), bot_url.clone())),
+        );
+        fanout::FanOut {
+            bots,
+            keyboard: ctx.keyboard.clone(),
+            options,
+            concurrency: concurrency.fan_out,
+            chunk_delay: args.chunk_delay,
+            follow_migration: !args.no_follow_migration,
+            dedupe,
+            primary_quality,
+            qualities,
+            primary_caption_lang: summary.caption_lang.clone(),
+            caption_langs: caption_langs
+                .into_iter()
+                .map(|(chat, lang)| (chat, lang.filter(|lang| found_langs.contains(lang))))
+                .collect(),
+        }
+    });
+    let drip = fan_out.is_some() && args.test_chat.is_none();
+
+    // Media groups
+    // Posts the secondary chats have not got yet
+    let mut posts = Vec::new();
+    let mut albums = albums.into_iter();
+    let mut first = true;
+    while let Some(album) = albums.next() {
+        if !first {
+            match args.album_delay {
+                Some(range) => {
+                    let delay = range.pick();
+                    let next = chrono::Local::now() + delay;
+                    log::info!(
+                        "Waiting {} before album {} (at {})",
+                        humantime::format_duration(delay),
+                        album.label,
+                        next.format("%H:%M:%S")
+                    );
+                    DELAY_HANDLES_INTERRUPT.store(true, std::sync::atomic::Ordering::SeqCst);
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {}
+                        _ = tokio::signal::ctrl_c() => {
+                            log::warn!("Interrupted; album {} and later posts were not sent", album.label);
+                            let pending = std::iter::once(album)
+                                .chain(albums)
+                                .flat_map(|album| {
+                                    let items = album.items.into_iter().map(|item| item.entry.path);
+                                    items.chain(album.subtitles)
+                                })
+                                .chain(animations.into_iter().map(|post| post.path))
+                                .chain(stickers);
+                            for path in pending {
+                                summary.skipped.push((path, "interrupted".to_string()));
+                            }
+                            DELAY_HANDLES_INTERRUPT.store(false, std::sync::atomic::Ordering::SeqCst);
+                            return Err(Abort::stopped(130));
+                        }
+                    }
+                    DELAY_HANDLES_INTERRUPT.store(false, std::sync::atomic::Ordering::SeqCst);
+                }
+                None => tokio::time::sleep(args.chunk_delay).await,
+            }
+        }
+        first = false;
+        ctx.route(album.thread);
+        let sent = send_album(&mut ctx, album, summary).await;
+        match &mut fan_out {
+            Some(fan_out) if drip => {
+                let reports = &mut summary.destinations;
+                fan_out
+                    .deliver(&mut secondary_chats, sent, reports, &settings.qualities)
+                    .await;
+            }
+            _ => posts.extend(sent),
+        }
+    }
+
+    // Animations
+    let mut queued = animations.len() + stickers.len();
+    for post in animations {
+        queued -= 1;
+        let paths = [post.path.clone()];
+        let bytes = file_size(&post.upload_path);
+        if !ctx.should_send(&paths, summary) {
+            ctx.progress.skip(1, bytes);
+            continue;
+        }
+        if let Some(budget) = &mut ctx.budget {
+            budget.acquire(0, 1, queued).await;
+        }
+        ctx.intent(&paths);
+        ctx.route(package_threads.get(&post.path).copied());
+        let mut media =
+            InputMediaAnimation::new(InputFile::file(&post.upload_path)).caption(post.caption);
+        media.parse_mode = post.parse_mode;
+        media.has_spoiler = args.spoiler;
+        media.thumbnail = post.meta.thumbnail;
+        media.width = post.meta.width;
+        media.height = post.meta.height;
+        media.duration = post.meta.duration;
+        let media = vec![InputMedia::Animation(media)];
+        let keyboard = post.keyboard.as_ref().or(ctx.keyboard.as_ref());
+        let result = match send::send_media(
+            &ctx.bot,
+            ctx.target.clone(),
+            &ctx.options,
+            media.clone(),
+            keyboard,
+            std::slice::from_ref(&post.upload_path),
+        )
+        .await
+        {
+            Err(RequestError::MigrateToChatId(new_id)) if !args.no_follow_migration => {
+                ctx.migrated(new_id, summary);
+                send::send_media(
+                    &ctx.bot,
+                    ctx.target.clone(),
+                    &ctx.options,
+                    media.clone(),
+                    post.keyboard.as_ref().or(ctx.keyboard.as_ref()),
+                    std::slice::from_ref(&post.upload_path),
+                )
+                .await
+            }
+            other => other,
+        };
+        match result {
+            Ok(messages) => {
+                let uploads = std::slice::from_ref(&post.upload_path);
+                let ids = ctx.done(&paths, uploads, &messages, summary).await;
+                if let Some(text) = &args.edit_caption_after {
+                    edit_caption(&ctx, &messages[0], text, post.parse_mode).await;
+                }
+                ctx.discard(&ids).await;
+                log::info!("Sent animation {:?}", post.path);
+                summary.sent += 1;
+                summary.bytes_sent += bytes;
+                let upload = send::Resend::Media(media);
+                let captions = post
+                    .captions
+                    .into_iter()
+                    .map(|(lang, text)| {
+                        let caption = fanout::Caption {
+                            index: 0,
+                            text,
+                            parse_mode: post.parse_mode,
+                        };
+                        (lang, caption)
+                    })
+                    .collect();
+                posts.push(fanout::Post {
+                    paths: vec![post.path],
+                    uploads: vec![post.upload_path],
+                    resend: upload.reuse(&messages),
+                    upload,
+                    variants: Default::default(),
+                    captions,
+                    keyboard: post.keyboard,
+                });
+            }
+            Err(e) => {
+                let error = api_errors::describe(&e);
+                log::error!("Failed to send animation {:?}: {}", post.path, error);
+                summary.errors.insert(post.path.clone(), error);
+                summary.failed.push(post.path);
+            }
+        }
+        ctx.progress.advance(1, bytes);
+    }
+
+    // Stickers
+    for path in stickers {
+        queued -= 1;
+        let paths = [path.clone()];
+        let bytes = file_size(&path);
+        if !ctx.should_send(&paths, summary) {
+            ctx.progress.skip(1, bytes);
+            continue;
+        }
+        if let Some(budget) = &mut ctx.budget {
+            budget.acquire(0, 1, queued).await;
+        }
+        ctx.intent(&paths);
+        ctx.route(package_threads.get(&path).copied());
+        let keyboard = ctx.keyboard.as_ref();
+        let result = match send::send_sticker(
+            &ctx.bot,
+            ctx.target.clone(),
+            &ctx.options,
+            InputFile::file(&path),
+            keyboard,
+            Some(&path),
+        )
+        .await
+        {
+            Err(RequestError::MigrateToChatId(new_id)) if !args.no_follow_migration => {
+                ctx.migrated(new_id, summary);
+                send::send_sticker(
+                    &ctx.bot,
+                    ctx.target.clone(),
+                    &ctx.options,
+                    InputFile::file(&path),
+                    ctx.keyboard.as_ref(),
+                    Some(&path),
+                )
+                .await
+            }
+            other => other,
+        };
+        match result {
+            Ok(message) => {
+                let ids = ctx
+                    .done(&paths, &paths, std::slice::from_ref(&message), summary)
+                    .await;
+                ctx.discard(&ids).await;
+                log::info!("Sent sticker {:?}", path);
+                summary.sent += 1;
+                summary.bytes_sent += bytes;
+                let upload = send::Resend::Sticker(InputFile::file(&path));
+                posts.push(fanout::Post {
+                    paths: vec![path.clone()],
+                    uploads: vec![path],
+                    resend: upload.reuse(std::slice::from_ref(&message)),
+                    upload,
+                    variants: Default::default(),
+                    captions: Default::default(),
+                    keyboard: None,
+                });
+            }
+            Err(e) => {
+                let error = api_errors::describe(&e);
+                log::error!("Telegram rejected sticker {:?}: {}", path, error);
+                summary.errors.insert(path.clone(), error);
+                summary.failed.push(path);
+            }
+        }
+        ctx.progress.advance(1, bytes);
+    }
+
+    // Where the primary chat's messages ended up after a migration
+    summary.chat = Some(recipient_name(&ctx.target));
+
+    if args.test_chat.is_some() && !confirm_rehearsal(args, &secondary_chats).await {
+        log::warn!("Not posting to {}", secondary_chats.join(", "));
+        if args.cleanup_test {
+            ctx.delete(&summary.message_ids).await;
+        }
+        return Err(Abort::stopped(1));
+    }
+
+    // The rest to the secondary chats
+    if let Some(fan_out) = &mut fan_out {
+        let reports = &mut summary.destinations;
+        fan_out
+            .deliver(&mut secondary_chats, posts, reports, &settings.qualities)
+            .await;
+        for dest in &summary.destinations {
+            ctx.expire(&dest.chat, &dest.message_ids);
+        }
+        summary
+            .migrations
+            .extend(summary.destinations.iter().filter_map(|dest| {
+                let from = dest.migrated_from.clone()?;
+                Some((from, dest.chat.clone()))
+            }));
+    }
+
+    if let Some(hash) = &batch_hash
+        && summary.sent > 0
+        && summary.failed.is_empty()
+        && summary
+            .destinations
+            .iter()
+            .all(|dest| dest.failed.is_empty())
+    {
+        let now = chrono::Utc::now().timestamp();
+        for chat in &dedup_chats {
+            if let Err(e) = ctx.ledger.batch_sent(chat, hash, now) {
+                log::warn!("Could not write {}: {}", ledger::LEDGER_FILE, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Whether to post the rehearsal to `chats`: asked on the terminal, or yes
+// once --test-delay has passed.
+async fn confirm_rehearsal(args: &Cli, chats: &[String]) -> bool {
+    let chats = chats.join(", ");
+    if let Some(delay) = args.test_delay {
+        log::warn!(
+            "Rehearsal done, posting to {} in {}",
+            chats,
+            humantime::format_duration(delay)
+        );
+        tokio::time::sleep(delay).await;
+        return true;
+    }
+    task::spawn_blocking(move || {
+        eprint!("Rehearsal done. Post it to {}? [y/N] ", chats);
+        let mut answer = String::new();
+        let _ = std::io::stdin().read_line(&mut answer);
+        matches!(answer.trim(), "y" | "Y" | "yes")
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// Everything a send needs once processing is over
+struct SendContext<'a> {
+    bot: Bot,
+    target: Recipient,
+    // --thread-id (when the primary chat is the only destination) and --effect-id
+    options: send::PostOptions,
+    args: &'a Cli,
+    keyboard: Option<InlineKeyboardMarkup>,
+    budget: Option<budget::Budget>,
+    ledger: ledger::Ledger,
+    progress: progress::Progress,
+    // The topic posts go to unless a package manifest routes them elsewhere
+    default_thread: Option<ThreadId>,
+    // The primary chat's quality, when it has one
+    quality: Option<quality::Quality>,
+    // With --dedupe-retries, the scratch chat posts are checked in
+    dedupe: Option<Recipient>,
+}
+
+impl SendContext<'_> {
+    // Points the next post at `thread`, or back at the default topic.
+    fn route(&mut self, thread: Option<i32>) {
+        self.options.thread = thread
+            .filter(|_| !self.args.validate)
+            .map(|id| ThreadId(MessageId(id)))
+            .or(self.default_thread);
+        if let Some(id) = thread {
+            log::debug!("Posting in thread {}", id);
+        }
+    }
+
+    // Consults the ledger; files it rules out are recorded as skipped.
+    fn should_send(&self, paths: &[PathBuf], summary: &mut Summary) -> bool {
+        if self.ledger.should_send(paths) {
+            return true;
+        }
+        for path in paths {
+            summary.skipped.push((
+                path.clone(),
+                "already sent or in doubt (ledger)".to_string(),
+            ));
+        }
+        false
+    }
+
+    fn intent(&mut self, paths: &[PathBuf]) {
+        if let Err(e) = self.ledger.intent(paths) {
+            log::warn!("Could not write {}: {}", ledger::LEDGER_FILE, e);
+        }
+    }
+
+    // Records a successful send and returns the ids of its messages.
+    // `uploads` are the files read for `paths`, of which the upload hashed
+    // those it read in full.
+    async fn done(
+        &mut self,
+        paths: &[PathBuf],
+        uploads: &[PathBuf],
+        messages: &[Message],
+        summary: &mut Summary,
+    ) -> Vec<i32> {
+        let ids: Vec<i32> = messages.iter().map(|m| m.id.0).collect();
+        if let Err(e) = self.ledger.posted(paths, ids.clone()) {
+            log::warn!("Could not write {}: {}", ledger::LEDGER_FILE, e);
+        }
+        // Resending a chunk the resumed run left in doubt may have posted
+        // it twice
+        if let Some(scratch) = &self.dedupe
+            && self.ledger.in_doubt(paths)
+        {
+            let earlier = self.ledger.posted_ids(paths);
+            dedupe::after_retry(&self.bot, &self.target, scratch, earlier, messages).await;
+        }
+        summary.message_ids.extend(&ids);
+        summary
+            .sent_items
+            .extend(paths.iter().zip(messages).map(|(path, message)| SentItem {
+                path: path.clone(),
+                message_id: message.id.0,
+                file_id: send::file_id(message),
+            }));
+        summary.post_ids.extend(ids.first());
+        let hashes: std::collections::BTreeMap<PathBuf, String> = paths
+            .iter()
+            .zip(uploads)
+            .filter_map(|(path, upload)| Some((path.clone(), digest::streamed(upload)?)))
+            .collect();
+        summary.hashes.extend(hashes.clone());
+        let chat = recipient_name(&self.target);
+        if let Err(e) = self.ledger.done(paths, &chat, ids.clone(), hashes) {
+            log::warn!("Could not write {}: {}", ledger::LEDGER_FILE, e);
+        }
+        self.expire(&chat, &ids);
+        ids
+    }
+
+    // Moves over to the supergroup the chat was upgraded to, for this send
+    // and the rest of the run.
+    fn migrated(&mut self, new_id: ChatId, summary: &mut Summary) {
+        let old = recipient_name(&self.target);
+        log::warn!(
+            "Chat {} was upgraded to a supergroup, resending to its new id {}",
+            old,
+            new_id
+        );
+        summary.migrations.push((old, new_id.to_string()));
+        self.target = Recipient::from(new_id);
+    }
+
+    // With --delete-after, schedules the messages for the reaper.
+    fn expire(&mut self, chat: &str, ids: &[i32]) {
+        let Some(ttl) = self.args.delete_after else {
+            return;
+        };
+        if ids.is_empty() {
+            return;
+        }
+        let delete_at = chrono::Utc::now().timestamp() + ttl.as_secs() as i64;
+        if let Err(e) = self.ledger.expire(chat, ids.to_vec(), delete_at) {
+            log::warn!("Could not write {}: {}", ledger::LEDGER_FILE, e);
+        }
+    }
+
+    // With --validate, deletes the messages that were just accepted.
+    async fn discard(&self, ids: &[i32]) {
+        if self.args.validate {
+            self.delete(ids).await;
+        }
+    }
+
+    async fn delete(&self, ids: &[i32]) {
+        // deleteMessages takes at most 100 ids
+        for batch in ids.chunks(100) {
+            let message_ids = batch.iter().map(|id| MessageId(*id));
+            let request = self.bot.delete_messages(self.target.clone(), message_ids);
+            match send::logged(&self.bot, request).await {
+                Ok(_) => log::info!("Deleted {} message(s) again", batch.len()),
+                Err(e) => log::error!(
+                    "Could not delete messages {:?} from {}: {}",
+                    batch,
+                    recipient_name(&self.target),
+                    api_errors::describe(&e)
+                ),
+            }
+        }
+    }
+}
+
+// Replaces the caption of a sent message with --edit-caption-after; a
+// failure is logged and leaves the original caption.
+async fn edit_caption(
+    ctx: &SendContext<'_>,
+    message: &Message,
+    text: &str,
+    parse_mode: Option<ParseMode>,
+) {
+    let id = message.id;
+    match send::edit_caption(&ctx.bot, ctx.target.clone(), id, text, parse_mode).await {
+        Ok(_) => log::info!("Edited the caption of message {}", id.0),
+        Err(RequestError::Api(teloxide::ApiError::MessageNotModified)) => {
+            log::info!("Message {} already has that caption", id.0)
+        }
+        Err(e) => log::warn!(
+            "Could not edit the caption of message {}: {}",
+            id.0,
+            api_errors::describe(&e)
+        ),
+    }
+}
+
+// Posts an album's caption as a reply to the message of its captioned item; a failure is
+// logged and leaves the album as sent.
+async fn send_caption_reply(
+    ctx: &SendContext<'_>,
+    first: &Message,
+    caption: &str,
+    parse_mode: Option<ParseMode>,
+    keyboard: Option<&InlineKeyboardMarkup>,
+) -> Vec<i32> {
+    let options = send::PostOptions {
+        reply: Some(ReplyParameters::new(first.id)),
+        ..ctx.options.clone()
+    };
+    let sent = send::send_text(
+        &ctx.bot,
+        ctx.target.clone(),
+        &options,
+        caption,
+        parse_mode,
+        keyboard,
+    )
+    .await;
+    match sent {
+        Ok(messages) => {
+            log::info!("Posted the caption as a reply to message {}", first.id.0);
+            messages.iter().map(|m| m.id.0).collect()
+        }
+        Err(e) => {
+            log::error!("Failed to post the caption: {}", api_errors::describe(&e));
+            Vec::new()
+        }
+    }
+}
+
+// Makes an over-long caption fit for --long-caption, returning the rest when
+// it is to be posted as a comment.
+fn cut_caption(caption: &mut String, mode: LongCaption, format: CaptionFormat) -> Option<String> {
+    if entities::text_len(caption, format) <= MAX_CAPTION_CHARS {
+        return None;
+    }
+    match mode {
+        LongCaption::Truncate => {
+            let (head, _) = entities::cut(caption, format, MAX_CAPTION_CHARS - 1);
+            *caption = format!("{}…", head);
+            None
+        }
+        LongCaption::Comment => {
+            let (head, rest) = entities::cut(caption, format, MAX_CAPTION_CHARS);
+            let rest = rest.to_string();
+            *caption = head.to_string();
+            Some(rest)
+        }
+    }
+}
+
+// Posts the rest of a --long-caption comment caption under the channel post
+// `first`, in its discussion group, or else as a reply to it. Returns the
+// message ids and whether they are in the discussion group; a failure is
+// logged and leaves the album as sent.
+async fn send_caption_comment(
+    ctx: &SendContext<'_>,
+    first: &Message,
+    text: &str,
+    parse_mode: Option<ParseMode>,
+) -> (Vec<i32>, bool) {
+    let chat = recipient_name(&ctx.target);
+    let group = match chats::lookup(&ctx.bot, &chat).await {
+        Ok(chats::ChatKind::Channel {
+            discussion: Some(group),
+        }) => Some(group),
+        Ok(kind) => {
+            log::warn!(
+                "{} is a {} without a discussion group, so the rest of the caption goes in a reply",
+                chat,
+                kind.describe()
+            );
+            None
+        }
+        Err(e) => {
+            log::warn!(
+                "Could not look up the discussion group of {}: {}",
+                chat,
+                api_errors::describe(&e)
+            );
+            None
+        }
+    };
+    let copy = match group {
+        Some(group) => {
+            match discussion::find_copy(&ctx.bot, first.chat.id, group, first.id).await {
+                Ok(Some(copy)) => Some((group, copy)),
+                Ok(None) => {
+                    log::warn!(
+                        "Message {} was not copied into discussion group {} in time, so the rest of the caption goes in a reply",
+                        first.id.0,
+                        group
+                    );
+                    None
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Could not find message {} in discussion group {}: {}",
+                        first.id.0,
+                        group,
+                        api_errors::describe(&e)
+                    );
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    let Some((group, copy)) = copy else {
+        return (
+            send_caption_reply(ctx, first, text, parse_mode, None).await,
+            false,
+        );
+    };
+    let options = send::PostOptions {
+        reply: Some(ReplyParameters::new(copy)),
+        ..Default::default()
+    };
+    let sent = send::send_text(
+        &ctx.bot,
+        Recipient::Id(group),
+        &options,
+        text,
+        parse_mode,
+        None,
+    )
+    .await;
+    let messages = match sent {
+        Ok(messages) => messages,
+        Err(e) => {
+            log::error!(
+                "Failed to post the rest of the caption: {}",
+                api_errors::describe(&e)
+            );
+            return (Vec::new(), true);
+        }
+    };
+    log::info!(
+        "Posted the rest of the caption as a comment on message {}",
+        first.id.0
+    );
+    if ctx.args.pin_comment
+        && let Some(comment) = messages.first()
+    {
+        let request = ctx
+            .bot
+            .pin_chat_message(group, comment.id)
+            .disable_notification(true);
+        let pinned = send::logged(&ctx.bot, request).await;
+        if let Err(e) = pinned {
+            log::warn!(
+                "Could not pin the comment in {}: {}",
+                group,
+                api_errors::describe(&e)
+            );
+        }
+    }
+    (messages.iter().map(|m| m.id.0).collect(), true)
+}
+
+// One message of an album: its files, the copies uploaded for them, the
+// media, the bytes uploaded and the keyboard of a single message
+type Chunk = (
+    Vec<PathBuf>,
+    Vec<PathBuf>,
+    Vec<InputMedia>,
+    u64,
+    Option<InlineKeyboardMarkup>,
+);
+
+// Sends an album as consecutive media groups of --album-size items. The first
+// item (or the --caption-on-index one) carries the album caption, else the
+// first item's own caption; with --album-caption the others carry theirs.
+async fn send_album(
+    ctx: &mut SendContext<'_>,
+    album: Album,
+    summary: &mut Summary,
+) -> Vec<fanout::Post> {
+    let args = ctx.args;
+    let label = album.label;
+    let mut posts = Vec::new();
+    let album_caption = album.caption;
+    let mut report = AlbumReport {
+        label: label.clone(),
+        sent: 0,
+        failed: 0,
+        caption_message_ids: Vec::new(),
+        comment_message_ids: Vec::new(),
+    };
+    let caption_index = match args.caption_on_index {
+        Some(index) if index >= album.items.len() => {
+            log::warn!(
+                "Album {} has {} item(s), so --caption-on-index {} is out of range; captioning the first",
+                label,
+                album.items.len(),
+                index
+            );
+            0
+        }
+        Some(index) => index,
+        None => 0,
+    };
+    let lang_captions = match &album_caption {
+        Some(_) => album.captions,
+        None => album
+            .items
+            .first()
+            .map(|item| item.captions.clone())
+            .unwrap_or_default(),
+    };
+    // The album's caption goes out in the album's format, an item's in its own
+    let caption_parse_mode = match album_caption {
+        Some(_) => album.parse_mode,
+        None => album.items.first().and_then(|item| item.parse_mode),
+    };
+    let caption = album_caption.or_else(|| album.items.first().map(|item| item.caption.clone()));
+    // Where the captioned item is (chunk, position), and with
+    // --caption-placement reply the caption taken off it
+    let mut caption_at = None;
+    let mut reply_caption = None;
+
+    let lengths = albums::chunk_lengths(&album.items, args.album_size, args.max_chunk_bytes);
+    // Only single messages carry buttons. Those of files in a media group
+    // go on the caption reply with --buttons-on-reply.
+    let mut start = 0;
+    let mut grouped_keyboard = None;
+    for &length in &lengths {
+        if length > 1 && grouped_keyboard.is_none() {
+            grouped_keyboard = album.items[start..start + length]
+                .iter()
+                .find_map(|item| item.keyboard.clone());
+        }
+        start += length;
+    }
+    let reply_keyboard = if args.buttons_on_reply && lengths.iter().any(|length| *length > 1) {
+        grouped_keyboard.or_else(|| ctx.keyboard.clone())
+    } else {
+        if grouped_keyboard.is_some() {
+            log::warn!(
+                "Album {} goes out as a media group, which cannot carry buttons; leaving out those of its files (--buttons-on-reply puts them on a caption reply)",
+                label
+            );
+        }
+        None
+    };
+    let placement = match reply_keyboard {
+        Some(_) => CaptionPlacement::Reply,
+        None => args.caption_placement,
+    };
+    let mut caption = caption;
+    let mut lang_captions = lang_captions;
+    let mut comment = None;
+    if placement == CaptionPlacement::Media
+        && let Some(mode) = args.long_caption
+    {
+        // Measured and cut as the caption is sent
+        let format = caption_format(caption_parse_mode);
+        if let Some(caption) = &mut caption {
+            comment = cut_caption(caption, mode, format);
+        }
+        for caption in lang_captions.values_mut() {
+            cut_caption(caption, LongCaption::Truncate, format);
+        }
+    }
+    let mut items = album.items.into_iter();
+    let mut chunks: Vec<Chunk> = Vec::new();
+    let mut first_index = 0;
+    for (chunk_index, length) in lengths.into_iter().enumerate() {
+        let chunk: Vec<AlbumItem> = items.by_ref().take(length).collect();
+        let paths: Vec<PathBuf> = chunk.iter().map(|item| item.entry.path.clone()).collect();
+        let uploads: Vec<PathBuf> = chunk.iter().map(|item| item.upload.clone()).collect();
+        let bytes = chunk.iter().map(|item| item.bytes).sum();
+        let keyboard = match chunk.as_slice() {
+            [item] => item.keyboard.clone().or_else(|| ctx.keyboard.clone()),
+            _ => None,
+        };
+        let media: Vec<InputMedia> = chunk
+            .into_iter()
+            .enumerate()
+            .map(|(i, item)| {
+                if first_index + i == caption_index {
+                    caption_at = Some((chunk_index, i));
+                    let caption = caption.clone().unwrap_or_default();
+                    if placement == CaptionPlacement::Reply {
+                        reply_caption = Some((caption, caption_parse_mode));
+                        albums::with_caption(item.media, String::new(), None)
+                    } else {
+                        albums::with_caption(item.media, caption, caption_parse_mode)
+                    }
+                } else if args.album_caption.is_some() && !item.caption.is_empty() {
+                    albums::with_caption(item.media, item.caption, item.parse_mode)
+                } else {
+                    item.media
+                }
+            })
+            .collect();
+        chunks.push((paths, uploads, media, bytes, keyboard));
+        first_index += length;
+    }
+    // Subtitles follow the album as document groups
+    for subtitles in album.subtitles.chunks(albums::MAX_ITEMS) {
+        let media = subtitles
+            .iter()
+            .map(|path| InputMedia::Document(InputMediaDocument::new(InputFile::file(path))))
+            .collect();
+        let bytes = subtitles.iter().map(|path| file_size(path)).sum();
+        let keyboard = ctx.keyboard.clone();
+        chunks.push((
+            subtitles.to_vec(),
+            subtitles.to_vec(),
+            media,
+            bytes,
+            keyboard,
+        ));
+    }
+
+    let mut queued: usize = chunks.iter().map(|(paths, ..)| paths.len()).sum();
+    for (chunk_index, (paths, uploads, media, bytes, keyboard)) in chunks.into_iter().enumerate() {
+        if chunk_index > 0 {
+            tokio::time::sleep(args.chunk_delay).await;
+        }
+        queued -= paths.len();
+        if !ctx.should_send(&paths, summary) {
+            ctx.progress.skip(paths.len(), bytes);
+            continue;
+        }
+        if let Some(budget) = &mut ctx.budget {
+            budget.acquire(1, media.len() as u32, queued).await;
+        }
+
+        log::info!("Sending {} media items from {}...", media.len(), label);
+        let single = media.len() == 1;
+        ctx.intent(&paths);
+        // In the primary chat's quality; `media` stays the originals
+        let (sent, sent_uploads) = match &ctx.quality {
+            Some(quality) => match quality::variant(&media, &uploads, quality).await {
+                Ok(variant) => variant,
+                Err(e) => {
+                    log::warn!("Sending {:?} as it is: {}", paths, e);
+                    (media.clone(), uploads.clone())
+                }
+            },
+            None => (media.clone(), uploads.clone()),
+        };
+        let result = match send::send_media(
+            &ctx.bot,
+            ctx.target.clone(),
+            &ctx.options,
+            sent.clone(),
+            keyboard.as_ref(),
+            &sent_uploads,
+        )
+        .await
+        {
+            Err(RequestError::MigrateToChatId(new_id)) if !args.no_follow_migration => {
+                ctx.migrated(new_id, summary);
+                send::send_media(
+                    &ctx.bot,
+                    ctx.target.clone(),
+                    &ctx.options,
+                    sent.clone(),
+                    keyboard.as_ref(),
+                    &sent_uploads,
+                )
+                .await
+            }
+            other => other,
+        };
+        match result {
+            Ok(messages) => {
+                let mut ids = ctx.done(&paths, &sent_uploads, &messages, summary).await;
+                let upload = send::Resend::Media(sent);
+                let resend = upload.reuse(&messages);
+                if resend.is_none() {
+                    log::debug!("No file_ids returned for {:?}", paths);
+                }
+                // The originals, for chats without a quality
+                let mut variants = std::collections::HashMap::new();
+                if ctx.quality.is_some() {
+                    let upload = send::Resend::Media(media);
+                    variants.insert(None, fanout::Variant { upload, uploads });
+                }
+                // The other caption languages, for the chunk with the caption
+                let captions = caption_at
+                    .filter(|(chunk, _)| *chunk == chunk_index)
+                    .filter(|_| placement == CaptionPlacement::Media)
+                    .map(|(_, index)| {
+                        lang_captions
+                            .iter()
+                            .map(|(lang, text)| {
+                                let caption = fanout::Caption {
+                                    index,
+                                    text: text.clone(),
+                                    parse_mode: caption_parse_mode,
+                                };
+                                (lang.clone(), caption)
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                posts.push(fanout::Post {
+                    paths: paths.clone(),
+                    uploads: sent_uploads,
+                    upload,
+                    resend,
+                    variants,
+                    captions,
+                    keyboard,
+                });
+                if single {
+                    log::info!("Successfully sent {:?}", paths[0]);
+                } else {
+                    log::info!("Successfully sent media group!");
+                }
+                summary.sent += paths.len();
+                summary.bytes_sent += bytes;
+                report.sent += paths.len();
+                let captioned = caption_at
+                    .filter(|(chunk, _)| *chunk == chunk_index)
+                    .map(|(_, position)| messages.get(position).unwrap_or(&messages[0]));
+                if let Some(message) = captioned
+                    && let Some((caption, parse_mode)) = reply_caption.take()
+                {
+                    if !caption.trim().is_empty() {
+                        let keyboard = reply_keyboard.as_ref();
+                        let caption_ids =
+                            send_caption_reply(ctx, message, &caption, parse_mode, keyboard).await;
+                        summary.message_ids.extend(&caption_ids);
+                        ctx.expire(&recipient_name(&ctx.target), &caption_ids);
+                        ids.extend(&caption_ids);
+                        report.caption_message_ids = caption_ids;
+                    } else if reply_keyboard.is_some() {
+                        log::warn!(
+                            "Album {} has no caption to put its buttons under; leaving them out",
+                            label
+                        );
+                    }
+                }
+                if let Some(message) = captioned
+                    && let Some(rest) = comment.take()
+                {
+                    let (comment_ids, in_discussion) =
+                        send_caption_comment(ctx, message, &rest, caption_parse_mode).await;
+                    if in_discussion {
+                        report.comment_message_ids = comment_ids;
+                    } else {
+                        summary.message_ids.extend(&comment_ids);
+                        ctx.expire(&recipient_name(&ctx.target), &comment_ids);
+                        ids.extend(&comment_ids);
+                        report.caption_message_ids = comment_ids;
+                    }
+                }
+                if let Some(message) = captioned
+                    && let Some(text) = &args.edit_caption_after
+                {
+                    edit_caption(ctx, message, text, caption_parse_mode).await;
+                }
+                ctx.discard(&ids).await;
+            }
+            Err(e) => {
+                let error = api_errors::describe(&e);
+                log::error!("Failed to send media group: {}", error);
+                report.failed += paths.len();
+                for path in &paths {
+                    summary.errors.insert(path.clone(), error.clone());
+                }
+                summary.failed.extend(paths.iter().cloned());
+            }
+        }
+        ctx.progress.advance(paths.len(), bytes);
+    }
+    summary.albums.push(report);
+    posts
+}
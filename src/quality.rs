@@ -0,0 +1,250 @@
+// ---------------------------
+// Per-destination quality
+// ---------------------------
+
+use crate::{cache, subprocess};
+use image::GenericImageView;
+use image::codecs::jpeg::JpegEncoder;
+use serde::Deserialize;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use teloxide::types::{InputFile, InputMedia};
+use tokio::task;
+
+/// x264 quality of the scaled-down videos when the quality sets no crf
+const DEFAULT_CRF: u8 = 23;
+
+/// A `[qualities.<name>]` table: how photos and videos are scaled down for
+/// the chats using it. A table with nothing set sends the originals.
+#[derive(Clone, Debug, Default, Deserialize, Hash, PartialEq, Eq)]
+pub struct Quality {
+    // Photos and videos taller than this are scaled down to it
+    #[serde(default)]
+    pub max_height: Option<u32>,
+    // x264 constant rate factor of re-encoded videos (lower is better)
+    #[serde(default)]
+    pub crf: Option<u8>,
+}
+
+impl Quality {
+    /// "max 720p, crf 26", or "originals".
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(height) = self.max_height {
+            parts.push(format!("max {}p", height));
+        }
+        if let Some(crf) = self.crf {
+            parts.push(format!("crf {}", crf));
+        }
+        if parts.is_empty() {
+            "originals".to_string()
+        } else {
+            parts.join(", ")
+        }
+    }
+
+    // Artifact cache kind of copies made in this quality
+    fn kind(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        format!("quality-{:08x}", hasher.finish() as u32)
+    }
+
+    // Whether a `height` pixels tall item is sent as it is
+    fn keeps(&self, height: Option<u32>, video: bool) -> bool {
+        let fits = match (self.max_height, height) {
+            (None, _) => true,
+            (Some(max), Some(height)) => height <= max,
+            (Some(_), None) => false,
+        };
+        fits && !(video && self.crf.is_some())
+    }
+
+    // Size of a `width`×`height` video scaled down the way ffmpeg's
+    // scale=-2:H does it, keeping the width even
+    fn scaled(&self, width: u16, height: u16) -> (u16, u16) {
+        match self.max_height {
+            Some(max) if u32::from(height) > max && height > 0 => {
+                let width = (f64::from(width) * f64::from(max) / f64::from(height) / 2.0).round()
+                    as u32
+                    * 2;
+                (width.min(u32::from(u16::MAX)) as u16, max as u16)
+            }
+            _ => (width, height),
+        }
+    }
+}
+
+/// `[photo_preset]`: the size and JPEG quality --photo-preset custom
+/// shrinks photos to
+#[derive(Clone, Copy, Debug, Deserialize, Hash)]
+pub struct PhotoPreset {
+    // Longest side in pixels
+    #[serde(default = "default_max_edge")]
+    pub max_edge: u32,
+    #[serde(default = "default_jpeg_quality")]
+    pub jpeg_quality: u8,
+}
+
+impl PhotoPreset {
+    /// --photo-preset web
+    pub const WEB: PhotoPreset = PhotoPreset {
+        max_edge: 2560,
+        jpeg_quality: 88,
+    };
+}
+
+fn default_max_edge() -> u32 {
+    PhotoPreset::WEB.max_edge
+}
+
+fn default_jpeg_quality() -> u8 {
+    PhotoPreset::WEB.jpeg_quality
+}
+
+/// A JPEG copy of `photo` no longer than the preset's max_edge, or None
+/// when that copy would not be smaller than the photo.
+pub async fn apply_preset(photo: PathBuf, preset: PhotoPreset) -> Result<Option<PathBuf>, String> {
+    task::spawn_blocking(move || {
+        let temp_file = crate::temp_path(&format!("temp_preset_{}.jpg", uuid::Uuid::new_v4()));
+        let mut hasher = DefaultHasher::new();
+        preset.hash(&mut hasher);
+        let kind = format!("preset-{:08x}", hasher.finish() as u32);
+        let made = cache::reuse_or_make(&photo, &kind, &temp_file, || {
+            let img = crate::albums::open_upright(&photo)?;
+            let img = img.resize(
+                preset.max_edge,
+                preset.max_edge,
+                image::imageops::FilterType::Lanczos3,
+            );
+            let file = std::fs::File::create(&temp_file).map_err(|e| e.to_string())?;
+            img.into_rgb8()
+                .write_with_encoder(JpegEncoder::new_with_quality(file, preset.jpeg_quality))
+                .map_err(|e| e.to_string())
+        });
+        match made {
+            Ok(_) if crate::file_size(&temp_file) < crate::file_size(&photo) => Ok(Some(temp_file)),
+            Ok(_) => {
+                let _ = std::fs::remove_file(&temp_file);
+                Ok(None)
+            }
+            Err(e) => {
+                let _ = std::fs::remove_file(&temp_file);
+                Err(e)
+            }
+        }
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// `media` (uploading `uploads`) as the chats of `quality` get it: photos
+/// and videos over its limits replaced by scaled-down copies, everything
+/// else left as it is. Copies are cached by file and quality, so chats
+/// sharing a quality share one encode.
+pub async fn variant(
+    media: &[InputMedia],
+    uploads: &[PathBuf],
+    quality: &Quality,
+) -> Result<(Vec<InputMedia>, Vec<PathBuf>), String> {
+    let mut variant = Vec::new();
+    let mut variant_uploads = Vec::new();
+    for (item, upload) in media.iter().zip(uploads) {
+        let (item, upload) = match item.clone() {
+            InputMedia::Photo(mut m) => {
+                let height = crate::albums::photo_dimensions(upload).map(|(_, h)| h);
+                if quality.keeps(height, false) {
+                    (InputMedia::Photo(m), upload.clone())
+                } else {
+                    let copy = make(upload.clone(), quality.clone(), false).await?;
+                    m.media = InputFile::file(&copy);
+                    (InputMedia::Photo(m), copy)
+                }
+            }
+            InputMedia::Video(mut m) => {
+                if quality.keeps(m.height.map(u32::from), true) {
+                    (InputMedia::Video(m), upload.clone())
+                } else {
+                    let copy = make(upload.clone(), quality.clone(), true).await?;
+                    m.media = InputFile::file(&copy);
+                    if let (Some(width), Some(height)) = (m.width, m.height) {
+                        (m.width, m.height) = {
+                            let (width, height) = quality.scaled(width, height);
+                            (Some(width), Some(height))
+                        };
+                    }
+                    (InputMedia::Video(m), copy)
+                }
+            }
+            other => (other, upload.clone()),
+        };
+        variant.push(item);
+        variant_uploads.push(upload);
+    }
+    Ok((variant, variant_uploads))
+}
+
+async fn make(upload: PathBuf, quality: Quality, video: bool) -> Result<PathBuf, String> {
+    task::spawn_blocking(move || {
+        let ext = if video { "mp4" } else { "jpg" };
+        let temp_file = crate::temp_path(&format!("temp_quality_{}.{}", uuid::Uuid::new_v4(), ext));
+        let made = cache::reuse_or_make(&upload, &quality.kind(), &temp_file, || {
+            if video {
+                encode_video(&upload, &temp_file, &quality)
+            } else {
+                shrink_photo(&upload, &temp_file, &quality)
+            }
+        });
+        match made {
+            Ok(_) => Ok(temp_file),
+            Err(e) => {
+                let _ = std::fs::remove_file(&temp_file);
+                Err(format!(
+                    "could not make the {} copy of {:?}: {}",
+                    ext, upload, e
+                ))
+            }
+        }
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+// Re-encodes as a faststart H.264 MP4 no taller than max_height; audio is
+// copied.
+fn encode_video(video: &Path, dest: &Path, quality: &Quality) -> Result<(), String> {
+    let scale = match quality.max_height {
+        Some(max) => format!("scale=-2:'min(ih,{})'", max),
+        None => "null".to_string(),
+    };
+    let crf = quality.crf.unwrap_or(DEFAULT_CRF).to_string();
+    let output = subprocess::output(
+        std::process::Command::new("ffmpeg")
+            .args(["-hide_banner", "-v", crate::ffmpeg_loglevel(), "-y", "-i"])
+            .arg(video)
+            .args(["-map", "0:v:0", "-map", "0:a?", "-vf", &scale])
+            .args(["-c:v", "libx264", "-crf", &crf, "-pix_fmt", "yuv420p"])
+            .args(["-c:a", "copy", "-movflags", "+faststart"])
+            .arg(dest),
+        subprocess::Work::Transcode,
+        video,
+        Some(dest),
+    )
+    .map_err(|e| format!("could not run ffmpeg: {}", e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(crate::stderr_snippet(&output.stderr))
+    }
+}
+
+fn shrink_photo(photo: &Path, dest: &Path, quality: &Quality) -> Result<(), String> {
+    let img = crate::albums::open_upright(photo)?;
+    let (width, height) = img.dimensions();
+    let max = quality.max_height.unwrap_or(height).min(height);
+    let img = img.resize(width, max, image::imageops::FilterType::Lanczos3);
+    let file = std::fs::File::create(dest).map_err(|e| e.to_string())?;
+    img.into_rgb8()
+        .write_with_encoder(JpegEncoder::new_with_quality(file, 90))
+        .map_err(|e| e.to_string())
+}
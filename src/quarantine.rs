@@ -0,0 +1,154 @@
+// ---------------------------
+// Quarantine of failing files
+// ---------------------------
+
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Record of what is in a quarantine directory and where it came from
+pub const MANIFEST: &str = "quarantine.jsonl";
+
+// Sidecars that move with their file: captions, metadata, thumbnail and
+// subtitles
+const SIDECAR_EXTENSIONS: [&str; 7] = ["txt", "md", "html", "json", "thumb.jpg", "srt", "vtt"];
+
+/// One quarantined file, a JSON line of the manifest
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// Where it is now
+    pub file: PathBuf,
+    /// Where it was watched, and goes back to on retry
+    pub from: PathBuf,
+    /// Extensions of the sidecars moved with it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sidecars: Vec<String>,
+    /// The error of its last failure
+    pub error: String,
+    pub failures: u32,
+    /// Unix time it was quarantined
+    pub at: i64,
+}
+
+/// Moves `path` and its sidecars into `dir` and records why; returns its
+/// new path.
+pub fn put(dir: &Path, path: &Path, error: &str, failures: u32) -> Result<PathBuf, String> {
+    std::fs::create_dir_all(dir).map_err(|e| format!("cannot create {:?}: {}", dir, e))?;
+    let from = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let file = free_name(dir, path);
+    move_file(path, &file)?;
+    let mut sidecars = Vec::new();
+    for ext in SIDECAR_EXTENSIONS {
+        let sidecar = path.with_extension(ext);
+        // album.txt belongs to the directory, not to album.jpg
+        if !sidecar.is_file() || crate::albums::is_album_caption_file(&sidecar) {
+            continue;
+        }
+        match move_file(&sidecar, &file.with_extension(ext)) {
+            Ok(()) => sidecars.push(ext.to_string()),
+            Err(e) => log::warn!("Leaving {:?} behind: {}", sidecar, e),
+        }
+    }
+    let entry = Entry {
+        file: file.clone(),
+        from,
+        sidecars,
+        error: error.to_string(),
+        failures,
+        at: chrono::Utc::now().timestamp(),
+    };
+    let line = serde_json::to_string(&entry).map_err(|e| e.to_string())?;
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(MANIFEST))
+        .and_then(|mut manifest| writeln!(manifest, "{}", line))
+        .map_err(|e| format!("cannot write {:?}: {}", dir.join(MANIFEST), e))?;
+    Ok(file)
+}
+
+/// The files quarantined in `dir`, oldest first.
+pub fn list(dir: &Path) -> Result<Vec<Entry>, String> {
+    let path = dir.join(MANIFEST);
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("cannot read {:?}: {}", path, e)),
+    };
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(|e| format!("{:?}: {}", path, e)))
+        .collect()
+}
+
+/// Moves the quarantined `files` (all of them when empty), given by their
+/// path or name in `dir`, back where they came from; returns where they went.
+pub fn retry(dir: &Path, files: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let wanted = |entry: &Entry| {
+        files.is_empty()
+            || files
+                .iter()
+                .any(|file| entry.file == *file || entry.file.file_name() == Some(file.as_os_str()))
+    };
+    let mut kept = Vec::new();
+    let mut restored = Vec::new();
+    for entry in list(dir)? {
+        if !wanted(&entry) {
+            kept.push(entry);
+            continue;
+        }
+        if entry.from.exists() {
+            log::error!(
+                "Not moving {:?} back: {:?} exists again",
+                entry.file,
+                entry.from
+            );
+            kept.push(entry);
+            continue;
+        }
+        if let Err(e) = move_file(&entry.file, &entry.from) {
+            log::error!("Cannot move {:?} back: {}", entry.file, e);
+            kept.push(entry);
+            continue;
+        }
+        for ext in &entry.sidecars {
+            let sidecar = entry.file.with_extension(ext);
+            if let Err(e) = move_file(&sidecar, &entry.from.with_extension(ext)) {
+                log::warn!("Cannot move {:?} back: {}", sidecar, e);
+            }
+        }
+        restored.push(entry.from);
+    }
+    let mut text = String::new();
+    for entry in &kept {
+        text += &serde_json::to_string(entry).map_err(|e| e.to_string())?;
+        text.push('\n');
+    }
+    let path = dir.join(MANIFEST);
+    std::fs::write(&path, text).map_err(|e| format!("cannot write {:?}: {}", path, e))?;
+    Ok(restored)
+}
+
+// `dir`'s entry for the file name of `path`, numbered when taken
+fn free_name(dir: &Path, path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default();
+    let mut file = dir.join(name);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = crate::file_ext(path);
+    let mut n = 1;
+    while file.exists() {
+        file = dir.join(format!("{}-{}.{}", stem, n, ext));
+        n += 1;
+    }
+    file
+}
+
+// A rename, or a copy and delete when `to` is on another filesystem
+fn move_file(from: &Path, to: &Path) -> Result<(), String> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(from, to)
+        .and_then(|_| std::fs::remove_file(from))
+        .map_err(|e| format!("cannot move {:?} to {:?}: {}", from, to, e))
+}
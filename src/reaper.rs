@@ -0,0 +1,86 @@
+// ---------------------------
+// Deleting expired posts
+// ---------------------------
+
+use crate::ledger::{self, Expiry};
+use crate::send;
+use teloxide::RequestError;
+use teloxide::prelude::*;
+use teloxide::types::{MessageId, Recipient};
+
+// deleteMessages takes at most this many ids
+const MAX_IDS: usize = 100;
+
+/// `telegoy reaper`: deletes the messages whose --delete-after time has
+/// passed and drops them from the ledger. With `dry_run` it only lists them.
+pub async fn run(bot: &Bot, dry_run: bool) -> Result<(), String> {
+    let now = chrono::Utc::now().timestamp();
+    let due = ledger::due(now)?;
+    if due.is_empty() {
+        log::debug!("No posts to delete yet");
+        return Ok(());
+    }
+    if dry_run {
+        for expiry in &due {
+            println!(
+                "{}: would delete {:?} (due {})",
+                expiry.chat,
+                expiry.message_ids,
+                chrono::DateTime::from_timestamp(expiry.delete_at, 0).unwrap_or_default()
+            );
+        }
+        return Ok(());
+    }
+    let mut done = Vec::new();
+    for expiry in due {
+        if delete(bot, &expiry).await {
+            done.push(expiry);
+        }
+    }
+    ledger::forget(&done)
+}
+
+// Whether the messages are gone or can never be deleted; false leaves them
+// for the next run.
+async fn delete(bot: &Bot, expiry: &Expiry) -> bool {
+    for batch in expiry.message_ids.chunks(MAX_IDS) {
+        let ids = batch.iter().map(|id| MessageId(*id));
+        loop {
+            // Messages deleted by hand are skipped by Telegram, not an error
+            let request = bot.delete_messages(Recipient::from(expiry.chat.clone()), ids.clone());
+            match send::logged(bot, request).await {
+                Ok(_) => {
+                    log::info!(
+                        "[{}] Deleted {} expired message(s)",
+                        expiry.chat,
+                        batch.len()
+                    );
+                    break;
+                }
+                Err(RequestError::RetryAfter(secs)) => {
+                    log::warn!("Rate limited, waiting {}s", secs.seconds());
+                    tokio::time::sleep(secs.duration()).await;
+                }
+                Err(e @ (RequestError::Network(_) | RequestError::Io(_))) => {
+                    log::warn!(
+                        "[{}] Could not delete {:?}, will retry: {}",
+                        expiry.chat,
+                        batch,
+                        crate::api_errors::describe(&e)
+                    );
+                    return false;
+                }
+                Err(e) => {
+                    log::error!(
+                        "[{}] Giving up on deleting {:?}: {}",
+                        expiry.chat,
+                        batch,
+                        crate::api_errors::describe(&e)
+                    );
+                    break;
+                }
+            }
+        }
+    }
+    true
+}
@@ -0,0 +1,181 @@
+// ---------------------------
+// After the batch
+// ---------------------------
+
+use crate::collect::Inputs;
+use crate::{Cli, Summary, links, rerun_command};
+use std::path::Path;
+use toml_edit::{DocumentMut, Item, Key, Value};
+
+/// Deals with what the batch leaves to do: chats that became supergroups,
+/// --rerun-command for the failed files and --copy-links.
+pub fn finish(args: &Cli, inputs: &Inputs, summary: &mut Summary) {
+    if !summary.migrations.is_empty() {
+        if args.update_config_on_migrate {
+            match update_config_chats(&summary.migrations) {
+                Ok(true) => summary.config_updated = true,
+                Ok(false) => log::warn!("No chat id to replace in config.toml"),
+                Err(e) => log::error!("Could not update config.toml: {}", e),
+            }
+        }
+        if !summary.config_updated {
+            for (from, to) in &summary.migrations {
+                log::warn!(
+                    "Chat {} was upgraded to a supergroup: replace it with {} in your config (or pass --update-config-on-migrate)",
+                    from,
+                    to
+                );
+            }
+        }
+    }
+
+    if let Some(out) = &args.rerun_command
+        && !summary.failed.is_empty()
+    {
+        if args.from_package.is_some() {
+            log::warn!("No --rerun-command for a --from-package run; send the package again");
+        } else {
+            let command = rerun_command(args, &summary.failed, &inputs.inline_captions);
+            if out == Path::new("-") {
+                summary.rerun_command = Some(command);
+            } else {
+                match std::fs::write(out, format!("#!/bin/sh\n{}\n", command)) {
+                    Ok(()) => log::info!(
+                        "Wrote the command retrying {} failed file(s) to {:?}",
+                        summary.failed.len(),
+                        out
+                    ),
+                    Err(e) => log::error!("Cannot write {:?}: {}", out, e),
+                }
+            }
+        }
+    }
+
+    if args.copy_links {
+        let links = summary.links();
+        if links.is_empty() {
+            log::warn!("No links to copy: private chats and basic groups have no t.me links");
+        } else {
+            match links::copy(&links.join("\n")) {
+                Ok(()) => log::info!("Copied {} link(s) to the clipboard", links.len()),
+                Err(e) => log::warn!("Could not copy the links to the clipboard: {}", e),
+            }
+        }
+    }
+}
+
+// Replaces each migrated chat id in config.toml where the config names a
+// chat: the top-level and the profiles' chat_id, and the [chats."<id>"]
+// tables. Comments and layout are kept. Whether anything changed.
+fn update_config_chats(migrations: &[(String, String)]) -> Result<bool, String> {
+    let path = Path::new("config.toml");
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.to_string()),
+    };
+    let Some(updated) = migrate_config_chats(&text, migrations)? else {
+        return Ok(false);
+    };
+    std::fs::write(path, updated).map_err(|e| e.to_string())?;
+    Ok(true)
+}
+
+// `text` with the migrated chats replaced, or None when it names none of
+// them.
+fn migrate_config_chats(
+    text: &str,
+    migrations: &[(String, String)],
+) -> Result<Option<String>, String> {
+    let mut config: DocumentMut = text
+        .parse()
+        .map_err(|e: toml_edit::TomlError| e.to_string())?;
+    let mut changed = migrate_chat_id(config.get_mut("chat_id"), migrations);
+    if let Some(profiles) = config.get_mut("profiles").and_then(Item::as_table_like_mut) {
+        for (_, profile) in profiles.iter_mut() {
+            if let Some(profile) = profile.as_table_like_mut() {
+                changed |= migrate_chat_id(profile.get_mut("chat_id"), migrations);
+            }
+        }
+    }
+    if let Some(chats) = config.get_mut("chats").and_then(Item::as_table_mut) {
+        for (from, to) in migrations {
+            if chats.contains_key(to) {
+                continue;
+            }
+            if let Some(options) = chats.remove(from) {
+                // Quoted like the [chats."<id>"] headers are written
+                let key = format!("\"{}\"", to)
+                    .parse()
+                    .unwrap_or_else(|_| Key::new(to));
+                chats.insert_formatted(&key, options);
+                changed = true;
+            }
+        }
+    }
+    Ok(changed.then(|| config.to_string()))
+}
+
+// Replaces a chat_id value that is one of the migrated chats, as an
+// integer or a string like it was written.
+fn migrate_chat_id(item: Option<&mut Item>, migrations: &[(String, String)]) -> bool {
+    let Some(value) = item.and_then(Item::as_value_mut) else {
+        return false;
+    };
+    let chat = match value {
+        Value::String(chat) => chat.value().clone(),
+        Value::Integer(chat) => chat.value().to_string(),
+        _ => return false,
+    };
+    let Some((_, to)) = migrations.iter().find(|(from, _)| *from == chat) else {
+        return false;
+    };
+    let mut migrated = match (&*value, to.parse::<i64>()) {
+        (Value::Integer(_), Ok(id)) => Value::from(id),
+        _ => Value::from(to.as_str()),
+    };
+    *migrated.decor_mut() = value.decor().clone();
+    *value = migrated;
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_only_the_chat_entries() {
+        let config = r#"chat_id = -123 # the channel
+scratch_chat_id = "-123"
+bot_tokens = ["-123"]
+
+[keyboard]
+buttons = [[{ text = "Chat", callback = "-123" }]]
+
+[profiles.backup]
+chat_id = "-123"
+
+[chats."-123"]
+quality = "small"
+"#;
+        let migrations = [("-123".to_string(), "-100123".to_string())];
+        let migrated = migrate_config_chats(config, &migrations).unwrap().unwrap();
+        assert_eq!(
+            migrated,
+            r#"chat_id = -100123 # the channel
+scratch_chat_id = "-123"
+bot_tokens = ["-123"]
+
+[keyboard]
+buttons = [[{ text = "Chat", callback = "-123" }]]
+
+[profiles.backup]
+chat_id = "-100123"
+
+[chats."-100123"]
+quality = "small"
+"#
+        );
+        assert_eq!(migrate_config_chats(&migrated, &migrations).unwrap(), None);
+    }
+}
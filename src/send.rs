@@ -0,0 +1,594 @@
+// ---------------------------
+// Sending media
+// ---------------------------
+
+use crate::chats::{self, ChatKind};
+use crate::throttle::Limits;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use teloxide::RequestError;
+use teloxide::prelude::*;
+use teloxide::requests::{HasPayload, Output, Payload, Request};
+use teloxide::types::{
+    EffectId, FileId, FileMeta, InlineKeyboardMarkup, InputFile, InputMedia, MessageId, ParseMode,
+    Recipient, ReplyParameters, ThreadId,
+};
+
+/// Log target of --debug-requests
+pub const REQUEST_LOG: &str = "telegoy::requests";
+
+static DEBUG_REQUESTS: AtomicBool = AtomicBool::new(false);
+
+// Set once the server rejected a video's cover or start_timestamp, after
+// which those fields are left out of every send
+static VIDEO_EXTRAS_REJECTED: AtomicBool = AtomicBool::new(false);
+
+static RATE_LIMITS: OnceLock<Limits> = OnceLock::new();
+
+/// Applies --limit-rate/--limit-rate-per-file to every upload made through
+/// this module.
+pub fn set_rate_limits(limits: Limits) {
+    let _ = RATE_LIMITS.set(limits);
+}
+
+// The upload to send for `file`: a fresh reader of `path`, rate-limited
+// when limits are set and hashing what it reads, when `file` is an upload
+// rather than a file_id or URL.
+fn limited(file: InputFile, path: Option<&Path>) -> InputFile {
+    let Some(path) = path else {
+        return file;
+    };
+    let is_upload = serde_json::to_value(&file)
+        .ok()
+        .and_then(|v| v.as_str().map(|r| r.starts_with("attach://")))
+        .unwrap_or(false);
+    if is_upload {
+        RATE_LIMITS.get_or_init(Limits::default).input_file(path)
+    } else {
+        file
+    }
+}
+
+/// Turns on --debug-requests logging for every request made through this
+/// module, including those handed to [`logged`].
+pub fn enable_request_logging() {
+    DEBUG_REQUESTS.store(true, Ordering::Relaxed);
+}
+
+/// Makes `request`, a call that uploads nothing, logging its payload as it
+/// goes into the JSON body, and the response, under --debug-requests.
+pub async fn logged<R>(bot: &Bot, request: R) -> Result<Output<R>, RequestError>
+where
+    R: Request<Err = RequestError>,
+    R::Payload: Serialize,
+    Output<R>: Serialize,
+{
+    if !DEBUG_REQUESTS.load(Ordering::Relaxed) {
+        return request.send().await;
+    }
+    let method = &method_name::<R::Payload>();
+    let body = serde_json::to_value(request.payload_ref()).unwrap_or_default();
+    log_request(bot, method, &body);
+    let result = request.send().await;
+    log_response(bot, method, &result);
+    result
+}
+
+// Effects Telegram offers to every bot, by the names --effect-id accepts
+const EFFECTS: [(&str, &str); 6] = [
+    ("fire", "5104841245755180586"),
+    ("thumbs-up", "5107584321108051014"),
+    ("thumbs-down", "5104858069142078462"),
+    ("heart", "5159385139981059251"),
+    ("party", "5046509860389126442"),
+    ("poop", "5046589136895476101"),
+];
+
+/// Settings applied to every message sent to one destination
+#[derive(Clone, Debug, Default)]
+pub struct PostOptions {
+    /// Forum topic posted in
+    pub thread: Option<ThreadId>,
+    /// Message effect; Telegram only shows these in private chats
+    pub effect: Option<EffectId>,
+    /// Message replied to (--reply-to), which only exists in the primary chat
+    pub reply: Option<ReplyParameters>,
+}
+
+impl PostOptions {
+    /// The options for `chat`, without the effect unless it is a private
+    /// chat and without the thread when it has no forum topics. Chats whose
+    /// type was not looked up count as private when their id is positive,
+    /// and as having topics.
+    pub fn for_chat(&self, chat: &str) -> PostOptions {
+        let kind = chats::known(chat);
+        let private = match kind {
+            Some(kind) => kind == ChatKind::Private,
+            None => chat.parse::<i64>().is_ok_and(|id| id > 0),
+        };
+        if self.effect.is_some() && !private {
+            log::warn!(
+                "Not using --effect-id for {}: effects only work in private chats",
+                chat
+            );
+        }
+        let topics = kind.is_none_or(ChatKind::has_topics);
+        if let (Some(thread), Some(kind)) = (self.thread, kind)
+            && !topics
+        {
+            log::warn!(
+                "Not using --thread-id {} for {}: it is a {} without forum topics",
+                thread,
+                chat,
+                kind.describe()
+            );
+        }
+        PostOptions {
+            thread: self.thread.filter(|_| topics),
+            effect: self.effect.clone().filter(|_| private),
+            reply: self.reply.clone(),
+        }
+    }
+}
+
+/// Parses an effect name (`fire`, `heart`, ...) or one of their ids.
+pub fn parse_effect(value: &str) -> Result<EffectId, String> {
+    EFFECTS
+        .iter()
+        .find(|(name, id)| value.eq_ignore_ascii_case(name) || value == *id)
+        .map(|(_, id)| EffectId(id.to_string()))
+        .ok_or_else(|| {
+            let names: Vec<&str> = EFFECTS.iter().map(|(name, _)| *name).collect();
+            format!("unknown effect (use one of {} or its id)", names.join(", "))
+        })
+}
+
+/// Sends one chunk: a media group, or a plain message when the chunk holds a
+/// single item (media groups need at least two). Only a plain message can
+/// carry `keyboard`. `options` apply to every message. `paths` are the
+/// files uploaded for the items, used to describe attachments in the request
+/// log and reopened at the limited rate under --limit-rate.
+///
+/// Bot API servers older than 8.3 reject videos' `cover` and
+/// `start_timestamp`; the chunk is then sent again without them.
+pub async fn send_media(
+    bot: &Bot,
+    target: Recipient,
+    options: &PostOptions,
+    mut media: Vec<InputMedia>,
+    keyboard: Option<&InlineKeyboardMarkup>,
+    paths: &[PathBuf],
+) -> Result<Vec<Message>, RequestError> {
+    if VIDEO_EXTRAS_REJECTED.load(Ordering::Relaxed) {
+        strip_video_extras(&mut media);
+    }
+    let fallback = has_video_extras(&media).then(|| media.clone());
+    let result = send_logged(bot, target.clone(), options, media, keyboard, paths).await;
+    match (result, fallback) {
+        (Err(e), Some(mut media)) if rejects_video_extras(&e) => {
+            if !VIDEO_EXTRAS_REJECTED.swap(true, Ordering::Relaxed) {
+                log::warn!(
+                    "{} does not support video covers or start timestamps; sending without them",
+                    bot.api_url()
+                );
+            }
+            strip_video_extras(&mut media);
+            send_logged(bot, target, options, media, keyboard, paths).await
+        }
+        (result, _) => result,
+    }
+}
+
+fn has_video_extras(media: &[InputMedia]) -> bool {
+    media.iter().any(|item| {
+        matches!(item, InputMedia::Video(m) if m.cover.is_some() || m.start_timestamp.is_some())
+    })
+}
+
+fn strip_video_extras(media: &mut [InputMedia]) {
+    for item in media {
+        if let InputMedia::Video(m) = item {
+            m.cover = None;
+            m.start_timestamp = None;
+        }
+    }
+}
+
+// An older server names the field it cannot handle in the error text.
+fn rejects_video_extras(err: &RequestError) -> bool {
+    let RequestError::Api(api) = err else {
+        return false;
+    };
+    let text = api.to_string().to_lowercase();
+    text.contains("cover") || text.contains("start_timestamp")
+}
+
+/// Sends an uploaded or already known sticker.
+pub async fn send_sticker(
+    bot: &Bot,
+    target: Recipient,
+    options: &PostOptions,
+    sticker: InputFile,
+    keyboard: Option<&InlineKeyboardMarkup>,
+    path: Option<&Path>,
+) -> Result<Message, RequestError> {
+    let mut request = bot.send_sticker(target, limited(sticker, path));
+    request.payload_mut().message_thread_id = options.thread;
+    request.payload_mut().message_effect_id = options.effect.clone();
+    request.payload_mut().reply_parameters = options.reply.clone();
+    if let Some(markup) = keyboard {
+        request = request.reply_markup(markup.clone());
+    }
+    let paths: Vec<PathBuf> = path.map(Path::to_path_buf).into_iter().collect();
+    logged_upload(bot, request, &paths).await
+}
+
+/// Longest text a single message can hold
+const MESSAGE_LIMIT: usize = 4096;
+
+/// Sends `text` as one or more messages of at most 4096 characters, cut at
+/// line breaks (or spaces) where possible. Each part gets `options`, the
+/// last one `keyboard` too.
+pub async fn send_text(
+    bot: &Bot,
+    target: Recipient,
+    options: &PostOptions,
+    text: &str,
+    parse_mode: Option<ParseMode>,
+    keyboard: Option<&InlineKeyboardMarkup>,
+) -> Result<Vec<Message>, RequestError> {
+    let mut messages = Vec::new();
+    let parts = split_text(text, MESSAGE_LIMIT);
+    let last = parts.len().saturating_sub(1);
+    for (i, part) in parts.into_iter().enumerate() {
+        let keyboard = keyboard.filter(|_| i == last);
+        let mut request = bot.send_message(target.clone(), part);
+        request.payload_mut().message_thread_id = options.thread;
+        request.payload_mut().message_effect_id = options.effect.clone();
+        request.payload_mut().reply_parameters = options.reply.clone();
+        request.payload_mut().parse_mode = parse_mode;
+        request.payload_mut().reply_markup = keyboard.cloned().map(Into::into);
+        messages.push(logged(bot, request).await?);
+    }
+    Ok(messages)
+}
+
+/// Replaces the caption of message `id`.
+pub async fn edit_caption(
+    bot: &Bot,
+    target: Recipient,
+    id: MessageId,
+    caption: &str,
+    parse_mode: Option<ParseMode>,
+) -> Result<Message, RequestError> {
+    let mut request = bot.edit_message_caption(target, id).caption(caption);
+    request.payload_mut().parse_mode = parse_mode;
+    logged(bot, request).await
+}
+
+// Splits `text` into parts of at most `max` characters, preferring to cut
+// after a newline, then after a space.
+fn split_text(text: &str, max: usize) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = text;
+    while let Some((limit, _)) = rest.char_indices().nth(max) {
+        let head = &rest[..limit];
+        let cut = head
+            .rfind('\n')
+            .or_else(|| head.rfind(' '))
+            .map_or(limit, |at| at + 1);
+        parts.push(&rest[..cut]);
+        rest = &rest[cut..];
+    }
+    if !rest.is_empty() {
+        parts.push(rest);
+    }
+    parts
+}
+
+// Makes `request`, which uploads `paths`, logging it like `logged` with
+// each attachment reference followed by the file's name and size (file
+// contents are never logged).
+async fn logged_upload<R>(
+    bot: &Bot,
+    request: R,
+    paths: &[PathBuf],
+) -> Result<Output<R>, RequestError>
+where
+    R: Request<Err = RequestError>,
+    R::Payload: Serialize,
+    Output<R>: Serialize,
+{
+    if !DEBUG_REQUESTS.load(Ordering::Relaxed) {
+        return request.send().await;
+    }
+    let method = &method_name::<R::Payload>();
+    let mut body = serde_json::to_value(request.payload_ref()).unwrap_or_default();
+    match body.get_mut("media").and_then(|media| media.as_array_mut()) {
+        Some(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                describe_upload(item, "media", paths.get(i));
+                describe_extras(item);
+            }
+        }
+        None => {
+            for field in [
+                "photo",
+                "video",
+                "animation",
+                "audio",
+                "document",
+                "sticker",
+            ] {
+                describe_upload(&mut body, field, paths.first());
+            }
+            describe_extras(&mut body);
+        }
+    }
+    log_request(bot, method, &body);
+    let result = request.send().await;
+    log_response(bot, method, &result);
+    result
+}
+
+// Names the upload in `field` of a sent item after `path`.
+fn describe_upload(item: &mut serde_json::Value, field: &str, path: Option<&PathBuf>) {
+    if let Some(reference) = item[field].as_str().filter(|r| r.starts_with("attach://"))
+        && let Some(path) = path
+    {
+        item[field] = attachment(reference, path).into();
+    }
+}
+
+// Marks the thumbnail and cover uploads of a sent item.
+fn describe_extras(item: &mut serde_json::Value) {
+    if let Some(reference) = item["thumbnail"].as_str() {
+        item["thumbnail"] = format!("{} (generated thumbnail)", reference).into();
+    }
+    if let Some(reference) = item["cover"].as_str() {
+        item["cover"] = format!("{} (cover image)", reference).into();
+    }
+}
+
+fn attachment(reference: &str, path: &Path) -> String {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or_default();
+    format!("{} ({}, {} bytes)", reference, name, size)
+}
+
+// The bot token is part of every request URL; it is masked everywhere.
+fn mask_token(bot: &Bot, text: &str) -> String {
+    text.replace(bot.token(), "<token>")
+}
+
+// The Bot API method of payload `P`: teloxide names the payloads after
+// the methods, capitalized (GetMe for getMe).
+fn method_name<P: Payload>() -> String {
+    let mut chars = P::NAME.chars();
+    chars
+        .next()
+        .map(|first| first.to_lowercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+fn log_request(bot: &Bot, method: &str, body: &serde_json::Value) {
+    let text = format!(
+        "POST {}bot{}/{}\n{}",
+        bot.api_url(),
+        bot.token(),
+        method,
+        serde_json::to_string_pretty(body).unwrap_or_default()
+    );
+    log::debug!(target: REQUEST_LOG, "{}", mask_token(bot, &text));
+}
+
+fn log_response<T: Serialize>(bot: &Bot, method: &str, result: &Result<T, RequestError>) {
+    let text = match result {
+        Ok(response) => serde_json::to_string(response).unwrap_or_default(),
+        Err(e) => format!("error: {}", e),
+    };
+    log::debug!(target: REQUEST_LOG, "{} response: {}", method, mask_token(bot, &text));
+}
+
+async fn send_logged(
+    bot: &Bot,
+    target: Recipient,
+    options: &PostOptions,
+    mut media: Vec<InputMedia>,
+    keyboard: Option<&InlineKeyboardMarkup>,
+    paths: &[PathBuf],
+) -> Result<Vec<Message>, RequestError> {
+    for (item, path) in media.iter_mut().zip(paths) {
+        let file = match item {
+            InputMedia::Photo(m) => &mut m.media,
+            InputMedia::Video(m) => &mut m.media,
+            InputMedia::Animation(m) => &mut m.media,
+            InputMedia::Audio(m) => &mut m.media,
+            InputMedia::Document(m) => &mut m.media,
+        };
+        let placeholder = InputFile::file_id(FileId(String::new()));
+        *file = limited(std::mem::replace(file, placeholder), Some(path));
+    }
+    let reply_markup = keyboard.cloned().map(Into::into);
+    if media.len() != 1 {
+        let mut request = bot.send_media_group(target, media);
+        request.payload_mut().message_thread_id = options.thread;
+        request.payload_mut().message_effect_id = options.effect.clone();
+        request.payload_mut().reply_parameters = options.reply.clone();
+        return logged_upload(bot, request, paths).await;
+    }
+    let message = match media.remove(0) {
+        InputMedia::Photo(m) => {
+            let mut request = bot.send_photo(target, m.media);
+            let payload = request.payload_mut();
+            payload.reply_markup = reply_markup;
+            payload.message_thread_id = options.thread;
+            payload.message_effect_id = options.effect.clone();
+            payload.reply_parameters = options.reply.clone();
+            payload.caption = m.caption;
+            payload.parse_mode = m.parse_mode;
+            payload.caption_entities = m.caption_entities;
+            payload.show_caption_above_media = Some(m.show_caption_above_media);
+            payload.has_spoiler = Some(m.has_spoiler);
+            logged_upload(bot, request, paths).await?
+        }
+        InputMedia::Video(m) => {
+            let mut request = bot.send_video(target, m.media);
+            let payload = request.payload_mut();
+            payload.reply_markup = reply_markup;
+            payload.message_thread_id = options.thread;
+            payload.message_effect_id = options.effect.clone();
+            payload.reply_parameters = options.reply.clone();
+            payload.caption = m.caption;
+            payload.parse_mode = m.parse_mode;
+            payload.caption_entities = m.caption_entities;
+            payload.show_caption_above_media = Some(m.show_caption_above_media);
+            payload.has_spoiler = Some(m.has_spoiler);
+            payload.thumbnail = m.thumbnail;
+            payload.cover = m.cover;
+            payload.start_timestamp = m.start_timestamp;
+            payload.width = m.width.map(u32::from);
+            payload.height = m.height.map(u32::from);
+            payload.duration = m.duration.map(u32::from);
+            payload.supports_streaming = m.supports_streaming;
+            logged_upload(bot, request, paths).await?
+        }
+        InputMedia::Animation(m) => {
+            let mut request = bot.send_animation(target, m.media);
+            let payload = request.payload_mut();
+            payload.reply_markup = reply_markup;
+            payload.message_thread_id = options.thread;
+            payload.message_effect_id = options.effect.clone();
+            payload.reply_parameters = options.reply.clone();
+            payload.caption = m.caption;
+            payload.parse_mode = m.parse_mode;
+            payload.caption_entities = m.caption_entities;
+            payload.show_caption_above_media = Some(m.show_caption_above_media);
+            payload.has_spoiler = Some(m.has_spoiler);
+            payload.thumbnail = m.thumbnail;
+            payload.width = m.width.map(u32::from);
+            payload.height = m.height.map(u32::from);
+            payload.duration = m.duration.map(u32::from);
+            logged_upload(bot, request, paths).await?
+        }
+        InputMedia::Audio(m) => {
+            let mut request = bot.send_audio(target, m.media);
+            let payload = request.payload_mut();
+            payload.reply_markup = reply_markup;
+            payload.message_thread_id = options.thread;
+            payload.message_effect_id = options.effect.clone();
+            payload.reply_parameters = options.reply.clone();
+            payload.caption = m.caption;
+            payload.parse_mode = m.parse_mode;
+            payload.caption_entities = m.caption_entities;
+            payload.thumbnail = m.thumbnail;
+            payload.duration = m.duration.map(u32::from);
+            payload.performer = m.performer;
+            payload.title = m.title;
+            logged_upload(bot, request, paths).await?
+        }
+        InputMedia::Document(m) => {
+            let mut request = bot.send_document(target, m.media);
+            let payload = request.payload_mut();
+            payload.reply_markup = reply_markup;
+            payload.message_thread_id = options.thread;
+            payload.message_effect_id = options.effect.clone();
+            payload.reply_parameters = options.reply.clone();
+            payload.caption = m.caption;
+            payload.parse_mode = m.parse_mode;
+            payload.caption_entities = m.caption_entities;
+            payload.thumbnail = m.thumbnail;
+            payload.disable_content_type_detection = m.disable_content_type_detection;
+            logged_upload(bot, request, paths).await?
+        }
+    };
+    Ok(vec![message])
+}
+
+/// A post to send again to other chats, by upload or by file_id
+#[derive(Clone)]
+pub enum Resend {
+    Media(Vec<InputMedia>),
+    Sticker(InputFile),
+}
+
+impl Resend {
+    /// The same post by the file_ids in `messages`, the reply to sending it.
+    /// File ids only work for the bot that received them.
+    pub fn reuse(&self, messages: &[Message]) -> Option<Resend> {
+        match self {
+            Resend::Media(media) => reuse_file_ids(media.clone(), messages).map(Resend::Media),
+            Resend::Sticker(_) => {
+                let sticker = messages.first()?.sticker()?;
+                Some(Resend::Sticker(InputFile::file_id(sticker.file.id.clone())))
+            }
+        }
+    }
+}
+
+/// Id of the photo (its largest size), video, animation, audio, document
+/// or sticker in `message`.
+pub fn file_id(message: &Message) -> Option<String> {
+    Some(file(message)?.id.to_string())
+}
+
+/// The same file's unique id, which stays the same across bots and when the
+/// message is forwarded.
+pub fn file_unique_id(message: &Message) -> Option<String> {
+    Some(file(message)?.unique_id.to_string())
+}
+
+fn file(message: &Message) -> Option<&FileMeta> {
+    message
+        .photo()
+        .and_then(|photo| photo.last())
+        .map(|photo| &photo.file)
+        .or(message.video().map(|video| &video.file))
+        .or(message.animation().map(|animation| &animation.file))
+        .or(message.audio().map(|audio| &audio.file))
+        .or(message.document().map(|document| &document.file))
+        .or(message.sticker().map(|sticker| &sticker.file))
+}
+
+/// Replaces each item's upload by the file_id Telegram returned for it, so
+/// the same post can be sent elsewhere without uploading again.
+pub fn reuse_file_ids(media: Vec<InputMedia>, messages: &[Message]) -> Option<Vec<InputMedia>> {
+    if media.len() != messages.len() {
+        return None;
+    }
+    media
+        .into_iter()
+        .zip(messages)
+        .map(|(item, message)| {
+            let file = |id: &FileId| InputFile::file_id(id.clone());
+            Some(match item {
+                InputMedia::Photo(mut m) => {
+                    m.media = file(&message.photo()?.last()?.file.id);
+                    InputMedia::Photo(m)
+                }
+                InputMedia::Video(mut m) => {
+                    m.media = file(&message.video()?.file.id);
+                    m.thumbnail = None;
+                    InputMedia::Video(m)
+                }
+                InputMedia::Animation(mut m) => {
+                    m.media = file(&message.animation()?.file.id);
+                    m.thumbnail = None;
+                    InputMedia::Animation(m)
+                }
+                InputMedia::Audio(mut m) => {
+                    m.media = file(&message.audio()?.file.id);
+                    m.thumbnail = None;
+                    InputMedia::Audio(m)
+                }
+                InputMedia::Document(mut m) => {
+                    m.media = file(&message.document()?.file.id);
+                    m.thumbnail = None;
+                    InputMedia::Document(m)
+                }
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,390 @@
+// ---------------------------
+// Config & destinations
+// ---------------------------
+
+use crate::StreamingMode;
+use crate::{CaptionPlacement, Cli, Concurrency, IMAGE_EXTENSIONS, PhotoPresetChoice, Settings};
+use crate::{Summary, VIDEO_EXTENSIONS, api_errors, chats, keyboard, quality};
+use crate::{apply_profile, default_settings, ffmpeg_installed};
+use crate::{file_ext, load_settings, load_thumbnail, make_bot, parse_tag};
+use crate::{subprocess, wait_for_api};
+use std::collections::HashMap;
+use std::time::Duration;
+use teloxide::types::{InlineKeyboardMarkup, Recipient};
+
+/// The config with the command line applied, and where the batch goes
+pub struct Setup {
+    pub settings: Settings,
+    // --profile's bot, else TELOXIDE_TOKEN
+    pub bot_token: Option<String>,
+    pub bot_url: reqwest::Url,
+    // The first chat, posted to by the main bot, and the ones the fan-out
+    // resends to
+    pub chat_id: String,
+    pub secondary_chats: Vec<String>,
+    // Each chat's [qualities] and caption language; the first chat's apart
+    pub primary_quality: Option<String>,
+    pub qualities: HashMap<String, Option<String>>,
+    pub primary_lang: Option<String>,
+    pub caption_langs: HashMap<String, Option<String>>,
+    pub photo_preset: Option<quality::PhotoPreset>,
+    pub streaming: StreamingMode,
+    pub concurrency: Concurrency,
+    pub gif_to_mp4: bool,
+    pub keyboard: Option<InlineKeyboardMarkup>,
+    // Where --dedupe-retries looks for earlier posts
+    pub dedupe: Option<Recipient>,
+    // --thumbnail, read once for every video
+    pub batch_thumbnail: Option<Vec<u8>>,
+    // Whether the sends are recorded in the ledger, which --validate leaves
+    // alone
+    pub ledger: bool,
+}
+
+impl Setup {
+    /// `chat` as the only destination, with the config's defaults and no
+    /// ledger: how the builder API sends a post.
+    pub fn new(chat_id: String, bot_url: reqwest::Url, args: &Cli) -> Setup {
+        let settings = default_settings();
+        Setup {
+            streaming: streaming(args, &settings),
+            settings,
+            bot_token: None,
+            bot_url,
+            chat_id,
+            secondary_chats: Vec::new(),
+            primary_quality: None,
+            qualities: HashMap::new(),
+            primary_lang: None,
+            caption_langs: HashMap::new(),
+            photo_preset: None,
+            concurrency: Concurrency::new(args),
+            gif_to_mp4: gif_to_mp4(args),
+            keyboard: None,
+            dedupe: None,
+            batch_thumbnail: None,
+            ledger: false,
+        }
+    }
+}
+
+/// Loads the config and checks everything the batch needs before any work
+/// starts; the first problem found is returned.
+pub async fn load(
+    args: &mut Cli,
+    package_chat_id: Option<String>,
+    summary: &mut Summary,
+) -> Result<Setup, String> {
+    // Environment variables prefixed with TELEGOY_ override the config
+    let mut settings: Settings = match load_settings() {
+        Ok(s) => s,
+        Err(e) => {
+            if args.chat_id.is_empty() && package_chat_id.is_none() {
+                return Err(format!(
+                    "Configuration error: {}, and no --chat-id or package chat to fall back on",
+                    e
+                ));
+            }
+            log::error!("Configuration error: {}", e);
+            // Mock settings if only CLI is used
+            default_settings()
+        }
+    };
+
+    // Profile: its chat, server and bot replace the top-level settings
+    let bot_token = apply_profile(&mut settings, args.profile.as_deref())?;
+
+    subprocess::set_timeout(settings.subprocess_timeout_secs);
+
+    let photo_preset = match args.photo_preset {
+        PhotoPresetChoice::None => None,
+        PhotoPresetChoice::Web => Some(quality::PhotoPreset::WEB),
+        PhotoPresetChoice::Custom => match settings.photo_preset {
+            Some(preset) => Some(preset),
+            None => {
+                return Err(
+                    "--photo-preset custom needs a [photo_preset] table in the config".to_string(),
+                );
+            }
+        },
+    };
+
+    for (kind, tag) in &mut settings.type_hashtags {
+        match parse_tag(tag) {
+            Ok(valid) => *tag = valid,
+            Err(e) => {
+                return Err(format!(
+                    "Invalid [type_hashtags] {} = {:?}: {}",
+                    kind, tag, e
+                ));
+            }
+        }
+    }
+
+    // Watermark: CLI > config, checked before any work starts
+    if !args.no_watermark {
+        args.watermark = args.watermark.take().or(settings.watermark.take());
+    }
+    if let Some(logo) = &args.watermark
+        && let Err(e) = image::open(logo)
+    {
+        return Err(format!("Cannot read watermark {:?}: {}", logo, e));
+    }
+
+    let streaming = streaming(args, &settings);
+
+    let concurrency = Concurrency::new(args);
+    if args.concurrency.is_some() || args.concurrency_auto {
+        log::info!(
+            "Processing {} video(s) at a time, posting to {} extra chat(s) at a time",
+            concurrency.processing,
+            concurrency.fan_out
+        );
+    }
+
+    let gif_to_mp4 = gif_to_mp4(args);
+
+    // Keyboard layout, validated before any work starts
+    let keyboard_config = match &args.keyboard_file {
+        Some(path) => match keyboard::load_file(path) {
+            Ok(config) => Some(config),
+            Err(e) => return Err(format!("Cannot read keyboard file {:?}: {}", path, e)),
+        },
+        None => settings.keyboard.take(),
+    };
+    let keyboard = match keyboard_config.map(|config| config.build()).transpose() {
+        Ok(keyboard) => keyboard,
+        Err(e) => return Err(format!("Invalid keyboard: {}", e)),
+    };
+
+    // Determine final Chat ID (CLI arg takes precedence over package, then Config/Env)
+    let mut secondary_chats = args.chat_id.clone();
+    let mut chat_id = if secondary_chats.is_empty() {
+        package_chat_id.unwrap_or(std::mem::take(&mut settings.chat_id))
+    } else {
+        secondary_chats.remove(0)
+    };
+    if let Some(ttl) = args.delete_after
+        && ttl > Duration::from_secs(48 * 3600)
+    {
+        log::warn!(
+            "Telegram only lets bots delete messages up to 48 hours old, so --delete-after {} may leave the posts in place",
+            humantime::format_duration(ttl)
+        );
+    }
+    // Where --dedupe-retries looks at the messages before a retried post
+    let dedupe = match &settings.scratch_chat_id {
+        Some(scratch) if args.dedupe_retries => Some(Recipient::from(scratch.clone())),
+        None if args.dedupe_retries => return Err(
+            "--dedupe-retries needs a scratch_chat_id in the config to look at earlier posts in"
+                .to_string(),
+        ),
+        _ => None,
+    };
+    if args.validate {
+        let Some(scratch) = settings.scratch_chat_id.clone() else {
+            return Err("--validate needs a scratch_chat_id in the config to send to".to_string());
+        };
+        log::warn!(
+            "Validating: sending to {} instead of {} and deleting every message again",
+            scratch,
+            std::iter::once(&chat_id)
+                .chain(&secondary_chats)
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        chat_id = scratch;
+        secondary_chats.clear();
+    }
+    if args.edit_caption_after.is_some() && args.caption_placement == CaptionPlacement::Reply {
+        return Err(
+            "--edit-caption-after edits media captions, which --caption-placement reply leaves empty"
+                .to_string(),
+        );
+    }
+    if let Some(test_chat) = &args.test_chat {
+        if args.caption_placement == CaptionPlacement::Reply {
+            return Err(
+                "--test-chat cannot rehearse --caption-placement reply, whose replies are only posted in the first chat"
+                    .to_string(),
+            );
+        }
+        secondary_chats.insert(0, std::mem::replace(&mut chat_id, test_chat.clone()));
+        log::info!("Rehearsing in {} first", chat_id);
+        summary.rehearsal = true;
+    }
+    // `me` from any of the places a chat can come from
+    for chat in std::iter::once(&mut chat_id).chain(&mut secondary_chats) {
+        if chat == "me" {
+            let Some(owner) = settings.owner_id else {
+                return Err(
+                    "Chat `me` needs owner_id (your numeric user id) in the config; bots cannot post to Saved Messages, so it is the bot's chat with you"
+                        .to_string(),
+                );
+            };
+            *chat = owner.to_string();
+        }
+    }
+    summary.chat = Some(chat_id.clone());
+    let bot_url = reqwest::Url::parse(&settings.api_url)
+        .map_err(|e| format!("Invalid api_url {:?}: {}", settings.api_url, e))?;
+
+    log::info!("Starting uploader. Target Chat: {}", chat_id);
+    if !secondary_chats.is_empty() {
+        log::info!("Also posting to: {}", secondary_chats.join(", "));
+        if args.caption_placement == CaptionPlacement::Reply {
+            log::warn!(
+                "Caption replies are only posted in {}; the other chats get the albums without captions",
+                chat_id
+            );
+        }
+    }
+    // Each chat's quality from its [chats] entry, checked before any work starts
+    let mut qualities = HashMap::new();
+    for chat in std::iter::once(&chat_id).chain(&secondary_chats) {
+        let name = settings
+            .chats
+            .get(chat)
+            .and_then(|options| options.quality.clone());
+        if let Some(name) = &name
+            && !settings.qualities.contains_key(name)
+        {
+            return Err(format!(
+                "Chat {} uses quality {:?}, but the config has no [qualities.{}]",
+                chat, name, name
+            ));
+        }
+        qualities.insert(chat.clone(), name);
+    }
+    let primary_quality = qualities.remove(&chat_id).flatten();
+    // Each chat's caption language the same way, the first chat falling back
+    // to the top-level (or --profile) caption_lang
+    let mut caption_langs = HashMap::new();
+    for chat in std::iter::once(&chat_id).chain(&secondary_chats) {
+        let lang = settings
+            .chats
+            .get(chat)
+            .and_then(|options| options.caption_lang.clone())
+            .or_else(|| settings.caption_lang.clone().filter(|_| *chat == chat_id));
+        if let Some(lang) = &lang
+            && (lang.is_empty()
+                || !lang
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+        {
+            return Err(format!(
+                "Chat {} has caption_lang {:?}; use a language code such as \"en\" or \"pt-BR\"",
+                chat, lang
+            ));
+        }
+        caption_langs.insert(chat.clone(), lang);
+    }
+    let primary_lang = caption_langs.remove(&chat_id).flatten();
+
+    // Pre-flight: fail fast on an unreachable server or a bad token, before
+    // any thumbnail or probing work
+    if !args.dry_run && (!args.no_preflight || args.wait_for_api.is_some()) {
+        let bot = make_bot(bot_token.as_deref(), bot_url.clone());
+        match wait_for_api(&bot, args.wait_for_api.unwrap_or_default()).await {
+            Ok(me) => {
+                log::info!("Connected to {} as @{}", settings.api_url, me.username());
+                summary.bot = Some(me.username().to_string());
+            }
+            Err(e) => {
+                return Err(format!(
+                    "Bot API at {} is not usable: {}",
+                    settings.api_url,
+                    api_errors::describe(&e)
+                ));
+            }
+        }
+    }
+
+    // What each destination is, for the options that only work in some
+    if !args.dry_run && !args.no_chat_lookup {
+        let bot = make_bot(bot_token.as_deref(), bot_url.clone());
+        for chat in std::iter::once(&chat_id).chain(&secondary_chats) {
+            match chats::lookup(&bot, chat).await {
+                Ok(kind) => log::debug!("{} is a {}", chat, kind.describe()),
+                Err(e) => log::warn!(
+                    "Could not look up chat {}: {}",
+                    chat,
+                    api_errors::describe(&e)
+                ),
+            }
+        }
+    }
+
+    // Pre-flight: a batch thumbnail must be readable before any work starts
+    let batch_thumbnail = match &args.thumbnail {
+        Some(thumb_path) => match load_thumbnail(thumb_path.clone()).await {
+            Ok(bytes) => Some(bytes),
+            Err(e) => return Err(format!("Cannot use thumbnail {:?}: {}", thumb_path, e)),
+        },
+        None => None,
+    };
+
+    if let Some(dir) = &args.save_thumbnails
+        && let Err(e) = std::fs::create_dir_all(dir)
+    {
+        return Err(format!(
+            "Cannot create thumbnail directory {:?}: {}",
+            dir, e
+        ));
+    }
+    if let Some(path) = &args.prepend_media {
+        let ext = file_ext(path);
+        if !path.is_file()
+            || !(IMAGE_EXTENSIONS.contains(&ext.as_str())
+                || VIDEO_EXTENSIONS.contains(&ext.as_str()))
+        {
+            return Err(format!(
+                "--prepend-media {:?} is not a photo or video file",
+                path
+            ));
+        }
+    }
+
+    Ok(Setup {
+        settings,
+        bot_token,
+        bot_url,
+        chat_id,
+        secondary_chats,
+        primary_quality,
+        qualities,
+        primary_lang,
+        caption_langs,
+        photo_preset,
+        streaming,
+        concurrency,
+        gif_to_mp4,
+        keyboard,
+        dedupe,
+        batch_thumbnail,
+        ledger: !args.validate,
+    })
+}
+
+// Streaming: CLI > config > on
+fn streaming(args: &Cli, settings: &Settings) -> StreamingMode {
+    match (args.streaming, args.no_streaming) {
+        (Some(mode), _) => mode,
+        (None, true) => StreamingMode::Off,
+        (None, false) if settings.supports_streaming => StreamingMode::On,
+        (None, false) => StreamingMode::Off,
+    }
+}
+
+fn gif_to_mp4(args: &Cli) -> bool {
+    args.as_animation && !args.no_gif_to_mp4 && {
+        let installed = ffmpeg_installed();
+        if !installed && args.gif_to_mp4 {
+            log::warn!(
+                "--gif-to-mp4 needs ffmpeg, which was not found; GIFs are uploaded as they are"
+            );
+        }
+        installed
+    }
+}
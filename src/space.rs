@@ -0,0 +1,23 @@
+// ---------------------------
+// Temp space checks
+// ---------------------------
+
+use std::path::Path;
+
+/// How much more temp space than the estimated copies take is asked for,
+/// since encoder output sizes are only a guess
+pub const SAFETY_FACTOR: f64 = 1.5;
+
+/// Bytes an unprivileged process can still write to the filesystem of `dir`.
+#[cfg(unix)]
+pub fn free_bytes(dir: &Path) -> Result<u64, String> {
+    let stat =
+        nix::sys::statvfs::statvfs(dir).map_err(|e| format!("cannot statvfs {:?}: {}", dir, e))?;
+    #[allow(clippy::useless_conversion)]
+    Ok(u64::from(stat.blocks_available()) * u64::from(stat.fragment_size()))
+}
+
+#[cfg(not(unix))]
+pub fn free_bytes(_dir: &Path) -> Result<u64, String> {
+    Err("free space is only known on Unix".to_string())
+}
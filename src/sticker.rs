@@ -0,0 +1,90 @@
+// ---------------------------
+// Animated sticker validation
+// ---------------------------
+
+use flate2::read::GzDecoder;
+use std::io::Read;
+use std::path::Path;
+
+// Limits from https://core.telegram.org/stickers#animated-stickers
+const TGS_MAX_BYTES: u64 = 64 * 1024;
+const WEBM_MAX_BYTES: u64 = 256 * 1024;
+const STICKER_SIDE: u16 = 512;
+const MAX_SECONDS: f64 = 3.0;
+
+pub const EXTENSIONS: [&str; 2] = ["tgs", "webm"];
+
+/// Checks the constraints Telegram enforces on animated stickers, returning
+/// a description of the first violation.
+pub async fn validate(path: &Path, ext: &str) -> Result<(), String> {
+    let size = tokio::fs::metadata(path)
+        .await
+        .map_err(|e| e.to_string())?
+        .len();
+    match ext {
+        "tgs" => {
+            if size > TGS_MAX_BYTES {
+                return Err(format!("{} bytes, limit is {}", size, TGS_MAX_BYTES));
+            }
+            let path = path.to_path_buf();
+            tokio::task::spawn_blocking(move || validate_tgs(&path))
+                .await
+                .map_err(|e| e.to_string())?
+        }
+        _ => {
+            if size > WEBM_MAX_BYTES {
+                return Err(format!("{} bytes, limit is {}", size, WEBM_MAX_BYTES));
+            }
+            let probed = crate::get_video_metadata(path.display().to_string()).await?;
+            match (probed.width, probed.height) {
+                (Some(w), Some(h)) => check_sides(w, h)?,
+                _ => log::warn!("Could not probe dimensions of {:?}", path),
+            }
+            if let Some(d) = probed.duration
+                && f64::from(d) > MAX_SECONDS
+            {
+                return Err(format!("{}s long, limit is {}s", d, MAX_SECONDS));
+            }
+            Ok(())
+        }
+    }
+}
+
+fn check_sides(width: u16, height: u16) -> Result<(), String> {
+    if width.max(height) != STICKER_SIDE || width.min(height) > STICKER_SIDE {
+        return Err(format!(
+            "{}x{}, one side must be {} px and the other at most {} px",
+            width, height, STICKER_SIDE, STICKER_SIDE
+        ));
+    }
+    Ok(())
+}
+
+// A .tgs file is a gzipped Lottie JSON document.
+fn validate_tgs(path: &Path) -> Result<(), String> {
+    let file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+    let mut json = String::new();
+    GzDecoder::new(file)
+        .read_to_string(&mut json)
+        .map_err(|e| format!("not a gzipped Lottie file ({})", e))?;
+    let lottie: serde_json::Value =
+        serde_json::from_str(&json).map_err(|e| format!("invalid Lottie JSON ({})", e))?;
+
+    let number = |key: &str| lottie.get(key).and_then(|v| v.as_f64());
+    if number("w") != Some(512.0) || number("h") != Some(512.0) {
+        return Err("Lottie canvas must be 512x512".to_string());
+    }
+    if number("fr") != Some(60.0) {
+        return Err("Lottie frame rate must be 60 fps".to_string());
+    }
+    if let (Some(ip), Some(op)) = (number("ip"), number("op"))
+        && (op - ip) / 60.0 > MAX_SECONDS
+    {
+        return Err(format!(
+            "{:.1}s long, limit is {}s",
+            (op - ip) / 60.0,
+            MAX_SECONDS
+        ));
+    }
+    Ok(())
+}
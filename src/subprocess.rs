@@ -0,0 +1,238 @@
+// ---------------------------
+// ffmpeg/ffprobe with timeouts
+// ---------------------------
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Default limit for ffprobe and other quick reads of a file
+const PROBE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Default limit for thumbnails, remuxes and transcodes
+const TRANSCODE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How long kill_all waits for killed children to exit
+const KILL_WAIT: Duration = Duration::from_secs(2);
+
+// subprocess_timeout_secs from the config, replacing both defaults
+static TIMEOUT: OnceLock<Option<Duration>> = OnceLock::new();
+
+// Children still running, by pid, with the file each one writes
+static RUNNING: Mutex<Option<HashMap<u32, Option<PathBuf>>>> = Mutex::new(None);
+
+// Set by shutdown; no new children are started afterwards
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// What a subprocess does, which picks its default timeout
+#[derive(Clone, Copy)]
+pub enum Work {
+    Probe,
+    Transcode,
+}
+
+pub fn set_timeout(secs: Option<u64>) {
+    let _ = TIMEOUT.set(secs.map(Duration::from_secs));
+}
+
+fn timeout(work: Work) -> Duration {
+    TIMEOUT.get().copied().flatten().unwrap_or(match work {
+        Work::Probe => PROBE_TIMEOUT,
+        Work::Transcode => TRANSCODE_TIMEOUT,
+    })
+}
+
+/// Runs `command` on `file` like `Command::output`, killing its process
+/// group when it outlives its timeout. That case is logged and returned as
+/// an error of kind `TimedOut`; stdin is always closed. `writes` is the
+/// output file, which kill_all removes if the run is cut short.
+pub fn output(
+    command: &mut Command,
+    work: Work,
+    file: &Path,
+    writes: Option<&Path>,
+) -> std::io::Result<Output> {
+    // Its own process group, so anything it starts is killed along with it
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(command, 0);
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // Spawned under the lock so shutdown cannot miss it
+    let mut child = running(|children| {
+        if STOPPING.load(Ordering::SeqCst) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Interrupted,
+                "shutting down",
+            ));
+        }
+        let child = command.spawn()?;
+        children.insert(child.id(), writes.map(Path::to_path_buf));
+        Ok(child)
+    })?;
+    // Drained while it runs so a full pipe cannot stall it
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+    let pid = child.id();
+    let status = wait(&mut child, command, work, file);
+    running(|children| children.remove(&pid));
+    let status = status?;
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+fn wait(
+    child: &mut Child,
+    command: &Command,
+    work: Work,
+    file: &Path,
+) -> std::io::Result<std::process::ExitStatus> {
+    let limit = timeout(work);
+    let started = Instant::now();
+    let mut pause = Duration::from_millis(5);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if started.elapsed() >= limit {
+            let program = command.get_program().to_string_lossy().into_owned();
+            log::warn!(
+                "{} ran for over {}s on {:?}; killing it",
+                program,
+                limit.as_secs(),
+                file
+            );
+            kill(child);
+            let _ = child.wait();
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("{} timed out after {}s", program, limit.as_secs()),
+            ));
+        }
+        std::thread::sleep(pause);
+        pause = (pause * 2).min(Duration::from_millis(100));
+    }
+}
+
+fn running<T>(f: impl FnOnce(&mut HashMap<u32, Option<PathBuf>>) -> T) -> T {
+    f(RUNNING.lock().unwrap().get_or_insert_with(HashMap::new))
+}
+
+/// Like kill_all, for when the process is about to exit: children the
+/// other threads try to start from then on fail right away.
+pub fn shutdown() {
+    STOPPING.store(true, Ordering::SeqCst);
+    kill_all();
+}
+
+/// Kills every child still running, waits briefly for them to exit and
+/// removes the files they were writing. Called on every way out of a run.
+pub fn kill_all() {
+    let children = running(|children| children.clone());
+    if children.is_empty() {
+        return;
+    }
+    log::warn!(
+        "Stopping {} running ffmpeg/ffprobe process(es)",
+        children.len()
+    );
+    for &pid in children.keys() {
+        kill_group(pid);
+    }
+    // The threads waiting on them reap them and take them off the list
+    let started = Instant::now();
+    while started.elapsed() < KILL_WAIT && running(|children| !children.is_empty()) {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    for partial in children.into_values().flatten() {
+        if std::fs::remove_file(&partial).is_ok() {
+            log::debug!("Removed partial output {:?}", partial);
+        }
+    }
+}
+
+fn drain(pipe: Option<impl Read + Send + 'static>) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        bytes
+    })
+}
+
+fn kill(child: &mut Child) {
+    if !kill_group(child.id()) {
+        let _ = child.kill();
+    }
+}
+
+// Kills the process group `pid` leads; false where there are none
+#[cfg(unix)]
+fn kill_group(pid: u32) -> bool {
+    use nix::sys::signal::{Signal, killpg};
+    use nix::unistd::Pid;
+    killpg(Pid::from_raw(pid as i32), Signal::SIGKILL).is_ok()
+}
+
+#[cfg(not(unix))]
+fn kill_group(_pid: u32) -> bool {
+    false
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shutdown_reaps_children_and_removes_their_output() {
+        let partial =
+            std::env::temp_dir().join(format!("telegoy-partial-{}.mp4", uuid::Uuid::new_v4()));
+        std::fs::write(&partial, b"half a video").unwrap();
+        let writes = partial.clone();
+        let started = Instant::now();
+        let run = std::thread::spawn(move || {
+            output(
+                Command::new("sleep").arg("30"),
+                Work::Transcode,
+                Path::new("input.mp4"),
+                Some(&writes),
+            )
+        });
+        let pid = loop {
+            if let Some(pid) = running(|children| children.keys().next().copied()) {
+                break pid;
+            }
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "sleep never started"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        };
+
+        shutdown();
+        let killed = run.join().unwrap().unwrap();
+        assert!(!killed.status.success());
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(running(|children| children.is_empty()));
+        assert!(!partial.exists());
+        // Reaped, not left as a zombie of the test process
+        assert!(!Path::new(&format!("/proc/{}", pid)).exists());
+
+        let late = output(
+            &mut Command::new("true"),
+            Work::Probe,
+            Path::new("input.mp4"),
+            None,
+        );
+        assert_eq!(late.unwrap_err().kind(), std::io::ErrorKind::Interrupted);
+    }
+}
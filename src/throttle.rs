@@ -0,0 +1,195 @@
+// ---------------------------
+// Upload bandwidth limits
+// ---------------------------
+
+use crate::digest;
+use crate::plan::format_bytes;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+use std::time::{Duration, Instant};
+use teloxide::types::InputFile;
+use tokio::io::{AsyncRead, ReadBuf};
+
+// Largest read handed to the upload at once, so waits stay short
+const MAX_READ: usize = 64 * 1024;
+
+/// Token bucket refilled at `rate` bytes per second, holding at most one
+/// second worth of bytes so idle time does not turn into a burst.
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Bucket {
+            rate: rate as f64,
+            tokens: 0.0,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) -> f64 {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+        self.tokens
+    }
+
+    // Time until `bytes` tokens are available
+    fn wait(&self, bytes: f64) -> Duration {
+        Duration::from_secs_f64(((bytes - self.tokens) / self.rate).max(0.001))
+    }
+}
+
+/// --limit-rate (shared by every upload) and --limit-rate-per-file; when
+/// both are set the stricter one applies at any moment.
+#[derive(Clone, Default)]
+pub struct Limits {
+    total: Option<(u64, Arc<Mutex<Bucket>>)>,
+    per_file: Option<u64>,
+}
+
+impl Limits {
+    pub fn new(total: Option<u64>, per_file: Option<u64>) -> Self {
+        Limits {
+            total: total.map(|rate| (rate, Arc::new(Mutex::new(Bucket::new(rate))))),
+            per_file,
+        }
+    }
+
+    /// The configured limits, e.g. "2.0 MB/s per file, 10.0 MB/s total".
+    pub fn describe(&self) -> Option<String> {
+        let mut parts = Vec::new();
+        if let Some(rate) = self.per_file {
+            parts.push(format!("{}/s per file", format_bytes(rate)));
+        }
+        if let Some((rate, _)) = &self.total {
+            parts.push(format!("{}/s total", format_bytes(*rate)));
+        }
+        (!parts.is_empty()).then(|| parts.join(", "))
+    }
+
+    /// A fresh upload of `path` read at the limited rate, and hashed as it
+    /// is read (see `digest::streamed`). Every send needs its own: a reader
+    /// shared between requests is buffered in memory first.
+    pub fn input_file(&self, path: &Path) -> InputFile {
+        let name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        InputFile::read(Throttled {
+            path: path.to_path_buf(),
+            file: None,
+            own: self.per_file.map(Bucket::new),
+            total: self.total.as_ref().map(|(_, bucket)| bucket.clone()),
+            sleep: None,
+            started: Instant::now(),
+            read: 0,
+            hash: Some(digest::Streaming::default()),
+        })
+        .file_name(name)
+    }
+}
+
+/// Reads a file no faster than its limits allow, if any, hashing it on the
+/// way. The file is opened on the
+/// first read, so building many uploads up front holds no descriptors.
+struct Throttled {
+    path: PathBuf,
+    file: Option<tokio::fs::File>,
+    own: Option<Bucket>,
+    total: Option<Arc<Mutex<Bucket>>>,
+    sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+    started: Instant,
+    read: u64,
+    // Taken when the end of the file is reached
+    hash: Option<digest::Streaming>,
+}
+
+impl AsyncRead for Throttled {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        if this.file.is_none() {
+            this.file = Some(tokio::fs::File::from_std(std::fs::File::open(&this.path)?));
+            this.started = Instant::now();
+        }
+        loop {
+            if let Some(sleep) = &mut this.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
+            // Reads wait for a whole chunk rather than trickling out a few
+            // bytes at a time
+            let mut wanted = buf.remaining().min(MAX_READ) as f64;
+            let mut allowed = wanted;
+            if let Some(own) = &mut this.own {
+                wanted = wanted.min(own.rate);
+                allowed = allowed.min(own.refill());
+            }
+            let mut total = this.total.as_ref().map(|total| total.lock().unwrap());
+            if let Some(total) = &mut total {
+                wanted = wanted.min(total.rate);
+                allowed = allowed.min(total.refill());
+            }
+            let wait = [this.own.as_ref(), total.as_deref()]
+                .into_iter()
+                .flatten()
+                .map(|bucket| bucket.wait(wanted))
+                .max()
+                .unwrap_or_default();
+            drop(total);
+            if allowed >= wanted {
+                let limit = allowed as usize;
+                let mut part = ReadBuf::new(buf.initialize_unfilled_to(limit));
+                let file = this.file.as_mut().expect("opened above");
+                ready!(Pin::new(file).poll_read(cx, &mut part))?;
+                let n = part.filled().len();
+                if let Some(hash) = &mut this.hash {
+                    hash.update(part.filled());
+                }
+                buf.advance(n);
+                this.take(n);
+                return Poll::Ready(Ok(()));
+            }
+            this.sleep = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+    }
+}
+
+impl Throttled {
+    fn take(&mut self, n: usize) {
+        if let Some(own) = &mut self.own {
+            own.tokens -= n as f64;
+        }
+        if let Some(total) = &self.total {
+            total.lock().unwrap().tokens -= n as f64;
+        }
+        self.read += n as u64;
+        if n == 0
+            && let Some(hash) = self.hash.take()
+        {
+            hash.finish_upload(&self.path);
+            let seconds = self.started.elapsed().as_secs_f64().max(0.001);
+            log::info!(
+                "Uploaded {:?}: {} at {}/s",
+                self.path,
+                format_bytes(self.read),
+                format_bytes((self.read as f64 / seconds) as u64)
+            );
+        }
+    }
+}
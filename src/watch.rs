@@ -0,0 +1,336 @@
+// ---------------------------
+// Watch mode
+// ---------------------------
+
+use notify::Watcher as _;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
+use walkdir::WalkDir;
+
+/// Time a new file's size and modification time must stay the same before
+/// it is sent, so files still being copied in are not picked up half-written
+const SETTLE: Duration = Duration::from_secs(3);
+
+// Longest wait for changes when nothing is pending
+const IDLE: Duration = Duration::from_secs(60);
+
+/// How --watch notices new files
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum WatchBackend {
+    /// Filesystem events (inotify, FSEvents, ...), reacting at once
+    Notify,
+    /// Rescan the directories every --poll-interval, for mounts such as
+    /// NFS or SMB where changes made elsewhere raise no events
+    Poll,
+}
+
+/// Something that reports paths that may have appeared or changed
+trait Backend: Send {
+    /// Waits at most `timeout` and returns the paths seen changing.
+    fn wait(&mut self, timeout: Duration) -> Vec<PathBuf>;
+}
+
+// Filesystem events through the notify crate
+struct Events {
+    // Stops watching when dropped
+    _watcher: notify::RecommendedWatcher,
+    events: mpsc::Receiver<notify::Result<notify::Event>>,
+}
+
+impl Backend for Events {
+    fn wait(&mut self, timeout: Duration) -> Vec<PathBuf> {
+        let first = match self.events.recv_timeout(timeout) {
+            Ok(event) => event,
+            Err(mpsc::RecvTimeoutError::Timeout) => return Vec::new(),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                std::thread::sleep(timeout);
+                return Vec::new();
+            }
+        };
+        let mut paths = Vec::new();
+        for event in std::iter::once(first).chain(self.events.try_iter()) {
+            match event {
+                Ok(event) => paths.extend(event.paths),
+                Err(e) => log::warn!("Watch error: {}", e),
+            }
+        }
+        paths
+    }
+}
+
+// Periodic listing of the directories
+struct Scan {
+    roots: Vec<PathBuf>,
+    recursive: bool,
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+impl Backend for Scan {
+    fn wait(&mut self, timeout: Duration) -> Vec<PathBuf> {
+        let due = self.last.map_or(Duration::ZERO, |last| {
+            self.interval.saturating_sub(last.elapsed())
+        });
+        if due > timeout {
+            std::thread::sleep(timeout);
+            return Vec::new();
+        }
+        std::thread::sleep(due);
+        self.last = Some(Instant::now());
+        let depth = if self.recursive { usize::MAX } else { 1 };
+        self.roots
+            .iter()
+            .flat_map(|root| WalkDir::new(root).min_depth(1).max_depth(depth))
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.into_path())
+            .collect()
+    }
+}
+
+/// How settled files are gathered into one batch (and so one album)
+#[derive(Clone, Copy, Debug)]
+pub struct Grouping {
+    /// Quiet time after the last new or changed file before the batch is
+    /// handed out
+    pub window: Duration,
+    /// Longest a settled file waits for the batch to close, however busy
+    /// the directories stay
+    pub max_wait: Duration,
+}
+
+/// New media files in the watched directories, each handed out once after
+/// it stopped changing; the backend only decides how changes are noticed.
+pub struct DirWatcher {
+    backend: Box<dyn Backend>,
+    grouping: Grouping,
+    // Files already sent or handed out, canonicalized
+    seen: HashSet<PathBuf>,
+    // New files waiting to settle: last (size, mtime) and when it last changed
+    pending: HashMap<PathBuf, (Option<(u64, SystemTime)>, Instant)>,
+    // Settled files of the batch being gathered, and when the first settled
+    batch: Vec<PathBuf>,
+    batch_since: Option<Instant>,
+    // When a file last appeared or changed
+    last_activity: Instant,
+    // Sends in a row that failed, of the files handed out again for that
+    failures: HashMap<PathBuf, u32>,
+}
+
+impl DirWatcher {
+    pub fn new(
+        backend: WatchBackend,
+        roots: &[PathBuf],
+        recursive: bool,
+        poll_interval: Duration,
+        grouping: Grouping,
+    ) -> Result<Self, String> {
+        let backend: Box<dyn Backend> = match backend {
+            WatchBackend::Notify => {
+                let (sender, events) = mpsc::channel();
+                let mut watcher = notify::recommended_watcher(sender).map_err(|e| e.to_string())?;
+                let mode = if recursive {
+                    notify::RecursiveMode::Recursive
+                } else {
+                    notify::RecursiveMode::NonRecursive
+                };
+                for root in roots {
+                    watcher
+                        .watch(root, mode)
+                        .map_err(|e| format!("cannot watch {:?}: {}", root, e))?;
+                }
+                Box::new(Events {
+                    _watcher: watcher,
+                    events,
+                })
+            }
+            WatchBackend::Poll => Box::new(Scan {
+                roots: roots.to_vec(),
+                recursive,
+                interval: poll_interval,
+                last: None,
+            }),
+        };
+        Ok(DirWatcher {
+            backend,
+            grouping,
+            seen: HashSet::new(),
+            pending: HashMap::new(),
+            batch: Vec::new(),
+            batch_since: None,
+            last_activity: Instant::now(),
+            failures: HashMap::new(),
+        })
+    }
+
+    /// Records files that were sent some other way so they are not handed out.
+    pub fn mark_seen(&mut self, files: &[PathBuf]) {
+        self.seen.extend(files.iter().map(|path| canonical(path)));
+    }
+
+    /// Counts a failed send of `path` and hands it out again once it has
+    /// settled; returns how many sends of it failed in a row.
+    pub fn failed(&mut self, path: &Path) -> u32 {
+        let path = canonical(path);
+        self.pending.insert(path.clone(), (None, Instant::now()));
+        let failures = self.failures.entry(path).or_default();
+        *failures += 1;
+        *failures
+    }
+
+    /// Records that `path` was sent (or skipped), ending its run of failures.
+    pub fn succeeded(&mut self, path: &Path) {
+        self.failures.remove(&canonical(path));
+    }
+
+    /// Stops tracking `path` before it is moved away, so it counts as new
+    /// should it come back.
+    pub fn forget(&mut self, path: &Path) {
+        let path = canonical(path);
+        self.seen.remove(&path);
+        self.pending.remove(&path);
+        self.failures.remove(&path);
+    }
+
+    /// Blocks until at least one new media file has settled and the
+    /// directories stayed quiet for the grouping window (or the batch hit
+    /// its max wait), and returns the settled files in name order.
+    pub fn next_batch(&mut self) -> Vec<PathBuf> {
+        loop {
+            for path in self.backend.wait(self.timeout()) {
+                let path = canonical(&path);
+                if self.seen.contains(&path) || !is_candidate(&path) {
+                    continue;
+                }
+                self.pending.entry(path).or_insert_with(|| {
+                    self.last_activity = Instant::now();
+                    (None, Instant::now())
+                });
+            }
+            let mut ready = Vec::new();
+            let mut changed = false;
+            self.pending.retain(|path, (last, since)| {
+                let Some(current) = stat(path) else {
+                    // Removed (or renamed) before it settled
+                    return false;
+                };
+                if *last != Some(current) {
+                    changed |= last.is_some();
+                    (*last, *since) = (Some(current), Instant::now());
+                    true
+                } else if since.elapsed() >= SETTLE {
+                    ready.push(path.clone());
+                    false
+                } else {
+                    true
+                }
+            });
+            if changed {
+                self.last_activity = Instant::now();
+            }
+            if !ready.is_empty() {
+                self.seen.extend(ready.iter().cloned());
+                self.batch.extend(ready);
+                self.batch_since.get_or_insert_with(Instant::now);
+            }
+            let quiet =
+                self.pending.is_empty() && self.last_activity.elapsed() >= self.grouping.window;
+            let overdue = self
+                .batch_since
+                .is_some_and(|since| since.elapsed() >= self.grouping.max_wait);
+            if !self.batch.is_empty() && (quiet || overdue) {
+                if overdue && !quiet {
+                    log::info!(
+                        "Sending {} file(s) after waiting {} for the directories to go quiet",
+                        self.batch.len(),
+                        humantime::format_duration(self.grouping.max_wait)
+                    );
+                }
+                self.batch_since = None;
+                let mut batch = std::mem::take(&mut self.batch);
+                batch.sort();
+                return batch;
+            }
+        }
+    }
+
+    // How long to wait for changes before looking at the files again
+    fn timeout(&self) -> Duration {
+        let mut timeout = if self.pending.is_empty() {
+            IDLE
+        } else {
+            SETTLE
+        };
+        if !self.batch.is_empty() {
+            let window = self
+                .grouping
+                .window
+                .saturating_sub(self.last_activity.elapsed());
+            timeout = timeout.min(window);
+            if let Some(since) = self.batch_since {
+                timeout = timeout.min(self.grouping.max_wait.saturating_sub(since.elapsed()));
+            }
+        }
+        // Backends return early on changes; this only keeps a zero timeout
+        // from spinning
+        timeout.max(Duration::from_millis(10))
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn is_candidate(path: &Path) -> bool {
+    path.is_file()
+        && crate::is_media_ext(&crate::file_ext(path))
+        && !crate::albums::is_thumb_sidecar(path)
+}
+
+fn stat(path: &Path) -> Option<(u64, SystemTime)> {
+    let meta = std::fs::metadata(path).ok()?;
+    Some((meta.len(), meta.modified().ok()?))
+}
+
+/// Why `backend` will likely miss changes in `dir`, if it would: events
+/// are not raised for changes made by other hosts on network filesystems.
+pub fn check_backend(dir: &Path, backend: WatchBackend) -> Result<Option<String>, String> {
+    let Some(fs) = network_fs(dir)? else {
+        return Ok(None);
+    };
+    Ok((backend == WatchBackend::Notify).then(|| {
+        format!(
+            "{:?} is on {}, where filesystem events may not fire; use --watch-backend poll",
+            dir, fs
+        )
+    }))
+}
+
+// Name of the network filesystem `dir` is on, from the statfs type
+#[cfg(target_os = "linux")]
+fn network_fs(dir: &Path) -> Result<Option<&'static str>, String> {
+    use nix::sys::statfs::{self, FsType};
+    // Not all of these have a constant in nix
+    const CIFS: FsType = FsType(0xFF53_4D42);
+    const SMB2: FsType = FsType(0xFE53_4D42);
+    const NINE_P: FsType = FsType(0x0102_1997);
+    let fs_type = statfs::statfs(dir)
+        .map_err(|e| format!("cannot statfs {:?}: {}", dir, e))?
+        .filesystem_type();
+    Ok(match fs_type {
+        statfs::NFS_SUPER_MAGIC => Some("NFS"),
+        statfs::SMB_SUPER_MAGIC | CIFS | SMB2 => Some("SMB/CIFS"),
+        statfs::FUSE_SUPER_MAGIC => Some("a FUSE mount (e.g. sshfs)"),
+        statfs::AFS_SUPER_MAGIC => Some("AFS"),
+        statfs::CODA_SUPER_MAGIC => Some("Coda"),
+        NINE_P => Some("9p"),
+        _ => None,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn network_fs(_dir: &Path) -> Result<Option<&'static str>, String> {
+    Ok(None)
+}
@@ -0,0 +1,114 @@
+// ---------------------------
+// Watermark overlay
+// ---------------------------
+
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, GenericImageView, ImageFormat, imageops};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+// Side of the square the logo is fitted into, and its distance from the
+// edges, as fractions of the media's shorter edge
+const SIZE: f32 = 0.15;
+const MARGIN: f32 = 0.03;
+
+/// Where --watermark goes on the media
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum Position {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+/// A logo composited onto copies of photos and videos before upload
+#[derive(Clone, Debug)]
+pub struct Watermark {
+    pub logo: PathBuf,
+    pub position: Position,
+    pub opacity: f32,
+}
+
+impl Watermark {
+    /// Artifact cache kind of media carrying this watermark. It covers the
+    /// logo file too, so replacing the logo makes new copies.
+    pub fn kind(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        std::fs::canonicalize(&self.logo)
+            .unwrap_or_else(|_| self.logo.clone())
+            .hash(&mut hasher);
+        if let Ok(meta) = std::fs::metadata(&self.logo) {
+            (meta.len(), meta.modified().ok()).hash(&mut hasher);
+        }
+        (self.position, self.opacity.to_bits()).hash(&mut hasher);
+        format!("watermark-{:08x}", hasher.finish() as u32)
+    }
+
+    /// Writes `photo` with the logo on it to `dest`, as PNG when `dest` is
+    /// a .png and as JPEG otherwise.
+    pub fn photo(&self, photo: &Path, dest: &Path) -> Result<(), String> {
+        let img = crate::albums::open_upright(photo)?;
+        let logo =
+            image::open(&self.logo).map_err(|e| format!("cannot read {:?}: {}", self.logo, e))?;
+        let side = ((img.width().min(img.height()) as f32 * SIZE) as u32).max(1);
+        let mut logo = logo
+            .resize(side, side, imageops::FilterType::Lanczos3)
+            .into_rgba8();
+        for pixel in logo.pixels_mut() {
+            pixel[3] = (f32::from(pixel[3]) * self.opacity).round() as u8;
+        }
+        let (x, y) = self.offset(img.dimensions(), logo.dimensions());
+        let mut canvas = img.into_rgba8();
+        imageops::overlay(&mut canvas, &logo, x, y);
+
+        let marked = DynamicImage::ImageRgba8(canvas);
+        if dest.extension().is_some_and(|ext| ext == "png") {
+            marked.save_with_format(dest, ImageFormat::Png)
+        } else {
+            let file = std::fs::File::create(dest).map_err(|e| e.to_string())?;
+            marked
+                .into_rgb8()
+                .write_with_encoder(JpegEncoder::new_with_quality(file, 95))
+        }
+        .map_err(|e| e.to_string())
+    }
+
+    // Top-left corner of a `logo`-sized watermark on an image of `size`
+    fn offset(&self, size: (u32, u32), logo: (u32, u32)) -> (i64, i64) {
+        let margin = (size.0.min(size.1) as f32 * MARGIN) as i64;
+        let free_x = i64::from(size.0) - i64::from(logo.0);
+        let free_y = i64::from(size.1) - i64::from(logo.1);
+        let x = match self.position {
+            Position::TopLeft | Position::BottomLeft => margin,
+            Position::TopRight | Position::BottomRight => free_x - margin,
+            Position::Center => free_x / 2,
+        };
+        let y = match self.position {
+            Position::TopLeft | Position::TopRight => margin,
+            Position::BottomLeft | Position::BottomRight => free_y - margin,
+            Position::Center => free_y / 2,
+        };
+        (x, y)
+    }
+
+    /// ffmpeg filtergraph putting input 1 (the logo) onto the video of
+    /// input 0, scaled and placed the way `photo` does it, as `[out]`.
+    pub fn filter(&self) -> String {
+        let side = format!("min(main_w,main_h)*{}", SIZE);
+        let margin = format!("min(W,H)*{}", MARGIN);
+        let (x, y) = match self.position {
+            Position::TopLeft => (margin.clone(), margin),
+            Position::TopRight => (format!("W-w-{}", margin), margin),
+            Position::BottomLeft => (margin.clone(), format!("H-h-{}", margin)),
+            Position::BottomRight => (format!("W-w-{}", margin), format!("H-h-{}", margin)),
+            Position::Center => ("(W-w)/2".to_string(), "(H-h)/2".to_string()),
+        };
+        format!(
+            "[1:v][0:v]scale2ref=w='if(gte(iw,ih),{side},-1)':h='if(gte(iw,ih),-1,{side})'[logo][video];\
+             [logo]format=rgba,colorchannelmixer=aa={opacity}[faded];\
+             [video][faded]overlay=x='{x}':y='{y}',format=yuv420p[out]",
+            opacity = self.opacity,
+        )
+    }
+}
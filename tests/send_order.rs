@@ -0,0 +1,262 @@
+// Sends a batch to a mock Bot API server and checks the order the files
+// arrive in, across the media groups --album-size and --max-chunk-bytes
+// split it into.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+// A request as the server saw it: method name and multipart parts (name,
+// file name, content)
+struct Request {
+    method: String,
+    parts: Vec<(String, Option<String>, Vec<u8>)>,
+}
+
+// Answers every request on a port of its own; returns its URL and what it
+// was sent.
+fn mock_server() -> (String, Arc<Mutex<Vec<Request>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let seen = requests.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            answer(stream, &seen);
+        }
+    });
+    (url, requests)
+}
+
+// Recorded before the reply, so it is there once the run is over
+fn answer(mut stream: TcpStream, seen: &Mutex<Vec<Request>>) -> Option<()> {
+    let mut reader = BufReader::new(stream.try_clone().ok()?);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let method = line
+        .split_whitespace()
+        .nth(1)?
+        .rsplit('/')
+        .next()?
+        .to_lowercase();
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).ok()?;
+        let Some((name, value)) = line.trim_end().split_once(':') else {
+            break;
+        };
+        headers.insert(name.to_lowercase(), value.trim().to_string());
+    }
+    let mut body = Vec::new();
+    if headers
+        .get("transfer-encoding")
+        .is_some_and(|v| v.eq_ignore_ascii_case("chunked"))
+    {
+        loop {
+            line.clear();
+            reader.read_line(&mut line).ok()?;
+            let size = usize::from_str_radix(line.trim(), 16).ok()?;
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk).ok()?;
+            body.extend_from_slice(&chunk[..size]);
+            if size == 0 {
+                break;
+            }
+        }
+    } else {
+        let length = headers
+            .get("content-length")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        body.resize(length, 0);
+        reader.read_exact(&mut body).ok()?;
+    }
+    let parts = headers
+        .get("content-type")
+        .and_then(|v| v.split_once("boundary="))
+        .map(|(_, boundary)| multipart(&body, boundary))
+        .unwrap_or_default();
+    let request = Request { method, parts };
+
+    let message = |id: usize| {
+        serde_json::json!({
+            "message_id": id,
+            "date": 0,
+            "chat": {"id": 1, "type": "private", "first_name": "x"},
+            "photo": [{"file_id": "f", "file_unique_id": "u", "width": 2, "height": 2}],
+        })
+    };
+    let result = match request.method.as_str() {
+        "getme" => serde_json::json!({
+            "id": 1, "is_bot": true, "first_name": "b", "username": "bot",
+            "can_join_groups": true, "can_read_all_group_messages": false,
+            "supports_inline_queries": false, "can_connect_to_business": false,
+            "has_main_web_app": false,
+        }),
+        "getchat" => serde_json::json!({
+            "id": 1, "type": "private", "first_name": "x", "accent_color_id": 0,
+            "max_reaction_count": 1,
+            "accepted_gift_types": {
+                "unlimited_gifts": true, "limited_gifts": true,
+                "unique_gifts": true, "premium_subscription": true,
+            },
+        }),
+        "sendmediagroup" => (0..media_order(&request).len()).map(message).collect(),
+        _ => message(0),
+    };
+    seen.lock().unwrap().push(request);
+    let body = serde_json::json!({"ok": true, "result": result}).to_string();
+    let _ = write!(
+        stream,
+        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    Some(())
+}
+
+fn multipart(body: &[u8], boundary: &str) -> Vec<(String, Option<String>, Vec<u8>)> {
+    let delimiter = format!("--{}", boundary.trim_matches('"'));
+    split(body, delimiter.as_bytes())
+        .into_iter()
+        .filter_map(|part| {
+            let end = part.windows(4).position(|w| w == b"\r\n\r\n")?;
+            let head = String::from_utf8_lossy(&part[..end]);
+            let field = |key: &str| {
+                let start = head.find(&format!("{}=\"", key))? + key.len() + 2;
+                Some(head[start..start + head[start..].find('"')?].to_string())
+            };
+            let content = part[end + 4..]
+                .strip_suffix(b"\r\n")
+                .unwrap_or(&part[end + 4..]);
+            Some((field(" name")?, field("filename"), content.to_vec()))
+        })
+        .collect()
+}
+
+fn split<'a>(body: &'a [u8], delimiter: &[u8]) -> Vec<&'a [u8]> {
+    let mut parts = Vec::new();
+    let mut rest = body;
+    while let Some(at) = rest.windows(delimiter.len()).position(|w| w == delimiter) {
+        parts.push(&rest[..at]);
+        rest = &rest[at + delimiter.len()..];
+    }
+    parts
+}
+
+// The file names of a sendMediaGroup's `media` entries, in their order
+fn media_order(request: &Request) -> Vec<String> {
+    let part = |name: &str| request.parts.iter().find(|(n, _, _)| n == name);
+    let Some((_, _, media)) = part("media") else {
+        return Vec::new();
+    };
+    let media: Vec<serde_json::Value> = serde_json::from_slice(media).unwrap();
+    media
+        .iter()
+        .map(|entry| {
+            let attach = entry["media"].as_str().unwrap();
+            let id = attach.strip_prefix("attach://").unwrap();
+            part(id).and_then(|(_, file, _)| file.clone()).unwrap()
+        })
+        .collect()
+}
+
+// The file names of every message sent, in the order they were sent
+fn sent_files(requests: &[Request]) -> Vec<Vec<String>> {
+    requests
+        .iter()
+        .filter(|request| request.method.starts_with("send"))
+        .map(|request| match request.method.as_str() {
+            "sendmediagroup" => media_order(request),
+            _ => request
+                .parts
+                .iter()
+                .filter_map(|(_, file, _)| file.clone())
+                .collect(),
+        })
+        .collect()
+}
+
+// A directory of `count` photos of different sizes, named in input order
+fn batch(count: u32) -> (PathBuf, Vec<String>) {
+    let dir = std::env::temp_dir().join(format!("telegoy-order-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(dir.join("photos")).unwrap();
+    let mut names = Vec::new();
+    for i in 0..count {
+        let name = format!("{:02}.png", i + 1);
+        let side = 2 + i * 3;
+        let photo = image::RgbImage::from_fn(side, side, |x, y| {
+            image::Rgb([(x * 37 + i) as u8, (y * 91) as u8, (x ^ y) as u8])
+        });
+        photo.save(dir.join("photos").join(&name)).unwrap();
+        names.push(name);
+    }
+    (dir, names)
+}
+
+fn send(dir: &Path, url: &str, args: &[&str]) {
+    std::fs::write(
+        dir.join("config.toml"),
+        format!("chat_id = \"1\"\napi_url = \"{}\"\n", url),
+    )
+    .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_telegoy"))
+        .current_dir(dir)
+        .env("TELOXIDE_TOKEN", "123:SECRET")
+        .args([
+            "photos",
+            "--check-order",
+            "--no-cache",
+            "--chunk-delay",
+            "0s",
+        ])
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn album_size_chunks_keep_the_input_order() {
+    let (dir, names) = batch(8);
+    let (url, requests) = mock_server();
+    send(&dir, &url, &["--album-size", "3"]);
+
+    let sent = sent_files(&requests.lock().unwrap());
+    let lengths: Vec<usize> = sent.iter().map(Vec::len).collect();
+    assert_eq!(lengths, [3, 3, 2]);
+    assert_eq!(sent.concat(), names);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn byte_chunks_keep_the_input_order() {
+    let (dir, names) = batch(7);
+    let sizes: Vec<u64> = names
+        .iter()
+        .map(|name| {
+            std::fs::metadata(dir.join("photos").join(name))
+                .unwrap()
+                .len()
+        })
+        .collect();
+    // Room for the two biggest, so every chunk holds at least two
+    let mut sorted = sizes.clone();
+    sorted.sort_unstable();
+    let max_bytes = (sorted[sorted.len() - 1] + sorted[sorted.len() - 2]).to_string();
+    let (url, requests) = mock_server();
+    send(&dir, &url, &["--max-chunk-bytes", &max_bytes]);
+
+    let sent = sent_files(&requests.lock().unwrap());
+    assert!(sent.len() > 1, "not split into chunks: {:?}", sent);
+    assert_eq!(sent.concat(), names);
+    std::fs::remove_dir_all(&dir).unwrap();
+}